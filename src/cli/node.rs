@@ -4,6 +4,7 @@ use super::{
 };
 use crate::cli::party::{
     async_protocol::AsyncProtocol,
+    codec,
     musig2_instance::ProtocolMessage,
     musig2_party::{incoming, Outgoing},
     traits::state_machine::{Msg, StateMachine},
@@ -115,15 +116,18 @@ impl Node {
     }
 
     /// Broadcast messages from running session to other peers
-    /// Only peers that subscribe to the same topic can receive the message
-    pub fn publish_msg(&mut self, msg: Msg<ProtocolMessage>, topic: Topic) {
+    /// Only peers that subscribe to the same topic can receive the message. Nothing is published
+    /// if the message can't be [encoded](codec::encode).
+    pub fn publish_msg(
+        &mut self,
+        msg: Msg<ProtocolMessage>,
+        topic: Topic,
+    ) -> Result<(), codec::CodecError> {
         // Serialize message to data stream
-        let json = serde_json::to_string(&msg).expect("can jsonify response");
+        let bytes = codec::encode(&msg)?;
 
-        self.swarm
-            .behaviour_mut()
-            .floodsub
-            .publish(topic, json.as_bytes());
+        self.swarm.behaviour_mut().floodsub.publish(topic, bytes);
+        Ok(())
     }
 
     /// Call on other peers to complete the musig2 aggregate signature
@@ -214,8 +218,10 @@ impl Node {
                 match event {
                     EventType::AsyncResponse(m) => {
                         let topic = self.swarm.behaviour_mut().options().topic.clone();
-                        self.publish_msg(m, topic.clone());
-                        info!("publish msg succeed, topic: {:?}", topic);
+                        match self.publish_msg(m, topic.clone()) {
+                            Ok(()) => info!("publish msg succeed, topic: {:?}", topic),
+                            Err(err) => error!("publish msg failed: {}", err),
+                        }
                     }
                     // EventType::Response(_resp) => {
                     //     debug!("EventType::Response, has been deprecated")
@@ -255,6 +261,11 @@ impl Node {
     }
 }
 
+/// Frames raw bytes as the 32-byte message the signers sign
+///
+/// No hashing or tagging is applied: a message shorter than 32 bytes is left-padded with
+/// zero bytes, a longer one is truncated to its first 32 bytes. Hash longer messages before
+/// formatting them, otherwise messages sharing a 32-byte prefix get the same signature.
 pub fn format_musig_msg(msg: Vec<u8>) -> Vec<u8> {
     let v = if msg.as_slice().len() < 32 {
         let mut v = vec![0u8; 32 - msg.as_slice().len()];
@@ -265,3 +276,33 @@ pub fn format_musig_msg(msg: Vec<u8>) -> Vec<u8> {
     };
     Vec::from(&v[..32])
 }
+
+/// Recovers the raw bytes framed by [format_musig_msg], i.e. strips the zero padding
+///
+/// Only exact for raw messages of at most 32 bytes which don't start with a zero byte.
+pub fn unformat_musig_msg(msg: &[u8]) -> Vec<u8> {
+    msg.iter().skip_while(|b| **b == 0).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_musig_msg, unformat_musig_msg};
+    use crate::cli::protocals::{
+        key::PrivateKey,
+        musig2::{bip340_sign, verify},
+    };
+    use secp256k1::Message;
+
+    #[test]
+    fn formatted_message_round_trips_and_verifies() {
+        let raw = Vec::from("test".as_bytes());
+        let msg = format_musig_msg(raw.clone());
+        assert_eq!(msg.len(), 32);
+        assert_eq!(unformat_musig_msg(&msg), raw);
+        let msg = Message::parse_slice(&msg).unwrap();
+
+        // Single signer BIP340 signature over the formatted message
+        let (signature, pk) = bip340_sign(&PrivateKey::generate_random().unwrap(), &msg);
+        assert_eq!(verify(&signature, &msg, &pk), Ok(true));
+    }
+}
@@ -0,0 +1,180 @@
+//! Adapter running a state machine over a floodsub swarm.
+use super::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::cli::party::{
+    codec,
+    musig2_party::{incoming, Incoming, Outgoing},
+    traits::state_machine::Msg,
+    ProtocolMessage,
+};
+use futures::StreamExt;
+use libp2p::{
+    floodsub::{Floodsub, FloodsubEvent, Topic},
+    swarm::SwarmEvent,
+    PeerId, Swarm,
+};
+use log::{info, warn};
+use std::collections::{HashSet, VecDeque};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Capacity of the channels between the swarm and the protocol
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Runs `swarm` in the background and returns the incoming stream and outgoing sink to pass to
+/// [AsyncProtocol::new](crate::cli::party::async_protocol::AsyncProtocol::new)
+///
+/// Messages published on `topic` are [decoded](codec::decode) into the incoming stream of party
+/// `me`, the ones over [DEFAULT_MAX_MESSAGE_SIZE] are dropped before decoding. Outgoing messages
+/// are [encoded](codec::encode) and published on `topic`: broadcasts (`receiver: None`) reach
+/// every peer, while floodsub has no direct send, so a message to `Some(party)` reaches every
+/// peer too and the others drop it. Outgoing messages are held back until `peers` peers
+/// subscribed to `topic`, since floodsub doesn't deliver to peers it doesn't know to be
+/// subscribed. The swarm stops once the outgoing sink is dropped.
+pub fn floodsub_adapter(
+    mut swarm: Swarm<Floodsub>,
+    topic: Topic,
+    me: u16,
+    peers: usize,
+) -> (Incoming<ProtocolMessage>, Outgoing<ProtocolMessage>) {
+    let (in_tx, in_rx) = broadcast::channel::<Msg<ProtocolMessage>>(CHANNEL_CAPACITY);
+    let (out_tx, mut out_rx) = broadcast::channel::<Msg<ProtocolMessage>>(CHANNEL_CAPACITY);
+    swarm.behaviour_mut().subscribe(topic.clone());
+
+    tokio::spawn(async move {
+        let mut subscribed: HashSet<PeerId> = HashSet::new();
+        let mut pending = VecDeque::new();
+        loop {
+            tokio::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(FloodsubEvent::Message(msg)) => {
+                        // Reject oversized messages before decoding them
+                        if msg.data.len() > DEFAULT_MAX_MESSAGE_SIZE {
+                            warn!(
+                                "drop message from {:?}: {} bytes exceeds the limit of {} bytes",
+                                msg.source,
+                                msg.data.len(),
+                                DEFAULT_MAX_MESSAGE_SIZE
+                            );
+                            continue;
+                        }
+                        match codec::decode(&msg.data) {
+                            Ok(msg) => {
+                                // No one listening just means the protocol is over
+                                let _ = in_tx.send(msg);
+                            }
+                            Err(err) => warn!("drop undecodable message from {:?}: {}", msg.source, err),
+                        }
+                    }
+                    SwarmEvent::Behaviour(FloodsubEvent::Subscribed { peer_id, topic: t }) if t == topic => {
+                        info!("peer {} subscribed to {:?}", peer_id, topic);
+                        subscribed.insert(peer_id);
+                    }
+                    SwarmEvent::Behaviour(FloodsubEvent::Unsubscribed { peer_id, topic: t }) if t == topic => {
+                        subscribed.remove(&peer_id);
+                    }
+                    _ => (),
+                },
+                msg = out_rx.recv() => match msg {
+                    Ok(msg) => pending.push_back(msg),
+                    Err(RecvError::Lagged(n)) => warn!("{} outgoing messages were lost", n),
+                    Err(RecvError::Closed) => break,
+                },
+            }
+
+            if subscribed.len() >= peers {
+                while let Some(msg) = pending.pop_front() {
+                    match codec::encode(&msg) {
+                        Ok(bytes) => swarm.behaviour_mut().publish(topic.clone(), bytes),
+                        Err(err) => warn!("drop unencodable message: {}", err),
+                    }
+                }
+            }
+        }
+    });
+
+    (incoming(in_rx, me), Outgoing { sender: out_tx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::floodsub_adapter;
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::party::{async_protocol::AsyncProtocol, musig2_instance::Musig2Instance};
+    use crate::cli::protocals::KeyPair;
+    use futures::future::join_all;
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade::Version},
+        floodsub::{Floodsub, Topic},
+        identity,
+        mplex::MplexConfig,
+        noise::{self, NoiseConfig},
+        Multiaddr, PeerId, Swarm, Transport,
+    };
+    use rand::Rng;
+    use std::time::Duration;
+
+    fn memory_swarm() -> (Swarm<Floodsub>, PeerId) {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let xx_keypair = noise::Keypair::<noise::X25519Spec>::new()
+            .into_authentic(&keypair)
+            .unwrap();
+        let transport = MemoryTransport::default()
+            .upgrade(Version::V1)
+            .authenticate(NoiseConfig::xx(xx_keypair).into_authenticated())
+            .multiplex(MplexConfig::new())
+            .boxed();
+        (
+            Swarm::new(transport, Floodsub::new(peer_id), peer_id),
+            peer_id,
+        )
+    }
+
+    #[tokio::test]
+    async fn three_parties_sign_over_memory_transport() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let topic = Topic::new("adapter-test");
+
+        let mut swarms = vec![];
+        let mut addrs: Vec<Multiaddr> = vec![];
+        for _ in 0..3 {
+            let (mut swarm, peer_id) = memory_swarm();
+            let port: u64 = rand::thread_rng().gen_range(1..u64::MAX);
+            let addr: Multiaddr = format!("/memory/{}", port).parse().unwrap();
+            swarm.listen_on(addr.clone()).unwrap();
+            swarms.push((swarm, peer_id));
+            addrs.push(addr);
+        }
+        let peer_ids: Vec<PeerId> = swarms.iter().map(|(_, peer_id)| *peer_id).collect();
+        for (i, (swarm, _)) in swarms.iter_mut().enumerate() {
+            for (j, peer_id) in peer_ids.iter().enumerate() {
+                if i != j {
+                    swarm.behaviour_mut().add_node_to_partial_view(*peer_id);
+                }
+                if i < j {
+                    swarm.dial_addr(addrs[j].clone()).unwrap();
+                }
+            }
+        }
+
+        let runs = swarms.into_iter().enumerate().map(|(i, (swarm, _))| {
+            let party_i = i as u16 + 1;
+            let (incoming, outgoing) = floodsub_adapter(swarm, topic.clone(), party_i, 2);
+            let instance = Musig2Instance::with_fixed_seed(
+                party_i,
+                3,
+                message.clone(),
+                KeyPair::create().unwrap(),
+            );
+            async move { AsyncProtocol::new(instance, incoming, outgoing).run().await }
+        });
+        let results = tokio::time::timeout(Duration::from_secs(30), join_all(runs))
+            .await
+            .expect("protocol didn't complete over the memory transport");
+
+        let results: Vec<_> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert!(results[0].verify());
+        for result in &results[1..] {
+            assert_eq!(result.to_bytes(), results[0].to_bytes());
+        }
+    }
+}
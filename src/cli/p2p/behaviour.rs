@@ -1,13 +1,13 @@
 //! P2P handling for musig2 nodes.
 use super::{CallMessage, SwarmOptions};
-use crate::cli::party::{musig2_instance::ProtocolMessage, traits::state_machine::Msg};
+use crate::cli::party::codec;
 use libp2p::{
     floodsub::{Floodsub, FloodsubEvent},
     mdns::{Mdns, MdnsEvent},
     swarm::NetworkBehaviourEventProcess,
     NetworkBehaviour,
 };
-use log::info;
+use log::{info, warn};
 
 #[derive(NetworkBehaviour)]
 pub struct SignatureBehaviour {
@@ -57,11 +57,22 @@ impl NetworkBehaviourEventProcess<void::Void> for SignatureBehaviour {
 impl NetworkBehaviourEventProcess<FloodsubEvent> for SignatureBehaviour {
     fn inject_event(&mut self, event: FloodsubEvent) {
         if let FloodsubEvent::Message(msg) = event {
+            // Reject oversized messages before decoding them
+            if msg.data.len() > self.options.max_message_size {
+                warn!(
+                    "drop message from {:?}: {} bytes exceeds the limit of {} bytes",
+                    msg.source,
+                    msg.data.len(),
+                    self.options.max_message_size
+                );
+                return;
+            }
+
             // Forward musig2 protocol messages to session
             //
             // If there is more than one session, there should be a layer of filtering
             // since the forwarding destination should be determined.
-            if let Ok(resp) = serde_json::from_slice::<Msg<ProtocolMessage>>(&msg.data) {
+            if let Ok(resp) = codec::decode(&msg.data) {
                 info!("received message form peers");
                 self.options().tx_party.send(resp).unwrap();
             }
@@ -3,12 +3,14 @@ use libp2p::{floodsub::Topic, identity::Keypair, swarm::SwarmBuilder, Multiaddr,
 use std::{env, error::Error, path::PathBuf};
 
 // pub mod addr;
+pub mod adapter;
 pub mod behaviour;
 pub mod msg;
 // pub mod swarm;
 pub mod transport;
 
 // pub use addr::{MultiaddrWithPeerId, MultiaddrWithoutPeerId};
+pub use adapter::floodsub_adapter;
 pub use behaviour::*;
 pub use msg::*;
 pub use transport::build_transport;
@@ -27,6 +29,8 @@ pub type Keyring = KeyPair;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "/ip4/0.0.0.0/tcp/0";
 const DEFAULT_TOPIC: &str = "test";
+/// Messages from peers larger than this are dropped before decoding
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
 
 /// Defines the configuration for an musig2 swarm.
 #[derive(Clone)]
@@ -44,6 +48,8 @@ pub struct SwarmOptions {
     pub listening_addrs: Multiaddr,
     /// Enables mdns for peer discovery and announcement when true.
     pub mdns: bool,
+    /// Maximum size in bytes of a message received from peers.
+    pub max_message_size: usize,
     /// Responsible for transferring the data flow from other parties to the state machine
     pub tx_node: broadcast::Sender<CallMessage>,
     pub tx_party: broadcast::Sender<Msg<ProtocolMessage>>,
@@ -72,6 +78,7 @@ impl SwarmOptions {
             listening_addrs: DEFAULT_LISTENING_ADDRESS.parse().unwrap(),
             // listening_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
             mdns: true,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
         }
     }
 }
@@ -1,19 +1,49 @@
 //! Instruments for executing protocol in async environment
-use super::watcher::{BlindWatcher, ProtocolWatcher, When};
+use super::watcher::{BlindWatcher, CollectingWatcher, NonCriticalEvent, ProtocolWatcher, When};
 use futures::{
-    future::{Either, FutureExt},
+    future::{self, BoxFuture, Either, FutureExt, Shared},
     sink::Sink,
-    stream::{self, FusedStream, Stream, StreamExt},
+    stream::{FusedStream, Stream, StreamExt},
     SinkExt,
 };
 use log::info;
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::{self, Debug},
     future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use tokio::time::{self, timeout_at};
+use tokio::{
+    sync::mpsc,
+    time::{self, timeout_at},
+};
+
+use crate::cli::party::traits::state_machine::{Fragment, IsCritical, Msg, StateMachine};
+
+/// Time given to deliver the queued messages once the protocol is cancelled, see
+/// [AsyncProtocol::drain_on_cancel]
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Length in bytes the reason of a received [abort](AsyncProtocol::set_broadcast_abort) is cut
+/// to
+pub const MAX_ABORT_REASON_LEN: usize = 256;
 
-use crate::cli::party::traits::state_machine::{IsCritical, Msg, StateMachine};
+/// Fragmented messages a sender may have partially received at once, see
+/// [AsyncProtocol::set_max_message_size]
+pub const MAX_FRAGMENTED_PER_SENDER: usize = 4;
+
+/// Fragments a message may be split into, see [AsyncProtocol::set_max_message_size]
+pub const MAX_FRAGMENT_COUNT: u16 = 4096;
+
+/// Bytes of partially received fragmented messages buffered at once, see
+/// [AsyncProtocol::set_max_message_size]
+pub const MAX_FRAGMENT_BYTES: usize = 16 * 1024 * 1024;
 
 /// Executes protocol in async environment using [tokio] backend
 ///
@@ -51,16 +81,38 @@ use crate::cli::party::traits::state_machine::{IsCritical, Msg, StateMachine};
 /// should be ecrypted, authenticated), then stream and sink must meet these assumptions (e.g. encrypt,
 /// authenticate messages)
 #[derive(Clone)]
-pub struct AsyncProtocol<SM, I, O, W = BlindWatcher> {
+pub struct AsyncProtocol<SM: StateMachine, I: Stream, O, W = BlindWatcher> {
     state: Option<SM>,
     incoming: I,
     outgoing: O,
     deadline: Option<time::Instant>,
     current_round: Option<u16>,
     watcher: W,
+    announce_completion: bool,
+    max_incoming_size: Option<usize>,
+    events: Option<Vec<Step>>,
+    send_before_proceed: bool,
+    acks: Option<Acks>,
+    paused: bool,
+    delivery: Option<DeliveryReport>,
+    cancel: Option<Shared<BoxFuture<'static, ()>>>,
+    drain_on_cancel: bool,
+    buffer: Option<IncomingBuffer<I::Item>>,
+    proceed_retries: Option<ProceedRetries>,
+    sequence: Option<SequenceReport>,
+    timings: PhaseTimings,
+    bytes_outgoing: Option<mpsc::Sender<Msg<Vec<u8>>>>,
+    timeout_strategy: Option<Arc<dyn TimeoutStrategy>>,
+    total_deadline: Option<Duration>,
+    total_deadline_at: Option<time::Instant>,
+    retransmit: Option<Retransmit<SM::MessageBody>>,
+    progress: Progress,
+    fragmentation: Option<Fragmentation<SM::MessageBody>>,
+    broadcast_abort: bool,
+    pending: Option<PendingMessages<SM::MessageBody>>,
 }
 
-impl<SM, I, O> AsyncProtocol<SM, I, O, BlindWatcher> {
+impl<SM: StateMachine, I: Stream, O> AsyncProtocol<SM, I, O, BlindWatcher> {
     /// Constructs new protocol executor from initial state, channels of incoming and outgoing
     /// messages
     pub fn new(state: SM, incoming: I, outgoing: O) -> Self {
@@ -71,11 +123,33 @@ impl<SM, I, O> AsyncProtocol<SM, I, O, BlindWatcher> {
             deadline: None,
             current_round: None,
             watcher: BlindWatcher,
+            announce_completion: false,
+            max_incoming_size: None,
+            events: None,
+            send_before_proceed: true,
+            acks: None,
+            paused: false,
+            delivery: None,
+            cancel: None,
+            drain_on_cancel: false,
+            buffer: None,
+            proceed_retries: None,
+            sequence: None,
+            timings: PhaseTimings::default(),
+            bytes_outgoing: None,
+            timeout_strategy: None,
+            total_deadline: None,
+            total_deadline_at: None,
+            retransmit: None,
+            progress: Progress::default(),
+            fragmentation: None,
+            broadcast_abort: false,
+            pending: None,
         }
     }
 }
 
-impl<SM, I, O, W> AsyncProtocol<SM, I, O, W> {
+impl<SM: StateMachine, I: Stream, O, W> AsyncProtocol<SM, I, O, W> {
     /// Sets new protocol watcher
     ///
     /// Protocol watcher looks after protocol execution. See list of observable events in
@@ -84,15 +158,279 @@ impl<SM, I, O, W> AsyncProtocol<SM, I, O, W> {
     /// Default watcher: [BlindWatcher] that does nothing with received events. For development
     /// purposes it's convenient to pick [StderrWatcher](watcher::StderrWatcher).
     pub fn set_watcher<WR>(self, watcher: WR) -> AsyncProtocol<SM, I, O, WR> {
+        self.map_watcher(|_| watcher)
+    }
+
+    /// Replaces the watcher by the one made of it by `f`, keeping everything else
+    fn map_watcher<WR>(self, f: impl FnOnce(W) -> WR) -> AsyncProtocol<SM, I, O, WR> {
         AsyncProtocol {
             state: self.state,
             incoming: self.incoming,
             outgoing: self.outgoing,
             deadline: self.deadline,
             current_round: self.current_round,
-            watcher,
+            watcher: f(self.watcher),
+            announce_completion: self.announce_completion,
+            max_incoming_size: self.max_incoming_size,
+            events: self.events,
+            send_before_proceed: self.send_before_proceed,
+            acks: self.acks,
+            paused: self.paused,
+            delivery: self.delivery,
+            cancel: self.cancel,
+            drain_on_cancel: self.drain_on_cancel,
+            buffer: self.buffer,
+            proceed_retries: self.proceed_retries,
+            sequence: self.sequence,
+            timings: self.timings,
+            bytes_outgoing: self.bytes_outgoing,
+            timeout_strategy: self.timeout_strategy,
+            total_deadline: self.total_deadline,
+            total_deadline_at: self.total_deadline_at,
+            retransmit: self.retransmit,
+            progress: self.progress,
+            fragmentation: self.fragmentation,
+            broadcast_abort: self.broadcast_abort,
+            pending: self.pending,
         }
     }
+
+    /// Broadcasts the [completion message](StateMachine::completion_message) once the protocol
+    /// is finished, so peers learn that it succeeded and can stop promptly
+    pub fn announce_completion(mut self, enable: bool) -> Self {
+        self.announce_completion = enable;
+        self
+    }
+
+    /// Broadcasts the [abort message](StateMachine::abort_message) once the protocol hits a
+    /// critical error, so peers stop promptly instead of waiting for the round timeout
+    ///
+    /// A party receiving an abort message stops with [Error::Aborted] if it enabled this as
+    /// well, otherwise abort messages are ignored. Abort messages aren't authenticated: any peer
+    /// able to send on behalf of a participant can stop the session, so only enable it where
+    /// the transport authenticates the senders. Aborts are honoured from the other participants
+    /// only, and their reason is cut to [MAX_ABORT_REASON_LEN] bytes.
+    pub fn set_broadcast_abort(mut self, enable: bool) -> Self {
+        self.broadcast_abort = enable;
+        self
+    }
+
+    /// Rejects incoming messages whose encoded body is larger than `limit` bytes
+    ///
+    /// Protects the party from peers sending enormous messages. Oversized message aborts the
+    /// protocol with [Error::RecvOversized]. [Fragmented](AsyncProtocol::set_max_message_size)
+    /// messages are checked on the raw bytes received so far, before they're reassembled and
+    /// decoded. Transports decoding the wire bytes themselves should drop oversized frames
+    /// before decoding, like [floodsub_adapter](crate::cli::p2p::floodsub_adapter) does.
+    pub fn set_max_incoming_size(mut self, limit: usize) -> Self {
+        self.max_incoming_size = Some(limit);
+        self
+    }
+
+    /// Reads incoming messages ahead into a buffer of `capacity` messages
+    ///
+    /// Messages which are ready are taken from the transport before waiting for the next one,
+    /// so a burst arriving during a slow [proceed](StateMachine::proceed) is pulled out at once.
+    /// The buffer never holds more than `capacity` messages. Once it's full, a message the
+    /// state machine [still needs](StateMachine::is_message_needed) takes the place of one which
+    /// isn't needed, and `policy` decides about the others. Keep `capacity` at least the number
+    /// of parties, so a full round of needed messages fits.
+    pub fn set_incoming_buffer(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.buffer = Some(IncomingBuffer {
+            items: VecDeque::new(),
+            capacity,
+            policy,
+        });
+        self
+    }
+
+    /// Keeps up to `capacity` messages of a later round aside and handles them again once the
+    /// round changes
+    ///
+    /// A message the state machine rejects as [not for this round](StateMachine::is_wrong_round)
+    /// is otherwise reported as a non-critical error and dropped, so the sender has to
+    /// retransmit it. Once `capacity` messages wait, further ones are dropped as usual.
+    pub fn buffer_early_messages(mut self, capacity: usize) -> Self {
+        self.pending = Some(PendingMessages {
+            capacity,
+            waiting: vec![],
+            ready: VecDeque::new(),
+        });
+        self
+    }
+
+    /// Flushes messages queued while handling an incoming message before proceeding the state
+    ///
+    /// Enabled by default: replies get out to peers without waiting for a possibly expensive
+    /// [proceed](StateMachine::proceed), which lowers their latency at the cost of one more
+    /// send per incoming message. Once disabled, the executor proceeds first and then sends
+    /// everything in the queue at once, so the replies are lost if proceeding fails.
+    pub fn send_before_proceed(mut self, enable: bool) -> Self {
+        self.send_before_proceed = enable;
+        self
+    }
+
+    /// Reads how many receivers every sent message reached from `report`
+    ///
+    /// `report` must be shared with the outgoing sink which [records](DeliveryReport::record)
+    /// the receivers reached for every message, like
+    /// [ConfirmingOutgoing](super::musig2_party::ConfirmingOutgoing). A broadcast message that
+    /// reached nobody is reported to the [watcher](ProtocolWatcher::broadcast_not_delivered).
+    pub fn confirm_delivery(mut self, report: DeliveryReport) -> Self {
+        self.delivery = Some(report);
+        self
+    }
+
+    /// Sends the already queued outgoing messages when [cancelled](AsyncProtocol::run_with_cancel)
+    ///
+    /// Gives peers a chance to receive our last round messages instead of waiting for them
+    /// until the round timeout. Draining is bounded by [DRAIN_TIMEOUT].
+    pub fn drain_on_cancel(mut self, enable: bool) -> Self {
+        self.drain_on_cancel = enable;
+        self
+    }
+
+    /// Reports sequence anomalies recorded in `report` to the
+    /// [watcher](ProtocolWatcher::sequence_gap)
+    ///
+    /// `report` must be shared with the incoming stream which checks the sequence numbers, like
+    /// [SequenceCheck](crate::cli::p2p::transport::SequenceCheck), while the peers stamp their
+    /// messages with [SequencingSink](crate::cli::p2p::transport::SequencingSink).
+    pub fn track_sequence(mut self, report: SequenceReport) -> Self {
+        self.sequence = Some(report);
+        self
+    }
+
+    /// Acknowledges received messages and expects peers to acknowledge ours
+    ///
+    /// If the round timeout is reached while some peer hasn't acknowledged our message, the
+    /// protocol aborts with [Error::MessageNotAcknowledged] rather than
+    /// [Error::HandleIncomingTimeout], i.e. our message was likely lost, not just a peer being
+    /// slow. Costs an extra message per received message, see
+    /// [StateMachine::acknowledgement].
+    pub fn require_acknowledgements(mut self, enable: bool) -> Self {
+        self.acks = if enable { Some(Acks::default()) } else { None };
+        self
+    }
+
+    /// Retries proceeding the round up to `retries` times on non critical errors, sleeping
+    /// `delay` before every retry
+    ///
+    /// Every failure is still reported to the [watcher](ProtocolWatcher::caught_non_critical_error).
+    /// Once the retries are used up, the last error aborts the protocol as [Error::Proceed].
+    /// Retries stop at the round deadline as well: if the next retry would start after it, the
+    /// last error aborts the protocol. By default the executor doesn't retry and carries on
+    /// after a non critical error.
+    pub fn retry_proceed(mut self, retries: u16, delay: Duration) -> Self {
+        self.proceed_retries = Some(ProceedRetries {
+            max: retries,
+            delay,
+        });
+        self
+    }
+
+    /// Forwards outgoing messages already encoded to the wire format into `sender`
+    ///
+    /// Suits byte-oriented transports: messages are taken by
+    /// [message_queue_bytes](StateMachine::message_queue_bytes), so they're serialized once and
+    /// never reach the outgoing sink. Sending waits while the channel is full, so a slow
+    /// transport holds the protocol back instead of piling messages up.
+    /// [Delivery confirmation](AsyncProtocol::confirm_delivery) relies on the sink, thus it
+    /// isn't reported for forwarded messages.
+    pub fn forward_bytes(mut self, sender: mpsc::Sender<Msg<Vec<u8>>>) -> Self {
+        self.bytes_outgoing = Some(sender);
+        self
+    }
+
+    /// Cancels every subsequent execution of the protocol once `cancel` is resolved
+    ///
+    /// Like [run_with_cancel](AsyncProtocol::run_with_cancel), but set up front, e.g. for a
+    /// session that's cancelled when its client disconnects. Besides waiting for incoming
+    /// messages and sending outgoing ones, cancellation is checked before proceeding the
+    /// state. A [proceed](StateMachine::proceed) already running is never interrupted, the
+    /// protocol returns [Error::Cancelled] at the next step instead.
+    pub fn with_cancel<C>(mut self, cancel: C) -> Self
+    where
+        C: Future<Output = ()> + Send + 'static,
+    {
+        self.cancel = Some(cancel.boxed().shared());
+        self
+    }
+
+    /// Waits at most `timeout` for the messages of every round
+    ///
+    /// Takes precedence over the [round timeout](StateMachine::round_timeout) of the state
+    /// machine. Once it's reached, the protocol aborts with [Error::HandleIncomingTimeout].
+    /// Same as a [FixedTimeout] [strategy](AsyncProtocol::set_timeout_strategy).
+    pub fn set_round_timeout(self, timeout: Duration) -> Self {
+        self.set_timeout_strategy(FixedTimeout(timeout))
+    }
+
+    /// Picks the timeout of every round with `strategy`, e.g. [ExponentialBackoff]
+    ///
+    /// Takes precedence over the [round timeout](StateMachine::round_timeout) of the state
+    /// machine, and replaces the timeout [set](AsyncProtocol::set_round_timeout) before.
+    pub fn set_timeout_strategy(mut self, strategy: impl TimeoutStrategy + 'static) -> Self {
+        self.timeout_strategy = Some(Arc::new(strategy));
+        self
+    }
+
+    /// Re-sends our last batch of outgoing messages when the round timeout is reached, up to
+    /// `max_retries` times per round
+    ///
+    /// Recovers from a peer missing our message on a lossy transport: the round deadline is
+    /// reset after every retransmission, and only once the retries are used up the protocol
+    /// aborts with [Error::HandleIncomingTimeout]. Peers must tolerate receiving a message
+    /// twice. Messages [forwarded as bytes](AsyncProtocol::forward_bytes) aren't retransmitted.
+    pub fn set_retransmit(mut self, max_retries: u8) -> Self {
+        self.retransmit = Some(Retransmit {
+            max_retries,
+            retries: 0,
+            last_batch: vec![],
+        });
+        self
+    }
+
+    /// Caps the whole execution at `deadline`, counted from the start of the protocol
+    ///
+    /// Unlike the round timeout, it isn't reset when a round is entered, so a protocol that
+    /// keeps progressing still aborts with [Error::TotalDeadlineExceeded] once it's reached.
+    /// Whichever of the two fires first aborts the protocol.
+    pub fn set_total_deadline(mut self, deadline: Duration) -> Self {
+        self.total_deadline = Some(deadline);
+        self
+    }
+}
+
+impl<SM, I, O, W> AsyncProtocol<SM, I, O, W>
+where
+    SM: StateMachine,
+    SM::MessageBody: DeserializeOwned,
+    I: Stream,
+{
+    /// Splits outgoing messages whose wire encoding exceeds `bytes` into fragments
+    ///
+    /// Suits transports capping the message size, like gossipsub. Fragments are made by
+    /// [fragment_message](StateMachine::fragment_message) and reassembled before the message
+    /// is handled, so every party needs the limit set. A message which can't be split to fit,
+    /// e.g. because the limit doesn't leave room for the fragment header, aborts the protocol
+    /// with [Error::SendOversized]. Messages [forwarded as bytes](AsyncProtocol::forward_bytes)
+    /// aren't split.
+    ///
+    /// Incoming fragments are buffered within bounds: a sender may have at most
+    /// [MAX_FRAGMENTED_PER_SENDER] messages in flight, a message at most [MAX_FRAGMENT_COUNT]
+    /// fragments, and [MAX_FRAGMENT_BYTES] bytes are buffered in total. A message whose
+    /// fragments don't complete within the round they started in and the next one is dropped.
+    pub fn set_max_message_size(mut self, bytes: usize) -> Self {
+        self.fragmentation = Some(Fragmentation {
+            max_size: bytes,
+            next_id: 0,
+            partial: HashMap::new(),
+            buffered: 0,
+            decode: Msg::from_wire_bytes,
+        });
+        self
+    }
 }
 
 impl<SM, I, O, IErr, W> AsyncProtocol<SM, I, O, W>
@@ -100,6 +438,7 @@ where
     SM: StateMachine,
     SM::Err: Send,
     SM: Send + 'static,
+    SM::MessageBody: Serialize + Clone,
     I: Stream<Item = Result<Msg<SM::MessageBody>, IErr>> + FusedStream + Unpin,
     O: Sink<Msg<SM::MessageBody>> + Unpin,
     W: ProtocolWatcher<SM>,
@@ -108,9 +447,181 @@ where
     ///
     /// Returns protocol output or first occurred critical error
     pub async fn run(&mut self) -> Result<SM::Output, Error<SM::Err, IErr, O::Error>> {
-        if self.current_round.is_some() {
+        let result = match self.advance(None).await {
+            Ok(Some(output)) => Ok(output),
+            Ok(None) => unreachable!("executor pauses only when given a target round"),
+            Err(err) => Err(err),
+        };
+        if matches!(result, Err(Error::Cancelled)) && self.drain_on_cancel {
+            // Sending checks for cancellation, which is resolved by now
+            let cancel = self.cancel.take();
+            match time::timeout(DRAIN_TIMEOUT, self.send_outgoing()).await {
+                Ok(Ok(())) => (),
+                Ok(Err(_)) => info!("queued messages weren't drained: send failed"),
+                Err(_) => info!("queued messages weren't drained: timeout reached"),
+            }
+            self.cancel = cancel;
+        }
+        result
+    }
+
+    /// Executes the protocol collecting every caught non critical error
+    ///
+    /// Returns protocol output along with non critical errors in order they were caught, or
+    /// first occurred critical error. The errors are still passed to the watcher as well.
+    pub async fn run_verbose(
+        self,
+    ) -> Result<(SM::Output, Vec<NonCriticalEvent<SM::Err>>), Error<SM::Err, IErr, O::Error>>
+    where
+        SM::Err: Clone,
+    {
+        let mut protocol = self.map_watcher(|inner| CollectingWatcher {
+            inner,
+            events: vec![],
+        });
+        let output = protocol.run().await?;
+        Ok((output, protocol.watcher.events))
+    }
+
+    /// Executes the protocol until it's finished or `cancel` is resolved
+    ///
+    /// Cancellation is observed while waiting for incoming messages and before sending
+    /// outgoing ones, so the state is never left half-proceeded. Returns [Error::Cancelled]
+    /// once cancelled, see also [drain_on_cancel](AsyncProtocol::drain_on_cancel).
+    pub async fn run_with_cancel<C>(
+        &mut self,
+        cancel: C,
+    ) -> Result<SM::Output, Error<SM::Err, IErr, O::Error>>
+    where
+        C: Future<Output = ()> + Send + 'static,
+    {
+        let previous = self.cancel.replace(cancel.boxed().shared());
+        let result = self.run().await;
+        self.cancel = previous;
+        result
+    }
+
+    /// Executes the protocol until the state machine reaches the `target` round
+    ///
+    /// Returns `None` once the `target` round is reached, the executor is paused then and a
+    /// subsequent `run` or `run_until_round` continues from there. Returns the protocol output
+    /// if the protocol is finished before the `target` round is reached.
+    pub async fn run_until_round(
+        &mut self,
+        target: u16,
+    ) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        self.advance(Some(target)).await
+    }
+
+    /// State of the protocol, `None` once the output is picked
+    pub fn state(&self) -> Option<&SM> {
+        self.state.as_ref()
+    }
+
+    /// Time spent in every phase of the execution so far
+    pub fn phase_timings(&self) -> PhaseTimings {
+        self.timings
+    }
+
+    /// Round the state machine is in, `None` until the protocol is started
+    pub fn current_round(&self) -> Option<u16> {
+        self.current_round
+    }
+
+    /// Whether [run](AsyncProtocol::run) or [run_until_round](AsyncProtocol::run_until_round)
+    /// is executing the protocol
+    pub fn is_running(&self) -> bool {
+        self.progress.is_running()
+    }
+
+    /// Handle to watch the progress from another task
+    ///
+    /// Running the protocol borrows the executor, so [current_round](AsyncProtocol::current_round)
+    /// can't be called meanwhile. The handle is updated whenever a new round is entered.
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    async fn advance(
+        &mut self,
+        target: Option<u16>,
+    ) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        let _running = self.progress.start_running();
+        self.advance_inner(target).await
+    }
+
+    async fn advance_inner(
+        &mut self,
+        target: Option<u16>,
+    ) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        if self.paused {
+            self.paused = false;
+        } else if self.current_round.is_some() {
             return Err(Error::Exhausted);
+        } else if let Some(output) = self.start().await? {
+            return Ok(Some(output));
+        }
+
+        loop {
+            if let (Some(target), Some(round)) = (target, self.current_round) {
+                if round >= target {
+                    self.paused = true;
+                    return Ok(None);
+                }
+            }
+            if let Some(output) = self.step().await? {
+                return Ok(Some(output));
+            }
+        }
+    }
+
+    /// Turns the executor into a stream of [events](ProtocolEvent) of the protocol execution
+    ///
+    /// The last item is either [ProtocolEvent::Finished] carrying the protocol output or
+    /// [ProtocolEvent::Failed] carrying the first occurred critical error.
+    pub fn into_stream(
+        mut self,
+    ) -> impl Stream<Item = ProtocolEvent<SM::Output, Error<SM::Err, IErr, O::Error>>> {
+        self.events = Some(vec![]);
+        async_stream::stream! {
+            let mut result = if self.paused {
+                self.paused = false;
+                Ok(None)
+            } else if self.current_round.is_some() {
+                Err(Error::Exhausted)
+            } else {
+                self.start().await
+            };
+            loop {
+                let steps = self.events.as_mut().map(|e| e.split_off(0)).unwrap_or_default();
+                for step in steps {
+                    yield ProtocolEvent::from(step);
+                }
+                match result {
+                    Ok(Some(output)) => {
+                        yield ProtocolEvent::Finished(output);
+                        break;
+                    }
+                    Err(err) => {
+                        yield ProtocolEvent::Failed(err);
+                        break;
+                    }
+                    Ok(None) => result = self.step().await,
+                }
+            }
         }
+    }
+
+    /// Proceeds the initial state and sends its messages
+    async fn start(&mut self) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        self.total_deadline_at = self
+            .total_deadline
+            .map(|deadline| time::Instant::now() + deadline);
+        let deadline = self.total_deadline_at;
+        Self::enforce_total_deadline(deadline, self.start_inner()).await
+    }
+
+    async fn start_inner(&mut self) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
         info!("refresh_timer");
         self.refresh_timer()?;
         info!("proceed_if_needed");
@@ -120,14 +631,23 @@ where
         info!("refresh_timer");
         self.refresh_timer()?;
 
-        if let Some(result) = self.finish_if_possible() {
-            return result;
-        }
+        self.finish_step().await
+    }
 
-        loop {
-            info!("----loop----");
-            info!("handle_incoming");
-            self.handle_incoming().await?;
+    /// Handles a next incoming message, then proceeds and sends messages if needed
+    async fn step(&mut self) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        let deadline = self.total_deadline_at;
+        Self::enforce_total_deadline(deadline, self.step_inner()).await
+    }
+
+    async fn step_inner(&mut self) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        info!("----loop----");
+        info!("handle_incoming");
+        self.handle_incoming().await?;
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if self.send_before_proceed {
             info!("send_outgoing");
             self.send_outgoing().await?;
             info!("refresh_timer");
@@ -139,33 +659,175 @@ where
             self.send_outgoing().await?;
             info!("refresh_timer");
             self.refresh_timer()?;
+        } else {
+            info!("proceed_if_needed");
+            self.proceed_if_needed().await?;
+            info!("refresh_timer");
+            self.refresh_timer()?;
+            info!("send_outgoing");
+            self.send_outgoing().await?;
+        }
+
+        self.finish_step().await
+    }
 
-            if let Some(result) = self.finish_if_possible() {
-                return result;
+    async fn finish_step(&mut self) -> Result<Option<SM::Output>, Error<SM::Err, IErr, O::Error>> {
+        match self.finish_if_possible() {
+            Some(result) => self.announce_if_needed(result).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn announce_if_needed(
+        &mut self,
+        result: Result<SM::Output, Error<SM::Err, IErr, O::Error>>,
+    ) -> Result<SM::Output, Error<SM::Err, IErr, O::Error>> {
+        let output = result?;
+        if self.announce_completion {
+            let state = self.state.as_ref().ok_or(InternalError::MissingState)?;
+            if let Some(body) = state.completion_message(&output) {
+                let msg = Msg {
+                    sender: state.party_ind(),
+                    receiver: None,
+                    body,
+                };
+                // Announcement is best effort: peers which already finished aren't listening
+                if self.outgoing.send(msg).await.is_err() {
+                    info!("completion announcement wasn't delivered");
+                }
             }
         }
+        Ok(output)
     }
 
     async fn handle_incoming(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        let started = Instant::now();
+        let result = self.handle_incoming_inner().await;
+        self.timings.handle_incoming += started.elapsed();
+        result
+    }
+
+    async fn handle_incoming_inner(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        if let Some(msg) = self
+            .pending
+            .as_mut()
+            .and_then(|pending| pending.ready.pop_front())
+        {
+            info!("replaying message of party {}", msg.sender);
+            return self.deliver(msg).await;
+        }
         let state = self.state.as_mut().ok_or(InternalError::MissingState)?;
         info!("async handle incoming rx_node");
-        match Self::enforce_timeout(self.deadline, self.incoming.next()).await {
-            Ok(Some(Ok(msg))) => match state.handle_incoming(msg) {
-                Ok(()) => (),
-                Err(err) if err.is_critical() => {
-                    info!("meet critical err");
-                    return Err(Error::HandleIncoming(err));
+        if let Some(buffer) = self.buffer.as_mut() {
+            // Reading ahead is bounded, so an always ready stream doesn't stall the protocol
+            for _ in 0..=buffer.capacity {
+                let item = match self.incoming.next().now_or_never() {
+                    Some(Some(item)) => item,
+                    _ => break,
+                };
+                let is_needed = |item: &I::Item| match item {
+                    Ok(msg) => state.is_message_needed(msg),
+                    Err(_) => true,
+                };
+                if buffer.push(item, is_needed).is_err() {
+                    return Err(Error::IncomingBufferOverflow {
+                        capacity: buffer.capacity,
+                    });
                 }
-                Err(err) => {
-                    info!("meet non-critical err");
-                    self.watcher
-                        .caught_non_critical_error(When::HandleIncoming, err)
+            }
+        }
+        let buffered = self.buffer.as_mut().and_then(|b| b.items.pop_front());
+        let next = Self::enforce_timeout(self.deadline, self.incoming.next());
+        let next = match (buffered, self.cancel.as_mut()) {
+            (Some(item), _) => Ok(Some(item)),
+            (None, Some(cancel)) => {
+                futures::pin_mut!(next);
+                match future::select(next, cancel).await {
+                    Either::Left((next, _)) => next,
+                    Either::Right(((), _)) => return Err(Error::Cancelled),
                 }
-            },
+            }
+            (None, None) => next.await,
+        };
+        match next {
+            Ok(Some(Ok(msg))) => {
+                if let Some(report) = self.sequence.as_ref() {
+                    for gap in report.take() {
+                        self.watcher
+                            .sequence_gap(gap.sender, gap.expected, gap.received);
+                    }
+                }
+                if let Some(events) = self.events.as_mut() {
+                    events.push(Step::MessageReceived(msg.sender));
+                }
+                if let Some(limit) = self.max_incoming_size {
+                    let size = encoded_size(&msg.body);
+                    if size > limit {
+                        return Err(Error::RecvOversized { size, limit });
+                    }
+                }
+                let msg = match self.fragmentation.as_mut() {
+                    Some(fragmentation) => {
+                        match fragmentation.reassemble(&*state, msg, self.max_incoming_size)? {
+                            Some(msg) => msg,
+                            None => return Ok(()),
+                        }
+                    }
+                    None => msg,
+                };
+                if let Some(reason) = state.abort_reason(&msg.body) {
+                    let by = msg.sender;
+                    let participant = (1..=state.parties()).contains(&by);
+                    if self.broadcast_abort && participant && by != state.party_ind() {
+                        return Err(Error::Aborted {
+                            by,
+                            reason: truncate_reason(reason),
+                        });
+                    }
+                    info!("ignore abort message claimed to come from party {}", by);
+                    return Ok(());
+                }
+                if let Some(acks) = self.acks.as_mut() {
+                    if let Some(round) = state.acknowledged_round(&msg.body) {
+                        acks.received.push((round, msg.sender));
+                        return Ok(());
+                    }
+                    if let Some(body) = state.acknowledgement(&msg) {
+                        let ack = Msg {
+                            sender: state.party_ind(),
+                            receiver: Some(msg.sender),
+                            body,
+                        };
+                        self.outgoing.send(ack).await.map_err(Error::Send)?;
+                    }
+                }
+                self.deliver(msg).await?;
+            }
             Ok(Some(Err(err))) => return Err(Error::Recv(err)),
             Ok(None) => return Err(Error::RecvEof),
             Err(_) => {
                 info!("meet other err");
+                if let Some(retransmit) = self.retransmit.as_mut() {
+                    if retransmit.retries < retransmit.max_retries {
+                        retransmit.retries += 1;
+                        info!(
+                            "round timeout reached, retransmission {} of {}",
+                            retransmit.retries, retransmit.max_retries
+                        );
+                        Self::send_one_by_one(&mut self.outgoing, retransmit.last_batch.clone())
+                            .await
+                            .map_err(Error::Send)?;
+                        self.deadline = round_deadline(self.timeout_strategy.as_deref(), state);
+                        return Ok(());
+                    }
+                }
+                if let Some(round) = self
+                    .acks
+                    .as_ref()
+                    .and_then(|acks| acks.unacknowledged(state.party_ind(), state.parties()))
+                {
+                    return Err(Error::MessageNotAcknowledged { round });
+                }
                 let err = state.round_timeout_reached();
                 return Err(Error::HandleIncomingTimeout(err));
             }
@@ -173,18 +835,85 @@ where
         Ok(())
     }
 
+    /// Passes the `msg` to the state machine, keeping it aside if it's for a later round
+    async fn deliver(
+        &mut self,
+        msg: Msg<SM::MessageBody>,
+    ) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        let state = self.state.as_mut().ok_or(InternalError::MissingState)?;
+        let copy = self.pending.as_ref().map(|_| msg.clone());
+        match state.handle_incoming(msg) {
+            Ok(()) => (),
+            Err(err) if err.is_critical() => {
+                info!("meet critical err");
+                if self.broadcast_abort {
+                    Self::send_abort(&mut self.outgoing, &*state, &err).await;
+                }
+                return Err(Error::HandleIncoming(err));
+            }
+            Err(err) => {
+                if let (true, Some(pending), Some(msg)) =
+                    (state.is_wrong_round(&err), self.pending.as_mut(), copy)
+                {
+                    if pending.waiting.len() < pending.capacity {
+                        info!("keeping message of party {} for a later round", msg.sender);
+                        pending.waiting.push(msg);
+                        return Ok(());
+                    }
+                }
+                info!("meet non-critical err");
+                self.watcher
+                    .caught_non_critical_error(When::HandleIncoming, err)
+            }
+        }
+        Ok(())
+    }
+
     async fn proceed_if_needed(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        let started = Instant::now();
+        let result = self.proceed_if_needed_inner().await;
+        self.timings.proceed += started.elapsed();
+        result
+    }
+
+    async fn proceed_if_needed_inner(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
         let mut state = self.state.take().ok_or(InternalError::MissingState)?;
-        if state.wants_to_proceed() {
+        let mut retries = 0;
+        while state.wants_to_proceed() {
+            let round = state.current_round();
             let (result, s) = tokio::task::spawn_blocking(move || (state.proceed(), state))
                 .await
                 .map_err(Error::ProceedPanicked)?;
             state = s;
 
             match result {
-                Ok(()) => (),
-                Err(err) if err.is_critical() => return Err(Error::Proceed(err)),
-                Err(err) => self.watcher.caught_non_critical_error(When::Proceed, err),
+                Ok(()) => break,
+                Err(err) if err.is_critical() => {
+                    if self.broadcast_abort {
+                        Self::send_abort(&mut self.outgoing, &state, &err).await;
+                    }
+                    return Err(Error::Proceed(RoundContext { round, error: err }));
+                }
+                Err(err) => match self.proceed_retries {
+                    Some(ProceedRetries { max, delay })
+                        if retries >= max
+                            || self.deadline.map_or(false, |deadline| {
+                                time::Instant::now() + delay >= deadline
+                            }) =>
+                    {
+                        return Err(Error::Proceed(RoundContext { round, error: err }))
+                    }
+                    Some(ProceedRetries { delay, .. }) => {
+                        retries += 1;
+                        info!("retrying to proceed round {}", round);
+                        self.watcher.caught_non_critical_error(When::Proceed, err);
+                        time::sleep(delay).await;
+                    }
+                    None => {
+                        self.watcher.caught_non_critical_error(When::Proceed, err);
+                        break;
+                    }
+                },
             }
         }
         self.state = Some(state);
@@ -192,20 +921,114 @@ where
     }
 
     async fn send_outgoing(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        let started = Instant::now();
+        let result = self.send_outgoing_inner().await;
+        self.timings.send_outgoing += started.elapsed();
+        result
+    }
+
+    async fn send_outgoing_inner(&mut self) -> Result<(), Error<SM::Err, IErr, O::Error>> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
         let state = self.state.as_mut().ok_or(InternalError::MissingState)?;
         info!("async send outgoing tx_node");
         if !state.message_queue().is_empty() {
-            let mut msgs = stream::iter(state.message_queue().drain(..).map(Ok));
+            if let Some(sender) = self.bytes_outgoing.as_ref() {
+                let round = state.current_round();
+                let msgs = state.message_queue_bytes().map_err(Error::Encode)?;
+                if let Some(acks) = self.acks.as_mut() {
+                    if !acks.sent.contains(&round) {
+                        acks.sent.push(round);
+                    }
+                }
+                if let Some(events) = self.events.as_mut() {
+                    events.extend(msgs.iter().map(|m| Step::MessageSent(m.receiver)));
+                }
+                for msg in msgs {
+                    sender
+                        .send(msg)
+                        .await
+                        .map_err(|_| Error::BytesChannelClosed)?;
+                }
+                return Ok(());
+            }
+            let msgs: Vec<_> = state.message_queue().drain(..).collect();
+            let msgs = match self.fragmentation.as_mut() {
+                Some(fragmentation) => fragmentation.split(&*state, msgs)?,
+                None => msgs,
+            };
+            if let Some(retransmit) = self.retransmit.as_mut() {
+                retransmit.last_batch = msgs.clone();
+            }
+            if let Some(acks) = self.acks.as_mut() {
+                let round = state.current_round();
+                if !acks.sent.contains(&round) {
+                    acks.sent.push(round);
+                }
+            }
+            if let Some(events) = self.events.as_mut() {
+                events.extend(msgs.iter().map(|m| Step::MessageSent(m.receiver)));
+            }
+            let round = state.current_round();
+            let receivers: Vec<_> = msgs.iter().map(|m| m.receiver).collect();
 
-            self.outgoing
-                .send_all(&mut msgs)
+            Self::send_one_by_one(&mut self.outgoing, msgs)
                 .await
                 .map_err(Error::Send)?;
+
+            if let Some(report) = self.delivery.as_ref() {
+                let undelivered = receivers
+                    .iter()
+                    .zip(report.take())
+                    .any(|(receiver, reached)| receiver.is_none() && reached == 0);
+                if undelivered {
+                    self.watcher.broadcast_not_delivered(round);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Sends the messages in order, waiting for the sink to be ready for every one of them
+    ///
+    /// Yields to the executor between messages, so other tasks sharing the thread, like the one
+    /// draining a bounded channel behind the sink, run before the next message is fed. The sink
+    /// is flushed once, after the last message.
+    async fn send_one_by_one(
+        outgoing: &mut O,
+        msgs: Vec<Msg<SM::MessageBody>>,
+    ) -> Result<(), O::Error> {
+        for msg in msgs {
+            outgoing.feed(msg).await?;
+            tokio::task::yield_now().await;
+        }
+        outgoing.flush().await
+    }
+
+    /// Broadcasts the [abort message](StateMachine::abort_message) for `err`, if there's any
+    async fn send_abort(outgoing: &mut O, state: &SM, err: &SM::Err) {
+        if let Some(body) = state.abort_message(err) {
+            let msg = Msg {
+                sender: state.party_ind(),
+                receiver: None,
+                body,
+            };
+            // Abort is best effort: we're stopping anyway, whether peers hear about it or not
+            if outgoing.send(msg).await.is_err() {
+                info!("abort message wasn't delivered");
+            }
+        }
+    }
+
+    fn is_cancelled(&mut self) -> bool {
+        self.cancel
+            .as_mut()
+            .map(|cancel| cancel.now_or_never().is_some())
+            .unwrap_or(false)
+    }
+
     fn finish_if_possible(&mut self) -> Option<Result<SM::Output, Error<SM::Err, IErr, O::Error>>> {
         let state = match self.state.as_mut() {
             Some(s) => s,
@@ -228,14 +1051,41 @@ where
         let state = self.state.as_mut().ok_or(InternalError::MissingState)?;
         let round_n = state.current_round();
         if self.current_round != Some(round_n) {
+            self.watcher.round_transition(self.current_round, round_n);
             self.current_round = Some(round_n);
-            self.deadline = state
-                .round_timeout()
-                .map(|timeout| time::Instant::now() + timeout);
+            self.progress.set_round(round_n);
+            if let Some(events) = self.events.as_mut() {
+                events.push(Step::RoundEntered(round_n));
+            }
+            self.deadline = round_deadline(self.timeout_strategy.as_deref(), state);
+            if let Some(retransmit) = self.retransmit.as_mut() {
+                retransmit.retries = 0;
+            }
+            if let Some(fragmentation) = self.fragmentation.as_mut() {
+                fragmentation.drop_stale(round_n);
+            }
+            // Messages kept for a later round are handled again, before reading new ones
+            if let Some(pending) = self.pending.as_mut() {
+                let waiting = std::mem::take(&mut pending.waiting);
+                pending.ready.extend(waiting);
+            }
         }
 
         Ok(())
     }
+    async fn enforce_total_deadline<T, F>(
+        deadline: Option<time::Instant>,
+        f: F,
+    ) -> Result<T, Error<SM::Err, IErr, O::Error>>
+    where
+        F: Future<Output = Result<T, Error<SM::Err, IErr, O::Error>>>,
+    {
+        match Self::enforce_timeout(deadline, f).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::TotalDeadlineExceeded),
+        }
+    }
+
     fn enforce_timeout<F>(
         deadline: Option<time::Instant>,
         f: F,
@@ -250,48 +1100,653 @@ where
     }
 }
 
-/// Represents error that can occur while executing protocol
-#[derive(Debug)]
-#[non_exhaustive]
-pub enum Error<E, RE, SE> {
-    /// Receiving next incoming message returned error
-    Recv(RE),
-    /// Incoming channel closed (got EOF)
-    RecvEof,
-    /// Sending outgoing message resulted in error
-    Send(SE),
-    /// [Handling incoming](crate::StateMachine::handle_incoming) message produced critical error
-    HandleIncoming(E),
-    /// Round timeout exceed when executor was waiting for new messages from other parties
-    HandleIncomingTimeout(E),
-    /// [Proceed method](crate::StateMachine::proceed) panicked
-    ProceedPanicked(tokio::task::JoinError),
-    /// State machine [proceeding](crate::StateMachine::proceed) produced critical error
-    Proceed(E),
-    /// StateMachine's [pick_output](crate::StateMachine::pick_output) method return error
-    Finish(E),
-    /// AsyncProtocol already executed protocol (or at least, tried to) and tired. You need to
-    /// construct new executor!
-    Exhausted,
-    /// Buggy StateMachine implementation
-    BadStateMachine(BadStateMachineReason),
-    /// Buggy AsyncProtocol implementation!
-    ///
-    /// If you've got this error, please, report bug.
-    InternalErr(InternalError),
+/// Runs the `sessions` one after another, stopping at the first failing one
+///
+/// Returns the outputs in order of the sessions.
+pub async fn run_many<SM, I, O, IErr, W>(
+    sessions: Vec<AsyncProtocol<SM, I, O, W>>,
+) -> Result<Vec<SM::Output>, BatchError<SM::Output, Error<SM::Err, IErr, O::Error>>>
+where
+    SM: StateMachine,
+    SM::Err: Send,
+    SM: Send + 'static,
+    SM::MessageBody: Serialize + Clone,
+    I: Stream<Item = Result<Msg<SM::MessageBody>, IErr>> + FusedStream + Unpin,
+    O: Sink<Msg<SM::MessageBody>> + Unpin,
+    W: ProtocolWatcher<SM>,
+{
+    run_batch(sessions, None).await
 }
 
-impl<E, RE, SE> From<BadStateMachineReason> for Error<E, RE, SE> {
-    fn from(reason: BadStateMachineReason) -> Self {
-        Error::BadStateMachine(reason)
-    }
+/// Like [run_many], but the whole batch must be done by `deadline`
+///
+/// Bounds the total work of the batch, unlike the round timeouts of every session. The session
+/// in progress once the deadline is hit is [cancelled](AsyncProtocol::run_with_cancel), the
+/// following ones aren't started, and [BatchError::DeadlineExceeded] tells which one it was.
+pub async fn run_many_until<SM, I, O, IErr, W>(
+    sessions: Vec<AsyncProtocol<SM, I, O, W>>,
+    deadline: time::Instant,
+) -> Result<Vec<SM::Output>, BatchError<SM::Output, Error<SM::Err, IErr, O::Error>>>
+where
+    SM: StateMachine,
+    SM::Err: Send,
+    SM: Send + 'static,
+    SM::MessageBody: Serialize + Clone,
+    I: Stream<Item = Result<Msg<SM::MessageBody>, IErr>> + FusedStream + Unpin,
+    O: Sink<Msg<SM::MessageBody>> + Unpin,
+    W: ProtocolWatcher<SM>,
+{
+    run_batch(sessions, Some(deadline)).await
 }
 
-impl<E, RE, SE> From<InternalError> for Error<E, RE, SE> {
-    fn from(err: InternalError) -> Self {
-        Error::InternalErr(err)
-    }
-}
+async fn run_batch<SM, I, O, IErr, W>(
+    sessions: Vec<AsyncProtocol<SM, I, O, W>>,
+    deadline: Option<time::Instant>,
+) -> Result<Vec<SM::Output>, BatchError<SM::Output, Error<SM::Err, IErr, O::Error>>>
+where
+    SM: StateMachine,
+    SM::Err: Send,
+    SM: Send + 'static,
+    SM::MessageBody: Serialize + Clone,
+    I: Stream<Item = Result<Msg<SM::MessageBody>, IErr>> + FusedStream + Unpin,
+    O: Sink<Msg<SM::MessageBody>> + Unpin,
+    W: ProtocolWatcher<SM>,
+{
+    let mut completed = vec![];
+    for (index, mut session) in sessions.into_iter().enumerate() {
+        let result = match deadline {
+            Some(deadline) if time::Instant::now() >= deadline => {
+                return Err(BatchError::DeadlineExceeded { index, completed })
+            }
+            Some(deadline) => session.run_with_cancel(time::sleep_until(deadline)).await,
+            None => session.run().await,
+        };
+        match result {
+            Ok(output) => completed.push(output),
+            Err(Error::Cancelled) if deadline.is_some() => {
+                return Err(BatchError::DeadlineExceeded { index, completed })
+            }
+            Err(error) => {
+                return Err(BatchError::Session {
+                    index,
+                    error,
+                    completed,
+                })
+            }
+        }
+    }
+    Ok(completed)
+}
+
+/// Error of a batch of sessions run by [run_many] or [run_many_until]
+///
+/// Carries the outputs of the sessions completed before the error.
+#[derive(Debug)]
+pub enum BatchError<T, E> {
+    /// Session at `index` failed
+    Session {
+        index: usize,
+        error: E,
+        completed: Vec<T>,
+    },
+    /// Batch deadline was hit while the session at `index` was in progress
+    DeadlineExceeded { index: usize, completed: Vec<T> },
+}
+
+impl<T, E: fmt::Display> fmt::Display for BatchError<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Session { index, error, .. } => write!(f, "session {} failed: {}", index, error),
+            Self::DeadlineExceeded { index, .. } => {
+                write!(f, "batch deadline hit during session {}", index)
+            }
+        }
+    }
+}
+
+/// Deadline of the current round of `state`, picked by `strategy` if there's one
+fn round_deadline<SM: StateMachine>(
+    strategy: Option<&dyn TimeoutStrategy>,
+    state: &SM,
+) -> Option<time::Instant> {
+    match strategy {
+        Some(strategy) => {
+            strategy.deadline_for_round(state.current_round(), state.is_round_expensive())
+        }
+        None => state.round_timeout(),
+    }
+    .map(|timeout| time::Instant::now() + timeout)
+}
+
+/// Picks the timeout of every round, see [AsyncProtocol::set_timeout_strategy]
+pub trait TimeoutStrategy: Send + Sync {
+    /// Time to wait for the messages of `round`, `None` waits forever
+    ///
+    /// `is_expensive` tells whether proceeding the round is
+    /// [expensive](StateMachine::is_round_expensive), such a round may deserve a longer deadline.
+    fn deadline_for_round(&self, round: u16, is_expensive: bool) -> Option<Duration>;
+}
+
+/// Same timeout for every round
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedTimeout(pub Duration);
+
+impl TimeoutStrategy for FixedTimeout {
+    fn deadline_for_round(&self, _round: u16, _is_expensive: bool) -> Option<Duration> {
+        Some(self.0)
+    }
+}
+
+/// Timeout of `base` for round 1, multiplied by `factor` every next round, up to `max`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExponentialBackoff {
+    pub base: Duration,
+    pub factor: u32,
+    pub max: Duration,
+}
+
+impl TimeoutStrategy for ExponentialBackoff {
+    fn deadline_for_round(&self, round: u16, _is_expensive: bool) -> Option<Duration> {
+        let exp = u32::from(round.saturating_sub(1));
+        let timeout = self
+            .factor
+            .checked_pow(exp)
+            .and_then(|multiplier| self.base.checked_mul(multiplier))
+            .unwrap_or(self.max);
+        Some(timeout.min(self.max))
+    }
+}
+
+/// Numbers of receivers reached by the messages sent through a sink, in sending order
+///
+/// Shared between the sink, which records them, and the [executor](AsyncProtocol::confirm_delivery).
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryReport(Arc<Mutex<Vec<usize>>>);
+
+impl DeliveryReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a sent message reached `receivers` receivers
+    pub fn record(&self, receivers: usize) {
+        self.0.lock().expect("lock is poisoned").push(receivers);
+    }
+
+    fn take(&self) -> Vec<usize> {
+        self.0.lock().expect("lock is poisoned").split_off(0)
+    }
+}
+
+/// Progress of the executor, see [AsyncProtocol::progress]
+///
+/// Shared between the executor, which updates it, and the tasks watching it.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// Current round plus one, 0 until the protocol is started
+    round: Arc<AtomicU32>,
+    running: Arc<AtomicBool>,
+}
+
+impl Progress {
+    /// Round the state machine is in, `None` until the protocol is started
+    pub fn current_round(&self) -> Option<u16> {
+        match self.round.load(Ordering::SeqCst) {
+            0 => None,
+            round => Some((round - 1) as u16),
+        }
+    }
+
+    /// Whether the protocol is being executed
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    fn set_round(&self, round: u16) {
+        self.round.store(u32::from(round) + 1, Ordering::SeqCst);
+    }
+
+    fn start_running(&self) -> RunningGuard {
+        self.running.store(true, Ordering::SeqCst);
+        RunningGuard(self.running.clone())
+    }
+}
+
+/// Keeps the [progress](Progress) running until dropped
+///
+/// A run cancelled by dropping its future, e.g. by a timeout, isn't reported as running then.
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Sequence numbers of incoming messages which didn't follow the previous ones
+///
+/// Shared between the incoming stream, which records them, and the
+/// [executor](AsyncProtocol::track_sequence).
+#[derive(Debug, Clone, Default)]
+pub struct SequenceReport(Arc<Mutex<Vec<SequenceGap>>>);
+
+/// Message of `sender` numbered `received` arrived while `expected` was expected next
+///
+/// `received > expected` means messages are missing, `received < expected` means the message
+/// is duplicated or reordered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequenceGap {
+    pub sender: u16,
+    pub expected: u64,
+    pub received: u64,
+}
+
+impl SequenceReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an out of sequence message
+    pub fn record(&self, gap: SequenceGap) {
+        self.0.lock().expect("lock is poisoned").push(gap);
+    }
+
+    fn take(&self) -> Vec<SequenceGap> {
+        self.0.lock().expect("lock is poisoned").split_off(0)
+    }
+}
+
+/// Cumulative time spent in every phase of the execution, see [AsyncProtocol::phase_timings]
+///
+/// Tells whether a session is network or compute bound: `handle_incoming` includes waiting
+/// for the messages, `proceed` is the computation of the state machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PhaseTimings {
+    pub send_outgoing: Duration,
+    pub handle_incoming: Duration,
+    pub proceed: Duration,
+}
+
+/// Event of protocol execution yielded by [AsyncProtocol::into_stream]
+#[derive(Debug)]
+pub enum ProtocolEvent<T, E> {
+    /// State machine entered the round
+    RoundEntered(u16),
+    /// Message was sent, `receiver` is `None` for broadcast message
+    MessageSent { receiver: Option<u16> },
+    /// Message was received from `sender`
+    MessageReceived { sender: u16 },
+    /// Protocol successfully terminated with the output
+    Finished(T),
+    /// Protocol terminated with critical error
+    Failed(E),
+}
+
+/// Progress of execution recorded while the executor runs as a stream
+#[derive(Debug, Clone)]
+enum Step {
+    RoundEntered(u16),
+    MessageSent(Option<u16>),
+    MessageReceived(u16),
+}
+
+impl<T, E> From<Step> for ProtocolEvent<T, E> {
+    fn from(step: Step) -> Self {
+        match step {
+            Step::RoundEntered(round) => ProtocolEvent::RoundEntered(round),
+            Step::MessageSent(receiver) => ProtocolEvent::MessageSent { receiver },
+            Step::MessageReceived(sender) => ProtocolEvent::MessageReceived { sender },
+        }
+    }
+}
+
+/// What a full [incoming buffer](AsyncProtocol::set_incoming_buffer) does with messages which
+/// aren't needed anymore
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest message which isn't needed
+    DropOldest,
+    /// Drops the newest message which isn't needed
+    DropNewest,
+    /// Aborts the protocol with [Error::IncomingBufferOverflow]
+    Error,
+}
+
+/// Incoming messages read ahead, see [AsyncProtocol::set_incoming_buffer]
+#[derive(Debug, Clone)]
+struct IncomingBuffer<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> IncomingBuffer<T> {
+    /// Buffers the `item`, returns `Err(())` if it overflows under [OverflowPolicy::Error]
+    ///
+    /// The buffer holds at most `capacity` items, a needed `item` replaces the oldest item which
+    /// isn't needed. If every buffered item is needed, the `item` overflows whatever it is.
+    fn push<F>(&mut self, item: T, is_needed: F) -> Result<(), ()>
+    where
+        F: Fn(&T) -> bool,
+    {
+        if self.items.len() < self.capacity {
+            self.items.push_back(item);
+            return Ok(());
+        }
+        let replaceable = self.items.iter().position(|i| !is_needed(i));
+        match (self.policy, replaceable) {
+            (_, Some(i)) if is_needed(&item) => {
+                self.items.remove(i);
+                self.items.push_back(item);
+            }
+            (OverflowPolicy::Error, _) => return Err(()),
+            (OverflowPolicy::DropNewest, _) | (OverflowPolicy::DropOldest, None) => (),
+            (OverflowPolicy::DropOldest, Some(i)) => {
+                self.items.remove(i);
+                self.items.push_back(item);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Messages of a later round, see [AsyncProtocol::buffer_early_messages]
+#[derive(Debug, Clone)]
+struct PendingMessages<B> {
+    capacity: usize,
+    /// Messages waiting for the round to change
+    waiting: Vec<Msg<B>>,
+    /// Messages to be handled again, in the order they were received
+    ready: VecDeque<Msg<B>>,
+}
+
+/// Retries of a failed proceed, see [AsyncProtocol::retry_proceed]
+#[derive(Debug, Clone, Copy)]
+struct ProceedRetries {
+    max: u16,
+    delay: Duration,
+}
+
+/// Last batch of outgoing messages, see [AsyncProtocol::set_retransmit]
+#[derive(Debug, Clone)]
+struct Retransmit<B> {
+    max_retries: u8,
+    retries: u8,
+    last_batch: Vec<Msg<B>>,
+}
+
+/// Splitting of outgoing messages and reassembly of incoming ones, see
+/// [AsyncProtocol::set_max_message_size]
+#[derive(Clone)]
+struct Fragmentation<B> {
+    max_size: usize,
+    next_id: u64,
+    /// Fragments received so far and the round the first one arrived in, by sender and
+    /// message id
+    partial: HashMap<(u16, u64), (u16, Vec<Option<Vec<u8>>>)>,
+    /// Bytes held in `partial`
+    buffered: usize,
+    decode: fn(&[u8]) -> serde_json::Result<Msg<B>>,
+}
+
+impl<B: Serialize> Fragmentation<B> {
+    /// Replaces every message over the size limit with its fragments
+    fn split<SM, RE, SE>(
+        &mut self,
+        state: &SM,
+        msgs: Vec<Msg<B>>,
+    ) -> Result<Vec<Msg<B>>, Error<SM::Err, RE, SE>>
+    where
+        SM: StateMachine<MessageBody = B>,
+    {
+        let limit = self.max_size;
+        let mut split = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let bytes = msg.to_wire_bytes().map_err(Error::Encode)?;
+            if bytes.len() <= limit {
+                split.push(msg);
+                continue;
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            let oversized = || Error::SendOversized {
+                size: bytes.len(),
+                limit,
+            };
+            let wrap = |index, count, bytes| {
+                state
+                    .fragment_message(Fragment {
+                        id,
+                        index,
+                        count,
+                        bytes,
+                    })
+                    .map(|body| Msg {
+                        sender: msg.sender,
+                        receiver: msg.receiver,
+                        body,
+                    })
+            };
+            // Fragment bytes are encoded as json numbers, taking up to 4 bytes each
+            let header = wrap(u16::MAX, u16::MAX, vec![]).ok_or_else(oversized)?;
+            let chunk = limit.saturating_sub(encoded_size(&header)) / 4;
+            if chunk == 0 || (bytes.len() + chunk - 1) / chunk > usize::from(MAX_FRAGMENT_COUNT) {
+                return Err(oversized());
+            }
+            let count = ((bytes.len() + chunk - 1) / chunk) as u16;
+            for (index, piece) in bytes.chunks(chunk).enumerate() {
+                split.push(wrap(index as u16, count, piece.to_vec()).ok_or_else(oversized)?);
+            }
+        }
+        Ok(split)
+    }
+
+    /// Stores `msg` if it's a fragment, returns the message once all its fragments arrived
+    ///
+    /// A message whose fragments add up to more than `max_incoming` bytes is rejected as soon
+    /// as the limit is crossed, before it's decoded.
+    fn reassemble<SM, RE, SE>(
+        &mut self,
+        state: &SM,
+        msg: Msg<B>,
+        max_incoming: Option<usize>,
+    ) -> Result<Option<Msg<B>>, Error<SM::Err, RE, SE>>
+    where
+        SM: StateMachine<MessageBody = B>,
+    {
+        let fragment = match state.as_fragment(&msg.body) {
+            Some(fragment) => fragment,
+            None => return Ok(Some(msg)),
+        };
+        let sender = msg.sender;
+        let bad = || Error::BadFragment { sender };
+        if fragment.index >= fragment.count || fragment.count > MAX_FRAGMENT_COUNT {
+            return Err(bad());
+        }
+        let key = (sender, fragment.id);
+        if !self.partial.contains_key(&key) {
+            let in_flight = self.partial.keys().filter(|(s, _)| *s == sender).count();
+            if in_flight >= MAX_FRAGMENTED_PER_SENDER {
+                return Err(bad());
+            }
+        }
+        let round = state.current_round();
+        let (_, parts) = self
+            .partial
+            .entry(key)
+            .or_insert_with(|| (round, vec![None; usize::from(fragment.count)]));
+        if parts.len() != usize::from(fragment.count) {
+            return Err(bad());
+        }
+        let replaced = parts[usize::from(fragment.index)]
+            .replace(fragment.bytes.clone())
+            .map_or(0, |old| old.len());
+        self.buffered = self.buffered - replaced + fragment.bytes.len();
+        let size = parts.iter().flatten().map(Vec::len).sum();
+        let complete = parts.iter().all(Option::is_some);
+        if let Some(limit) = max_incoming {
+            if size > limit {
+                self.remove(key);
+                return Err(Error::RecvOversized { size, limit });
+            }
+        }
+        if self.buffered > MAX_FRAGMENT_BYTES {
+            let size = self.buffered;
+            self.remove(key);
+            return Err(Error::RecvOversized {
+                size,
+                limit: MAX_FRAGMENT_BYTES,
+            });
+        }
+        if !complete {
+            return Ok(None);
+        }
+        let parts = self.remove(key);
+        let bytes: Vec<u8> = parts.into_iter().flatten().flatten().collect();
+        let msg = (self.decode)(&bytes).map_err(|_| bad())?;
+        // Fragments must not smuggle in a message on behalf of another party
+        if msg.sender != sender {
+            return Err(bad());
+        }
+        Ok(Some(msg))
+    }
+
+    /// Forgets the fragments of the message `key`, returning them
+    fn remove(&mut self, key: (u16, u64)) -> Vec<Option<Vec<u8>>> {
+        let (_, parts) = self.partial.remove(&key).unwrap_or_default();
+        self.buffered -= parts.iter().flatten().map(Vec::len).sum::<usize>();
+        parts
+    }
+
+    /// Drops the messages still incomplete since before the `round` preceding the current one
+    ///
+    /// A peer ahead of us may have started sending the message of the next round, so the
+    /// fragments received during the previous round are kept.
+    fn drop_stale(&mut self, round: u16) {
+        let stale: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, (started, _))| started.saturating_add(1) < round)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            self.remove(key);
+        }
+    }
+}
+
+/// Rounds we sent messages in, and acknowledgements received from the peers
+#[derive(Debug, Clone, Default)]
+struct Acks {
+    sent: Vec<u16>,
+    received: Vec<(u16, u16)>,
+}
+
+impl Acks {
+    /// First round whose message isn't acknowledged by every peer
+    fn unacknowledged(&self, me: u16, parties: u16) -> Option<u16> {
+        self.sent.iter().copied().find(|&round| {
+            (1..=parties)
+                .filter(|&party| party != me)
+                .any(|party| !self.received.contains(&(round, party)))
+        })
+    }
+}
+
+/// `reason` cut to [MAX_ABORT_REASON_LEN] bytes, on a character boundary
+fn truncate_reason(reason: &str) -> String {
+    let mut end = reason.len().min(MAX_ABORT_REASON_LEN);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    reason[..end].to_owned()
+}
+
+/// Size of the json encoding of `body`, counted without buffering it
+fn encoded_size<B: Serialize>(body: &B) -> usize {
+    struct Counter(usize);
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    match serde_json::to_writer(&mut counter, body) {
+        Ok(()) => counter.0,
+        Err(_) => usize::MAX,
+    }
+}
+
+/// Represents error that can occur while executing protocol
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error<E, RE, SE> {
+    /// Receiving next incoming message returned error
+    Recv(RE),
+    /// Incoming channel closed (got EOF)
+    RecvEof,
+    /// Received message exceeds the [size limit](AsyncProtocol::set_max_incoming_size)
+    RecvOversized { size: usize, limit: usize },
+    /// Sending outgoing message resulted in error
+    Send(SE),
+    /// Outgoing message couldn't be [encoded](AsyncProtocol::forward_bytes) to the wire format
+    Encode(serde_json::Error),
+    /// Receiver of the [forwarded bytes](AsyncProtocol::forward_bytes) is dropped
+    BytesChannelClosed,
+    /// Outgoing message of `size` bytes can't be split into
+    /// [fragments](AsyncProtocol::set_max_message_size) of at most `limit` bytes
+    SendOversized { size: usize, limit: usize },
+    /// Fragments received from `sender` don't reassemble into a valid message
+    BadFragment { sender: u16 },
+    /// [Handling incoming](crate::StateMachine::handle_incoming) message produced critical error
+    HandleIncoming(E),
+    /// Round timeout exceed when executor was waiting for new messages from other parties
+    HandleIncomingTimeout(E),
+    /// Round timeout exceed while message sent in the `round` wasn't
+    /// [acknowledged](AsyncProtocol::require_acknowledgements) by every peer
+    MessageNotAcknowledged { round: u16 },
+    /// Party `by` [aborted](AsyncProtocol::set_broadcast_abort) the protocol for the `reason`
+    Aborted { by: u16, reason: String },
+    /// Protocol was [cancelled](AsyncProtocol::run_with_cancel)
+    Cancelled,
+    /// Protocol ran past its [total deadline](AsyncProtocol::set_total_deadline)
+    TotalDeadlineExceeded,
+    /// Incoming message arrived while the [buffer](AsyncProtocol::set_incoming_buffer) was full
+    /// and it couldn't take the place of a message which isn't needed
+    IncomingBufferOverflow { capacity: usize },
+    /// [Proceed method](crate::StateMachine::proceed) panicked
+    ProceedPanicked(tokio::task::JoinError),
+    /// State machine [proceeding](crate::StateMachine::proceed) produced critical error
+    Proceed(RoundContext<E>),
+    /// StateMachine's [pick_output](crate::StateMachine::pick_output) method return error
+    Finish(E),
+    /// AsyncProtocol already executed protocol (or at least, tried to) and tired. You need to
+    /// construct new executor!
+    Exhausted,
+    /// Buggy StateMachine implementation
+    BadStateMachine(BadStateMachineReason),
+    /// Buggy AsyncProtocol implementation!
+    ///
+    /// If you've got this error, please, report bug.
+    InternalErr(InternalError),
+}
+
+impl<E, RE, SE> From<BadStateMachineReason> for Error<E, RE, SE> {
+    fn from(reason: BadStateMachineReason) -> Self {
+        Error::BadStateMachine(reason)
+    }
+}
+
+impl<E, RE, SE> From<InternalError> for Error<E, RE, SE> {
+    fn from(err: InternalError) -> Self {
+        Error::InternalErr(err)
+    }
+}
 
 impl<E, RE, SE> fmt::Display for Error<E, RE, SE>
 where
@@ -307,20 +1762,62 @@ where
             Self::RecvEof => {
                 write!(f, "receive next message: unexpected eof")
             }
+            Self::RecvOversized { size, limit } => {
+                write!(
+                    f,
+                    "receive next message: {} bytes exceeds the limit of {} bytes",
+                    size, limit
+                )
+            }
             Self::Send(err) => {
                 write!(f, "send a message: {}", err)
             }
+            Self::Encode(err) => {
+                write!(f, "encode a message: {}", err)
+            }
+            Self::BytesChannelClosed => {
+                write!(f, "send a message: bytes channel is closed")
+            }
+            Self::SendOversized { size, limit } => {
+                write!(
+                    f,
+                    "send a message: {} bytes can't be split into fragments of {} bytes",
+                    size, limit
+                )
+            }
+            Self::BadFragment { sender } => {
+                write!(
+                    f,
+                    "receive next message: bad fragments from party {}",
+                    sender
+                )
+            }
             Self::HandleIncoming(err) => {
                 write!(f, "handle received message: {}", err)
             }
             Self::HandleIncomingTimeout(err) => {
                 write!(f, "round timeout reached: {}", err)
             }
+            Self::MessageNotAcknowledged { round } => {
+                write!(f, "message of round {} wasn't acknowledged", round)
+            }
+            Self::Aborted { by, reason } => {
+                write!(f, "protocol was aborted by party {}: {}", by, reason)
+            }
+            Self::Cancelled => {
+                write!(f, "protocol was cancelled")
+            }
+            Self::TotalDeadlineExceeded => {
+                write!(f, "total protocol deadline exceeded")
+            }
+            Self::IncomingBufferOverflow { capacity } => {
+                write!(f, "incoming buffer of {} messages overflowed", capacity)
+            }
             Self::ProceedPanicked(err) => {
                 write!(f, "proceed round panicked: {}", err)
             }
             Self::Proceed(err) => {
-                write!(f, "round proceed error: {}", err)
+                write!(f, "round {} proceed error: {}", err.round, err.error)
             }
             Self::Finish(err) => {
                 write!(f, "couldn't finish protocol: {}", err)
@@ -348,12 +1845,22 @@ where
         match self {
             Self::Recv(err) => Some(err),
             Self::Send(err) => Some(err),
+            Self::Encode(err) => Some(err),
             Self::HandleIncoming(err) => Some(err),
             Self::HandleIncomingTimeout(err) => Some(err),
             Self::ProceedPanicked(err) => Some(err),
-            Self::Proceed(err) => Some(err),
+            Self::Proceed(err) => Some(&err.error),
             Self::Finish(err) => Some(err),
             Self::RecvEof => None,
+            Self::BytesChannelClosed => None,
+            Self::RecvOversized { .. } => None,
+            Self::SendOversized { .. } => None,
+            Self::BadFragment { .. } => None,
+            Self::MessageNotAcknowledged { .. } => None,
+            Self::Aborted { .. } => None,
+            Self::Cancelled => None,
+            Self::TotalDeadlineExceeded => None,
+            Self::IncomingBufferOverflow { .. } => None,
             Self::Exhausted => None,
             Self::BadStateMachine(_) => None,
             Self::InternalErr(_) => None,
@@ -361,6 +1868,14 @@ where
     }
 }
 
+/// Error attributed to the round in which it occurred
+#[derive(Debug)]
+pub struct RoundContext<E> {
+    /// Round the state machine was in when the error occurred
+    pub round: u16,
+    pub error: E,
+}
+
 /// Reason why StateMachine implementation looks buggy
 #[derive(Debug)]
 #[non_exhaustive]
@@ -368,6 +1883,9 @@ pub enum BadStateMachineReason {
     /// [StateMachine::is_finished](crate::StateMachine::is_finished) returned `true`,
     /// but [StateMachine::pick_output](crate::StateMachine::pick_output) returned `None`
     ProtocolFinishedButNoResult,
+    /// [StateMachine::pick_output](crate::StateMachine::pick_output) was about to return an
+    /// output which doesn't pass its own verification
+    OutputFailsVerification,
 }
 
 impl fmt::Display for BadStateMachineReason {
@@ -377,6 +1895,9 @@ impl fmt::Display for BadStateMachineReason {
                 f,
                 "couldn't obtain protocol output although it is completed"
             ),
+            Self::OutputFailsVerification => {
+                write!(f, "protocol output doesn't pass verification")
+            }
         }
     }
 }
@@ -387,3 +1908,1455 @@ impl fmt::Display for BadStateMachineReason {
 pub enum InternalError {
     MissingState,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        run_many, run_many_until, AsyncProtocol, BatchError, DeliveryReport, Error,
+        ExponentialBackoff, FixedTimeout, Fragmentation, OverflowPolicy, PhaseTimings,
+        ProtocolEvent, RoundContext, SequenceReport, TimeoutStrategy, MAX_ABORT_REASON_LEN,
+        MAX_FRAGMENTED_PER_SENDER, MAX_FRAGMENT_COUNT,
+    };
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::p2p::transport::{SequenceCheck, Sequenced};
+    use crate::cli::party::traits::state_machine::{Fragment, IsCritical, StateMachine};
+    use crate::cli::party::watcher::{ProtocolWatcher, When};
+    use crate::cli::party::{
+        musig2_instance::{self, ProtocolMessage, M},
+        musig2_party::{incoming, ConfirmingOutgoing, Outgoing},
+        rounds::{MessageRound1, ProceedError},
+        traits::state_machine::Msg,
+        Musig2Instance,
+    };
+    use crate::cli::protocals::KeyPair;
+    use futures::stream::{self, StreamExt};
+    use std::{collections::HashMap, convert::Infallible, time::Duration};
+    use tokio::sync::{broadcast, mpsc};
+
+    /// How a [TestParty] moves on to the next round
+    enum Advance {
+        /// Enters the next round on every needed incoming message
+        OnIncoming,
+        /// Proceeds whenever asked to
+        OnProceed,
+        /// Proceeds round `r` once the message `expected[r]` is received, right away if it's
+        /// `None`
+        AfterReceiving(Vec<Option<&'static str>>),
+    }
+
+    /// Configurable party 1 of 2 the executor is tested with
+    ///
+    /// Construct it with one of the presets and adjust the fields as needed.
+    struct TestParty {
+        round: u16,
+        /// Party finishes once it enters this round
+        rounds: u16,
+        advance: Advance,
+        /// Bodies broadcast when proceeding round `r`, at `broadcasts[r]`
+        broadcasts: Vec<Vec<&'static str>>,
+        /// Whether every incoming message is acknowledged to its sender
+        acknowledges: bool,
+        /// Whether a message expected by a later round is rejected as
+        /// [wrong round](TestError::WrongRound)
+        rejects_early: bool,
+        /// Only messages starting with this prefix are
+        /// [needed](StateMachine::is_message_needed)
+        needed: Option<&'static str>,
+        /// Proceeding fails with a non critical error this many times first
+        hiccups: u16,
+        /// Proceeding this round fails with a critical error
+        fail_at: Option<u16>,
+        received: Vec<&'static str>,
+        queue: Vec<Msg<&'static str>>,
+    }
+
+    impl TestParty {
+        fn new(advance: Advance, rounds: u16) -> Self {
+            TestParty {
+                round: 0,
+                rounds,
+                advance,
+                broadcasts: vec![],
+                acknowledges: false,
+                rejects_early: false,
+                needed: None,
+                hiccups: 0,
+                fail_at: None,
+                received: vec![],
+                queue: vec![],
+            }
+        }
+
+        /// Greets peers, acknowledges the greeting it receives and says goodbye
+        fn echo() -> Self {
+            TestParty {
+                broadcasts: vec![vec!["hello"], vec!["bye"]],
+                acknowledges: true,
+                ..Self::new(Advance::AfterReceiving(vec![None, Some("hello")]), 2)
+            }
+        }
+
+        /// Enters the next round on every incoming message, finishes after `rounds` rounds
+        fn ticker(rounds: u16) -> Self {
+            Self::new(Advance::OnIncoming, rounds)
+        }
+
+        /// Collects two "data" messages, any other message isn't needed
+        fn collector() -> Self {
+            TestParty {
+                needed: Some("data"),
+                ..Self::new(Advance::OnIncoming, 2)
+            }
+        }
+
+        /// Fails to proceed round 1 with a non critical error `hiccups` times, then finishes
+        fn flaky(hiccups: u16) -> Self {
+            TestParty {
+                round: 1,
+                hiccups,
+                ..Self::new(Advance::OnProceed, 2)
+            }
+        }
+
+        /// Keeps proceeding until it fails to proceed the round `fail_at`
+        fn fails_at(fail_at: u16) -> Self {
+            TestParty {
+                fail_at: Some(fail_at),
+                ..Self::new(Advance::OnProceed, u16::MAX)
+            }
+        }
+
+        /// Waits for "r1" in round 1 and for "r2" in round 2, rejecting "r2" as premature
+        fn two_rounds() -> Self {
+            TestParty {
+                round: 1,
+                rejects_early: true,
+                ..Self::new(
+                    Advance::AfterReceiving(vec![None, Some("r1"), Some("r2")]),
+                    3,
+                )
+            }
+        }
+
+        /// Broadcasts `bodies` as its only round, then finishes
+        fn burst(bodies: Vec<&'static str>) -> Self {
+            TestParty {
+                broadcasts: vec![bodies],
+                ..Self::new(Advance::OnProceed, 1)
+            }
+        }
+
+        fn expected(&self) -> &[Option<&'static str>] {
+            match &self.advance {
+                Advance::AfterReceiving(expected) => expected,
+                Advance::OnIncoming | Advance::OnProceed => &[],
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum TestError {
+        Critical,
+        Hiccup,
+        WrongRound,
+    }
+
+    impl IsCritical for TestError {
+        fn is_critical(&self) -> bool {
+            matches!(self, TestError::Critical)
+        }
+    }
+
+    impl StateMachine for TestParty {
+        type MessageBody = &'static str;
+        type Err = TestError;
+        type Output = Vec<&'static str>;
+
+        fn handle_incoming(&mut self, msg: Msg<&'static str>) -> Result<(), TestError> {
+            let mut later = self.expected().iter().skip(usize::from(self.round) + 1);
+            if self.rejects_early && later.any(|body| *body == Some(msg.body)) {
+                return Err(TestError::WrongRound);
+            }
+            if self.acknowledges {
+                self.queue.push(Msg {
+                    sender: 1,
+                    receiver: Some(msg.sender),
+                    body: "ack",
+                });
+            }
+            if matches!(self.advance, Advance::OnIncoming) && self.is_message_needed(&msg) {
+                self.round += 1;
+            }
+            self.received.push(msg.body);
+            Ok(())
+        }
+
+        fn message_queue(&mut self) -> &mut Vec<Msg<&'static str>> {
+            &mut self.queue
+        }
+
+        fn wants_to_proceed(&self) -> bool {
+            if self.is_finished() {
+                return false;
+            }
+            match &self.advance {
+                Advance::OnIncoming => false,
+                Advance::OnProceed => true,
+                Advance::AfterReceiving(expected) => {
+                    match expected.get(usize::from(self.round)).copied().flatten() {
+                        Some(body) => self.received.contains(&body),
+                        None => true,
+                    }
+                }
+            }
+        }
+
+        fn proceed(&mut self) -> Result<(), TestError> {
+            if !self.wants_to_proceed() {
+                return Ok(());
+            }
+            if self.fail_at == Some(self.round) {
+                return Err(TestError::Critical);
+            }
+            if self.hiccups > 0 {
+                self.hiccups -= 1;
+                return Err(TestError::Hiccup);
+            }
+            let bodies = self
+                .broadcasts
+                .get(usize::from(self.round))
+                .cloned()
+                .unwrap_or_default();
+            for body in bodies {
+                self.queue.push(Msg {
+                    sender: 1,
+                    receiver: None,
+                    body,
+                });
+            }
+            self.round += 1;
+            Ok(())
+        }
+
+        fn round_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn round_timeout_reached(&mut self) -> TestError {
+            TestError::Critical
+        }
+
+        fn is_wrong_round(&self, error: &TestError) -> bool {
+            matches!(error, TestError::WrongRound)
+        }
+
+        fn is_message_needed(&self, msg: &Msg<&'static str>) -> bool {
+            self.needed
+                .map(|prefix| msg.body.starts_with(prefix))
+                .unwrap_or(true)
+        }
+
+        fn is_finished(&self) -> bool {
+            self.round == self.rounds
+        }
+
+        fn pick_output(&mut self) -> Option<Result<Vec<&'static str>, TestError>> {
+            Some(Ok(self.received.clone()))
+        }
+
+        fn current_round(&self) -> u16 {
+            self.round
+        }
+
+        fn total_rounds(&self) -> Option<u16> {
+            Some(self.rounds)
+        }
+
+        fn party_ind(&self) -> u16 {
+            1
+        }
+
+        fn parties(&self) -> u16 {
+            2
+        }
+    }
+
+    type TestEvent = ProtocolEvent<Vec<&'static str>, Error<TestError, (), Infallible>>;
+
+    async fn echo_events(send_before_proceed: bool) -> Vec<TestEvent> {
+        let echo = TestParty::echo();
+        let hello = Msg {
+            sender: 2,
+            receiver: None,
+            body: "hello",
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(hello)]).fuse();
+        let outgoing = futures::sink::drain();
+        AsyncProtocol::new(echo, incoming, outgoing)
+            .send_before_proceed(send_before_proceed)
+            .into_stream()
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn total_deadline_cuts_off_progressing_protocol() {
+        let run = |total_deadline: Duration| {
+            let ticker = TestParty::ticker(5);
+            // Every round completes in 30ms, well within the round timeout
+            let incoming = Box::pin(stream::iter(0..5).then(|_| async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Ok::<_, ()>(Msg {
+                    sender: 2,
+                    receiver: None,
+                    body: "tick",
+                })
+            }))
+            .fuse();
+            async move {
+                AsyncProtocol::new(ticker, incoming, futures::sink::drain())
+                    .set_round_timeout(Duration::from_millis(100))
+                    .set_total_deadline(total_deadline)
+                    .run()
+                    .await
+            }
+        };
+
+        let result = run(Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(Error::TotalDeadlineExceeded)));
+        run(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[test]
+    fn backoff_grows_with_round() {
+        let backoff = ExponentialBackoff {
+            base: Duration::from_millis(100),
+            factor: 2,
+            max: Duration::from_secs(1),
+        };
+        let round1 = backoff.deadline_for_round(1, false).unwrap();
+        let round3 = backoff.deadline_for_round(3, false).unwrap();
+        assert_eq!(round1, Duration::from_millis(100));
+        assert_eq!(round3, Duration::from_millis(400));
+        assert!(round3 > round1);
+        assert_eq!(
+            backoff.deadline_for_round(u16::MAX, false),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            FixedTimeout(round1).deadline_for_round(3, true),
+            Some(round1)
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_strategy_overrides_round_timeout() {
+        let echo = TestParty::echo();
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let started = std::time::Instant::now();
+        let result = AsyncProtocol::new(echo, incoming, futures::sink::drain())
+            .set_timeout_strategy(ExponentialBackoff {
+                base: Duration::from_millis(50),
+                factor: 2,
+                max: Duration::from_secs(1),
+            })
+            .run()
+            .await;
+        assert!(matches!(result, Err(Error::HandleIncomingTimeout(_))));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn progress_is_observed_while_running() {
+        tokio::time::pause();
+        let ticker = TestParty::ticker(5);
+        let incoming = Box::pin(stream::iter(0..5).then(|_| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, ()>(Msg {
+                sender: 2,
+                receiver: None,
+                body: "tick",
+            })
+        }))
+        .fuse();
+        let mut protocol = AsyncProtocol::new(ticker, incoming, futures::sink::drain());
+        let progress = protocol.progress();
+        assert_eq!(protocol.current_round(), None);
+        assert!(!protocol.is_running());
+
+        let run = tokio::spawn(async move {
+            protocol.run().await.unwrap();
+            protocol
+        });
+        let watch = async {
+            let mut observed: Vec<u16> = vec![];
+            let mut started = false;
+            loop {
+                if let Some(round) = progress.current_round() {
+                    if observed.last() != Some(&round) {
+                        observed.push(round);
+                    }
+                }
+                if progress.is_running() {
+                    started = true;
+                } else if started {
+                    return observed;
+                }
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        };
+        let observed = tokio::time::timeout(Duration::from_secs(5), watch)
+            .await
+            .unwrap();
+        let protocol = run.await.unwrap();
+
+        assert!(observed.len() > 2, "observed rounds {:?}", observed);
+        assert!(observed.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(progress.current_round(), Some(5));
+        assert_eq!(protocol.current_round(), Some(5));
+        assert!(!protocol.is_running());
+    }
+
+    #[tokio::test]
+    async fn cancelled_run_is_not_running() {
+        tokio::time::pause();
+        let echo = TestParty::echo();
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let mut protocol = AsyncProtocol::new(echo, incoming, futures::sink::drain());
+        let progress = protocol.progress();
+
+        let run = tokio::time::timeout(Duration::from_millis(50), protocol.run()).await;
+        assert!(run.is_err());
+        assert!(!progress.is_running());
+        assert!(!protocol.is_running());
+    }
+
+    #[tokio::test]
+    async fn overridden_round_timeout_is_enforced() {
+        let echo = TestParty::echo();
+        // The peer never says hello, and the party itself has no round timeout
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let started = std::time::Instant::now();
+        let result = AsyncProtocol::new(echo, incoming, futures::sink::drain())
+            .set_round_timeout(Duration::from_millis(50))
+            .run()
+            .await;
+        assert!(matches!(result, Err(Error::HandleIncomingTimeout(_))));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn phase_timings_are_populated() {
+        let echo = TestParty::echo();
+        let hello = Msg {
+            sender: 2,
+            receiver: None,
+            body: "hello",
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(hello)]).fuse();
+        let mut protocol = AsyncProtocol::new(echo, incoming, futures::sink::drain());
+        assert_eq!(protocol.phase_timings(), PhaseTimings::default());
+
+        protocol.run().await.unwrap();
+        let timings = protocol.phase_timings();
+        assert!(timings.send_outgoing > Duration::default());
+        assert!(timings.handle_incoming > Duration::default());
+        assert!(timings.proceed > Duration::default());
+    }
+
+    #[tokio::test]
+    async fn forwarded_bytes_match_serialized_messages() {
+        let echo = TestParty::echo();
+        let hello = Msg {
+            sender: 2,
+            receiver: None,
+            body: "hello",
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(hello)]).fuse();
+        let (tx, mut outgoing_rx) = broadcast::channel(10);
+        let (bytes_tx, mut bytes_rx) = mpsc::channel(10);
+        AsyncProtocol::new(echo, incoming, Outgoing { sender: tx })
+            .forward_bytes(bytes_tx)
+            .run()
+            .await
+            .unwrap();
+
+        let expected = vec![(None, "hello"), (Some(2), "ack"), (None, "bye")]
+            .into_iter()
+            .map(|(receiver, body)| Msg {
+                sender: 1,
+                receiver,
+                body: serde_json::to_vec(&Msg {
+                    sender: 1,
+                    receiver,
+                    body,
+                })
+                .unwrap(),
+            });
+        for msg in expected {
+            assert_eq!(bytes_rx.try_recv().unwrap(), msg);
+        }
+        assert!(bytes_rx.try_recv().is_err());
+        // Nothing reaches the typed sink
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn batch_deadline_cuts_off_second_session() {
+        let session = |greeted: bool| {
+            let echo = TestParty::echo();
+            let hello = Msg {
+                sender: 2,
+                receiver: None,
+                body: "hello",
+            };
+            let greetings = if greeted {
+                vec![Ok::<_, ()>(hello)]
+            } else {
+                vec![]
+            };
+            let incoming = stream::iter(greetings).chain(stream::pending()).fuse();
+            AsyncProtocol::new(echo, incoming, futures::sink::drain())
+        };
+        // The second session never gets a greeting, so it's stuck until the deadline
+        let sessions = vec![session(true), session(false), session(true)];
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(200);
+
+        match run_many_until(sessions, deadline).await {
+            Err(BatchError::DeadlineExceeded { index, completed }) => {
+                assert_eq!(index, 1);
+                assert_eq!(completed.len(), 1);
+            }
+            other => panic!("expected the deadline to be hit, got {:?}", other),
+        }
+
+        let sessions = vec![session(true), session(true), session(true)];
+        assert_eq!(run_many(sessions).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn send_before_proceed_changes_message_ordering() {
+        let position = |events: &[TestEvent]| {
+            let ack = events
+                .iter()
+                .position(|e| matches!(e, ProtocolEvent::MessageSent { receiver: Some(2) }))
+                .unwrap();
+            let round = events
+                .iter()
+                .position(|e| matches!(e, ProtocolEvent::RoundEntered(2)))
+                .unwrap();
+            (ack, round)
+        };
+
+        let events = echo_events(true).await;
+        assert!(matches!(events.last(), Some(ProtocolEvent::Finished(_))));
+        let (ack, round) = position(&events);
+        assert!(ack < round, "ack is sent before proceeding by default");
+
+        let events = echo_events(false).await;
+        assert!(matches!(events.last(), Some(ProtocolEvent::Finished(_))));
+        let (ack, round) = position(&events);
+        assert!(round < ack, "ack is sent after proceeding");
+    }
+
+    #[tokio::test]
+    async fn lost_message_is_retransmitted() {
+        let run = |retransmit: Option<u8>| async move {
+            let echo = TestParty::echo();
+            let (out_tx, mut out_rx) = futures::channel::mpsc::unbounded::<Msg<&'static str>>();
+            let (in_tx, in_rx) = futures::channel::mpsc::unbounded();
+            // Lossy link: the first message is dropped, the peer greets back on every hello
+            tokio::spawn(async move {
+                let mut dropped = false;
+                while let Some(msg) = out_rx.next().await {
+                    if !dropped {
+                        dropped = true;
+                        continue;
+                    }
+                    if msg.body == "hello" {
+                        let reply = Msg {
+                            sender: 2,
+                            receiver: None,
+                            body: "hello",
+                        };
+                        let _ = in_tx.unbounded_send(reply);
+                    }
+                }
+            });
+
+            let mut protocol = AsyncProtocol::new(echo, in_rx.map(Ok::<_, ()>), out_tx)
+                .set_round_timeout(Duration::from_millis(50));
+            if let Some(max_retries) = retransmit {
+                protocol = protocol.set_retransmit(max_retries);
+            }
+            protocol.run().await
+        };
+
+        assert!(matches!(
+            run(None).await,
+            Err(Error::HandleIncomingTimeout(_))
+        ));
+        run(Some(2)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn signing_completes_over_lossy_link_with_retransmission() {
+        tokio::time::pause();
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(100);
+        let party =
+            |i| Musig2Instance::with_fixed_seed(i, 2, message.clone(), KeyPair::create().unwrap());
+
+        // Party 1 loses its first message, and retransmits it twice before party 2 shows up, so
+        // party 2 receives it again once it's past the round
+        let (lossy_tx, mut lossy_rx) = futures::channel::mpsc::unbounded();
+        let wire = tx.clone();
+        tokio::spawn(async move {
+            let mut dropped = false;
+            while let Some(msg) = lossy_rx.next().await {
+                if dropped {
+                    let _ = wire.send(msg);
+                }
+                dropped = true;
+            }
+        });
+        let mut party1 = AsyncProtocol::new(party(1), incoming(tx.subscribe(), 1), lossy_tx)
+            .set_round_timeout(Duration::from_secs(1))
+            .set_retransmit(3);
+        let mut party2 = AsyncProtocol::new(
+            party(2),
+            incoming(tx.subscribe(), 2),
+            Outgoing { sender: tx.clone() },
+        )
+        .set_round_timeout(Duration::from_secs(1))
+        .set_retransmit(3);
+
+        let party1 = tokio::spawn(async move { party1.run().await });
+        let party2 = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            party2.run().await
+        });
+        let first = party1.await.unwrap().unwrap();
+        let second = party2.await.unwrap().unwrap();
+        assert_eq!(first.hash(), second.hash());
+    }
+
+    #[tokio::test]
+    async fn cancelled_after_first_round() {
+        let echo = TestParty::echo();
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel::<()>();
+        // Client disconnects as soon as our round 1 message is out
+        tokio::spawn(async move {
+            rx.next().await;
+            let _ = cancel_tx.send(());
+        });
+
+        let mut protocol = AsyncProtocol::new(echo, incoming, tx).with_cancel(async move {
+            let _ = cancel_rx.await;
+        });
+        let result = protocol.run().await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(protocol.state().map(|echo| echo.round), Some(1));
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_drained_on_cancel() {
+        let sent = |drain: bool| async move {
+            let echo = TestParty::echo();
+            let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+            let (tx, rx) = futures::channel::mpsc::unbounded();
+            let result = AsyncProtocol::new(echo, incoming, tx)
+                .drain_on_cancel(drain)
+                .run_with_cancel(futures::future::ready(()))
+                .await;
+            assert!(matches!(result, Err(Error::Cancelled)));
+            rx.map(|m| m.body).collect::<Vec<_>>().await
+        };
+
+        assert!(sent(false).await.is_empty());
+        assert_eq!(sent(true).await, vec!["hello"]);
+    }
+
+    async fn collect_burst(
+        policy: OverflowPolicy,
+    ) -> Result<Vec<&'static str>, Error<TestError, (), Infallible>> {
+        let burst = vec!["noise1", "noise2", "data1", "noise3", "data2"]
+            .into_iter()
+            .map(|body| {
+                Ok::<_, ()>(Msg {
+                    sender: 2,
+                    receiver: None,
+                    body,
+                })
+            });
+        let incoming = stream::iter(burst).chain(stream::pending()).fuse();
+        let collector = TestParty::collector();
+        AsyncProtocol::new(collector, incoming, futures::sink::drain())
+            .set_incoming_buffer(1, policy)
+            .run()
+            .await
+    }
+
+    #[tokio::test]
+    async fn incoming_buffer_overflow_policies() {
+        assert_eq!(
+            collect_burst(OverflowPolicy::DropOldest).await.unwrap(),
+            vec!["noise2", "data1", "data2"]
+        );
+        assert_eq!(
+            collect_burst(OverflowPolicy::DropNewest).await.unwrap(),
+            vec!["noise1", "data1", "data2"]
+        );
+        assert!(matches!(
+            collect_burst(OverflowPolicy::Error).await,
+            Err(Error::IncomingBufferOverflow { capacity: 1 })
+        ));
+    }
+
+    #[test]
+    fn incoming_buffer_is_capped_when_flooded_with_needed_messages() {
+        let is_needed = |body: &&str| body.starts_with("data");
+        for &policy in &[OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let mut buffer = IncomingBuffer {
+                items: VecDeque::new(),
+                capacity: 2,
+                policy,
+            };
+            buffer.push("noise", is_needed).unwrap();
+            for _ in 0..100 {
+                buffer.push("data", is_needed).unwrap();
+            }
+            assert_eq!(buffer.items, vec!["data", "data"]);
+        }
+
+        let mut buffer = IncomingBuffer {
+            items: VecDeque::from(vec!["data", "data"]),
+            capacity: 2,
+            policy: OverflowPolicy::Error,
+        };
+        assert!(buffer.push("data", is_needed).is_err());
+        assert_eq!(buffer.items.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn non_critical_proceed_errors_are_retried() {
+        let run = |retries| {
+            let flaky = TestParty::flaky(2);
+            let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+            async move {
+                AsyncProtocol::new(flaky, incoming, futures::sink::drain())
+                    .retry_proceed(retries, Duration::default())
+                    .run()
+                    .await
+            }
+        };
+
+        assert!(run(2).await.is_ok());
+        assert!(matches!(
+            run(1).await,
+            Err(Error::Proceed(RoundContext { round: 1, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn proceed_is_retried_after_delay() {
+        let run = |retries: Option<u16>| {
+            let flaky = TestParty::flaky(2);
+            let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+            let mut protocol = AsyncProtocol::new(flaky, incoming, futures::sink::drain());
+            if let Some(max) = retries {
+                protocol = protocol.retry_proceed(max, Duration::from_millis(10));
+            }
+            tokio::time::timeout(
+                Duration::from_millis(500),
+                async move { protocol.run().await },
+            )
+        };
+
+        let started = std::time::Instant::now();
+        assert!(run(Some(2)).await.unwrap().is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        // Without retries the failed round is never proceeded again
+        assert!(run(None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn proceed_retries_stop_at_round_deadline() {
+        let flaky = TestParty::flaky(2);
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let result = AsyncProtocol::new(flaky, incoming, futures::sink::drain())
+            .set_round_timeout(Duration::from_millis(50))
+            .retry_proceed(5, Duration::from_millis(100))
+            .run()
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Proceed(RoundContext { round: 1, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recovered_non_critical_errors_are_returned() {
+        let flaky = TestParty::flaky(1);
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let (_, caught) = AsyncProtocol::new(flaky, incoming, futures::sink::drain())
+            .retry_proceed(1, Duration::default())
+            .run_verbose()
+            .await
+            .unwrap();
+        assert_eq!(caught.len(), 1);
+        assert_eq!(caught[0].when, When::Proceed);
+    }
+
+    #[tokio::test]
+    async fn parties_exit_promptly_with_completion_announced() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, mut wire) = broadcast::channel(100);
+
+        let parties: Vec<_> = (1..=3)
+            .map(|i| {
+                let instance = Musig2Instance::with_fixed_seed(
+                    i,
+                    3,
+                    message.clone(),
+                    KeyPair::create().unwrap(),
+                );
+                AsyncProtocol::new(
+                    instance,
+                    incoming(tx.subscribe(), i),
+                    Outgoing { sender: tx.clone() },
+                )
+                .announce_completion(true)
+            })
+            .collect();
+
+        let mut handles = vec![];
+        for mut party in parties {
+            handles.push(tokio::spawn(async move { party.run().await }));
+        }
+
+        // Round timeout of `Musig2Instance` is 5 seconds
+        let results =
+            tokio::time::timeout(Duration::from_secs(3), futures::future::join_all(handles))
+                .await
+                .expect("parties should not wait for the round timeout");
+        let hashes: Vec<[u8; 32]> = results
+            .into_iter()
+            .map(|r| r.unwrap().unwrap().hash())
+            .collect();
+        assert!(hashes.windows(2).all(|w| w[0] == w[1]));
+
+        // Every party announced the result it agreed on
+        let mut announced = vec![];
+        while let Ok(msg) = wire.try_recv() {
+            if let ProtocolMessage(M::Complete(m)) = msg.body {
+                assert_eq!(m.result_hash, hashes[0].to_vec());
+                announced.push(msg.sender);
+            }
+        }
+        announced.sort_unstable();
+        assert_eq!(announced, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn overridden_timeout_blames_silent_parties_of_any_round() {
+        tokio::time::pause();
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+        // Party 2 never sends its round 1 message, which has no timeout of its own
+        let incoming = stream::pending::<Result<Msg<ProtocolMessage>, ()>>().fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_round_timeout(Duration::from_secs(1))
+            .run()
+            .await;
+        match result {
+            Err(Error::HandleIncomingTimeout(musig2_instance::Error::ProceedRound(
+                ProceedError::RoundTimedOut { round, party_ind },
+            ))) => assert_eq!((round, party_ind), (1, vec![2])),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_incoming_message_is_rejected() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+
+        let oversized = Msg {
+            sender: 2,
+            receiver: None,
+            body: ProtocolMessage(M::Round1(MessageRound1 {
+                ephemeral_keys: vec![],
+                message: vec![0u8; 1 << 20],
+                pubkey: vec![],
+            })),
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(oversized)]).fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_max_incoming_size(64 * 1024)
+            .run()
+            .await;
+        assert!(matches!(result, Err(Error::RecvOversized { .. })));
+    }
+
+    #[tokio::test]
+    async fn oversized_fragmented_message_is_rejected_before_reassembly() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+
+        // Each fragment fits the limit, the message they announce doesn't
+        let fragments = (0..4).map(|index| {
+            Ok::<_, ()>(Msg {
+                sender: 2,
+                receiver: None,
+                body: ProtocolMessage(M::Fragment(Fragment {
+                    id: 0,
+                    index,
+                    count: 8,
+                    bytes: vec![0u8; 20 * 1024],
+                })),
+            })
+        });
+        let incoming = stream::iter(fragments).chain(stream::pending()).fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_max_message_size(64 * 1024)
+            .set_max_incoming_size(64 * 1024)
+            .run()
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::RecvOversized {
+                size: 81920,
+                limit: 65536
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn fragments_in_flight_are_capped_per_sender() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+
+        // Every message is left incomplete
+        let fragments = (0..=MAX_FRAGMENTED_PER_SENDER as u64).map(|id| {
+            Ok::<_, ()>(Msg {
+                sender: 2,
+                receiver: None,
+                body: ProtocolMessage(M::Fragment(Fragment {
+                    id,
+                    index: 0,
+                    count: 2,
+                    bytes: vec![0u8; 16],
+                })),
+            })
+        });
+        let incoming = stream::iter(fragments).chain(stream::pending()).fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_max_message_size(64 * 1024)
+            .run()
+            .await;
+        assert!(matches!(result, Err(Error::BadFragment { sender: 2 })));
+    }
+
+    #[tokio::test]
+    async fn fragment_count_is_capped() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+
+        let fragment = Ok::<_, ()>(Msg {
+            sender: 2,
+            receiver: None,
+            body: ProtocolMessage(M::Fragment(Fragment {
+                id: 0,
+                index: 0,
+                count: MAX_FRAGMENT_COUNT + 1,
+                bytes: vec![0u8; 16],
+            })),
+        });
+        let incoming = stream::iter(vec![fragment]).chain(stream::pending()).fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_max_message_size(64 * 1024)
+            .run()
+            .await;
+        assert!(matches!(result, Err(Error::BadFragment { sender: 2 })));
+    }
+
+    #[test]
+    fn stale_fragments_are_dropped_on_round_change() {
+        let mut fragmentation = Fragmentation::<ProtocolMessage> {
+            max_size: 256,
+            next_id: 0,
+            partial: HashMap::new(),
+            buffered: 12,
+            decode: Msg::from_wire_bytes,
+        };
+        fragmentation
+            .partial
+            .insert((2, 0), (0, vec![Some(vec![0u8; 4]), None]));
+        fragmentation
+            .partial
+            .insert((3, 0), (1, vec![Some(vec![0u8; 8]), None]));
+
+        // Fragments of the previous round may still complete
+        fragmentation.drop_stale(1);
+        assert_eq!(fragmentation.partial.len(), 2);
+        fragmentation.drop_stale(2);
+        assert!(fragmentation.partial.contains_key(&(3, 0)));
+        assert_eq!(fragmentation.partial.len(), 1);
+        assert_eq!(fragmentation.buffered, 8);
+    }
+
+    fn abort_from(sender: u16, reason: String) -> Result<Msg<ProtocolMessage>, ()> {
+        Ok(Msg {
+            sender,
+            receiver: None,
+            body: ProtocolMessage(M::Abort { reason }),
+        })
+    }
+
+    #[tokio::test]
+    async fn abort_of_participant_is_honoured_with_reason_cut() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _rx) = broadcast::channel(20);
+        let incoming = stream::iter(vec![abort_from(2, "é".repeat(MAX_ABORT_REASON_LEN))])
+            .chain(stream::pending())
+            .fuse();
+
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+            .set_broadcast_abort(true)
+            .run()
+            .await;
+        match result {
+            Err(Error::Aborted { by: 2, reason }) => {
+                assert_eq!(reason, "é".repeat(MAX_ABORT_REASON_LEN / 2));
+            }
+            _ => panic!("abort wasn't honoured"),
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_is_ignored_unless_enabled_and_from_a_participant() {
+        tokio::time::pause();
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let cases = vec![
+            // Abort handling isn't enabled
+            (false, 2),
+            // Not a participant
+            (true, 0),
+            (true, 3),
+            // Claims to come from ourselves
+            (true, 1),
+        ];
+        for (enabled, sender) in cases {
+            let (tx, _rx) = broadcast::channel(20);
+            let incoming = stream::iter(vec![abort_from(sender, "stop".to_owned())])
+                .chain(stream::pending())
+                .fuse();
+            let instance =
+                Musig2Instance::with_fixed_seed(1, 2, message.clone(), KeyPair::create().unwrap());
+            let result = AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+                .set_broadcast_abort(enabled)
+                .set_round_timeout(Duration::from_millis(50))
+                .run()
+                .await;
+            assert!(
+                matches!(result, Err(Error::HandleIncomingTimeout(_))),
+                "abort from {} honoured",
+                sender
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_sent_in_fragments() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, mut wire) = broadcast::channel(1000);
+        let limit = 256;
+
+        let handles: Vec<_> = (1..=3)
+            .map(|i| {
+                let instance = Musig2Instance::with_fixed_seed(
+                    i,
+                    3,
+                    message.clone(),
+                    KeyPair::create().unwrap(),
+                );
+                let protocol = AsyncProtocol::new(
+                    instance,
+                    incoming(tx.subscribe(), i),
+                    Outgoing { sender: tx.clone() },
+                )
+                .set_max_message_size(limit);
+                tokio::spawn(async move { protocol.run().await })
+            })
+            .collect();
+        let results = futures::future::join_all(handles).await;
+        let hashes: Vec<[u8; 32]> = results
+            .into_iter()
+            .map(|r| r.unwrap().unwrap().hash())
+            .collect();
+        assert!(hashes.windows(2).all(|w| w[0] == w[1]));
+
+        let mut fragments = 0;
+        while let Ok(msg) = wire.try_recv() {
+            assert!(msg.to_wire_bytes().unwrap().len() <= limit);
+            match msg.body {
+                ProtocolMessage(M::Fragment(_)) => fragments += 1,
+                ProtocolMessage(M::Round1(_)) => panic!("round 1 message wasn't fragmented"),
+                _ => (),
+            }
+        }
+        assert!(fragments > 3);
+    }
+
+    #[tokio::test]
+    async fn withheld_acknowledgements_are_reported() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+
+        // The peer reveals its round 1 message, then goes silent
+        let mut peer =
+            Musig2Instance::with_fixed_seed(2, 2, message.clone(), KeyPair::create().unwrap());
+        peer.proceed().unwrap();
+        let round1: Vec<_> = peer.message_queue().drain(..).collect();
+        let ack = |round| Msg {
+            sender: 2,
+            receiver: Some(1),
+            body: ProtocolMessage(M::Ack(round)),
+        };
+
+        let run = |acks: Vec<Msg<ProtocolMessage>>| {
+            let (tx, rx) = broadcast::channel(20);
+            let incoming = stream::iter(round1.iter().cloned().chain(acks).map(Ok::<_, ()>))
+                .chain(stream::pending())
+                .fuse();
+            let instance =
+                Musig2Instance::with_fixed_seed(1, 2, message.clone(), KeyPair::create().unwrap());
+            async move {
+                let _rx = rx;
+                AsyncProtocol::new(instance, incoming, Outgoing { sender: tx })
+                    .require_acknowledgements(true)
+                    .run()
+                    .await
+            }
+        };
+
+        let (withheld, acknowledged) =
+            futures::future::join(run(vec![]), run(vec![ack(1), ack(2)])).await;
+        assert!(matches!(
+            withheld,
+            Err(Error::MessageNotAcknowledged { round: 1 })
+        ));
+        assert!(matches!(acknowledged, Err(Error::HandleIncomingTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn run_until_round_pauses_and_resumes() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _) = broadcast::channel(20);
+
+        let mut parties: Vec<_> = (1..=3)
+            .map(|i| {
+                let instance = Musig2Instance::with_fixed_seed(
+                    i,
+                    3,
+                    message.clone(),
+                    KeyPair::create().unwrap(),
+                );
+                AsyncProtocol::new(
+                    instance,
+                    incoming(tx.subscribe(), i),
+                    Outgoing { sender: tx.clone() },
+                )
+            })
+            .collect();
+
+        let mut paused = parties.remove(0);
+        let mut handles = vec![];
+        for mut party in parties {
+            handles.push(tokio::spawn(async move { party.run().await }));
+        }
+
+        assert!(paused.run_until_round(2).await.unwrap().is_none());
+        assert_eq!(paused.state().unwrap().current_round(), 2);
+        assert!(!paused.state().unwrap().is_finished());
+
+        let hash = paused.run().await.unwrap().hash();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap().hash(), hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn proceed_error_names_the_round() {
+        let state = TestParty::fails_at(2);
+        let ping = Msg {
+            sender: 2,
+            receiver: None,
+            body: "ping",
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(ping); 3]).fuse();
+        let result = AsyncProtocol::new(state, incoming, futures::sink::drain())
+            .run()
+            .await;
+
+        match result {
+            Err(Error::Proceed(err)) => assert_eq!(err.round, 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn early_message_is_replayed_once_round_changes() {
+        let run = |buffered: bool| async move {
+            let msgs = vec!["r2", "r1"].into_iter().map(|body| {
+                Ok::<_, ()>(Msg {
+                    sender: 2,
+                    receiver: None,
+                    body,
+                })
+            });
+            let incoming = stream::iter(msgs).chain(stream::pending()).fuse();
+            let state = TestParty::two_rounds();
+            let mut protocol = AsyncProtocol::new(state, incoming, futures::sink::drain());
+            if buffered {
+                protocol = protocol.buffer_early_messages(4);
+            }
+            tokio::time::timeout(Duration::from_secs(1), protocol.run()).await
+        };
+
+        assert!(matches!(run(true).await, Ok(Ok(_))));
+        // Without the buffer the round 2 message is dropped and never comes again
+        assert!(run(false).await.is_err());
+    }
+
+    /// Records rounds whose broadcast reached nobody
+    struct Undelivered(Vec<u16>);
+
+    impl<SM: StateMachine> ProtocolWatcher<SM> for &mut Undelivered {
+        fn caught_non_critical_error(&mut self, _when: When, _err: SM::Err) {}
+
+        fn broadcast_not_delivered(&mut self, round: u16) {
+            self.0.push(round);
+        }
+    }
+
+    #[tokio::test]
+    async fn receiverless_broadcast_is_reported() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _) = broadcast::channel(20);
+        let report = DeliveryReport::new();
+        let outgoing = ConfirmingOutgoing {
+            sender: tx,
+            report: report.clone(),
+        };
+
+        let mut undelivered = Undelivered(vec![]);
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let incoming = stream::iter(Vec::<Result<Msg<ProtocolMessage>, ()>>::new()).fuse();
+        let result = AsyncProtocol::new(instance, incoming, outgoing)
+            .confirm_delivery(report)
+            .set_watcher(&mut undelivered)
+            .run()
+            .await;
+
+        assert!(matches!(result, Err(Error::RecvEof)));
+        assert_eq!(undelivered.0, vec![1]);
+    }
+
+    /// Records every round transition
+    #[derive(Default)]
+    struct Transitions(Vec<(Option<u16>, u16)>);
+
+    impl<SM: StateMachine> ProtocolWatcher<SM> for &mut Transitions {
+        fn caught_non_critical_error(&mut self, _when: When, _err: SM::Err) {}
+
+        fn round_transition(&mut self, from: Option<u16>, to: u16) {
+            self.0.push((from, to));
+        }
+    }
+
+    #[tokio::test]
+    async fn round_transitions_are_reported() {
+        let echo = TestParty::echo();
+        let hello = Msg {
+            sender: 2,
+            receiver: None,
+            body: "hello",
+        };
+        let incoming = stream::iter(vec![Ok::<_, ()>(hello)]).fuse();
+
+        let mut transitions = Transitions::default();
+        AsyncProtocol::new(echo, incoming, futures::sink::drain())
+            .set_watcher(&mut transitions)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(transitions.0, vec![(None, 0), (Some(0), 1), (Some(1), 2)]);
+    }
+
+    #[derive(Default)]
+    struct Gaps(Vec<(u16, u64, u64)>);
+
+    impl<SM: StateMachine> ProtocolWatcher<SM> for &mut Gaps {
+        fn caught_non_critical_error(&mut self, _when: When, _err: SM::Err) {}
+
+        fn sequence_gap(&mut self, sender: u16, expected: u64, received: u64) {
+            self.0.push((sender, expected, received));
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_sequence_message_is_reported() {
+        let sequenced = |seq, body| Sequenced {
+            seq,
+            msg: Msg {
+                sender: 2,
+                receiver: None,
+                body,
+            },
+        };
+        // The second message overtook the first one
+        let burst = vec![sequenced(1, "data2"), sequenced(0, "data1")];
+        let report = SequenceReport::new();
+        let incoming = SequenceCheck::new(
+            stream::iter(burst)
+                .map(Ok::<_, ()>)
+                .chain(stream::pending()),
+            report.clone(),
+        )
+        .fuse();
+        let collector = TestParty::collector();
+
+        let mut gaps = Gaps::default();
+        let received = AsyncProtocol::new(collector, incoming, futures::sink::drain())
+            .track_sequence(report)
+            .set_watcher(&mut gaps)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(received, vec!["data2", "data1"]);
+        assert_eq!(gaps.0, vec![(2, 0, 1), (2, 2, 0)]);
+    }
+
+    #[tokio::test]
+    async fn stream_yields_protocol_events() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (tx, _) = broadcast::channel(20);
+
+        let mut parties: Vec<_> = (1..=3)
+            .map(|i| {
+                let instance = Musig2Instance::with_fixed_seed(
+                    i,
+                    3,
+                    message.clone(),
+                    KeyPair::create().unwrap(),
+                );
+                AsyncProtocol::new(
+                    instance,
+                    incoming(tx.subscribe(), i),
+                    Outgoing { sender: tx.clone() },
+                )
+            })
+            .collect();
+
+        let observed = parties.remove(0);
+        for mut party in parties {
+            tokio::spawn(async move { party.run().await });
+        }
+        let events: Vec<_> = observed.into_stream().collect().await;
+
+        let position = |round: u16| {
+            events
+                .iter()
+                .position(|e| matches!(e, ProtocolEvent::RoundEntered(r) if *r == round))
+                .unwrap()
+        };
+        assert_eq!(position(0), 0);
+        assert!(position(1) < position(2));
+        let sent = events
+            .iter()
+            .filter(|e| matches!(e, ProtocolEvent::MessageSent { receiver: None }))
+            .count();
+        let received = events
+            .iter()
+            .filter(|e| matches!(e, ProtocolEvent::MessageReceived { .. }))
+            .count();
+        assert_eq!(sent, 2);
+        assert_eq!(received, 4);
+        assert!(matches!(events.last(), Some(ProtocolEvent::Finished(_))));
+    }
+
+    /// Accepts a single item per poll, it's pending on every other `poll_ready`
+    struct OneAtATime {
+        ready: bool,
+        received: Vec<&'static str>,
+    }
+
+    impl futures::Sink<Msg<&'static str>> for OneAtATime {
+        type Error = Infallible;
+
+        fn poll_ready(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Infallible>> {
+            if self.ready {
+                std::task::Poll::Ready(Ok(()))
+            } else {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+
+        fn start_send(
+            mut self: std::pin::Pin<&mut Self>,
+            item: Msg<&'static str>,
+        ) -> Result<(), Infallible> {
+            assert!(self.ready, "item sent to a sink that isn't ready");
+            self.ready = false;
+            self.received.push(item.body);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Infallible>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Infallible>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_sink_receives_all_messages_in_order() {
+        let bodies = vec!["1", "2", "3", "4", "5"];
+        let burst = TestParty::burst(bodies.clone());
+        let incoming = stream::pending::<Result<Msg<&'static str>, ()>>().fuse();
+        let outgoing = OneAtATime {
+            ready: false,
+            received: vec![],
+        };
+        let mut protocol = AsyncProtocol::new(burst, incoming, outgoing);
+        protocol.run().await.unwrap();
+        assert_eq!(protocol.outgoing.received, bodies);
+    }
+}
@@ -5,6 +5,7 @@ use super::{
         state_machine::Msg,
     },
 };
+use serde::{Deserialize, Serialize};
 use std::{cmp::Ordering, ops};
 
 /// Received broadcast messages from every protocol participant
@@ -30,6 +31,38 @@ impl<B> ops::Index<u16> for BroadcastMsgs<B> {
     }
 }
 
+impl<B> BroadcastMsgs<B> {
+    /// Indices of the parties the messages came from, in ascending order
+    pub fn senders(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.msgs.len()).map(move |i| sender_at(self.my_ind, i))
+    }
+
+    /// Messages paired with the index of their sender, in ascending party's index order
+    pub fn iter_indexed(&self) -> impl Iterator<Item = (u16, &B)> {
+        self.senders().zip(self.msgs.iter())
+    }
+}
+
+/// Position of the message of `sender` among the messages received by `my_ind`, `None` for
+/// `my_ind` itself and the nonexistent party 0
+fn position_of(my_ind: u16, sender: u16) -> Option<usize> {
+    match Ord::cmp(&sender, &my_ind) {
+        Ordering::Less => usize::from(sender).checked_sub(1),
+        Ordering::Greater => Some(usize::from(sender) - 2),
+        Ordering::Equal => None,
+    }
+}
+
+/// Index of the party whose message is at position `i` of the messages received by `my_ind`
+fn sender_at(my_ind: u16, i: usize) -> u16 {
+    let party = i as u16 + 1;
+    if party < my_ind {
+        party
+    } else {
+        party + 1
+    }
+}
+
 impl<B> IntoIterator for BroadcastMsgs<B> {
     type Item = B;
     type IntoIter = <Vec<B> as IntoIterator>::IntoIter;
@@ -45,6 +78,9 @@ impl<M> MessageContainer for BroadcastMsgs<M> {
 }
 
 /// Receives broadcast messages from every protocol participant
+///
+/// Serializes the messages received so far, so a partially filled store can be restored.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BroadcastMsgsStore<M> {
     party_i: u16,
     msgs: Vec<Option<M>>,
@@ -72,6 +108,26 @@ impl<M> BroadcastMsgsStore<M> {
     pub fn messages_total(&self) -> usize {
         self.msgs.len()
     }
+
+    /// Whether a message was received from every party, i.e. the store can be finished
+    pub fn is_complete(&self) -> bool {
+        self.msgs_left == 0
+    }
+
+    /// Parties we haven't received a message from yet, in ascending order
+    pub fn missing_senders(&self) -> Vec<u16> {
+        self.msgs
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.is_none())
+            .map(|(i, _)| sender_at(self.party_i, i))
+            .collect()
+    }
+
+    /// Message received from the `sender`, if any
+    pub fn msg_from(&self, sender: u16) -> Option<&M> {
+        self.msgs.get(position_of(self.party_i, sender)?)?.as_ref()
+    }
 }
 
 impl<M> MessageStore for BroadcastMsgsStore<M> {
@@ -85,15 +141,13 @@ impl<M> MessageStore for BroadcastMsgsStore<M> {
         if msg.receiver.is_some() {
             return Err(StoreErr::ExpectedBroadcast);
         }
-        let party_j = match Ord::cmp(&msg.sender, &self.party_i) {
-            Ordering::Less => usize::from(msg.sender),
-            Ordering::Greater => usize::from(msg.sender) - 1,
-            Ordering::Equal => return Err(StoreErr::ItsFromMe),
-        };
-        let slot = self
-            .msgs
-            .get_mut(party_j - 1)
+        if msg.sender == self.party_i {
+            return Err(StoreErr::ItsFromMe);
+        }
+        let slot = position_of(self.party_i, msg.sender)
+            .and_then(|party_j| self.msgs.get_mut(party_j))
             .ok_or(StoreErr::UnknownSender { sender: msg.sender })?;
+        // Every sender has a single slot, so a full store only ever sees duplicates
         if slot.is_some() {
             return Err(StoreErr::MsgOverwrite);
         }
@@ -104,16 +158,7 @@ impl<M> MessageStore for BroadcastMsgsStore<M> {
     }
 
     fn contains_msg_from(&self, sender: u16) -> bool {
-        let party_j = match Ord::cmp(&sender, &self.party_i) {
-            Ordering::Less => usize::from(sender),
-            Ordering::Greater => usize::from(sender) - 1,
-            Ordering::Equal => return false,
-        };
-        match self.msgs.get(party_j - 1) {
-            None => false,
-            Some(None) => false,
-            Some(Some(_)) => true,
-        }
+        self.msg_from(sender).is_some()
     }
 
     fn wants_more(&self) -> bool {
@@ -131,25 +176,96 @@ impl<M> MessageStore for BroadcastMsgsStore<M> {
     }
 
     fn blame(&self) -> (u16, Vec<u16>) {
-        let ind = |i: u16| -> u16 {
-            if i < self.party_i - 1 {
-                i + 1
-            } else {
-                i + 2
-            }
+        (self.msgs_left as u16, self.missing_senders())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowing_store_is_rejected() {
+        let parties_n = 3;
+        let mut store = BroadcastMsgsStore::new(1, parties_n);
+        for sender in 2..=parties_n {
+            store
+                .push_msg(Msg {
+                    sender,
+                    receiver: None,
+                    body: sender,
+                })
+                .unwrap();
+        }
+        assert!(!store.wants_more());
+
+        // Neither a duplicate nor a party beyond `parties_n` fits into the full store
+        let duplicate = Msg {
+            sender: parties_n,
+            receiver: None,
+            body: 0,
         };
-        let guilty_parties = self
-            .msgs
-            .iter()
-            .enumerate()
-            .flat_map(|(i, m)| {
-                if m.is_none() {
-                    Some(ind(i as u16))
-                } else {
-                    None
-                }
+        assert_eq!(store.push_msg(duplicate), Err(StoreErr::MsgOverwrite));
+        let unknown = Msg {
+            sender: parties_n + 1,
+            receiver: None,
+            body: 0,
+        };
+        assert_eq!(
+            store.push_msg(unknown),
+            Err(StoreErr::UnknownSender {
+                sender: parties_n + 1
             })
-            .collect();
-        (self.msgs_left as u16, guilty_parties)
+        );
+        assert_eq!(store.messages_received(), usize::from(parties_n) - 1);
+        assert_eq!(store.finish().unwrap().msgs, vec![2, 3]);
+    }
+
+    #[test]
+    fn missing_senders_shrink_as_messages_arrive() {
+        let mut store = BroadcastMsgsStore::new(2, 3);
+        assert_eq!(store.missing_senders(), vec![1, 3]);
+        assert!(!store.is_complete());
+
+        store
+            .push_msg(Msg {
+                sender: 3,
+                receiver: None,
+                body: 3,
+            })
+            .unwrap();
+        assert_eq!(store.messages_received(), 1);
+        assert_eq!(store.missing_senders(), vec![1]);
+        assert!(!store.is_complete());
+
+        store
+            .push_msg(Msg {
+                sender: 1,
+                receiver: None,
+                body: 1,
+            })
+            .unwrap();
+        assert_eq!(store.messages_received(), 2);
+        assert!(store.missing_senders().is_empty());
+        assert!(store.is_complete());
+        assert!(!store.contains_msg_from(0));
+        assert!(!store.contains_msg_from(2));
+        assert!(store.contains_msg_from(3));
+        assert_eq!(store.finish().unwrap().msgs, vec![1, 3]);
+    }
+
+    #[test]
+    fn messages_are_paired_with_their_senders() {
+        let msgs = BroadcastMsgs {
+            my_ind: 2,
+            msgs: vec!["from 1", "from 3", "from 4"],
+        };
+        assert_eq!(msgs.senders().collect::<Vec<_>>(), vec![1, 3, 4]);
+        for (i, sender) in msgs.senders().enumerate() {
+            assert_eq!(position_of(2, sender), Some(i));
+        }
+        for (sender, msg) in msgs.iter_indexed() {
+            assert_eq!(*msg, format!("from {}", sender));
+        }
     }
 }
@@ -0,0 +1,330 @@
+//! Versioned binary wire format of the protocol messages
+//!
+//! Every frame is `length: u32 | version: u8 | tag: u8 | sender: u16 | receiver: u16 | body`,
+//! big-endian, where `length` counts the bytes following it and receiver `0` stands for a
+//! broadcast. The body depends on the tag:
+//!
+//! * commitment: the 32-byte [nonce commitment](CommittedSeed)
+//! * round 1: `nonce count: u8 | nonces | pubkey | message length: u32 | message`, with
+//!   33-byte compressed points
+//! * round 2: the 32-byte [signature fragment](MessageRound2::fragment_bytes)
+//! * completion: the [result hash](MessageComplete)
+//! * acknowledgement: the acknowledged round as `u16`
+//! * fragment: `id: u64 | index: u16 | count: u16 | bytes`
+//! * abort: the utf-8 reason
+//!
+//! Peers speaking another [PROTOCOL_VERSION] are rejected instead of misread, so a protocol
+//! change has to bump the version.
+use thiserror::Error;
+
+use super::{
+    musig2_instance::{ProtocolMessage, M},
+    rounds::{CommittedSeed, MessageComplete, MessageRound1, MessageRound2},
+    traits::state_machine::{Fragment, Msg},
+};
+use crate::cli::protocals::{error::Musig2Error, key::PublicKey};
+
+/// Version of the wire format written by [encode] and accepted by [decode]
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const TAG_COMMIT: u8 = 0;
+const TAG_ROUND1: u8 = 1;
+const TAG_ROUND2: u8 = 2;
+const TAG_COMPLETE: u8 = 3;
+const TAG_ACK: u8 = 4;
+const TAG_FRAGMENT: u8 = 5;
+const TAG_ABORT: u8 = 6;
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("unsupported protocol version {found}, this party speaks version {expected}")]
+    UnsupportedVersion { found: u8, expected: u8 },
+    #[error("unknown message type tag {0}")]
+    UnknownTag(u8),
+    #[error("frame is truncated or followed by trailing bytes")]
+    InvalidLength,
+    #[error("abort reason isn't valid utf-8")]
+    InvalidReason,
+    #[error("malformed message body: {0:?}")]
+    InvalidBody(Musig2Error),
+}
+
+impl From<Musig2Error> for CodecError {
+    fn from(err: Musig2Error) -> Self {
+        CodecError::InvalidBody(err)
+    }
+}
+
+/// Encodes `msg` into a length-prefixed frame of the current [PROTOCOL_VERSION]
+pub fn encode(msg: &Msg<ProtocolMessage>) -> Result<Vec<u8>, CodecError> {
+    let (tag, body) = match &msg.body.0 {
+        M::Commit(seed) => (TAG_COMMIT, seed.0.to_vec()),
+        M::Round1(m) => (TAG_ROUND1, encode_round1(m)?),
+        M::Round2(m) => (TAG_ROUND2, m.fragment_bytes()?.to_vec()),
+        M::Complete(m) => (TAG_COMPLETE, m.result_hash.clone()),
+        M::Ack(round) => (TAG_ACK, round.to_be_bytes().to_vec()),
+        M::Fragment(fragment) => {
+            let mut body = vec![];
+            body.extend_from_slice(&fragment.id.to_be_bytes());
+            body.extend_from_slice(&fragment.index.to_be_bytes());
+            body.extend_from_slice(&fragment.count.to_be_bytes());
+            body.extend_from_slice(&fragment.bytes);
+            (TAG_FRAGMENT, body)
+        }
+        M::Abort { reason } => (TAG_ABORT, reason.as_bytes().to_vec()),
+    };
+    let length = 6 + body.len();
+    if length > u32::MAX as usize {
+        return Err(CodecError::InvalidLength);
+    }
+
+    let mut bytes = Vec::with_capacity(4 + length);
+    bytes.extend_from_slice(&(length as u32).to_be_bytes());
+    bytes.push(PROTOCOL_VERSION);
+    bytes.push(tag);
+    bytes.extend_from_slice(&msg.sender.to_be_bytes());
+    bytes.extend_from_slice(&msg.receiver.unwrap_or(0).to_be_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+/// Decodes a single frame produced by [encode]
+///
+/// The version is checked before anything else, so a frame of another version is reported as
+/// [CodecError::UnsupportedVersion] however its body changed.
+pub fn decode(bytes: &[u8]) -> Result<Msg<ProtocolMessage>, CodecError> {
+    let mut reader = bytes;
+    let length = take(&mut reader, 4)?;
+    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    if reader.len() != length {
+        return Err(CodecError::InvalidLength);
+    }
+    let version = take(&mut reader, 1)?[0];
+    if version != PROTOCOL_VERSION {
+        return Err(CodecError::UnsupportedVersion {
+            found: version,
+            expected: PROTOCOL_VERSION,
+        });
+    }
+    let tag = take(&mut reader, 1)?[0];
+    let sender = take_u16(&mut reader)?;
+    let receiver = match take_u16(&mut reader)? {
+        0 => None,
+        i => Some(i),
+    };
+
+    let body = match tag {
+        TAG_COMMIT => {
+            let mut seed = [0u8; 32];
+            seed.copy_from_slice(take(&mut reader, 32)?);
+            M::Commit(CommittedSeed(seed))
+        }
+        TAG_ROUND1 => M::Round1(decode_round1(&mut reader)?),
+        TAG_ROUND2 => {
+            let mut fragment = [0u8; 32];
+            fragment.copy_from_slice(take(&mut reader, 32)?);
+            M::Round2(MessageRound2::from_fragment_bytes(fragment)?)
+        }
+        TAG_COMPLETE => M::Complete(MessageComplete {
+            result_hash: take_rest(&mut reader),
+        }),
+        TAG_ACK => M::Ack(take_u16(&mut reader)?),
+        TAG_FRAGMENT => {
+            let id = take(&mut reader, 8)?;
+            let mut id_bytes = [0u8; 8];
+            id_bytes.copy_from_slice(id);
+            M::Fragment(Fragment {
+                id: u64::from_be_bytes(id_bytes),
+                index: take_u16(&mut reader)?,
+                count: take_u16(&mut reader)?,
+                bytes: take_rest(&mut reader),
+            })
+        }
+        TAG_ABORT => M::Abort {
+            reason: String::from_utf8(take_rest(&mut reader))
+                .map_err(|_| CodecError::InvalidReason)?,
+        },
+        tag => return Err(CodecError::UnknownTag(tag)),
+    };
+    if !reader.is_empty() {
+        return Err(CodecError::InvalidLength);
+    }
+    Ok(Msg {
+        sender,
+        receiver,
+        body: ProtocolMessage(body),
+    })
+}
+
+fn encode_round1(msg: &MessageRound1) -> Result<Vec<u8>, CodecError> {
+    let compress = |point: &[u8]| -> Result<[u8; 33], CodecError> {
+        if point.len() != 65 {
+            return Err(Musig2Error::InvalidPublicKey.into());
+        }
+        let mut tt = [0u8; 65];
+        tt.copy_from_slice(point);
+        Ok(PublicKey::parse(&tt)?.serialize_compressed())
+    };
+    if msg.ephemeral_keys.len() > usize::from(u8::MAX) || msg.message.len() > u32::MAX as usize {
+        return Err(CodecError::InvalidLength);
+    }
+
+    let mut bytes = vec![msg.ephemeral_keys.len() as u8];
+    for nonce in msg.ephemeral_keys.iter() {
+        bytes.extend_from_slice(&compress(nonce)?);
+    }
+    bytes.extend_from_slice(&compress(&msg.pubkey)?);
+    bytes.extend_from_slice(&(msg.message.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&msg.message);
+    Ok(bytes)
+}
+
+fn decode_round1(reader: &mut &[u8]) -> Result<MessageRound1, CodecError> {
+    let nonce_count = take(reader, 1)?[0];
+    let mut ephemeral_keys = vec![];
+    for _ in 0..nonce_count {
+        ephemeral_keys.push(take_point(reader)?);
+    }
+    let pubkey = take_point(reader)?;
+    let len = take(reader, 4)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let message = take(reader, len)?.to_vec();
+    Ok(MessageRound1 {
+        ephemeral_keys,
+        message,
+        pubkey,
+    })
+}
+
+fn take<'a>(reader: &mut &'a [u8], n: usize) -> Result<&'a [u8], CodecError> {
+    if reader.len() < n {
+        return Err(CodecError::InvalidLength);
+    }
+    let (head, tail) = reader.split_at(n);
+    *reader = tail;
+    Ok(head)
+}
+
+fn take_rest(reader: &mut &[u8]) -> Vec<u8> {
+    let rest = reader.to_vec();
+    *reader = &[];
+    rest
+}
+
+fn take_u16(reader: &mut &[u8]) -> Result<u16, CodecError> {
+    let b = take(reader, 2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn take_point(reader: &mut &[u8]) -> Result<Vec<u8>, CodecError> {
+    let mut point = [0u8; 33];
+    point.copy_from_slice(take(reader, 33)?);
+    let pk = PublicKey::parse_compressed(&point)?;
+    Ok(pk.serialize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, CodecError, PROTOCOL_VERSION};
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::party::{
+        musig2_instance::{ProtocolMessage, M},
+        rounds::{CommittedSeed, MessageComplete, MessageRound2},
+        traits::state_machine::{Fragment, Msg, StateMachine},
+        Musig2Instance,
+    };
+    use crate::cli::protocals::KeyPair;
+
+    fn round1_msg() -> Msg<ProtocolMessage> {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut instance =
+            Musig2Instance::with_fixed_seed(2, 3, message, KeyPair::create().unwrap());
+        instance.proceed().unwrap();
+        instance.message_queue().pop().unwrap()
+    }
+
+    fn msg(receiver: Option<u16>, body: M) -> Msg<ProtocolMessage> {
+        Msg {
+            sender: 3,
+            receiver,
+            body: ProtocolMessage(body),
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let msgs = vec![
+            msg(None, M::Commit(CommittedSeed([5u8; 32]))),
+            round1_msg(),
+            msg(
+                Some(1),
+                M::Round2(MessageRound2::from_fragment_bytes([7u8; 32]).unwrap()),
+            ),
+            msg(
+                None,
+                M::Complete(MessageComplete {
+                    result_hash: vec![9u8; 32],
+                }),
+            ),
+            msg(Some(2), M::Ack(2)),
+            msg(
+                Some(1),
+                M::Fragment(Fragment {
+                    id: u64::MAX - 1,
+                    index: 3,
+                    count: 4,
+                    bytes: b"piece".to_vec(),
+                }),
+            ),
+            msg(
+                None,
+                M::Abort {
+                    reason: "nonce mismatch".into(),
+                },
+            ),
+        ];
+        for msg in msgs {
+            let bytes = encode(&msg).unwrap();
+            assert_eq!(bytes[4], PROTOCOL_VERSION);
+            let decoded = decode(&bytes).unwrap();
+            assert_eq!(
+                (decoded.sender, decoded.receiver),
+                (msg.sender, msg.receiver)
+            );
+            // Bodies don't implement PartialEq, their json encodings do compare
+            assert_eq!(
+                serde_json::to_vec(&decoded.body).unwrap(),
+                serde_json::to_vec(&msg.body).unwrap()
+            );
+            assert!(matches!(
+                decode(&bytes[..bytes.len() - 1]),
+                Err(CodecError::InvalidLength)
+            ));
+        }
+    }
+
+    #[test]
+    fn round1_points_are_compressed() {
+        let msg = round1_msg();
+        let json = msg.to_wire_bytes().unwrap();
+        assert!(encode(&msg).unwrap().len() < json.len());
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut bytes = encode(&round1_msg()).unwrap();
+        bytes[4] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            decode(&bytes),
+            Err(CodecError::UnsupportedVersion { found, expected })
+                if found == PROTOCOL_VERSION + 1 && expected == PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn unknown_tag_is_rejected() {
+        let mut bytes = encode(&msg(Some(2), M::Ack(1))).unwrap();
+        bytes[5] = 0xff;
+        assert!(matches!(decode(&bytes), Err(CodecError::UnknownTag(0xff))));
+    }
+}
@@ -1,13 +1,16 @@
 //! The structure of parties, Per party is a StateMachine
 pub mod async_protocol;
 mod broadcast;
+pub mod codec;
 pub mod musig2_instance;
 pub mod musig2_party;
+pub mod observer;
 mod rounds;
 pub mod session;
 mod sim;
 mod store_err;
 pub mod traits;
+pub mod two_party;
 pub mod watcher;
 
 pub type Store<C> = <C as traits::message::MessageContainer>::Store;
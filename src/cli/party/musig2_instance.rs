@@ -1,28 +1,162 @@
 use log::{info, warn};
+use rand_core::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::{fmt, mem::replace, time::Duration};
+use std::{collections::BTreeMap, fmt, mem::replace, time::Duration};
+use zeroize::{Zeroize, Zeroizing};
 
 use super::{
+    async_protocol::BadStateMachineReason,
     broadcast::BroadcastMsgs,
     rounds,
-    rounds::{MessageRound1, MessageRound2, Prepare, ProceedError, Round1, Round2, SignResult},
+    rounds::{
+        CommitNonces, CommittedSeed, MessageComplete, MessagePolicy, MessageRound1, MessageRound2,
+        NonceMode, NonceRng, Prepare, ProceedError, Round1, Round2, SignResult,
+    },
     store_err::StoreErr,
     traits::push::{Push, PushExt},
     traits::{
         message::MessageStore,
-        state_machine::{IsCritical, Msg, StateMachine},
+        state_machine::{Fragment, IsCritical, Msg, StateMachine},
     },
     Store,
 };
-use crate::cli::protocals::musig2::KeyPair;
+use crate::cli::protocals::{
+    key::{PrivateKey, PublicKey},
+    musig2::{KeyAggCache, KeyPair, PregeneratedNonce, NONCE_COUNT},
+};
 
 pub struct Musig2Instance {
     round: R,
+    /// Store of the nonce commitments, `None` unless the session
+    /// [commits to its nonces](Musig2Instance::with_nonce_commitment)
+    msgs0: Option<Store<BroadcastMsgs<CommittedSeed>>>,
     msgs1: Option<Store<BroadcastMsgs<MessageRound1>>>,
     msgs2: Option<Store<BroadcastMsgs<MessageRound2>>>,
     msgs_queue: Vec<Msg<ProtocolMessage>>,
+    /// Result hashes announced by the parties which already finished, at most one per peer
+    completions: BTreeMap<u16, Vec<u8>>,
     party_i: u16,
     party_n: u16,
+    config: SessionConfig,
+}
+
+/// Timeout of the round collecting the signature fragments
+const ROUND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything a session is configured with
+///
+/// Doesn't depend on the party, so parties can compare their configs to detect
+/// misconfiguration before signing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionConfig {
+    pub party_n: u16,
+    pub message: Vec<u8>,
+    /// Pinned signer set as serialized public keys, see [Musig2Instance::pin_signers]
+    pub signers: Option<Vec<Vec<u8>>>,
+    /// Number of nonces every signer contributes
+    pub nonce_count: usize,
+    pub nonce_mode: NonceMode,
+    pub round_timeout: Duration,
+    /// Whether the nonces are committed to in a round of their own before being revealed
+    #[serde(default)]
+    pub nonce_commitment: bool,
+}
+
+/// Serialized [Musig2Instance], see [Musig2Instance::dump_state]
+///
+/// Zeroized once dropped or [loaded](Musig2Instance::load_state).
+pub struct Snapshot(Zeroizing<Vec<u8>>);
+
+/// Content of a [Snapshot]: a session which didn't send its nonces yet, without the key pair
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    party_i: u16,
+    party_n: u16,
+    config: SessionConfig,
+    /// Public key of the key pair the session has to be restored with
+    public_key: PublicKey,
+    signers: Option<Vec<PublicKey>>,
+    tweak: Option<PrivateKey>,
+    key_agg_cache: Option<KeyAggCache>,
+    adaptor: Option<PublicKey>,
+    msgs0: Option<Store<BroadcastMsgs<CommittedSeed>>>,
+    msgs1: Option<Store<BroadcastMsgs<MessageRound1>>>,
+    msgs2: Option<Store<BroadcastMsgs<MessageRound2>>>,
+    completions: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Snapshot read back from storage
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Snapshot(Zeroizing::new(bytes))
+    }
+
+    /// Bytes to store, e.g. encrypted on disk
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Whether the snapshot was already [loaded](Musig2Instance::load_state)
+    pub fn is_consumed(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Builds a [Musig2Instance], see [Musig2Instance::builder]
+#[derive(Default)]
+pub struct Musig2InstanceBuilder {
+    party_i: Option<u16>,
+    party_n: Option<u16>,
+    message: Option<Vec<u8>>,
+    key_pair: Option<KeyPair>,
+    rng: Option<NonceRng>,
+}
+
+impl Musig2InstanceBuilder {
+    pub fn party_index(mut self, party_i: u16) -> Self {
+        self.party_i = Some(party_i);
+        self
+    }
+
+    pub fn party_count(mut self, party_n: u16) -> Self {
+        self.party_n = Some(party_n);
+        self
+    }
+
+    pub fn message(mut self, message: Vec<u8>) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    pub fn key_pair(mut self, key_pair: KeyPair) -> Self {
+        self.key_pair = Some(key_pair);
+        self
+    }
+
+    /// Draws the nonces from `rng`, see [Prepare::with_rng]
+    ///
+    /// Without it the nonces are drawn from the OS.
+    pub fn rng<R: RngCore + CryptoRng + Send + 'static>(mut self, rng: R) -> Self {
+        self.rng = Some(NonceRng::new(rng));
+        self
+    }
+
+    /// The instance, `None` if the party index, the party count, the message or the key pair
+    /// wasn't given
+    pub fn build(self) -> Option<Musig2Instance> {
+        let mut instance = Musig2Instance::with_fixed_seed(
+            self.party_i?,
+            self.party_n?,
+            self.message?,
+            self.key_pair?,
+        );
+        if let R::Prepare(prepare) = &mut instance.round {
+            prepare.rng = self.rng;
+            prepare.nonce_mode = NonceMode::Random;
+            instance.config.nonce_mode = NonceMode::Random;
+        }
+        Some(instance)
+    }
 }
 
 impl Musig2Instance {
@@ -32,20 +166,295 @@ impl Musig2Instance {
         message: Vec<u8>,
         key_pair: KeyPair,
     ) -> Self {
+        let config = SessionConfig {
+            party_n,
+            message: message.clone(),
+            signers: None,
+            nonce_count: NONCE_COUNT,
+            nonce_mode: NonceMode::Default,
+            round_timeout: ROUND_TIMEOUT,
+            nonce_commitment: false,
+        };
         Self {
             party_i,
             party_n,
-            round: R::Prepare(Prepare {
-                my_ind: party_i,
+            round: R::Prepare(Prepare::new(
+                party_i,
+                party_n,
                 key_pair,
                 message,
-            }),
+                NonceMode::Default,
+            )),
+            msgs0: None,
             msgs1: Some(Round1::expects_messages(party_i, party_n)),
             msgs2: Some(Round2::expects_messages(party_i, party_n)),
             msgs_queue: vec![],
+            completions: BTreeMap::new(),
+            config,
+        }
+    }
+
+    /// Builder of an instance, e.g. to draw the nonces from a generator other than the OS
+    pub fn builder() -> Musig2InstanceBuilder {
+        Musig2InstanceBuilder::default()
+    }
+
+    /// Creates an instance which commits to its nonces before the message is known
+    ///
+    /// The `Prepare` round then broadcasts the nonces only, forming a round 0 of nonce
+    /// commitments. The session stays in `Round1` until the message is
+    /// [bound](Musig2Instance::bind_message).
+    pub fn with_precommitted_nonces(party_i: u16, party_n: u16, key_pair: KeyPair) -> Self {
+        Self::with_fixed_seed(party_i, party_n, vec![], key_pair)
+    }
+
+    /// Creates an instance which runs an extra round before round 1, in which the parties
+    /// broadcast a commitment to their nonces
+    ///
+    /// The nonces are revealed in round 1 and checked against the commitments, so no party
+    /// can choose its nonces after seeing the others'. A party revealing other nonces fails
+    /// the round with [ProceedError::PartiesDidntRevealItsSeed]. Rounds are numbered from 1
+    /// starting with the commitment round, so the session has 3 rounds.
+    pub fn with_nonce_commitment(
+        party_i: u16,
+        party_n: u16,
+        message: Vec<u8>,
+        key_pair: KeyPair,
+    ) -> Self {
+        let mut instance = Self::with_fixed_seed(party_i, party_n, message, key_pair);
+        instance.msgs0 = Some(CommitNonces::expects_messages(party_i, party_n));
+        instance.config.nonce_commitment = true;
+        instance
+    }
+
+    /// Binds a [precommitted](Musig2Instance::with_precommitted_nonces) session to the
+    /// `message`
+    pub fn bind_message(&mut self, message: Vec<u8>) -> Result<()> {
+        match &mut self.round {
+            R::Prepare(prepare) if prepare.message.is_empty() => prepare.message = message.clone(),
+            R::Commit(commit) => commit
+                .round1
+                .bind_message(message.clone())
+                .map_err(Error::ProceedRound)?,
+            R::Round1(round) => round
+                .bind_message(message.clone())
+                .map_err(Error::ProceedRound)?,
+            _ => return Err(Error::ProceedRound(ProceedError::MessageAlreadyBound)),
+        }
+        self.config.message = message;
+        Ok(())
+    }
+
+    /// Configuration of the session
+    pub fn config(&self) -> SessionConfig {
+        self.config.clone()
+    }
+
+    /// Pins the signer set agreed in advance, the key of party `i` is at `signers[i - 1]`
+    ///
+    /// Public keys reported by the parties in `Round1` are checked against it. Fails with
+    /// [ProceedError::SignersAlreadyPinned] once the session started.
+    pub fn pin_signers(mut self, signers: Vec<PublicKey>) -> Result<Self> {
+        match &mut self.round {
+            R::Prepare(prepare) => {
+                self.config.signers =
+                    Some(signers.iter().map(|pk| pk.serialize().to_vec()).collect());
+                prepare.signers = Some(signers);
+                Ok(self)
+            }
+            _ => Err(Error::ProceedRound(ProceedError::SignersAlreadyPinned)),
+        }
+    }
+
+    /// Derives the nonces from the message and the signer set, hedged with fresh randomness
+    ///
+    /// Requires the [pinned signer set](Musig2Instance::pin_signers), see
+    /// [sign_deterministic_with_aux](crate::cli::protocals::musig2::sign_deterministic_with_aux)
+    /// for the security assumptions.
+    pub fn deterministic_nonce_with_aux(mut self) -> Self {
+        if let R::Prepare(prepare) = &mut self.round {
+            self.config.nonce_mode = NonceMode::DeterministicWithAux;
+            prepare.nonce_mode = NonceMode::DeterministicWithAux;
+        }
+        self
+    }
+
+    /// Sets a local policy the message must satisfy to be signed, see
+    /// [Prepare::with_message_policy]
+    pub fn message_policy(
+        mut self,
+        policy: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        if let R::Prepare(prepare) = &mut self.round {
+            prepare.policy = Some(MessagePolicy::new(policy));
+        }
+        self
+    }
+
+    /// Uses a nonce taken from a pool instead of generating one, see
+    /// [Prepare::with_pregenerated]
+    pub fn with_pregenerated_nonce(mut self, nonce: PregeneratedNonce) -> Self {
+        if let R::Prepare(prepare) = &mut self.round {
+            prepare.pregenerated = Some(nonce);
+        }
+        self
+    }
+
+    /// Signs for the aggregate key tweaked by `tweak`, see [Prepare::with_tweak]
+    pub fn tweak(mut self, tweak: PrivateKey) -> Self {
+        if let R::Prepare(prepare) = &mut self.round {
+            prepare.tweak = Some(tweak);
+        }
+        self
+    }
+
+    /// Reuses the aggregation of the signer keys, see [Prepare::with_key_agg_cache]
+    pub fn key_agg_cache(mut self, cache: KeyAggCache) -> Self {
+        if let R::Prepare(prepare) = &mut self.round {
+            prepare.key_agg_cache = Some(cache);
+        }
+        self
+    }
+
+    /// Aggregate public key of the session, known once the round 1 messages of all parties are
+    /// processed
+    ///
+    /// Returns `None` before that, see [Round2::aggregate_pubkey].
+    pub fn aggregate_pubkey(&self) -> Option<PublicKey> {
+        match &self.round {
+            R::Round2(round2) => Some(round2.aggregate_pubkey().clone()),
+            R::Finished(result) => Some(result.aggregated_key.clone()),
+            R::Prepare(_) | R::Commit(_) | R::Round1(_) | R::Gone => None,
+        }
+    }
+
+    /// Rounds, numbered as in [current_round](StateMachine::current_round), whose proceed is
+    /// expensive and better run off the async executor
+    pub fn expensive_rounds() -> Vec<u16> {
+        [
+            (0, Prepare::EXPENSIVE),
+            (1, Round1::EXPENSIVE),
+            (2, Round2::EXPENSIVE),
+        ]
+        .iter()
+        .filter(|(_, expensive)| *expensive)
+        .map(|(round, _)| *round)
+        .collect()
+    }
+
+    /// Snapshot of a session which didn't send its nonces yet, including the messages
+    /// received so far
+    ///
+    /// Restore it with [Musig2Instance::load_state], e.g. to resume a session after a process
+    /// restart. Neither the private key nor any nonce is part of the snapshot, the nonces are
+    /// generated once the restored session proceeds. A session past the `Prepare` round can't
+    /// be snapshotted, since resuming it would need its secret nonces, and two sessions
+    /// continued from one snapshot could sign different messages with them, which leaks the
+    /// private key. The [message policy](Musig2Instance::message_policy), the
+    /// [nonce generator](Musig2InstanceBuilder::rng) and a
+    /// [pregenerated nonce](Musig2Instance::with_pregenerated_nonce) aren't part of the
+    /// snapshot either.
+    pub fn dump_state(&self) -> serde_json::Result<Snapshot> {
+        let prepare = match &self.round {
+            R::Prepare(prepare) => prepare,
+            _ => {
+                return Err(serde::ser::Error::custom(
+                    "nonces are already sent, the session can't be snapshotted",
+                ))
+            }
+        };
+        let state = SessionState {
+            party_i: self.party_i,
+            party_n: self.party_n,
+            config: self.config.clone(),
+            public_key: prepare.key_pair.public_key.clone(),
+            signers: prepare.signers.clone(),
+            tweak: prepare.tweak.clone(),
+            key_agg_cache: prepare.key_agg_cache.clone(),
+            adaptor: prepare.adaptor.clone(),
+            msgs0: self.msgs0.clone(),
+            msgs1: self.msgs1.clone(),
+            msgs2: self.msgs2.clone(),
+            completions: self.completions.clone(),
+        };
+        serde_json::to_vec(&state).map(|bytes| Snapshot(Zeroizing::new(bytes)))
+    }
+
+    /// Restores a session from a [snapshot](Musig2Instance::dump_state) and consumes it
+    ///
+    /// The `key_pair` has to be the one the session was created with. Once loaded, the
+    /// snapshot is zeroized, so loading it again fails. Run the restored session with
+    /// [AsyncProtocol::new](super::async_protocol::AsyncProtocol::new).
+    pub fn load_state(snapshot: &mut Snapshot, key_pair: KeyPair) -> serde_json::Result<Self> {
+        if snapshot.is_consumed() {
+            return Err(serde::de::Error::custom("snapshot is already loaded"));
+        }
+        let state: SessionState = serde_json::from_slice(&snapshot.0)?;
+        if state.public_key != key_pair.public_key {
+            return Err(serde::de::Error::custom(
+                "snapshot was taken with another key pair",
+            ));
+        }
+        snapshot.0.zeroize();
+        let mut prepare = Prepare::new(
+            state.party_i,
+            state.party_n,
+            key_pair,
+            state.config.message.clone(),
+            state.config.nonce_mode,
+        );
+        prepare.signers = state.signers;
+        prepare.tweak = state.tweak;
+        prepare.key_agg_cache = state.key_agg_cache;
+        prepare.adaptor = state.adaptor;
+        Ok(Self {
+            round: R::Prepare(prepare),
+            msgs0: state.msgs0,
+            msgs1: state.msgs1,
+            msgs2: state.msgs2,
+            msgs_queue: vec![],
+            completions: state.completions,
+            party_i: state.party_i,
+            party_n: state.party_n,
+            config: state.config,
+        })
+    }
+
+    /// Our public key as sent in round 1, `None` once the key pair is gone
+    fn own_pubkey(&self) -> Option<Vec<u8>> {
+        let key_pair = match &self.round {
+            R::Prepare(prepare) => &prepare.key_pair,
+            R::Commit(commit) => &commit.round1.key_pair,
+            R::Round1(round1) => &round1.key_pair,
+            R::Round2(round2) => &round2.key_pair,
+            R::Finished(_) | R::Gone => return None,
+        };
+        Some(key_pair.public_key.serialize().to_vec())
+    }
+
+    /// Checks our result against the result hashes announced by the peers
+    fn check_completions(&self, result: &SignResult) -> Result<()> {
+        let hash = result.hash();
+        let party_ind: Vec<u16> = self
+            .completions
+            .iter()
+            .filter(|(_, h)| h[..] != hash[..])
+            .map(|(i, _)| *i)
+            .collect();
+        if party_ind.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ProceedRound(ProceedError::ResultMismatch {
+                party_ind,
+            }))
         }
     }
 
+    /// Number of rounds preceding round 1, i.e. 1 if the nonces are committed to
+    fn round_offset(&self) -> u16 {
+        u16::from(self.config.nonce_commitment)
+    }
+
     fn gmap_queue<'a, T, F>(&'a mut self, mut f: F) -> impl Push<Msg<T>> + 'a
     where
         F: FnMut(T) -> M + 'a,
@@ -56,6 +465,8 @@ impl Musig2Instance {
     /// Proceeds round state if it received enough messages and if it's cheap to compute or
     /// `may_block == true`
     fn proceed_round(&mut self, may_block: bool) -> Result<()> {
+        // Check whether enough nonce commitments have been received
+        let store0_wants_more = self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false);
         // Check whether enough messages have been received to complete the `Round1` of musig2
         let store1_wants_more = self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false);
         // Check whether enough messages have been received to complete the `Round2` of musig2
@@ -67,11 +478,15 @@ impl Musig2Instance {
             // which will construct the `Round1` message and add it to the corresponding message queue
             R::Prepare(p) if !p.is_expensive() || may_block => {
                 info!("R::Prepare {:?}", p);
-                // After proceed `Prepare` round, next_state is `Round1`
-                next_state = p
-                    .proceed(self.gmap_queue(M::Round1))
-                    .map(R::Round1)
-                    .map_err(Error::ProceedRound)?;
+                // After proceed `Prepare` round, next_state is `Round1`, or `Commit` if the
+                // nonces are committed to first
+                next_state = if self.config.nonce_commitment {
+                    p.proceed_committed(self.gmap_queue(M::Commit))
+                        .map(R::Commit)
+                } else {
+                    p.proceed(self.gmap_queue(M::Round1)).map(R::Round1)
+                }
+                .map_err(Error::ProceedRound)?;
 
                 true
             }
@@ -82,9 +497,29 @@ impl Musig2Instance {
                 next_state = s;
                 false
             }
+            // Reveal the nonces once the commitments of all the parties are received
+            R::Commit(round) if !store0_wants_more && (!round.is_expensive() || may_block) => {
+                info!("R::Commit {:?}", round);
+                let store = self.msgs0.take().expect("store gone before round complete");
+                let msgs = store.finish().map_err(Error::HandleMsg)?;
+                next_state = round
+                    .proceed(msgs, self.gmap_queue(M::Round1))
+                    .map(R::Round1)
+                    .map_err(Error::ProceedRound)?;
+                true
+            }
+            s @ R::Commit(_) => {
+                info!("R::Commit next, waiting for commitments");
+                next_state = s;
+                false
+            }
             // Proceed the `Round1` round if enough messages are received,
             // which will construct the `Round2` message and add it to the corresponding message queue
-            R::Round1(round) if !store1_wants_more && (!round.is_expensive() || may_block) => {
+            R::Round1(round)
+                if !store1_wants_more
+                    && round.is_message_bound()
+                    && (!round.is_expensive() || may_block) =>
+            {
                 info!("R::Round1 {:?}", round);
                 let store = self.msgs1.take().expect("store gone before round complete");
                 let msgs = store.finish().map_err(Error::HandleMsg)?;
@@ -109,10 +544,9 @@ impl Musig2Instance {
                 let store = self.msgs2.take().expect("store gone before round complete");
                 let msgs = store.finish().map_err(Error::HandleMsg)?;
                 //After `Round2` is processed, it enters the `Finish` round
-                next_state = round
-                    .proceed(msgs)
-                    .map(R::Finished)
-                    .map_err(Error::ProceedRound)?;
+                let result = round.proceed(msgs).map_err(Error::ProceedRound)?;
+                self.check_completions(&result)?;
+                next_state = R::Finished(result);
                 false
             }
             // Stay in Round2
@@ -146,14 +580,41 @@ impl StateMachine for Musig2Instance {
     // Proceed incoming messages
     fn handle_incoming(&mut self, msg: Msg<Self::MessageBody>) -> Result<()> {
         let current_round = self.current_round();
+        let offset = self.round_offset();
         info!("msg sender is {:?}", msg.sender);
         match msg.body {
+            ProtocolMessage(M::Commit(m)) => {
+                let store = self.msgs0.as_mut().ok_or(Error::OutOfOrderMsg {
+                    current_round,
+                    msg_round: 1,
+                })?;
+                store
+                    .push_msg(Msg {
+                        sender: msg.sender,
+                        receiver: msg.receiver,
+                        body: m,
+                    })
+                    .map_err(Error::HandleMsg)?;
+                self.proceed_round(false)
+            }
             ProtocolMessage(M::Round1(m)) => {
                 // `[critical-error]` Check whether the received message is out of date
                 let store = self.msgs1.as_mut().ok_or(Error::OutOfOrderMsg {
                     current_round,
-                    msg_round: 1,
+                    msg_round: 1 + offset,
                 })?;
+                // `[critical-error]` Another party announcing a different key under the same
+                // index means two parties are configured with one index
+                let pubkey = m.pubkey.clone();
+                let conflicting = match store.msg_from(msg.sender) {
+                    Some(stored) => stored.pubkey != pubkey,
+                    None => msg.sender == self.party_i && self.own_pubkey() != Some(pubkey),
+                };
+                if conflicting {
+                    return Err(Error::ProceedRound(ProceedError::DuplicatePartyIndex {
+                        party_ind: msg.sender,
+                    }));
+                }
                 // `[non-critical-error]` Check whether the received message can pass the pre-validation
                 store
                     .push_msg(Msg {
@@ -167,7 +628,7 @@ impl StateMachine for Musig2Instance {
             ProtocolMessage(M::Round2(m)) => {
                 let store = self.msgs2.as_mut().ok_or(Error::OutOfOrderMsg {
                     current_round,
-                    msg_round: 1,
+                    msg_round: 2 + offset,
                 })?;
                 store
                     .push_msg(Msg {
@@ -178,6 +639,29 @@ impl StateMachine for Musig2Instance {
                     .map_err(Error::HandleMsg)?;
                 self.proceed_round(false)
             }
+            ProtocolMessage(M::Complete(m)) => {
+                // `[non-critical-error]` Every peer announces its completion once
+                if msg.sender == 0 || msg.sender > self.party_n {
+                    return Err(Error::HandleMsg(StoreErr::UnknownSender {
+                        sender: msg.sender,
+                    }));
+                }
+                if msg.sender == self.party_i {
+                    return Err(Error::HandleMsg(StoreErr::ItsFromMe));
+                }
+                if self.completions.contains_key(&msg.sender) {
+                    return Err(Error::HandleMsg(StoreErr::MsgOverwrite));
+                }
+                info!("party {} announced completion", msg.sender);
+                self.completions.insert(msg.sender, m.result_hash);
+                match &self.round {
+                    R::Finished(result) => self.check_completions(result),
+                    _ => Ok(()),
+                }
+            }
+            ProtocolMessage(M::Ack(_))
+            | ProtocolMessage(M::Fragment(_))
+            | ProtocolMessage(M::Abort { .. }) => Ok(()),
         }
     }
 
@@ -186,14 +670,17 @@ impl StateMachine for Musig2Instance {
     }
 
     fn wants_to_proceed(&self) -> bool {
+        let store0_wants_more = self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false);
         let store1_wants_more = self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false);
         let store2_wants_more = self.msgs2.as_ref().map(|s| s.wants_more()).unwrap_or(false);
 
         match self.round {
             // `Prepare` round always need to be performed
             R::Prepare(_) => true,
-            // Proceed the `Round1` when there are enough messages.
-            R::Round1(_) => !store1_wants_more,
+            // Reveal the nonces when all the commitments are received.
+            R::Commit(_) => !store0_wants_more,
+            // Proceed the `Round1` when there are enough messages and the message is known.
+            R::Round1(ref round) => !store1_wants_more && round.is_message_bound(),
             // Proceed the `Round2` when there are enough messages.
             R::Round2(_) => !store2_wants_more,
             // If it is finished, it will not be proceed any further.
@@ -205,24 +692,47 @@ impl StateMachine for Musig2Instance {
         self.proceed_round(true)
     }
 
+    fn awaits_messages(&self) -> bool {
+        match self.round {
+            R::Commit(_) => self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            R::Round1(_) => self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            R::Round2(_) => self.msgs2.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            R::Prepare(_) | R::Finished(_) | R::Gone => false,
+        }
+    }
+
     fn round_timeout(&self) -> Option<Duration> {
         if matches!(self.round, R::Round2(_)) {
-            Some(Duration::from_secs(5))
+            Some(ROUND_TIMEOUT)
         } else {
             None
         }
     }
 
     fn round_timeout_reached(&mut self) -> Self::Err {
-        if !matches!(self.round, R::Round2(_)) {
-            panic!("no timeout was set")
+        // The executor may override the timeout, so any round can time out. The parties whose
+        // message the round still waits for are to blame
+        let party_ind = match self.round {
+            R::Commit(_) => self.msgs0.as_ref().map(|s| s.missing_senders()),
+            R::Round1(_) => self.msgs1.as_ref().map(|s| s.missing_senders()),
+            R::Round2(_) => self.msgs2.as_ref().map(|s| s.missing_senders()),
+            R::Prepare(_) | R::Finished(_) | R::Gone => None,
+        }
+        .unwrap_or_default();
+        Error::ProceedRound(ProceedError::RoundTimedOut {
+            round: self.current_round(),
+            party_ind,
+        })
+    }
+
+    fn is_round_expensive(&self) -> bool {
+        match &self.round {
+            R::Prepare(round) => round.is_expensive(),
+            R::Commit(round) => round.is_expensive(),
+            R::Round1(round) => round.is_expensive(),
+            R::Round2(round) => round.is_expensive(),
+            R::Finished(_) | R::Gone => false,
         }
-        let (_, parties) = self
-            .msgs2
-            .as_ref()
-            .expect("store is gone, but round is not over yet")
-            .blame();
-        Error::ProceedRound(ProceedError::PartiesDidntRevealItsSeed { party_ind: parties })
     }
 
     fn is_finished(&self) -> bool {
@@ -237,22 +747,109 @@ impl StateMachine for Musig2Instance {
         }
 
         match replace(&mut self.round, R::Gone) {
+            // Invariant independent of the checks made while proceeding: never output a
+            // signature which doesn't verify
+            R::Finished(result) if !result.verify() => Some(Err(Error::BadStateMachine(
+                BadStateMachineReason::OutputFailsVerification,
+            ))),
             R::Finished(result) => Some(Ok(result)),
             _ => unreachable!("guaranteed by match expression above"),
         }
     }
 
+    fn completion_message(&self, output: &Self::Output) -> Option<Self::MessageBody> {
+        Some(ProtocolMessage(M::Complete(MessageComplete {
+            result_hash: output.hash().to_vec(),
+        })))
+    }
+
+    fn abort_message(&self, error: &Self::Err) -> Option<Self::MessageBody> {
+        Some(ProtocolMessage(M::Abort {
+            reason: error.to_string(),
+        }))
+    }
+
+    fn abort_reason<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b str> {
+        match body {
+            ProtocolMessage(M::Abort { reason }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn acknowledgement(&self, msg: &Msg<Self::MessageBody>) -> Option<Self::MessageBody> {
+        let offset = self.round_offset();
+        match msg.body {
+            ProtocolMessage(M::Commit(_)) => Some(ProtocolMessage(M::Ack(1))),
+            ProtocolMessage(M::Round1(_)) => Some(ProtocolMessage(M::Ack(1 + offset))),
+            ProtocolMessage(M::Round2(_)) => Some(ProtocolMessage(M::Ack(2 + offset))),
+            ProtocolMessage(M::Complete(_))
+            | ProtocolMessage(M::Ack(_))
+            | ProtocolMessage(M::Fragment(_))
+            | ProtocolMessage(M::Abort { .. }) => None,
+        }
+    }
+
+    fn acknowledged_round(&self, body: &Self::MessageBody) -> Option<u16> {
+        match body {
+            ProtocolMessage(M::Ack(round)) => Some(*round),
+            _ => None,
+        }
+    }
+
+    fn fragment_message(&self, fragment: Fragment) -> Option<Self::MessageBody> {
+        Some(ProtocolMessage(M::Fragment(fragment)))
+    }
+
+    fn as_fragment<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b Fragment> {
+        match body {
+            ProtocolMessage(M::Fragment(fragment)) => Some(fragment),
+            _ => None,
+        }
+    }
+
+    fn is_wrong_round(&self, error: &Self::Err) -> bool {
+        // A message of a past round is stale, only the ones of a later round are worth keeping
+        matches!(error, Error::OutOfOrderMsg { current_round, msg_round } if msg_round > current_round)
+    }
+
+    fn is_message_needed(&self, msg: &Msg<Self::MessageBody>) -> bool {
+        // A round message is needed until its store received one from the sender
+        match msg.body {
+            ProtocolMessage(M::Commit(_)) => self
+                .msgs0
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Round1(_)) => self
+                .msgs1
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Round2(_)) => self
+                .msgs2
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Complete(_))
+            | ProtocolMessage(M::Ack(_))
+            | ProtocolMessage(M::Fragment(_))
+            | ProtocolMessage(M::Abort { .. }) => true,
+        }
+    }
+
     fn current_round(&self) -> u16 {
+        let offset = self.round_offset();
         match self.round {
             R::Prepare(_) => 0,
-            R::Round1(_) => 1,
-            R::Round2(_) => 2,
-            R::Finished(_) | R::Gone => 3,
+            R::Commit(_) => 1,
+            R::Round1(_) => 1 + offset,
+            R::Round2(_) => 2 + offset,
+            R::Finished(_) | R::Gone => 3 + offset,
         }
     }
 
     fn total_rounds(&self) -> Option<u16> {
-        Some(2)
+        Some(2 + self.round_offset())
     }
 
     fn party_ind(&self) -> u16 {
@@ -266,12 +863,10 @@ impl StateMachine for Musig2Instance {
 
 impl fmt::Debug for Musig2Instance {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let current_round = match &self.round {
-            R::Prepare(_) => "0",
-            R::Round1(_) => "1",
-            R::Round2(_) => "2",
-            R::Finished(_) => "[Finished]",
-            R::Gone => "[Gone]",
+        let current_round: String = match &self.round {
+            R::Finished(_) => "[Finished]".into(),
+            R::Gone => "[Gone]".into(),
+            _ => self.current_round().to_string(),
         };
         let msgs1 = match self.msgs1.as_ref() {
             Some(msgs) => format!("[{}/{}]", msgs.messages_received(), msgs.messages_total()),
@@ -296,6 +891,7 @@ impl fmt::Debug for Musig2Instance {
 #[allow(clippy::large_enum_variant)]
 pub enum R {
     Prepare(Prepare),
+    Commit(CommitNonces),
     Round1(Round1),
     Round2(Round2),
     Finished(SignResult),
@@ -308,12 +904,22 @@ pub enum R {
 ///
 /// Hides message structure so it could be changed without breaking semver policy.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ProtocolMessage(M);
+pub struct ProtocolMessage(pub(crate) M);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-enum M {
+pub(crate) enum M {
+    Commit(rounds::CommittedSeed),
     Round1(rounds::MessageRound1),
     Round2(rounds::MessageRound2),
+    Complete(rounds::MessageComplete),
+    /// Acknowledges receipt of the message of the given round
+    Ack(u16),
+    /// Piece of a message over the transport size limit
+    Fragment(Fragment),
+    /// Sender stopped the protocol after a critical error
+    Abort {
+        reason: String,
+    },
 }
 type Result<T> = std::result::Result<T, Error>;
 
@@ -324,9 +930,33 @@ pub enum Error {
     /// Received message didn't pass pre-validation
     HandleMsg(StoreErr),
     /// Received message which we didn't expect to receive (e.g. message from previous round)
+    ///
+    /// Only critical unless the message belongs to a past round.
     OutOfOrderMsg { current_round: u16, msg_round: u16 },
     /// [MusigInstance::pick_output] called twice
     DoublePickResult,
+    /// Broken invariant of the state machine, e.g. the signature it's about to output
+    /// doesn't verify
+    BadStateMachine(BadStateMachineReason),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ProceedRound(err) => write!(f, "proceed round: {:?}", err),
+            Error::HandleMsg(err) => write!(f, "handle message: {}", err),
+            Error::OutOfOrderMsg {
+                current_round,
+                msg_round,
+            } => write!(
+                f,
+                "message of round {} received in round {}",
+                msg_round, current_round
+            ),
+            Error::DoublePickResult => write!(f, "output is already picked"),
+            Error::BadStateMachine(reason) => write!(f, "buggy state machine: {}", reason),
+        }
+    }
 }
 
 impl IsCritical for Error {
@@ -341,6 +971,15 @@ impl IsCritical for Error {
                 warn!("Error::HandleMsg, non-critical error");
                 false
             }
+            // A message of a round we're already past is stale, e.g. a peer retransmitted it
+            // before receiving ours, and is just dropped
+            Error::OutOfOrderMsg {
+                current_round,
+                msg_round,
+            } if msg_round < current_round => {
+                warn!("Error::OutOfOrderMsg of a past round, non-critical error");
+                false
+            }
             Error::OutOfOrderMsg { .. } => {
                 warn!("Error::OutOfOrderMsg, critical error");
                 true
@@ -349,6 +988,452 @@ impl IsCritical for Error {
                 warn!("Error::DoublePickResult, critical error");
                 true
             }
+            Error::BadStateMachine(_) => {
+                warn!("Error::BadStateMachine, critical error");
+                true
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BadStateMachineReason, Error, Musig2Instance, ProtocolMessage, SessionConfig, M, R,
+    };
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::party::{
+        rounds::{MessageComplete, NonceMode, ProceedError, Round1, Round2, SignResult},
+        store_err::StoreErr,
+        traits::state_machine::{IsCritical, Msg, StateMachine},
+    };
+    use crate::cli::protocals::{key::PrivateKey, KeyAgg, KeyPair};
+
+    #[test]
+    fn signers_cannot_be_pinned_once_started() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let kp = KeyPair::create().unwrap();
+        let signers = vec![kp.public_key.clone(), KeyPair::create().unwrap().public_key];
+
+        let mut instance = Musig2Instance::with_fixed_seed(1, 2, message, kp);
+        instance.proceed().unwrap();
+        assert!(matches!(
+            instance.pin_signers(signers),
+            Err(Error::ProceedRound(ProceedError::SignersAlreadyPinned))
+        ));
+    }
+
+    #[test]
+    fn duplicate_into_full_store_is_not_critical() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut parties: Vec<Musig2Instance> = (1..=2)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 2, message.clone(), KeyPair::create().unwrap())
+            })
+            .collect();
+        let mut round1 = vec![];
+        for party in &mut parties {
+            party.proceed().unwrap();
+            round1.append(party.message_queue());
+        }
+        // Party 2 moves on to round 2, whose message fills the store of party 1 early
+        parties[1].handle_incoming(round1[0].clone()).unwrap();
+        parties[1].proceed().unwrap();
+        let round2 = parties[1].message_queue().split_off(0);
+        parties[0].handle_incoming(round2[0].clone()).unwrap();
+
+        let err = parties[0].handle_incoming(round2[0].clone()).unwrap_err();
+        assert!(!err.is_critical(), "{}", err);
+
+        parties[0].handle_incoming(round1[1].clone()).unwrap();
+        parties[0].proceed().unwrap();
+        assert!(parties[0].pick_output().unwrap().is_ok());
+    }
+
+    #[test]
+    fn completions_are_kept_once_per_peer() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut instance =
+            Musig2Instance::with_fixed_seed(1, 3, message, KeyPair::create().unwrap());
+        let complete = |sender| Msg {
+            sender,
+            receiver: None,
+            body: ProtocolMessage(M::Complete(MessageComplete {
+                result_hash: vec![sender as u8; 32],
+            })),
+        };
+
+        instance.handle_incoming(complete(2)).unwrap();
+        for _ in 0..100 {
+            let err = instance.handle_incoming(complete(2)).unwrap_err();
+            assert!(matches!(err, Error::HandleMsg(StoreErr::MsgOverwrite)));
+            assert!(!err.is_critical());
+        }
+        for &sender in &[0, 1, 4, u16::MAX] {
+            assert!(instance.handle_incoming(complete(sender)).is_err());
+        }
+        instance.handle_incoming(complete(3)).unwrap();
+        assert_eq!(
+            instance.completions.keys().copied().collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn timeout_blames_missing_senders_in_every_round() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut parties: Vec<Musig2Instance> = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap())
+            })
+            .collect();
+        let timed_out = |party: &mut Musig2Instance| match party.round_timeout_reached() {
+            Error::ProceedRound(ProceedError::RoundTimedOut { round, party_ind }) => {
+                (round, party_ind)
+            }
+            err => panic!("unexpected error: {}", err),
+        };
+        assert_eq!(timed_out(&mut parties[0]), (0, vec![]));
+
+        let mut round1 = vec![];
+        for party in &mut parties {
+            party.proceed().unwrap();
+            round1.append(party.message_queue());
+        }
+        // Party 3 goes silent after round 1
+        parties[0].handle_incoming(round1[2].clone()).unwrap();
+        assert_eq!(timed_out(&mut parties[0]), (1, vec![2]));
+
+        parties[0].handle_incoming(round1[1].clone()).unwrap();
+        parties[0].proceed().unwrap();
+        assert_eq!(timed_out(&mut parties[0]), (2, vec![2, 3]));
+    }
+
+    #[test]
+    fn stale_messages_are_not_critical() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut parties: Vec<Musig2Instance> = (1..=2)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 2, message.clone(), KeyPair::create().unwrap())
+            })
+            .collect();
+        let mut round1 = vec![];
+        for party in &mut parties {
+            party.proceed().unwrap();
+            round1.append(party.message_queue());
+        }
+        parties[0].handle_incoming(round1[1].clone()).unwrap();
+        parties[0].proceed().unwrap();
+        let round2 = parties[0].message_queue().split_off(0);
+        parties[1].handle_incoming(round1[0].clone()).unwrap();
+        parties[1].proceed().unwrap();
+        parties[1].handle_incoming(round2[0].clone()).unwrap();
+        parties[1].proceed().unwrap();
+        assert!(parties[1].is_finished());
+
+        // Retransmitted messages of both rounds arrive once they're past
+        for msg in [&round1[0], &round2[0]].iter() {
+            let err = parties[1].handle_incoming((*msg).clone()).unwrap_err();
+            assert!(
+                matches!(err, Error::OutOfOrderMsg { .. }) && !err.is_critical(),
+                "{}",
+                err
+            );
+        }
+        assert!(parties[1].pick_output().unwrap().is_ok());
+    }
+
+    #[test]
+    fn identical_sessions_have_equal_configs() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..2).map(|_| KeyPair::create().unwrap()).collect();
+        let signers: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+
+        let configs: Vec<SessionConfig> = key_pairs
+            .into_iter()
+            .enumerate()
+            .map(|(i, kp)| {
+                Musig2Instance::with_fixed_seed(i as u16 + 1, 2, message.clone(), kp)
+                    .pin_signers(signers.clone())
+                    .unwrap()
+                    .deterministic_nonce_with_aux()
+                    .config()
+            })
+            .collect();
+        assert_eq!(configs[0], configs[1]);
+
+        let json = serde_json::to_string(&configs[0]).unwrap();
+        let restored: SessionConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, configs[0]);
+
+        let other = Musig2Instance::with_fixed_seed(
+            1,
+            2,
+            format_musig_msg(Vec::from("other".as_bytes())),
+            KeyPair::create().unwrap(),
+        );
+        assert_ne!(other.config(), configs[0]);
+    }
+
+    #[test]
+    fn expensive_rounds_match_round_flags() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let party = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let prepare_expensive = match &party.round {
+            R::Prepare(prepare) => prepare.is_expensive(),
+            _ => unreachable!("fresh instance starts at Prepare"),
+        };
+        let flags = [
+            (0, prepare_expensive),
+            (1, Round1::EXPENSIVE),
+            (2, Round2::EXPENSIVE),
+        ];
+
+        let expensive = Musig2Instance::expensive_rounds();
+        for (round, flag) in flags.iter() {
+            assert_eq!(expensive.contains(round), *flag, "round {}", round);
+        }
+        assert!(expensive.iter().all(|round| *round < 3));
+    }
+
+    #[test]
+    fn aggregate_pubkey_is_known_after_round1() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let pks: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        let mut parties: Vec<Musig2Instance> = key_pairs
+            .into_iter()
+            .enumerate()
+            .map(|(i, kp)| Musig2Instance::with_fixed_seed(i as u16 + 1, 3, message.clone(), kp))
+            .collect();
+
+        let mut round1 = vec![];
+        for party in &mut parties {
+            assert_eq!(party.aggregate_pubkey(), None);
+            party.proceed().unwrap();
+            assert_eq!(party.aggregate_pubkey(), None);
+            round1.append(party.message_queue());
+        }
+        for party in &mut parties {
+            for msg in round1.iter().filter(|m| m.sender != party.party_ind()) {
+                party.handle_incoming(msg.clone()).unwrap();
+            }
+        }
+
+        let expected = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        for party in &parties {
+            assert_eq!(party.current_round(), 2);
+            assert_eq!(party.aggregate_pubkey(), Some(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn session_resumes_from_snapshot() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let mut parties: Vec<Musig2Instance> = key_pairs
+            .iter()
+            .zip(1..)
+            .map(|(key_pair, i)| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), key_pair.clone())
+            })
+            .collect();
+
+        let mut round1: Vec<Vec<_>> = vec![vec![]];
+        for party in parties.iter_mut().skip(1) {
+            party.proceed().unwrap();
+            // Nonces are sent, the session can't be snapshotted anymore
+            assert!(party.dump_state().is_err());
+            round1.push(party.message_queue().drain(..).collect());
+        }
+
+        // Party 1 is restarted before sending its nonces, with the nonces of party 2 received
+        parties[0].handle_incoming(round1[1][0].clone()).unwrap();
+        let mut snapshot = parties[0].dump_state().unwrap();
+        assert!(Musig2Instance::load_state(&mut snapshot, key_pairs[1].clone()).is_err());
+        assert!(!snapshot.is_consumed());
+        parties[0] = Musig2Instance::load_state(&mut snapshot, key_pairs[0].clone()).unwrap();
+        // The snapshot can't resume a second session
+        assert!(snapshot.is_consumed());
+        assert!(Musig2Instance::load_state(&mut snapshot, key_pairs[0].clone()).is_err());
+
+        parties[0].proceed().unwrap();
+        round1[0] = parties[0].message_queue().drain(..).collect();
+        for (i, party) in parties.iter_mut().enumerate() {
+            for (j, msgs) in round1.iter().enumerate() {
+                // The nonces of party 2 are restored from the snapshot
+                if i != j && (i, j) != (0, 1) {
+                    party.handle_incoming(msgs[0].clone()).unwrap();
+                }
+            }
+        }
+        let round2: Vec<Vec<_>> = parties
+            .iter_mut()
+            .map(|party| {
+                assert_eq!(party.current_round(), 2);
+                party.message_queue().drain(..).collect()
+            })
+            .collect();
+        for (i, party) in parties.iter_mut().enumerate() {
+            for (j, msgs) in round2.iter().enumerate() {
+                if i != j {
+                    party.handle_incoming(msgs[0].clone()).unwrap();
+                }
+            }
+        }
+
+        let hashes: Vec<[u8; 32]> = parties
+            .iter_mut()
+            .map(|party| {
+                assert!(party.is_finished());
+                party.pick_output().unwrap().unwrap().hash()
+            })
+            .collect();
+        assert!(hashes.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[test]
+    fn sign_result_lists_contributing_parties() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut parties: Vec<_> = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap())
+            })
+            .collect();
+        let mut round1 = vec![];
+        for party in &mut parties {
+            party.proceed().unwrap();
+            round1.append(party.message_queue());
+        }
+        for party in parties.iter_mut() {
+            for msg in round1.iter().filter(|m| m.sender != party.party_ind()) {
+                party.handle_incoming(msg.clone()).unwrap();
+            }
+        }
+        let mut round2 = vec![];
+        for party in &mut parties {
+            round2.append(party.message_queue());
+        }
+        for party in parties.iter_mut() {
+            for msg in round2.iter().filter(|m| m.sender != party.party_ind()) {
+                party.handle_incoming(msg.clone()).unwrap();
+            }
+        }
+
+        for party in &mut parties {
+            let result = party.pick_output().unwrap().unwrap();
+            assert_eq!(result.signers, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn unverifiable_result_is_caught_at_pick_output() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut party = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+        let r = KeyPair::create().unwrap().public_key;
+        party.round = R::Finished(SignResult {
+            r: r.clone(),
+            s: PrivateKey::parse(&[1u8; 32]).unwrap(),
+            commit: PrivateKey::parse(&[1u8; 32]).unwrap(),
+            aggregated_key: r,
+            signers: vec![],
+        });
+
+        assert!(matches!(
+            party.pick_output(),
+            Some(Err(Error::BadStateMachine(
+                BadStateMachineReason::OutputFailsVerification
+            )))
+        ));
+    }
+
+    /// Counts up from its initial value, fully predictable, so only fit for tests
+    struct CountingRng(u64);
+
+    impl rand_core::RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 += 1;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    // Not secure at all, the test only checks that the injected generator is used
+    impl rand_core::CryptoRng for CountingRng {}
+
+    #[test]
+    fn injected_rng_drives_nonces() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pair = KeyPair::create().unwrap();
+        let nonces = |start: u64| {
+            let mut party = Musig2Instance::builder()
+                .party_index(1)
+                .party_count(2)
+                .message(message.clone())
+                .key_pair(key_pair.clone())
+                .rng(CountingRng(start))
+                .build()
+                .unwrap();
+            party.proceed().unwrap();
+            match &party.message_queue()[0].body.0 {
+                M::Round1(msg) => msg.ephemeral_keys.clone(),
+                _ => unreachable!("Prepare sends the round 1 message"),
+            }
+        };
+
+        assert_eq!(nonces(0), nonces(0));
+        assert_ne!(nonces(0), nonces(1));
+        assert!(Musig2Instance::builder().party_index(1).build().is_none());
+    }
+
+    #[test]
+    fn only_messages_of_a_later_round_are_wrong_round() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let instance = Musig2Instance::with_fixed_seed(1, 2, message, KeyPair::create().unwrap());
+
+        assert!(instance.is_wrong_round(&Error::OutOfOrderMsg {
+            current_round: 1,
+            msg_round: 2,
+        }));
+        assert!(!instance.is_wrong_round(&Error::OutOfOrderMsg {
+            current_round: 2,
+            msg_round: 1,
+        }));
+        assert!(!instance.is_wrong_round(&Error::DoublePickResult));
+    }
+
+    #[test]
+    fn builder_draws_nonces_from_os_by_default() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pair = KeyPair::create().unwrap();
+        let nonces = || {
+            let mut party = Musig2Instance::builder()
+                .party_index(1)
+                .party_count(2)
+                .message(message.clone())
+                .key_pair(key_pair.clone())
+                .build()
+                .unwrap();
+            assert_eq!(party.config().nonce_mode, NonceMode::Random);
+            party.proceed().unwrap();
+            match &party.message_queue()[0].body.0 {
+                M::Round1(msg) => msg.ephemeral_keys.clone(),
+                _ => unreachable!("Prepare sends the round 1 message"),
+            }
+        };
+
+        assert_ne!(nonces(), nonces());
+    }
+}
@@ -1,6 +1,6 @@
 use super::{AsyncSession, MSessionId, Musig2Instance, MSESSION_ID};
 use crate::cli::party::{
-    async_protocol::AsyncProtocol,
+    async_protocol::{AsyncProtocol, DeliveryReport},
     musig2_instance::ProtocolMessage,
     sim::benchmark::{Benchmark, BenchmarkResults},
     traits::state_machine::*,
@@ -14,6 +14,7 @@ use futures::{
 use libp2p::{Multiaddr, PeerId};
 use std::{
     collections::HashMap,
+    convert::Infallible,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -46,6 +47,36 @@ impl<M> Sink<Msg<M>> for Outgoing<M> {
     }
 }
 
+/// Outgoing sink which reports how many receivers every message reached
+///
+/// Unlike [Outgoing], sending when no one is subscribed isn't an error: the message is recorded
+/// as reaching zero receivers, see [AsyncProtocol::confirm_delivery].
+pub struct ConfirmingOutgoing<M> {
+    pub sender: broadcast::Sender<Msg<M>>,
+    pub report: DeliveryReport,
+}
+
+impl<M> Sink<Msg<M>> for ConfirmingOutgoing<M> {
+    type Error = Infallible;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Msg<M>) -> Result<(), Self::Error> {
+        self.report.record(self.sender.send(item).unwrap_or(0));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
 pub fn incoming<M: Clone + Send + Unpin + 'static>(
     mut rx: broadcast::Receiver<Msg<M>>,
     me: u16,
@@ -0,0 +1,736 @@
+//! A view-only party, it follows a session and verifies the result without signing
+
+#![allow(non_snake_case)]
+
+use log::info;
+use secp256k1::Message;
+use std::{fmt, mem::replace, time::Duration};
+
+use super::{
+    broadcast::BroadcastMsgs,
+    musig2_instance::{Error, ProtocolMessage, M},
+    rounds::{
+        check_distinct_keys, check_distinct_nonces, parse_nonces, parse_public_key, CommitNonces,
+        CommittedSeed, MessageRound1, MessageRound2, ProceedError, Round1, Round2, SignResult,
+    },
+    traits::{
+        message::MessageStore,
+        state_machine::{Fragment, Msg, StateMachine},
+    },
+    Store,
+};
+use crate::cli::protocals::{
+    error::Musig2Error,
+    key::{PrivateKey, PublicKey},
+    musig2::*,
+    signature::*,
+};
+
+/// Observer consumes the `Round1` and `Round2` broadcasts of the signers, computes the
+/// aggregate key and the final signature, then verifies it.
+///
+/// It never holds a key and never emits messages. Its party index is `party_n + 1`,
+/// so it doesn't collide with any signer. Outputs whether the signature verifies, see
+/// [Musig2Verifier] for the signature itself.
+pub struct Observer(Musig2Verifier);
+
+impl Observer {
+    /// Creates an observer of a session between `party_n` signers signing `message`
+    pub fn new(party_n: u16, message: Vec<u8>) -> Self {
+        Self(Musig2Verifier::new(party_n, message))
+    }
+
+    /// Creates an observer of a session whose signers commit to their nonces, see
+    /// [Musig2Verifier::with_nonce_commitment]
+    pub fn with_nonce_commitment(party_n: u16, message: Vec<u8>) -> Self {
+        Self(Musig2Verifier::with_nonce_commitment(party_n, message))
+    }
+
+    /// Verifies the signature under the aggregate key tweaked by `tweak`, see
+    /// [Musig2Verifier::tweak]
+    pub fn tweak(self, tweak: PrivateKey) -> Self {
+        Self(self.0.tweak(tweak))
+    }
+}
+
+/// Verifier-only party, it reconstructs the signature of a session from the broadcasts of
+/// the signers
+///
+/// Like the [Observer] it never holds a key, never generates a nonce or a signature fragment
+/// and never emits messages, its party index is `party_n + 1`. Outputs the same [SignResult]
+/// as the signers once it verifies, or [ProceedError::InvalidAggregatedSignature] otherwise.
+pub struct Musig2Verifier {
+    round: O,
+    /// Store of the nonce commitments, `None` unless the signers
+    /// [commit to their nonces](Musig2Verifier::with_nonce_commitment)
+    msgs0: Option<Store<BroadcastMsgs<CommittedSeed>>>,
+    msgs1: Option<Store<BroadcastMsgs<MessageRound1>>>,
+    msgs2: Option<Store<BroadcastMsgs<MessageRound2>>>,
+    msgs_queue: Vec<Msg<ProtocolMessage>>,
+    /// Commitments of the signers, in party's index order, once the commitment round is over
+    commitments: Option<Vec<CommittedSeed>>,
+    party_n: u16,
+    message: Vec<u8>,
+    nonce_commitment: bool,
+    tweak: Option<PrivateKey>,
+}
+
+impl Musig2Verifier {
+    /// Creates a verifier of a session between `party_n` signers signing `message`
+    pub fn new(party_n: u16, message: Vec<u8>) -> Self {
+        Self {
+            round: O::Round1,
+            msgs0: None,
+            msgs1: Some(Round1::expects_messages(party_n + 1, party_n + 1)),
+            msgs2: Some(Round2::expects_messages(party_n + 1, party_n + 1)),
+            msgs_queue: vec![],
+            commitments: None,
+            party_n,
+            message,
+            nonce_commitment: false,
+            tweak: None,
+        }
+    }
+
+    /// Creates a verifier of a session whose signers commit to their nonces first, see
+    /// [Musig2Instance::with_nonce_commitment](super::Musig2Instance::with_nonce_commitment)
+    ///
+    /// The revealed nonces are checked against the commitments, and rounds are numbered like
+    /// the signers do, starting with the commitment round.
+    pub fn with_nonce_commitment(party_n: u16, message: Vec<u8>) -> Self {
+        let mut verifier = Self::new(party_n, message);
+        verifier.round = O::Commit;
+        verifier.msgs0 = Some(CommitNonces::expects_messages(party_n + 1, party_n + 1));
+        verifier.nonce_commitment = true;
+        verifier
+    }
+
+    /// Verifies the signature under the aggregate key tweaked by `tweak`, as the signers
+    /// [tweaking it](super::Musig2Instance::tweak) produce it
+    pub fn tweak(mut self, tweak: PrivateKey) -> Self {
+        self.tweak = Some(tweak);
+        self
+    }
+
+    /// Number of rounds preceding round 1, i.e. 1 if the nonces are committed to
+    fn round_offset(&self) -> u16 {
+        u16::from(self.nonce_commitment)
+    }
+
+    fn proceed_round(&mut self) -> Result<(), Error> {
+        let store0_wants_more = self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+        let store1_wants_more = self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+        let store2_wants_more = self.msgs2.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+
+        match replace(&mut self.round, O::Gone) {
+            O::Commit if !store0_wants_more => {
+                let store = self.msgs0.take().expect("store gone before round complete");
+                let msgs = store.finish().map_err(Error::HandleMsg)?;
+                self.commitments = Some(msgs.msgs);
+                self.round = O::Round1;
+                self.proceed_round()
+            }
+            O::Round1 if !store1_wants_more => {
+                let store = self.msgs1.take().expect("store gone before round complete");
+                let msgs = store.finish().map_err(Error::HandleMsg)?;
+                let (X_tilde, R, commit, tweak) =
+                    self.observe_round1(msgs).map_err(Error::ProceedRound)?;
+                self.round = O::Round2 {
+                    X_tilde,
+                    R,
+                    commit,
+                    tweak,
+                };
+                self.proceed_round()
+            }
+            O::Round2 {
+                X_tilde,
+                R,
+                commit,
+                tweak,
+            } if !store2_wants_more => {
+                let store = self.msgs2.take().expect("store gone before round complete");
+                let msgs = store.finish().map_err(Error::HandleMsg)?;
+                let result = self.observe_round2(msgs, X_tilde, R, commit, tweak);
+                info!("observer verify result: {}", result.is_ok());
+                self.round = O::Finished(result);
+                Ok(())
+            }
+            s => {
+                self.round = s;
+                Ok(())
+            }
+        }
+    }
+
+    /// Computes the aggregate key, the aggregate nonce and the challenge of the signers, along
+    /// with the tweak the signature is offset by if the key is tweaked
+    #[allow(clippy::type_complexity)]
+    fn observe_round1(
+        &self,
+        input: BroadcastMsgs<MessageRound1>,
+    ) -> Result<(PublicKey, PublicKey, PrivateKey, Option<PrivateKey>), ProceedError> {
+        if let Some(commitments) = &self.commitments {
+            let party_ind: Vec<u16> = input
+                .iter_indexed()
+                .filter(|(party_ind, msg)| {
+                    commitments.get(usize::from(*party_ind) - 1)
+                        != Some(&CommittedSeed::commit(msg))
+                })
+                .map(|(party_ind, _)| party_ind)
+                .collect();
+            if !party_ind.is_empty() {
+                return Err(ProceedError::PartiesDidntRevealItsSeed { party_ind });
+            }
+        }
+
+        let mut pks = vec![];
+        let mut nonces = vec![];
+        for (party_ind, msg) in input.iter_indexed() {
+            pks.push(parse_public_key(party_ind, &msg.pubkey)?);
+            if msg.ephemeral_keys.len() != NONCE_COUNT {
+                return Err(ProceedError::NonceCountMismatch {
+                    party_ind,
+                    expected: NONCE_COUNT,
+                    got: msg.ephemeral_keys.len(),
+                });
+            }
+            nonces.push(parse_nonces(party_ind, &msg.ephemeral_keys)?);
+        }
+        check_distinct_keys(&pks)?;
+        check_distinct_nonces(&nonces)?;
+
+        // Signers aggregate in canonical order
+        // Signatures are valid under the (tweaked) key lifted to even `y`
+        let key_agg = KeyAgg::key_aggregation_n(&KeyAgg::sort_canonical(&pks), 0)?;
+        let (X_tilde, _, tweak) = key_agg.signing_key(self.tweak.as_ref())?;
+        let R_j_vec = aggregate_nonces(&nonces)?;
+        let (commit, r, _) = compute_challenge_params(&self.message, &X_tilde, &R_j_vec)?;
+        Ok((X_tilde, r, commit, tweak))
+    }
+
+    /// Sums the signature fragments and verifies the final signature
+    fn observe_round2(
+        &self,
+        input: BroadcastMsgs<MessageRound2>,
+        X_tilde: PublicKey,
+        R: PublicKey,
+        commit: PrivateKey,
+        tweak: Option<PrivateKey>,
+    ) -> Result<SignResult, ProceedError> {
+        let mut fragments = vec![];
+        for (party_ind, msg) in input.iter_indexed() {
+            fragments.push(
+                PrivateKey::parse_slice(&msg.sign_fragment)
+                    .map_err(|_| ProceedError::InvalidPartialSignature { party_ind })?,
+            );
+        }
+        let (first, rest) = fragments
+            .split_first()
+            .ok_or(Musig2Error::InvalidInputLength)?;
+        let s = rest.iter().fold(
+            Ok(first.clone()),
+            |acc: Result<PrivateKey, Musig2Error>, s_i| acc?.add_scalar(s_i),
+        )?;
+        let s = match &tweak {
+            Some(tweak) => s.add_scalar(&commit.mul_scalar(tweak)?)?,
+            None => s,
+        };
+
+        let signature = Signature {
+            rx: PrivateKey::parse_slice(&R.x_coor())?,
+            s: s.clone(),
+        };
+        let message = Message::parse_slice(&self.message)?;
+        if !verify(&signature, &message, &X_tilde).unwrap_or(false) {
+            return Err(ProceedError::InvalidAggregatedSignature);
+        }
+        Ok(SignResult {
+            r: R,
+            s,
+            commit,
+            aggregated_key: X_tilde,
+            signers: (1..=self.party_n).collect(),
+        })
+    }
+}
+
+impl StateMachine for Musig2Verifier {
+    type MessageBody = ProtocolMessage;
+    type Err = Error;
+    type Output = SignResult;
+
+    fn handle_incoming(&mut self, msg: Msg<Self::MessageBody>) -> Result<(), Error> {
+        let current_round = self.current_round();
+        let offset = self.round_offset();
+        match msg.body {
+            ProtocolMessage(M::Commit(m)) if self.nonce_commitment => {
+                let store = self.msgs0.as_mut().ok_or(Error::OutOfOrderMsg {
+                    current_round,
+                    msg_round: 1,
+                })?;
+                store
+                    .push_msg(Msg {
+                        sender: msg.sender,
+                        receiver: msg.receiver,
+                        body: m,
+                    })
+                    .map_err(Error::HandleMsg)?;
+            }
+            ProtocolMessage(M::Round1(m)) => {
+                let store = self.msgs1.as_mut().ok_or(Error::OutOfOrderMsg {
+                    current_round,
+                    msg_round: 1 + offset,
+                })?;
+                store
+                    .push_msg(Msg {
+                        sender: msg.sender,
+                        receiver: msg.receiver,
+                        body: m,
+                    })
+                    .map_err(Error::HandleMsg)?;
+            }
+            ProtocolMessage(M::Round2(m)) => {
+                let store = self.msgs2.as_mut().ok_or(Error::OutOfOrderMsg {
+                    current_round,
+                    msg_round: 2 + offset,
+                })?;
+                store
+                    .push_msg(Msg {
+                        sender: msg.sender,
+                        receiver: msg.receiver,
+                        body: m,
+                    })
+                    .map_err(Error::HandleMsg)?;
+            }
+            // Completion announcements, acknowledgements and fragments carry nothing the
+            // observer needs, neither do commitments unless the nonces are committed to
+            ProtocolMessage(M::Commit(_))
+            | ProtocolMessage(M::Complete(_))
+            | ProtocolMessage(M::Ack(_))
+            | ProtocolMessage(M::Fragment(_))
+            | ProtocolMessage(M::Abort { .. }) => (),
+        }
+        Ok(())
+    }
+
+    fn message_queue(&mut self) -> &mut Vec<Msg<Self::MessageBody>> {
+        // Verifier never emits messages, the queue always stays empty
+        &mut self.msgs_queue
+    }
+
+    fn wants_to_proceed(&self) -> bool {
+        let store0_wants_more = self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+        let store1_wants_more = self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+        let store2_wants_more = self.msgs2.as_ref().map(|s| s.wants_more()).unwrap_or(false);
+
+        match self.round {
+            O::Commit => !store0_wants_more,
+            O::Round1 => !store1_wants_more,
+            O::Round2 { .. } => !store2_wants_more,
+            O::Finished(_) | O::Gone => false,
+        }
+    }
+
+    fn proceed(&mut self) -> Result<(), Error> {
+        self.proceed_round()
+    }
+
+    fn awaits_messages(&self) -> bool {
+        match self.round {
+            O::Commit => self.msgs0.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            O::Round1 => self.msgs1.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            O::Round2 { .. } => self.msgs2.as_ref().map(|s| s.wants_more()).unwrap_or(false),
+            O::Finished(_) | O::Gone => false,
+        }
+    }
+
+    fn round_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn round_timeout_reached(&mut self) -> Self::Err {
+        // The executor may set a timeout, the signers whose message the round still waits for
+        // are to blame
+        let party_ind = match self.round {
+            O::Commit => self.msgs0.as_ref().map(|s| s.missing_senders()),
+            O::Round1 => self.msgs1.as_ref().map(|s| s.missing_senders()),
+            O::Round2 { .. } => self.msgs2.as_ref().map(|s| s.missing_senders()),
+            O::Finished(_) | O::Gone => None,
+        }
+        .unwrap_or_default();
+        Error::ProceedRound(ProceedError::RoundTimedOut {
+            round: self.current_round(),
+            party_ind,
+        })
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.round, O::Finished(_))
+    }
+
+    fn pick_output(&mut self) -> Option<Result<Self::Output, Error>> {
+        match self.round {
+            O::Finished(_) => (),
+            O::Gone => return Some(Err(Error::DoublePickResult)),
+            _ => return None,
+        }
+
+        match replace(&mut self.round, O::Gone) {
+            O::Finished(result) => Some(result.map_err(Error::ProceedRound)),
+            _ => unreachable!("guaranteed by match expression above"),
+        }
+    }
+
+    fn abort_reason<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b str> {
+        match body {
+            ProtocolMessage(M::Abort { reason }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn as_fragment<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b Fragment> {
+        match body {
+            ProtocolMessage(M::Fragment(fragment)) => Some(fragment),
+            _ => None,
+        }
+    }
+
+    fn is_wrong_round(&self, error: &Self::Err) -> bool {
+        // A message of a past round is stale, only the ones of a later round are worth keeping
+        matches!(error, Error::OutOfOrderMsg { current_round, msg_round } if msg_round > current_round)
+    }
+
+    fn is_message_needed(&self, msg: &Msg<Self::MessageBody>) -> bool {
+        // A round message is needed until its store received one from the sender
+        match msg.body {
+            ProtocolMessage(M::Commit(_)) => self
+                .msgs0
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Round1(_)) => self
+                .msgs1
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Round2(_)) => self
+                .msgs2
+                .as_ref()
+                .map(|s| !s.contains_msg_from(msg.sender))
+                .unwrap_or(false),
+            ProtocolMessage(M::Complete(_))
+            | ProtocolMessage(M::Ack(_))
+            | ProtocolMessage(M::Fragment(_))
+            | ProtocolMessage(M::Abort { .. }) => true,
+        }
+    }
+
+    fn current_round(&self) -> u16 {
+        let offset = self.round_offset();
+        match self.round {
+            O::Commit => 1,
+            O::Round1 => 1 + offset,
+            O::Round2 { .. } => 2 + offset,
+            O::Finished(_) | O::Gone => 3 + offset,
+        }
+    }
+
+    fn total_rounds(&self) -> Option<u16> {
+        Some(2 + self.round_offset())
+    }
+
+    fn party_ind(&self) -> u16 {
+        self.party_n + 1
+    }
+
+    fn parties(&self) -> u16 {
+        self.party_n
+    }
+}
+
+impl StateMachine for Observer {
+    type MessageBody = ProtocolMessage;
+    type Err = Error;
+    type Output = bool;
+
+    fn handle_incoming(&mut self, msg: Msg<Self::MessageBody>) -> Result<(), Error> {
+        self.0.handle_incoming(msg)
+    }
+
+    fn message_queue(&mut self) -> &mut Vec<Msg<Self::MessageBody>> {
+        self.0.message_queue()
+    }
+
+    fn wants_to_proceed(&self) -> bool {
+        self.0.wants_to_proceed()
+    }
+
+    fn proceed(&mut self) -> Result<(), Error> {
+        self.0.proceed()
+    }
+
+    fn round_timeout(&self) -> Option<Duration> {
+        self.0.round_timeout()
+    }
+
+    fn round_timeout_reached(&mut self) -> Self::Err {
+        self.0.round_timeout_reached()
+    }
+
+    fn is_round_expensive(&self) -> bool {
+        self.0.is_round_expensive()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    fn pick_output(&mut self) -> Option<Result<Self::Output, Error>> {
+        self.0.pick_output().map(|result| match result {
+            Ok(_) => Ok(true),
+            Err(Error::ProceedRound(ProceedError::InvalidAggregatedSignature)) => Ok(false),
+            Err(err) => Err(err),
+        })
+    }
+
+    // Nothing is announced on completion, the output doesn't carry the signature
+
+    fn abort_message(&self, error: &Self::Err) -> Option<Self::MessageBody> {
+        self.0.abort_message(error)
+    }
+
+    fn abort_reason<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b str> {
+        self.0.abort_reason(body)
+    }
+
+    fn acknowledgement(&self, msg: &Msg<Self::MessageBody>) -> Option<Self::MessageBody> {
+        self.0.acknowledgement(msg)
+    }
+
+    fn acknowledged_round(&self, body: &Self::MessageBody) -> Option<u16> {
+        self.0.acknowledged_round(body)
+    }
+
+    fn fragment_message(&self, fragment: Fragment) -> Option<Self::MessageBody> {
+        self.0.fragment_message(fragment)
+    }
+
+    fn as_fragment<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b Fragment> {
+        self.0.as_fragment(body)
+    }
+
+    fn is_wrong_round(&self, error: &Self::Err) -> bool {
+        self.0.is_wrong_round(error)
+    }
+
+    fn is_message_needed(&self, msg: &Msg<Self::MessageBody>) -> bool {
+        self.0.is_message_needed(msg)
+    }
+
+    fn awaits_messages(&self) -> bool {
+        self.0.awaits_messages()
+    }
+
+    fn current_round(&self) -> u16 {
+        self.0.current_round()
+    }
+
+    fn total_rounds(&self) -> Option<u16> {
+        self.0.total_rounds()
+    }
+
+    fn party_ind(&self) -> u16 {
+        self.0.party_ind()
+    }
+
+    fn parties(&self) -> u16 {
+        self.0.parties()
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let current_round: String = match &self.0.round {
+            O::Finished(_) => "[Finished]".into(),
+            O::Gone => "[Gone]".into(),
+            _ => self.0.current_round().to_string(),
+        };
+        write!(f, "{{Observer at round={}}}", current_round)
+    }
+}
+
+impl fmt::Debug for Musig2Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let current_round: String = match &self.round {
+            O::Finished(_) => "[Finished]".into(),
+            O::Gone => "[Gone]".into(),
+            _ => self.current_round().to_string(),
+        };
+        write!(f, "{{Musig2Verifier at round={}}}", current_round)
+    }
+}
+
+// Rounds
+enum O {
+    Commit,
+    Round1,
+    Round2 {
+        X_tilde: PublicKey,
+        R: PublicKey,
+        commit: PrivateKey,
+        tweak: Option<PrivateKey>,
+    },
+    Finished(Result<SignResult, ProceedError>),
+    Gone,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Musig2Verifier, Observer};
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::party::{
+        async_protocol::AsyncProtocol,
+        broadcast::BroadcastMsgs,
+        musig2_party::{incoming, Outgoing},
+        rounds::{MessageRound1, NonceMode, Prepare, ProceedError},
+        Musig2Instance,
+    };
+    use crate::cli::protocals::{key::PrivateKey, KeyPair};
+    use tokio::sync::broadcast;
+
+    /// Runs the `signers` along with the `observer`, returns whether the observer verified
+    async fn observe(signers: Vec<Musig2Instance>, observer: Observer) -> bool {
+        let (tx, _) = broadcast::channel(20);
+        let n = signers.len() as u16;
+
+        // Every party subscribes before anyone starts sending
+        let signers: Vec<_> = signers
+            .into_iter()
+            .zip(1..)
+            .map(|(instance, i)| {
+                AsyncProtocol::new(
+                    instance,
+                    incoming(tx.subscribe(), i),
+                    Outgoing { sender: tx.clone() },
+                )
+            })
+            .collect();
+        let mut observer = AsyncProtocol::new(
+            observer,
+            incoming(tx.subscribe(), n + 1),
+            Outgoing { sender: tx.clone() },
+        );
+
+        let observed = tokio::spawn(async move { observer.run().await });
+        let mut handles = vec![];
+        for mut signer in signers {
+            handles.push(tokio::spawn(async move { signer.run().await.is_ok() }));
+        }
+
+        for h in handles {
+            assert!(h.await.unwrap());
+        }
+        observed.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn observer_verifies_three_signers() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let signers = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap())
+            })
+            .collect();
+        assert!(observe(signers, Observer::new(3, message)).await);
+    }
+
+    #[tokio::test]
+    async fn observer_verifies_committed_nonces() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let signers = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_nonce_commitment(
+                    i,
+                    3,
+                    message.clone(),
+                    KeyPair::create().unwrap(),
+                )
+            })
+            .collect();
+        assert!(observe(signers, Observer::with_nonce_commitment(3, message)).await);
+    }
+
+    #[tokio::test]
+    async fn observer_verifies_tweaked_key() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let tweak = PrivateKey::parse(&[7u8; 32]).unwrap();
+        let signers = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap())
+                    .tweak(tweak.clone())
+            })
+            .collect();
+        assert!(observe(signers, Observer::new(3, message).tweak(tweak)).await);
+    }
+
+    #[tokio::test]
+    async fn observer_rejects_signature_under_untweaked_key() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let tweak = PrivateKey::parse(&[7u8; 32]).unwrap();
+        let signers = (1..=3)
+            .map(|i| {
+                Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap())
+                    .tweak(tweak.clone())
+            })
+            .collect();
+        assert!(!observe(signers, Observer::new(3, message)).await);
+    }
+
+    #[test]
+    fn verifier_rejects_malformed_nonces() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut round1_msgs = vec![];
+        for i in 1..=2 {
+            let prepare = Prepare::new(
+                i,
+                2,
+                KeyPair::create().unwrap(),
+                message.clone(),
+                NonceMode::Default,
+            );
+            prepare.proceed(&mut round1_msgs).unwrap();
+        }
+        let msgs: Vec<MessageRound1> = round1_msgs.into_iter().map(|m| m.body).collect();
+        let verifier = Musig2Verifier::new(2, message);
+        let observe = |msgs: Vec<MessageRound1>| {
+            verifier
+                .observe_round1(BroadcastMsgs { my_ind: 3, msgs })
+                .unwrap_err()
+        };
+
+        let mut truncated = msgs.clone();
+        truncated[1].ephemeral_keys[0].truncate(33);
+        assert_eq!(
+            observe(truncated),
+            ProceedError::InvalidNonce {
+                party_ind: 2,
+                nonce_index: 0
+            }
+        );
+
+        let mut missing = msgs.clone();
+        missing[1].ephemeral_keys.pop();
+        assert!(matches!(
+            observe(missing),
+            ProceedError::NonceCountMismatch { party_ind: 2, .. }
+        ));
+
+        let mut replayed = msgs;
+        replayed[1].ephemeral_keys = replayed[0].ephemeral_keys.clone();
+        assert!(matches!(
+            observe(replayed),
+            ProceedError::DuplicateNonce { .. }
+        ));
+    }
+}
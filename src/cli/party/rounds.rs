@@ -9,9 +9,16 @@ use crate::cli::protocals::{
     musig2::*,
     signature::*,
 };
-use log::warn;
-use secp256k1::Message;
+use digest::Digest;
+use light_bitcoin_schnorr::taggedhash::*;
+use log::debug;
+use rand_core::{CryptoRng, OsRng, RngCore};
+use secp256k1::{
+    curve::{Jacobian, Scalar, ECMULT_CONTEXT},
+    Message,
+};
 use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
 
 /// Prepare round performs preprocessing operations to construct messages for the `Round1` of communication.
 ///
@@ -19,17 +26,263 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug)]
 pub struct Prepare {
     pub my_ind: u16,
+    pub party_n: u16,
     pub key_pair: KeyPair,
     pub message: Vec<u8>,
+    /// Signer set agreed in advance, the key of party `i` is at `signers[i - 1]`
+    pub signers: Option<Vec<PublicKey>>,
+    pub nonce_mode: NonceMode,
+    /// Local policy the message must satisfy to be signed, see [Prepare::with_message_policy]
+    pub policy: Option<MessagePolicy>,
+    /// Nonce taken from a pool instead of generating one, see [Prepare::with_pregenerated]
+    pub pregenerated: Option<PregeneratedNonce>,
+    /// Tweak of the aggregate key, see [Prepare::with_tweak]
+    pub tweak: Option<PrivateKey>,
+    /// Precomputed aggregation of the signer keys, see [Prepare::with_key_agg_cache]
+    pub key_agg_cache: Option<KeyAggCache>,
+    /// Adaptor point the signature is encrypted under, see [Prepare::with_adaptor]
+    pub adaptor: Option<PublicKey>,
+    /// Source of the nonces instead of the OS, see [Prepare::with_rng]
+    pub rng: Option<NonceRng>,
+}
+
+/// Predicate the message to sign is checked against, see [Prepare::with_message_policy]
+#[derive(Clone)]
+pub struct MessagePolicy(Arc<dyn Fn(&[u8]) -> bool + Send + Sync>);
+
+impl MessagePolicy {
+    pub fn new(policy: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Self {
+        MessagePolicy(Arc::new(policy))
+    }
+
+    /// Checks the `message` against the policy, an empty message isn't known yet and passes
+    fn check(&self, message: &[u8]) -> Result<()> {
+        if message.is_empty() || (self.0)(message) {
+            Ok(())
+        } else {
+            Err(ProceedError::MessageRejectedByPolicy)
+        }
+    }
+}
+
+impl fmt::Debug for MessagePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessagePolicy")
+    }
+}
+
+/// Random number generator the nonces are drawn from, see [Prepare::with_rng]
+///
+/// Whoever injects the generator vouches for it being cryptographically secure, e.g. a
+/// hardware RNG. Anything else, like a counting generator of a test, makes the nonces
+/// predictable and leaks the private key.
+pub struct NonceRng(Box<dyn CryptoRngCore + Send>);
+
+impl NonceRng {
+    pub fn new<R: RngCore + CryptoRng + Send + 'static>(rng: R) -> Self {
+        NonceRng(Box::new(rng))
+    }
+}
+
+/// [RngCore] marked as a [CryptoRng], so a generator can be boxed as both, see [NonceRng]
+pub trait CryptoRngCore: RngCore + CryptoRng {}
+
+impl<R: RngCore + CryptoRng + ?Sized> CryptoRngCore for R {}
+
+impl fmt::Debug for NonceRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NonceRng")
+    }
+}
+
+/// How `Prepare` derives the nonces of the session
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum NonceMode {
+    /// Nonces are derived by [sign]
+    Default,
+    /// Nonces are drawn at random by [sign_random], new ones on every [Prepare::proceed]
+    ///
+    /// They are drawn from the OS unless [another generator](Prepare::with_rng) is given.
+    Random,
+    /// Nonces are derived by [sign_deterministic_with_aux] from the private key, the message,
+    /// the pinned signer set and fresh auxiliary randomness from the OS
+    DeterministicWithAux,
 }
 
 impl Prepare {
+    /// Round preparing the session of party `my_ind` out of `party_n` signing the `message`
+    ///
+    /// Nothing else is configured, e.g. no signer set is pinned, see the `with_*` methods.
+    pub fn new(
+        my_ind: u16,
+        party_n: u16,
+        key_pair: KeyPair,
+        message: Vec<u8>,
+        nonce_mode: NonceMode,
+    ) -> Self {
+        Prepare {
+            my_ind,
+            party_n,
+            key_pair,
+            message,
+            signers: None,
+            nonce_mode,
+            policy: None,
+            pregenerated: None,
+            tweak: None,
+            key_agg_cache: None,
+            adaptor: None,
+            rng: None,
+        }
+    }
+
+    /// Signs with the `participants` only, a quorum chosen among the registered parties
+    ///
+    /// The signature is valid under the aggregate of the participating keys. The party indices
+    /// follow the canonical order of the keys, see [KeyAgg::sort_canonical], so the signers
+    /// only have to agree on the set. Messages are expected from the participants only, and the
+    /// keys they announce are pinned to the set, so any `nonce_mode` applies, the deterministic
+    /// ones included. Fails with [ProceedError::NotAParticipant] if our key isn't among the
+    /// `participants`.
+    pub fn for_participants(
+        key_pair: KeyPair,
+        participants: &[PublicKey],
+        message: Vec<u8>,
+        nonce_mode: NonceMode,
+    ) -> Result<Self> {
+        let signers = KeyAgg::sort_canonical(participants);
+        let my_ind = signers
+            .iter()
+            .position(|pk| *pk == key_pair.public_key)
+            .ok_or(ProceedError::NotAParticipant)?;
+        let mut prepare = Prepare::new(
+            my_ind as u16 + 1,
+            signers.len() as u16,
+            key_pair,
+            message,
+            nonce_mode,
+        );
+        prepare.signers = Some(signers);
+        Ok(prepare)
+    }
+
+    /// Sets a local policy the message is checked against before anything is broadcast
+    ///
+    /// E.g. a signer node may only sign transactions under some amount. Signing is aborted
+    /// with [ProceedError::MessageRejectedByPolicy] if `policy` returns `false`. A message
+    /// bound later, see [Round1::bind_message], is checked as well.
+    pub fn with_message_policy(
+        mut self,
+        policy: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.policy = Some(MessagePolicy::new(policy));
+        self
+    }
+
+    /// Uses the `nonce` taken from a [pool](KeyPair::pregenerate_nonces) instead of generating
+    /// one, the [nonce mode](NonceMode) is ignored then
+    ///
+    /// The nonce is consumed by [Prepare::proceed] and fails it with
+    /// [ProceedError::NonceKeyMismatch] if it was generated for another key pair.
+    pub fn with_pregenerated(mut self, nonce: PregeneratedNonce) -> Self {
+        self.pregenerated = Some(nonce);
+        self
+    }
+
+    /// Signs for the aggregate key tweaked by `tweak`, e.g. a BIP341 taproot output key
+    ///
+    /// The signature is valid under `X_tilde + tweak*G` lifted to even `y`, see
+    /// [KeyAgg::tweaked_key]. All parties must apply the identical tweak.
+    pub fn with_tweak(mut self, tweak: PrivateKey) -> Self {
+        self.tweak = Some(tweak);
+        self
+    }
+
+    /// Tagged hash of a message of any length, the 32-byte message to sign in its place
+    ///
+    /// Pass the digest as the `message` of [Prepare::new], so only the digest is kept and sent
+    /// in [MessageRound1] and a large message is never copied between the rounds nor broadcast.
+    /// The `MuSig/message` tag keeps the digest apart from a 32-byte message signed as is. All
+    /// parties must sign the identical digest, and the signature verifies against the digest.
+    pub fn prehash(message: &[u8]) -> [u8; 32] {
+        let mut h = sha2::Sha256::default().tagged(b"MuSig/message");
+        h.update(message);
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(h.finalize().as_slice());
+        digest
+    }
+
+    /// Reuses the aggregation of the signer keys computed once by [KeyAgg::precompute]
+    ///
+    /// The signer keys received in round 1 must be the keys of the cache, in any order,
+    /// otherwise the round fails with [ProceedError::KeyAggCacheMismatch].
+    pub fn with_key_agg_cache(mut self, cache: KeyAggCache) -> Self {
+        self.key_agg_cache = Some(cache);
+        self
+    }
+
+    /// Signs an [adaptor signature](AdaptorSignature) encrypted under the adaptor point
+    /// `T = t*G`, e.g. for an atomic swap
+    ///
+    /// The nonce is offset by `T`, so the round 2 fragments add up to `s'` rather than a valid
+    /// signature. Finish the session with [Round2::proceed_adaptor], the signature is then
+    /// obtained with [complete_adaptor] once `t` is known. All parties must use the identical
+    /// adaptor point.
+    pub fn with_adaptor(mut self, adaptor: PublicKey) -> Self {
+        self.adaptor = Some(adaptor);
+        self
+    }
+
+    /// Draws the nonces at random from `rng` rather than from the OS, see [NonceRng]
+    ///
+    /// Switches the [nonce mode](NonceMode) to [NonceMode::Random].
+    pub fn with_rng<R: RngCore + CryptoRng + Send + 'static>(mut self, rng: R) -> Self {
+        self.nonce_mode = NonceMode::Random;
+        self.rng = Some(NonceRng::new(rng));
+        self
+    }
+
     pub fn proceed<O>(self, mut output: O) -> Result<Round1>
     where
         O: Push<Msg<MessageRound1>>,
     {
+        check_party_index(self.my_ind, self.party_n)?;
+        if let Some(policy) = &self.policy {
+            policy.check(&self.message)?;
+        }
+
         // Generate `nonce` from the held private key
-        let (nonce, state1) = sign(self.key_pair.clone())?;
+        let (nonce, state1) = match (self.pregenerated, self.nonce_mode) {
+            (Some(pregenerated), _) => {
+                if *pregenerated.public_key() != self.key_pair.public_key {
+                    return Err(ProceedError::NonceKeyMismatch);
+                }
+                pregenerated.into_parts()
+            }
+            (None, NonceMode::Default) => sign(self.key_pair.clone())?,
+            (None, NonceMode::Random) => match self.rng {
+                Some(NonceRng(mut rng)) => sign_random(self.key_pair.clone(), &mut *rng)?,
+                None => sign_random(self.key_pair.clone(), &mut OsRng)?,
+            },
+            (None, NonceMode::DeterministicWithAux) => {
+                let signers = self
+                    .signers
+                    .as_ref()
+                    .ok_or(ProceedError::SignersNotPinned)?;
+                // Deterministic nonces are bound to the message, they can't be committed ahead
+                if self.message.is_empty() {
+                    return Err(ProceedError::MessageNotBound);
+                }
+                let mut aux_rand = [0u8; 32];
+                OsRng.fill_bytes(&mut aux_rand);
+                sign_deterministic_with_aux(
+                    self.key_pair.clone(),
+                    &self.message,
+                    signers,
+                    aux_rand,
+                )?
+            }
+        };
 
         // The message of the `Round1` needs to pass `nonce` and `public key`
         //
@@ -48,69 +301,432 @@ impl Prepare {
 
         Ok(Round1 {
             my_ind: self.my_ind,
+            party_n: self.party_n,
             state1,
             key_pair: self.key_pair.clone(),
             message: self.message,
+            signers: self.signers,
+            policy: self.policy,
+            tweak: self.tweak,
+            key_agg_cache: self.key_agg_cache,
+            adaptor: self.adaptor,
+            commitments: None,
         })
     }
+
+    /// Like [Prepare::proceed], but broadcasts only a commitment to the nonces
+    ///
+    /// The nonces are revealed by [CommitNonces::proceed] once the commitments of all the
+    /// parties are received, so no party can choose its nonces after seeing the others'.
+    pub fn proceed_committed<O>(self, mut output: O) -> Result<CommitNonces>
+    where
+        O: Push<Msg<CommittedSeed>>,
+    {
+        let my_ind = self.my_ind;
+        let mut msgs = vec![];
+        let round1 = self.proceed(&mut msgs)?;
+        let reveal = msgs.pop().expect("round 1 message is always sent").body;
+        output.push(Msg {
+            sender: my_ind,
+            receiver: None,
+            body: CommittedSeed::commit(&reveal),
+        });
+        Ok(CommitNonces { round1, reveal })
+    }
+
+    // We assume that computing hash is expensive operation (in real-world, it's not)
+    pub const EXPENSIVE: bool = false;
     pub fn is_expensive(&self) -> bool {
-        // We assume that computing hash is expensive operation (in real-world, it's not)
-        false
+        Self::EXPENSIVE
     }
 }
 
 #[derive(Debug)]
 pub struct Round1 {
     pub my_ind: u16,
+    pub party_n: u16,
     pub state1: State,
     pub key_pair: KeyPair,
     pub message: Vec<u8>,
+    pub signers: Option<Vec<PublicKey>>,
+    pub policy: Option<MessagePolicy>,
+    pub tweak: Option<PrivateKey>,
+    pub key_agg_cache: Option<KeyAggCache>,
+    pub adaptor: Option<PublicKey>,
+    /// Nonce commitments of all parties, see [Prepare::proceed_committed]
+    ///
+    /// The commitment of party `i` is at `commitments[i - 1]`.
+    pub commitments: Option<Vec<CommittedSeed>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MessageRound1 {
     pub ephemeral_keys: Vec<Vec<u8>>,
     pub message: Vec<u8>,
     pub pubkey: Vec<u8>,
 }
 
+impl MessageRound1 {
+    /// Packs the `Round1` messages of several parties into one blob
+    ///
+    /// Layout is a big-endian `u16` count followed by every message as
+    /// `sender: u16 | receiver: u16 (0 for broadcast) | nonce count: u8 | nonces | pubkey |
+    /// message length: u32 | message`, where nonces and pubkey are 33-byte compressed points.
+    pub fn encode_batch(msgs: &[Msg<MessageRound1>]) -> std::result::Result<Vec<u8>, Musig2Error> {
+        let compress = |point: &[u8]| -> std::result::Result<[u8; 33], Musig2Error> {
+            if point.len() != 65 {
+                return Err(Musig2Error::InvalidPublicKey);
+            }
+            let mut tt = [0u8; 65];
+            tt.copy_from_slice(point);
+            Ok(PublicKey::parse(&tt)?.serialize_compressed())
+        };
+        if msgs.len() > usize::from(u16::MAX) {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(msgs.len() as u16).to_be_bytes());
+        for msg in msgs {
+            if msg.body.ephemeral_keys.len() > usize::from(u8::MAX)
+                || msg.body.message.len() > u32::MAX as usize
+            {
+                return Err(Musig2Error::InvalidInputLength);
+            }
+            bytes.extend_from_slice(&msg.sender.to_be_bytes());
+            bytes.extend_from_slice(&msg.receiver.unwrap_or(0).to_be_bytes());
+            bytes.push(msg.body.ephemeral_keys.len() as u8);
+            for nonce in msg.body.ephemeral_keys.iter() {
+                bytes.extend_from_slice(&compress(nonce)?);
+            }
+            bytes.extend_from_slice(&compress(&msg.body.pubkey)?);
+            bytes.extend_from_slice(&(msg.body.message.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&msg.body.message);
+        }
+        Ok(bytes)
+    }
+
+    /// Unpacks a blob produced by [MessageRound1::encode_batch]
+    pub fn decode_batch(bytes: &[u8]) -> std::result::Result<Vec<Msg<MessageRound1>>, Musig2Error> {
+        fn take<'a>(reader: &mut &'a [u8], n: usize) -> std::result::Result<&'a [u8], Musig2Error> {
+            if reader.len() < n {
+                return Err(Musig2Error::InvalidInputLength);
+            }
+            let (head, tail) = reader.split_at(n);
+            *reader = tail;
+            Ok(head)
+        }
+        fn take_u16(reader: &mut &[u8]) -> std::result::Result<u16, Musig2Error> {
+            let b = take(reader, 2)?;
+            Ok(u16::from_be_bytes([b[0], b[1]]))
+        }
+        fn take_point(reader: &mut &[u8]) -> std::result::Result<Vec<u8>, Musig2Error> {
+            let mut point = [0u8; 33];
+            point.copy_from_slice(take(reader, 33)?);
+            let pk = PublicKey::parse_compressed(&point)?;
+            Ok(pk.serialize().to_vec())
+        }
+
+        let mut reader = bytes;
+        let count = take_u16(&mut reader)?;
+        let mut msgs = vec![];
+        for _ in 0..count {
+            let sender = take_u16(&mut reader)?;
+            let receiver = match take_u16(&mut reader)? {
+                0 => None,
+                i => Some(i),
+            };
+            let nonce_count = take(&mut reader, 1)?[0];
+            let mut ephemeral_keys = vec![];
+            for _ in 0..nonce_count {
+                ephemeral_keys.push(take_point(&mut reader)?);
+            }
+            let pubkey = take_point(&mut reader)?;
+            let len = take(&mut reader, 4)?;
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let message = take(&mut reader, len)?.to_vec();
+
+            msgs.push(Msg {
+                sender,
+                receiver,
+                body: MessageRound1 {
+                    ephemeral_keys,
+                    message,
+                    pubkey,
+                },
+            });
+        }
+        if !reader.is_empty() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        Ok(msgs)
+    }
+}
+
+/// Checks that `1 <= my_ind <= party_n`
+fn check_party_index(my_ind: u16, party_n: u16) -> Result<()> {
+    if my_ind == 0 || my_ind > party_n {
+        return Err(ProceedError::PartyIndexOutOfRange { my_ind, party_n });
+    }
+    Ok(())
+}
+
+/// Checks that no two parties announced the same public key, `keys[i]` being the key of
+/// party `i + 1`
+///
+/// Keys are compared by their compressed encoding, i.e. by normalized coordinates.
+pub(super) fn check_distinct_keys(keys: &[PublicKey]) -> Result<()> {
+    let encoded: Vec<_> = keys.iter().map(|pk| pk.serialize_compressed()).collect();
+    for key in encoded.iter() {
+        let party_ind: Vec<u16> = encoded
+            .iter()
+            .enumerate()
+            .filter(|(_, other)| *other == key)
+            .map(|(i, _)| i as u16 + 1)
+            .collect();
+        if party_ind.len() > 1 {
+            return Err(ProceedError::DuplicatePublicKey { party_ind });
+        }
+    }
+    Ok(())
+}
+
+/// Checks that no nonce was announced by two parties, `nonces[i]` being the nonces of party
+/// `i + 1`
+///
+/// A party replaying the nonces of another one breaks the security of the scheme. Nonces are
+/// compared by their compressed encoding within this session only, a party reusing its own
+/// nonces from a prior session isn't detected.
+pub(super) fn check_distinct_nonces(nonces: &[Vec<PublicKey>]) -> Result<()> {
+    let encoded: Vec<Vec<_>> = nonces
+        .iter()
+        .map(|party| party.iter().map(|r| r.serialize_compressed()).collect())
+        .collect();
+    let party_ind: Vec<u16> = encoded
+        .iter()
+        .enumerate()
+        .filter(|(i, party)| {
+            encoded
+                .iter()
+                .enumerate()
+                .any(|(j, other)| j != *i && party.iter().any(|r| other.contains(r)))
+        })
+        .map(|(i, _)| i as u16 + 1)
+        .collect();
+    if !party_ind.is_empty() {
+        return Err(ProceedError::DuplicateNonce { party_ind });
+    }
+    Ok(())
+}
+
+/// Parses the public key `party_ind` announced in round 1
+pub(super) fn parse_public_key(party_ind: u16, pubkey: &[u8]) -> Result<PublicKey> {
+    let mut tt = [0u8; 65];
+    if pubkey.len() != tt.len() {
+        return Err(ProceedError::InvalidPublicKey { party_ind });
+    }
+    tt.copy_from_slice(pubkey);
+    PublicKey::parse(&tt).map_err(|_| ProceedError::InvalidPublicKey { party_ind })
+}
+
+/// Parses the nonces of `party_ind`, each must be a valid curve point other than infinity
+///
+/// Their count is checked beforehand, see [Round1::check_nonce_counts].
+pub(super) fn parse_nonces(party_ind: u16, nonces: &[Vec<u8>]) -> Result<Vec<PublicKey>> {
+    nonces
+        .iter()
+        .enumerate()
+        .map(|(nonce_index, nonce)| {
+            let mut tt = [0u8; 65];
+            if nonce.len() != tt.len() {
+                return Err(ProceedError::InvalidNonce {
+                    party_ind,
+                    nonce_index,
+                });
+            }
+            tt.copy_from_slice(nonce);
+            PublicKey::parse(&tt).map_err(|_| ProceedError::InvalidNonce {
+                party_ind,
+                nonce_index,
+            })
+        })
+        .collect()
+}
+
 impl Round1 {
-    pub fn proceed<O>(self, input: BroadcastMsgs<MessageRound1>, mut output: O) -> Result<Round2>
+    /// Binds the round to the `message` when the nonces were committed before it was known
+    ///
+    /// The nonces exchanged in the preceding round don't depend on the message, so the
+    /// signers can commit to them ahead, e.g. while pre-signing a payment channel update.
+    pub fn bind_message(&mut self, message: Vec<u8>) -> Result<()> {
+        if self.is_message_bound() {
+            return Err(ProceedError::MessageAlreadyBound);
+        }
+        if let Some(policy) = &self.policy {
+            policy.check(&message)?;
+        }
+        self.message = message;
+        Ok(())
+    }
+
+    /// Whether the message to sign is known, the round can't proceed until it is
+    pub fn is_message_bound(&self) -> bool {
+        !self.message.is_empty()
+    }
+
+    /// Discards the nonces and returns to [Prepare], e.g. to retry the round once a peer
+    /// which caused a timeout rejoins
+    ///
+    /// The old nonces may have been observed by some parties, so they must never be used
+    /// again: the new ones are [drawn at random](NonceMode::Random), whatever the previous
+    /// mode was, since [NonceMode::Default] would reproduce the old ones. This changes the aggregate nonce, so every party has to restart.
+    pub fn restart_with_fresh_nonces(self) -> Prepare {
+        Prepare {
+            my_ind: self.my_ind,
+            party_n: self.party_n,
+            key_pair: self.key_pair,
+            message: self.message,
+            signers: self.signers,
+            nonce_mode: NonceMode::Random,
+            policy: self.policy,
+            pregenerated: None,
+            tweak: self.tweak,
+            key_agg_cache: self.key_agg_cache,
+            adaptor: self.adaptor,
+            rng: None,
+        }
+    }
+
+    pub fn proceed<O>(self, input: BroadcastMsgs<MessageRound1>, output: O) -> Result<Round2>
+    where
+        O: Push<Msg<MessageRound2>>,
+    {
+        self.proceed_inner(input, output, None)
+    }
+
+    /// Proceeds with the challenge taken from an outer protocol instead of computing it
+    ///
+    /// For composing MuSig2 into a larger Schnorr-based protocol. All parties must supply the
+    /// identical `challenge`. The result is then not a BIP340 signature, it satisfies
+    /// `s*G == R + challenge*X_tilde` with `R` negated if its `y` is odd.
+    pub fn proceed_with_challenge<O>(
+        self,
+        input: BroadcastMsgs<MessageRound1>,
+        output: O,
+        challenge: PrivateKey,
+    ) -> Result<Round2>
     where
         O: Push<Msg<MessageRound2>>,
     {
-        let mut pks = vec![];
+        self.proceed_inner(input, output, Some(challenge))
+    }
+
+    fn proceed_inner<O>(
+        self,
+        input: BroadcastMsgs<MessageRound1>,
+        mut output: O,
+        challenge: Option<PrivateKey>,
+    ) -> Result<Round2>
+    where
+        O: Push<Msg<MessageRound2>>,
+    {
+        check_party_index(self.my_ind, input.msgs.len() as u16 + 1)?;
+        if !self.is_message_bound() {
+            return Err(ProceedError::MessageNotBound);
+        }
+        self.check_signers(&input)?;
+        self.check_nonce_counts(&input)?;
+        self.check_messages(&input)?;
+        self.check_commitments(&input)?;
+
         let mut received_nonce = vec![];
         let cur_ind: usize = self.my_ind.into();
 
-        for i in 0..input.msgs.len() {
-            if i + 1 == cur_ind {
-                pks.push(self.key_pair.public_key.clone());
-            }
-            let mut tt = [0u8; 65];
-            tt.copy_from_slice(input.msgs[i].pubkey.as_slice());
+        // Sort by sender, so every party assembles the keys and nonces in the same order whatever
+        // order the transport delivered them in, and our own nonces go in at `cur_ind - 1`
+        let mut received = input
+            .iter_indexed()
+            .map(|(party_ind, msg)| (usize::from(party_ind), msg))
+            .collect::<Vec<_>>();
+        received.sort_by_key(|(party_ind, _)| *party_ind);
 
-            if let Ok(pk) = PublicKey::parse(&tt) {
-                pks.push(pk);
-            } else {
-                warn!("pks push failed, `PublicKey::parse(&tt)` meet error");
-            }
-            received_nonce.push(PublicKey::convert_from_vec(
-                input.msgs[i].ephemeral_keys.clone(),
-            ));
-        }
-        if input.msgs.len() + 1 == cur_ind {
-            pks.push(self.key_pair.public_key.clone());
+        // Keys are collected as the messages are processed
+        let mut key_agg_builder = KeyAggBuilder::new(input.msgs.len() + 1);
+        key_agg_builder.add(cur_ind - 1, self.key_pair.public_key.clone())?;
+        for (party_ind, msg) in received {
+            let pk = parse_public_key(party_ind as u16, &msg.pubkey)?;
+            key_agg_builder.add(party_ind - 1, pk)?;
+            received_nonce.push(parse_nonces(party_ind as u16, &msg.ephemeral_keys)?);
         }
-        let party_index: usize = (self.my_ind - 1) as usize;
-        println!("pks:{:?}", pks);
-        let key_agg = KeyAgg::key_aggregation_n(&pks, party_index)?;
+        let keys = key_agg_builder.keys()?;
+        check_distinct_keys(&keys)?;
+        let mut nonces = received_nonce.clone();
+        let own_nonce = self
+            .state1
+            .ephk_vec
+            .iter()
+            .map(|ephk| ephk.keypair.public_key.clone())
+            .collect();
+        nonces.insert(cur_ind - 1, own_nonce);
+        check_distinct_nonces(&nonces)?;
+        // Nonces cancelling out leave no aggregate nonce to sign with
+        aggregate_nonces(&nonces).map_err(|_| ProceedError::InvalidAggregateNonce)?;
+        // Aggregate in canonical order, so it doesn't depend on the order of the parties
+        let mut key_agg = match &self.key_agg_cache {
+            Some(cache) if !cache.matches(&keys) => return Err(ProceedError::KeyAggCacheMismatch),
+            Some(cache) => cache.key_agg(&keys, &self.key_pair.public_key)?,
+            None => key_agg_builder.finalize(&self.key_pair.public_key)?,
+        };
+        let pks = KeyAgg::sort_canonical(&keys);
+        debug!("party {} aggregated the keys {:?}", self.my_ind, pks);
+
+        // The parties sign for the (tweaked) key lifted to even `y`, negating their keys if it
+        // takes an odd number of negations to get there
+        let (signing_key, negated, tweak) = key_agg.signing_key(self.tweak.as_ref())?;
+        key_agg.X_tilde = signing_key;
+        let (commit, r, b_coefficients) = match &self.adaptor {
+            Some(adaptor) => self.state1.compute_adaptor_params_for_key(
+                &self.message,
+                &key_agg.X_tilde,
+                &received_nonce,
+                adaptor,
+            )?,
+            None => self.state1.compute_params_for_key(
+                &self.message,
+                &key_agg.X_tilde,
+                &received_nonce,
+            )?,
+        };
+        let external_challenge = challenge.is_some();
+        let commit = challenge.unwrap_or(commit);
+        let key_challenge = if negated {
+            commit.neg()
+        } else {
+            commit.clone()
+        };
         let (state2, sign_fragment) =
             self.state1
-                .sign_prime(&self.message, &pks, received_nonce.clone(), party_index)?;
-        let (commit, r, _) =
-            self.state1
-                .compute_global_params(&self.message, &pks, received_nonce, party_index)?;
+                .sign_with_key_agg(&key_agg, &key_challenge, r.clone(), &b_coefficients)?;
+
+        // Keep what every peer contributed, so its fragment can be verified on its own
+        let peers = input
+            .senders()
+            .zip(received_nonce)
+            .map(|(party_ind, nonces)| {
+                let public_key = keys[usize::from(party_ind) - 1].clone();
+                let position = pks
+                    .iter()
+                    .position(|pk| *pk == public_key)
+                    .ok_or(ProceedError::Musig2Error)?;
+                Ok(PeerContribution {
+                    party_ind,
+                    public_key,
+                    nonces,
+                    coefficient: key_agg.coefficients[position].clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         output.push(Msg {
             sender: self.my_ind,
             receiver: None,
@@ -121,33 +737,187 @@ impl Round1 {
 
         Ok(Round2 {
             my_ind: self.my_ind,
+            signers: input.senders().collect(),
             commit,
             r,
             state2,
             key_pair: self.key_pair,
             key_agg,
             message: self.message,
+            external_challenge,
+            tweak,
+            peers,
+            b_coefficients,
+            partial_challenge: key_challenge,
+            adaptor: self.adaptor,
         })
     }
+    /// Checks that every peer sent as many nonces as we did, the nonces are aggregated
+    /// position by position
+    fn check_nonce_counts(&self, input: &BroadcastMsgs<MessageRound1>) -> Result<()> {
+        let expected = self.state1.ephk_vec.len();
+        for (party_ind, msg) in input.iter_indexed() {
+            if msg.ephemeral_keys.len() != expected {
+                return Err(ProceedError::NonceCountMismatch {
+                    party_ind,
+                    expected,
+                    got: msg.ephemeral_keys.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the peers sign the same message, a peer which committed its nonces before
+    /// the message was known sent none
+    fn check_messages(&self, input: &BroadcastMsgs<MessageRound1>) -> Result<()> {
+        let party_ind: Vec<u16> = input
+            .iter_indexed()
+            .filter(|(_, msg)| !msg.message.is_empty() && msg.message != self.message)
+            .map(|(party_ind, _)| party_ind)
+            .collect();
+        if party_ind.is_empty() {
+            Ok(())
+        } else {
+            Err(ProceedError::MessageMismatch { party_ind })
+        }
+    }
+
+    /// Cross-checks the self-reported public keys against the pinned signer set
+    fn check_signers(&self, input: &BroadcastMsgs<MessageRound1>) -> Result<()> {
+        let signers = match &self.signers {
+            Some(signers) => signers,
+            None => return Ok(()),
+        };
+        let is_pinned = |party_ind: u16, pubkey: &[u8]| {
+            usize::from(party_ind)
+                .checked_sub(1)
+                .and_then(|i| signers.get(i))
+                .map(|pk| pk.serialize()[..] == *pubkey)
+                .unwrap_or(false)
+        };
+
+        if !is_pinned(self.my_ind, &self.key_pair.public_key.serialize()) {
+            return Err(ProceedError::KeyImpersonation {
+                party_ind: self.my_ind,
+            });
+        }
+        for (party_ind, msg) in input.iter_indexed() {
+            if !is_pinned(party_ind, &msg.pubkey) {
+                return Err(ProceedError::KeyImpersonation { party_ind });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the revealed nonces against the commitments of the preceding round, if any
+    fn check_commitments(&self, input: &BroadcastMsgs<MessageRound1>) -> Result<()> {
+        let commitments = match &self.commitments {
+            Some(commitments) => commitments,
+            None => return Ok(()),
+        };
+        let party_ind: Vec<u16> = input
+            .iter_indexed()
+            .filter(|(party_ind, msg)| {
+                commitments.get(usize::from(*party_ind) - 1) != Some(&CommittedSeed::commit(msg))
+            })
+            .map(|(party_ind, _)| party_ind)
+            .collect();
+        if !party_ind.is_empty() {
+            return Err(ProceedError::PartiesDidntRevealItsSeed { party_ind });
+        }
+        Ok(())
+    }
+
     pub fn expects_messages(party_i: u16, party_n: u16) -> Store<BroadcastMsgs<MessageRound1>> {
         BroadcastMsgsStore::new(party_i, party_n)
     }
 
+    // Sending cached message is the cheapest operation
+    pub const EXPENSIVE: bool = false;
+    pub fn is_expensive(&self) -> bool {
+        Self::EXPENSIVE
+    }
+}
+
+/// Optional round preceding [Round1] in which the parties commit to their nonces, see
+/// [Prepare::proceed_committed]
+#[derive(Debug)]
+pub struct CommitNonces {
+    pub round1: Round1,
+    /// Round 1 message revealing the committed nonces
+    pub reveal: MessageRound1,
+}
+
+impl CommitNonces {
+    /// Reveals our nonces once the commitments of all the parties are received
+    pub fn proceed<O>(self, input: BroadcastMsgs<CommittedSeed>, mut output: O) -> Result<Round1>
+    where
+        O: Push<Msg<MessageRound1>>,
+    {
+        let my_ind = self.round1.my_ind;
+        check_party_index(my_ind, input.msgs.len() as u16 + 1)?;
+        let mut commitments = input.msgs;
+        commitments.insert(usize::from(my_ind) - 1, CommittedSeed::commit(&self.reveal));
+        output.push(Msg {
+            sender: my_ind,
+            receiver: None,
+            body: self.reveal,
+        });
+        Ok(Round1 {
+            commitments: Some(commitments),
+            ..self.round1
+        })
+    }
+
+    pub fn expects_messages(party_i: u16, party_n: u16) -> Store<BroadcastMsgs<CommittedSeed>> {
+        BroadcastMsgsStore::new(party_i, party_n)
+    }
+
+    // Sending cached message is the cheapest operation
+    pub const EXPENSIVE: bool = false;
     pub fn is_expensive(&self) -> bool {
-        // Sending cached message is the cheapest operation
-        false
+        Self::EXPENSIVE
     }
 }
 
 #[derive(Debug)]
 pub struct Round2 {
     pub my_ind: u16,
+    /// Parties whose nonces were aggregated in round 1
+    pub signers: Vec<u16>,
     pub commit: PrivateKey,
     pub r: PublicKey,
     pub state2: StatePrime,
     pub key_pair: KeyPair,
+    /// Aggregation of the signer keys, `X_tilde` is the [x-only key](KeyAgg::x_only_key), or
+    /// the [tweaked key](KeyAgg::tweaked_key) if a tweak applies
     pub key_agg: KeyAgg,
     pub message: Vec<u8>,
+    /// `commit` was supplied by an outer protocol rather than computed
+    pub external_challenge: bool,
+    /// Tweak added to the aggregated `s` as `commit * tweak`, already negated along with the
+    /// tweaked key
+    pub tweak: Option<PrivateKey>,
+    /// Round 1 contributions of the peers, in order of [Round2::signers]
+    pub peers: Vec<PeerContribution>,
+    /// Nonce coefficients of the session, see [compute_challenge_params]
+    pub b_coefficients: Vec<PrivateKey>,
+    /// Challenge the key part of every fragment is multiplied by, it's `commit` negated along
+    /// with a tweaked key
+    pub partial_challenge: PrivateKey,
+    /// Adaptor point `r` is offset by, see [Prepare::with_adaptor]
+    pub adaptor: Option<PublicKey>,
+}
+
+/// What a peer contributed in round 1, needed to verify its signature fragment on its own
+#[derive(Debug)]
+pub struct PeerContribution {
+    pub party_ind: u16,
+    pub public_key: PublicKey,
+    pub nonces: Vec<PublicKey>,
+    /// Key aggregation coefficient of the peer
+    pub coefficient: PrivateKey,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -155,53 +925,499 @@ pub struct MessageRound2 {
     pub sign_fragment: Vec<u8>,
 }
 
+impl MessageRound2 {
+    /// Signature fragment as a 32-byte big-endian scalar
+    ///
+    /// Stable encoding for aggregators outside of this crate, they sum the fragments of all
+    /// signers modulo the curve order.
+    pub fn fragment_bytes(&self) -> std::result::Result<[u8; 32], Musig2Error> {
+        Ok(PrivateKey::parse_slice(&self.sign_fragment)
+            .map_err(|e| match e {
+                Musig2Error::InvalidPrivateKey => Musig2Error::ScalarOutOfRange,
+                e => e,
+            })?
+            .serialize())
+    }
+
+    /// Message carrying the fragment encoded as by [MessageRound2::fragment_bytes]
+    ///
+    /// Fails with [Musig2Error::ScalarOutOfRange] if `bytes` isn't below the curve order.
+    pub fn from_fragment_bytes(bytes: [u8; 32]) -> std::result::Result<Self, Musig2Error> {
+        let fragment = PrivateKey::parse(&bytes).map_err(|_| Musig2Error::ScalarOutOfRange)?;
+        Ok(Self {
+            sign_fragment: fragment.serialize().to_vec(),
+        })
+    }
+}
+
+/// Sum of the round 2 fragments of several signers, made by a relay to save bandwidth
+///
+/// Instead of every signer broadcasting its fragment to everyone, the signers of a group send
+/// it to the group's relay only, which [combines](PartialSum::combine) them and broadcasts a
+/// single sum. The parties then finish with [Round2::proceed_partial_sums].
+///
+/// Relaying works on the rounds only: [Musig2Instance](crate::cli::party::Musig2Instance)
+/// always broadcasts every fragment, so a relaying deployment drives the rounds itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartialSum {
+    /// Signers whose fragments are summed, in ascending order
+    pub parties: Vec<u16>,
+    /// Sum of the fragments as a 32-byte big-endian scalar
+    pub sum: Vec<u8>,
+}
+
+impl PartialSum {
+    /// Sums the fragments received by a relay, its own one included if it's a signer of the
+    /// group
+    ///
+    /// Fragments aren't checked here, the receivers of the sum verify it against the
+    /// contributions of all its signers at once.
+    pub fn combine(msgs: &[Msg<MessageRound2>]) -> Result<PartialSum> {
+        let mut parties: Vec<u16> = msgs.iter().map(|msg| msg.sender).collect();
+        parties.sort_unstable();
+        if parties.is_empty() || parties.windows(2).any(|w| w[0] == w[1]) {
+            return Err(ProceedError::InvalidPartialSum { party_ind: parties });
+        }
+        let mut sum: Option<PrivateKey> = None;
+        for msg in msgs {
+            let fragment = PrivateKey::parse_slice(&msg.body.sign_fragment).map_err(|_| {
+                ProceedError::InvalidPartialSignature {
+                    party_ind: msg.sender,
+                }
+            })?;
+            sum = Some(match sum {
+                Some(sum) => sum.add_scalar(&fragment)?,
+                None => fragment,
+            });
+        }
+        Ok(PartialSum {
+            parties,
+            sum: sum.map(|s| s.serialize().to_vec()).unwrap_or_default(),
+        })
+    }
+}
+
 impl Round2 {
+    /// Aggregate public key the signature will be valid under, lifted to even `y` like
+    /// [KeyAgg::x_only_key], or the [tweaked key](KeyAgg::tweaked_key) if a tweak applies
+    pub fn aggregate_pubkey(&self) -> &PublicKey {
+        &self.key_agg.X_tilde
+    }
+
     pub fn proceed(self, input: BroadcastMsgs<MessageRound2>) -> Result<SignResult> {
+        let received_round2 = self.verify_fragments(&input)?;
+        self.finish(&received_round2)
+    }
+
+    /// Like [proceed](Round2::proceed), but for a session signing an
+    /// [adaptor signature](Prepare::with_adaptor)
+    pub fn proceed_adaptor(self, input: BroadcastMsgs<MessageRound2>) -> Result<AdaptorSignature> {
+        let received_round2 = self.verify_fragments(&input)?;
+        self.finish_adaptor(&received_round2)
+    }
+
+    /// Verifies the fragments of the peers one by one
+    fn verify_fragments(&self, input: &BroadcastMsgs<MessageRound2>) -> Result<Vec<PrivateKey>> {
+        // Fragments are summed by position, they must come from exactly the round 1 signers
+        let round2: Vec<u16> = input.senders().collect();
+        if input.my_ind != self.my_ind || round2 != self.signers {
+            return Err(ProceedError::SignerSetChanged {
+                round1: self.signers.clone(),
+                round2,
+            });
+        }
+        // Verify every fragment on its own, so a bad one is blamed on the party that sent it
         let mut received_round2 = vec![];
-        for i in 0..input.msgs.len() {
-            received_round2.push(PrivateKey::parse_slice(&input.msgs[i].sign_fragment)?);
+        for (msg, peer) in input.msgs.iter().zip(&self.peers) {
+            let invalid = || ProceedError::InvalidPartialSignature {
+                party_ind: peer.party_ind,
+            };
+            let fragment = PrivateKey::parse_slice(&msg.sign_fragment).map_err(|_| invalid())?;
+            if !verify_partial(
+                &fragment,
+                &peer.public_key,
+                &peer.nonces,
+                &self.partial_challenge,
+                &peer.coefficient,
+                &self.b_coefficients,
+                &self.r,
+            ) {
+                return Err(invalid());
+            }
+            received_round2.push(fragment);
+        }
+        Ok(received_round2)
+    }
+
+    /// Like [proceed](Round2::proceed), but takes the fragments of the peers
+    /// [summed by relays](PartialSum) rather than one by one
+    ///
+    /// The sums must together cover every round 1 signer exactly once, apart from us. A sum
+    /// covering our own fragment is fine, it's taken out so it's counted once. Every sum is
+    /// verified against the contributions of its signers, a bad one is blamed on the whole
+    /// group as [ProceedError::InvalidPartialSum]. The signature is the same as with
+    /// [proceed](Round2::proceed).
+    pub fn proceed_partial_sums(self, sums: &[PartialSum]) -> Result<SignResult> {
+        let mut covered: Vec<u16> = sums
+            .iter()
+            .flat_map(|sum| sum.parties.iter().copied())
+            .filter(|party_ind| *party_ind != self.my_ind)
+            .collect();
+        covered.sort_unstable();
+        if covered != self.signers {
+            return Err(ProceedError::SignerSetChanged {
+                round1: self.signers,
+                round2: covered,
+            });
+        }
+        let mut received = vec![];
+        for sum in sums {
+            let invalid = || ProceedError::InvalidPartialSum {
+                party_ind: sum.parties.clone(),
+            };
+            let mut value = PrivateKey::parse_slice(&sum.sum).map_err(|_| invalid())?;
+            let peers: Vec<&PeerContribution> = self
+                .peers
+                .iter()
+                .filter(|peer| sum.parties.contains(&peer.party_ind))
+                .collect();
+            if peers.len() != sum.parties.len() {
+                if peers.is_empty() {
+                    continue;
+                }
+                value = value
+                    .add_scalar(&self.state2.s_i.neg())
+                    .map_err(|_| invalid())?;
+            }
+            let mut expected: Option<PublicKey> = None;
+            for peer in peers {
+                let point = partial_commitment(
+                    &peer.public_key,
+                    &peer.nonces,
+                    &self.partial_challenge,
+                    &peer.coefficient,
+                    &self.b_coefficients,
+                    &self.r,
+                )
+                .map_err(|_| invalid())?;
+                expected = Some(match expected {
+                    Some(acc) => acc.add_point(&point).map_err(|_| invalid())?,
+                    None => point,
+                });
+            }
+            if expected != Some(PublicKey::create_from_private_key(&value)) {
+                return Err(invalid());
+            }
+            received.push(value);
+        }
+        self.finish(&received)
+    }
+
+    /// Adds the verified fragments of the peers to ours and checks the resulting adaptor
+    /// signature, `s'⋅G + ±T == R' + c⋅X_tilde` with `R'` the nonce lifted to even `y`
+    fn finish_adaptor(self, received_round2: &[PrivateKey]) -> Result<AdaptorSignature> {
+        let adaptor = match self.adaptor {
+            Some(adaptor) => adaptor,
+            None => return Err(ProceedError::AdaptorModeMismatch),
+        };
+        let s_prime = sign_double_prime(self.state2, received_round2)?;
+        let s_prime = match &self.tweak {
+            Some(tweak) => s_prime.add_scalar(&self.commit.mul_scalar(tweak)?)?,
+            None => s_prime,
+        };
+
+        let (r, t) = if self.r.is_odd_y() {
+            (self.r.neg(), adaptor.neg())
+        } else {
+            (self.r.clone(), adaptor.clone())
+        };
+        let expected = r.add_point(&self.key_agg.X_tilde.mul_scalar(&self.commit)?)?;
+        if PublicKey::create_from_private_key(&s_prime).add_point(&t)? != expected {
+            return Err(ProceedError::InvalidAggregatedSignature);
+        }
+        Ok(AdaptorSignature {
+            R: self.r,
+            s_prime,
+            adaptor,
+        })
+    }
+
+    /// Adds the verified fragments of the peers to ours and checks the resulting signature
+    fn finish(self, received_round2: &[PrivateKey]) -> Result<SignResult> {
+        if self.adaptor.is_some() {
+            return Err(ProceedError::AdaptorModeMismatch);
+        }
+        let mut signers = self.signers;
+        signers.push(self.my_ind);
+        signers.sort_unstable();
+        let s = sign_double_prime(self.state2, received_round2)?;
+        let s = match &self.tweak {
+            Some(tweak) => s.add_scalar(&self.commit.mul_scalar(tweak)?)?,
+            None => s,
+        };
+
+        if self.external_challenge {
+            // Not a BIP340 signature, check `s*G == R + c*X_tilde` directly
+            let r = if self.r.is_odd_y() {
+                self.r.neg()
+            } else {
+                self.r.clone()
+            };
+            let expected = r.add_point(&self.key_agg.X_tilde.mul_scalar(&self.commit)?)?;
+            if PublicKey::create_from_private_key(&s) != expected {
+                return Err(ProceedError::InvalidAggregatedSignature);
+            }
+            return Ok(SignResult {
+                r: self.r,
+                s,
+                commit: self.commit,
+                aggregated_key: self.key_agg.X_tilde,
+                signers,
+            });
         }
-        let s = sign_double_prime(self.state2, &received_round2)?;
 
         let signature = Signature {
-            rx: PrivateKey::parse_slice(&self.r.x_coor()).unwrap(),
+            rx: PrivateKey::parse_slice(&self.r.x_coor())
+                .map_err(|_| ProceedError::InvalidAggregateNonce)?,
             s: s.clone(),
         };
 
-        assert!(verify(
-            &signature,
-            &Message::parse_slice(&self.message).unwrap(),
-            &self.key_agg.X_tilde,
-        )
-        .is_ok());
+        let message = Message::parse_slice(&self.message).map_err(Musig2Error::from)?;
+        if verify(&signature, &message, &self.key_agg.X_tilde).is_err() {
+            return Err(ProceedError::InvalidAggregatedSignature);
+        }
 
-        println!("party index:{} verify success.", self.my_ind);
+        debug!("party {} verified the aggregated signature", self.my_ind);
         Ok(SignResult {
             r: self.r,
             s,
             commit: self.commit,
+            aggregated_key: self.key_agg.X_tilde,
+            signers,
         })
     }
     pub fn expects_messages(party_i: u16, party_n: u16) -> Store<BroadcastMsgs<MessageRound2>> {
         BroadcastMsgsStore::new(party_i, party_n)
     }
+    // Round involves computing a hash, we assume it's expensive (again, in real-world it's not)
+    pub const EXPENSIVE: bool = false;
     pub fn is_expensive(&self) -> bool {
-        // Round involves computing a hash, we assume it's expensive (again, in real-world it's not)
-        false
+        Self::EXPENSIVE
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SignResult {
     pub r: PublicKey,
     pub s: PrivateKey,
     pub commit: PrivateKey,
+    /// Aggregated public key the signature is valid under
+    pub aggregated_key: PublicKey,
+    /// Parties whose signature fragments make up `s`, ourselves included, in ascending order
+    ///
+    /// Empty if unknown, e.g. for a result [parsed](SignResult::from_bytes) from a signature.
+    #[serde(default)]
+    pub signers: Vec<u16>,
+}
+
+/// Hex of the 64-byte [BIP340 signature](SignResult::to_bytes)
+impl fmt::Display for SignResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl SignResult {
+    /// Hash of the agreed signature, used by peers to cross-check the result
+    pub fn hash(&self) -> [u8; 32] {
+        let mut h = sha2::Sha256::default();
+        h.update(&self.r.serialize_compressed()[..]);
+        h.update(&self.s.serialize()[..]);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(h.finalize().as_slice());
+        hash
+    }
+
+    /// Checks `s*G == R + commit*X`, where `R` is lifted to even y
+    ///
+    /// Holds for both computed and [external](Round1::proceed_with_challenge) challenges, as it doesn't
+    /// recompute the challenge from the message.
+    pub fn verify(&self) -> bool {
+        let r = if self.r.is_odd_y() {
+            self.r.neg()
+        } else {
+            self.r.clone()
+        };
+        let expected = self
+            .aggregated_key
+            .mul_scalar(&self.commit)
+            .and_then(|cx| r.add_point(&cx));
+        match expected {
+            Ok(expected) => PublicKey::create_from_private_key(&self.s) == expected,
+            Err(_) => false,
+        }
+    }
+
+    /// Verifies the signature on `message` under `agg_pubkey` from the public output alone
+    ///
+    /// Unlike [SignResult::verify] it ignores `commit` and `aggregated_key` and recomputes the
+    /// challenge from `message`, so it's what a third party holding only the aggregate key
+    /// should use.
+    pub fn verify_message(
+        &self,
+        message: &[u8],
+        agg_pubkey: &PublicKey,
+    ) -> Result<(), Musig2Error> {
+        let signature = Signature {
+            rx: PrivateKey::parse(&self.r.x_coor())?,
+            s: self.s.clone(),
+        };
+        let message = Message::parse_slice(message)?;
+        if verify(&signature, &message, agg_pubkey)? {
+            Ok(())
+        } else {
+            Err(Musig2Error::Invalid)
+        }
+    }
+
+    /// DER-encoded signature, see [Signature::to_der] for the caveats
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_der(&self.r.x_coor(), &self.s.serialize())
+    }
+
+    /// 64-byte [BIP340](https://github.com/bitcoin/bips/blob/master/bip-0340.mediawiki) encoding
+    /// `x(R) || s`, as carried in a Taproot key path spend
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.r.x_coor());
+        bytes[32..].copy_from_slice(&self.s.serialize());
+        bytes
+    }
+
+    /// Witness stack element of a taproot key path spend
+    ///
+    /// The [BIP340 signature](SignResult::to_bytes) followed by `sighash_flag`, unless it's
+    /// `None` or `SIGHASH_DEFAULT` (`0x00`), which BIP341 requires to be omitted.
+    pub fn to_witness(&self, sighash_flag: Option<u8>) -> Vec<u8> {
+        let mut witness = self.to_bytes().to_vec();
+        match sighash_flag {
+            None | Some(0x00) => {}
+            Some(flag) => witness.push(flag),
+        }
+        witness
+    }
+
+    /// Inverse of [SignResult::to_bytes], lifting `x(R)` to the point with even y
+    ///
+    /// The encoding carries neither the key nor the challenge, so the caller supplies the key
+    /// the signature is claimed under and the signed message, and the challenge is recomputed
+    /// from them as in BIP340. A result signed with an
+    /// [external challenge](Round1::proceed_with_challenge) can't be reconstructed this way.
+    pub fn from_bytes(
+        bytes: &[u8; 64],
+        aggregated_key: &PublicKey,
+        message: &Message,
+    ) -> Result<Self, Musig2Error> {
+        let mut rx = [0u8; 32];
+        rx.copy_from_slice(&bytes[..32]);
+        let r = PublicKey::parse_x_coor(&rx)?;
+        let s = PrivateKey::parse_slice(&bytes[32..])?;
+        let commit = PrivateKey(schnorrsig_challenge(
+            &PrivateKey::parse(&rx)?,
+            aggregated_key,
+            message,
+        )?);
+        Ok(SignResult {
+            r,
+            s,
+            commit,
+            aggregated_key: aggregated_key.clone(),
+            signers: vec![],
+        })
+    }
+}
+
+/// Verifies many signatures at once, each on its message under its aggregate key
+///
+/// Items are `(signature, message, aggregate key)` triples, checked as by
+/// [SignResult::verify_message]. Draws random `a_i` (`a_1 = 1`) and checks
+/// `(Σ a_i⋅s_i)⋅G == Σ a_i⋅R_i + Σ a_i⋅e_i⋅P_i` once for the whole batch, which fails, except with
+/// negligible probability, if any signature is invalid. The error doesn't tell which one, verify
+/// them one by one to find out. Empty batch is valid.
+///
+/// ## Performance
+/// Batching pays off with a multi-scalar multiplication, which `libsecp256k1` doesn't have: every
+/// signature here costs two point multiplications, against a single Strauss multiplication in
+/// [SignResult::verify_message], so the batch isn't faster than verifying one by one. Compare
+/// both with `bench_batch_verify_100_signatures`.
+pub fn batch_verify(items: &[(SignResult, Vec<u8>, PublicKey)]) -> Result<(), Musig2Error> {
+    use core::ops::Neg;
+
+    let zero = Scalar::from_int(0);
+    let mut sum_s = Scalar::from_int(0);
+    let mut sum = Jacobian::default();
+    sum.set_infinity();
+    for (i, (result, message, agg_pubkey)) in items.iter().enumerate() {
+        if !agg_pubkey.0.is_valid_var() {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        let rx = PrivateKey::parse(&result.r.x_coor())?;
+        // `R` with even y, as the signature only commits to its x coordinate
+        let r = PublicKey::parse_x_coor(&result.r.x_coor())?;
+        let message = Message::parse_slice(message)?;
+        let e = schnorrsig_challenge(&rx, agg_pubkey, &message)?;
+
+        let a = if i == 0 {
+            Scalar::from_int(1)
+        } else {
+            PrivateKey::generate_random()?.0.clone()
+        };
+        sum_s = &sum_s + &(&a * &result.s.0);
+
+        let mut ar = Jacobian::default();
+        ECMULT_CONTEXT.ecmult(&mut ar, &Jacobian::from_ge(&r.0), &a, &zero);
+        let mut aep = Jacobian::default();
+        let ae = &a * &e;
+        ECMULT_CONTEXT.ecmult(&mut aep, &Jacobian::from_ge(&agg_pubkey.0), &ae, &zero);
+        sum = sum.add_var(&ar, None).add_var(&aep, None);
+    }
+
+    // (Σ a_i⋅s_i)⋅G - Σ a_i⋅R_i - Σ a_i⋅e_i⋅P_i
+    let mut check = Jacobian::default();
+    ECMULT_CONTEXT.ecmult(&mut check, &sum, &Scalar::from_int(1).neg(), &sum_s);
+    if check.is_infinity() {
+        Ok(())
+    } else {
+        Err(Musig2Error::Invalid)
+    }
+}
+
+/// Announces that the protocol is finished, carrying the [SignResult::hash]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageComplete {
+    pub result_hash: Vec<u8>,
 }
 
 // Messages
 
-#[derive(Clone, Debug)]
-pub struct CommittedSeed([u8; 32]);
+/// Commitment to the round 1 message of a party, broadcast before the nonces are revealed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommittedSeed(pub(super) [u8; 32]);
+
+impl CommittedSeed {
+    /// Tagged hash of the public key and the nonces announced in `msg`
+    pub fn commit(msg: &MessageRound1) -> Self {
+        let mut h = sha2::Sha256::default().tagged(b"MuSig/noncecommit");
+        h.update(&msg.pubkey);
+        for nonce in msg.ephemeral_keys.iter() {
+            h.update(&(nonce.len() as u32).to_be_bytes());
+            h.update(nonce);
+        }
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(h.finalize().as_slice());
+        CommittedSeed(hash)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct RevealedSeed {
@@ -215,7 +1431,96 @@ type Result<T> = std::result::Result<T, ProceedError>;
 
 #[derive(Debug, PartialEq)]
 pub enum ProceedError {
-    PartiesDidntRevealItsSeed { party_ind: Vec<u16> },
+    /// Parties revealed nonces other than the ones they committed to
+    PartiesDidntRevealItsSeed {
+        party_ind: Vec<u16>,
+    },
+    /// Parties announced a different result than ours
+    ResultMismatch {
+        party_ind: Vec<u16>,
+    },
+    /// Party's public key doesn't match the pinned signer set
+    KeyImpersonation {
+        party_ind: u16,
+    },
+    /// Deterministic nonces require the signer set to be pinned
+    SignersNotPinned,
+    /// The signer set can only be pinned before the session starts
+    SignersAlreadyPinned,
+    /// Pregenerated nonce belongs to another key pair
+    NonceKeyMismatch,
+    /// The message to sign isn't known yet
+    MessageNotBound,
+    /// The message to sign was already given
+    MessageAlreadyBound,
+    /// The message to sign doesn't satisfy the local policy, see [Prepare::with_message_policy]
+    MessageRejectedByPolicy,
+    /// Round 2 fragments didn't come from the parties that took part in round 1
+    SignerSetChanged {
+        round1: Vec<u16>,
+        round2: Vec<u16>,
+    },
+    /// Public key of the party isn't a valid curve point
+    InvalidPublicKey {
+        party_ind: u16,
+    },
+    /// Nonce of the party isn't a valid curve point
+    InvalidNonce {
+        party_ind: u16,
+        nonce_index: usize,
+    },
+    /// Party sent another number of nonces than we did
+    NonceCountMismatch {
+        party_ind: u16,
+        expected: usize,
+        got: usize,
+    },
+    /// Aggregate nonce `R` is the point at infinity or its x-coordinate isn't a valid scalar
+    InvalidAggregateNonce,
+    /// Aggregated signature doesn't verify, some party sent a wrong signature fragment
+    InvalidAggregatedSignature,
+    /// Signature fragment of the party doesn't verify against its nonces and public key
+    InvalidPartialSignature {
+        party_ind: u16,
+    },
+    /// [Sum of fragments](PartialSum) of the parties doesn't verify, one of them or their relay
+    /// cheated
+    InvalidPartialSum {
+        party_ind: Vec<u16>,
+    },
+    /// Two parties are configured with the same index, they announced different keys under it
+    DuplicatePartyIndex {
+        party_ind: u16,
+    },
+    /// Parties announced a message other than ours
+    MessageMismatch {
+        party_ind: Vec<u16>,
+    },
+    /// Parties announced the same public key
+    DuplicatePublicKey {
+        party_ind: Vec<u16>,
+    },
+    /// Parties announced the same nonce
+    DuplicateNonce {
+        party_ind: Vec<u16>,
+    },
+    /// Parties didn't send their message of the `round` before its timeout
+    RoundTimedOut {
+        round: u16,
+        party_ind: Vec<u16>,
+    },
+    /// Signer keys differ from the keys of the [KeyAggCache], see [Prepare::with_key_agg_cache]
+    KeyAggCacheMismatch,
+    /// Party index isn't in range `[1, party_n]`
+    PartyIndexOutOfRange {
+        my_ind: u16,
+        party_n: u16,
+    },
+    /// Our key isn't among the participants, see [Prepare::for_participants]
+    NotAParticipant,
+    /// Session is finished as a plain signature but signs an adaptor signature or vice
+    /// versa, see [Prepare::with_adaptor]
+    AdaptorModeMismatch,
     Musig2Error,
 }
 
@@ -224,3 +1529,870 @@ impl From<Musig2Error> for ProceedError {
         ProceedError::Musig2Error
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::party::traits::message::MessageStore;
+
+    /// Parties `1..=n` with fresh keys, all signing `message`
+    fn prepares(n: u16, message: &[u8]) -> Vec<Prepare> {
+        (1..=n)
+            .map(|i| {
+                Prepare::new(
+                    i,
+                    n,
+                    KeyPair::create().unwrap(),
+                    message.to_vec(),
+                    NonceMode::Default,
+                )
+            })
+            .collect()
+    }
+
+    /// Round 1 states of the parties and the messages they broadcast
+    fn round1_states(prepares: Vec<Prepare>) -> (Vec<Round1>, Vec<Msg<MessageRound1>>) {
+        let mut msgs = vec![];
+        let round1 = prepares
+            .into_iter()
+            .map(|prepare| prepare.proceed(&mut msgs).unwrap())
+            .collect();
+        (round1, msgs)
+    }
+
+    /// Messages of every party but `party`, as it receives them
+    fn received<M: Clone>(party: u16, msgs: &[Msg<M>]) -> BroadcastMsgs<M> {
+        BroadcastMsgs {
+            my_ind: party,
+            msgs: msgs
+                .iter()
+                .filter(|m| m.sender != party)
+                .map(|m| m.body.clone())
+                .collect(),
+        }
+    }
+
+    /// Round 2 states of the parties and the fragments they broadcast
+    fn round2_states(
+        round1: Vec<Round1>,
+        msgs: &[Msg<MessageRound1>],
+    ) -> (Vec<Round2>, Vec<Msg<MessageRound2>>) {
+        let mut round2_msgs = vec![];
+        let round2 = round1
+            .into_iter()
+            .map(|r1| {
+                let input = received(r1.my_ind, msgs);
+                r1.proceed(input, &mut round2_msgs).unwrap()
+            })
+            .collect();
+        (round2, round2_msgs)
+    }
+
+    /// Result of every party on the fragments of the others
+    fn sign_results(round2: Vec<Round2>, msgs: &[Msg<MessageRound2>]) -> Vec<Result<SignResult>> {
+        round2
+            .into_iter()
+            .map(|r2| {
+                let input = received(r2.my_ind, msgs);
+                r2.proceed(input)
+            })
+            .collect()
+    }
+
+    /// Runs the session of `prepares` to the end, every party has to succeed
+    fn run_session(prepares: Vec<Prepare>) -> Vec<SignResult> {
+        let (round1, msgs) = round1_states(prepares);
+        let (round2, msgs) = round2_states(round1, &msgs);
+        sign_results(round2, &msgs)
+            .into_iter()
+            .map(|result| result.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round1_batch_round_trip() {
+        let msgs: Vec<Msg<MessageRound1>> = (1..=5)
+            .map(|i| {
+                let key_pair = KeyPair::create().unwrap();
+                let (nonce, _) = sign(key_pair.clone()).unwrap();
+                Msg {
+                    sender: i,
+                    receiver: None,
+                    body: MessageRound1 {
+                        ephemeral_keys: PublicKey::convert_to_vec(nonce),
+                        message: vec![i as u8; 32],
+                        pubkey: key_pair.public_key.serialize().to_vec(),
+                    },
+                }
+            })
+            .collect();
+
+        let bytes = MessageRound1::encode_batch(&msgs).unwrap();
+        assert_eq!(MessageRound1::decode_batch(&bytes).unwrap(), msgs);
+        assert!(MessageRound1::decode_batch(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn reversed_delivery_gives_the_same_aggregate() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+
+        // Every party receives the messages of the others from the last sender to the first
+        fn reversed<M: Clone>(party: u16, msgs: &[Msg<M>]) -> BroadcastMsgs<M> {
+            let mut store = BroadcastMsgsStore::new(party, msgs.len() as u16);
+            for msg in msgs.iter().rev().filter(|m| m.sender != party) {
+                store.push_msg(msg.clone()).unwrap();
+            }
+            store.finish().unwrap()
+        }
+        let (round1, round1_msgs) = round1_states(prepares(3, &message));
+        let mut round2_msgs = vec![];
+        let round2: Vec<Round2> = round1
+            .into_iter()
+            .map(|r1| {
+                let input = reversed(r1.my_ind, &round1_msgs);
+                r1.proceed(input, &mut round2_msgs).unwrap()
+            })
+            .collect();
+        let results: Vec<SignResult> = round2
+            .into_iter()
+            .map(|r2| {
+                let input = reversed(r2.my_ind, &round2_msgs);
+                r2.proceed(input).unwrap()
+            })
+            .collect();
+
+        for result in &results {
+            assert_eq!(result.aggregated_key, results[0].aggregated_key);
+            assert_eq!(result.s, results[0].s);
+            assert!(result.verify());
+        }
+    }
+
+    #[test]
+    fn quorum_signs_under_aggregate_of_its_keys() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let registered: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let participants: Vec<PublicKey> = registered[1..]
+            .iter()
+            .map(|kp| kp.public_key.clone())
+            .collect();
+
+        let outsider = Prepare::for_participants(
+            registered[0].clone(),
+            &participants,
+            message.clone(),
+            NonceMode::Random,
+        );
+        assert_eq!(outsider.err(), Some(ProceedError::NotAParticipant));
+
+        let prepares: Vec<Prepare> = registered[1..]
+            .iter()
+            .map(|kp| {
+                Prepare::for_participants(
+                    kp.clone(),
+                    &participants,
+                    message.clone(),
+                    NonceMode::Random,
+                )
+                .unwrap()
+            })
+            .collect();
+        assert!(prepares.iter().all(|p| p.party_n == 2));
+        let results = run_session(prepares);
+
+        let quorum_key = KeyAgg::canonical(&participants, &participants[0])
+            .unwrap()
+            .x_only_key();
+        assert_eq!(results[0].aggregated_key, quorum_key);
+        assert_eq!(results[0].s, results[1].s);
+        assert!(results[0].verify_message(&message, &quorum_key).is_ok());
+    }
+
+    #[test]
+    fn proceed_with_external_challenge() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let challenge = PrivateKey::parse(&[7u8; 32]).unwrap();
+
+        let (round1, round1_msgs) = round1_states(prepares(2, &message));
+        let mut round2_msgs = vec![];
+        let round2 = round1
+            .into_iter()
+            .map(|r1| {
+                let input = received(r1.my_ind, &round1_msgs);
+                r1.proceed_with_challenge(input, &mut round2_msgs, challenge.clone())
+                    .unwrap()
+            })
+            .collect();
+        let results: Vec<SignResult> = sign_results(round2, &round2_msgs)
+            .into_iter()
+            .map(|result| result.unwrap())
+            .collect();
+
+        assert_eq!(results[0].s, results[1].s);
+        assert_eq!(results[0].commit, challenge);
+    }
+
+    #[test]
+    fn tweaked_signature_verifies_under_tweaked_key() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        // The tweak applies to the key lifted to even `y`, which matters if `X_tilde` has odd `y`
+        let (key_pairs, x_tilde) = loop {
+            let key_pairs: Vec<KeyPair> = (0..2).map(|_| KeyPair::create().unwrap()).collect();
+            let pks: Vec<PublicKey> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+            let key_agg = KeyAgg::canonical(&pks, &pks[0]).unwrap();
+            if key_agg.has_odd_y() {
+                break (key_pairs, key_agg.X_tilde);
+            }
+        };
+
+        // Cover both parities of the tweaked key
+        let mut parities = vec![];
+        for t in 1u8.. {
+            let tweak = PrivateKey::parse(&[t; 32]).unwrap();
+            let tweaked = x_tilde.neg().add_tweak(&tweak).unwrap();
+            if parities.contains(&tweaked.is_odd_y()) {
+                continue;
+            }
+            parities.push(tweaked.is_odd_y());
+
+            let results = run_session(
+                key_pairs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, key_pair)| {
+                        Prepare::new(
+                            i as u16 + 1,
+                            2,
+                            key_pair.clone(),
+                            message.clone(),
+                            NonceMode::Random,
+                        )
+                        .with_tweak(tweak.clone())
+                    })
+                    .collect(),
+            );
+
+            // The x-only tweaked key, as a verifier gets it from a taproot output
+            let output_key = PublicKey::parse_x_coor(&tweaked.x_coor()).unwrap();
+            assert_eq!(results[0].aggregated_key, output_key);
+            let signature = Signature {
+                rx: PrivateKey::parse_slice(&results[0].r.x_coor()).unwrap(),
+                s: results[0].s.clone(),
+            };
+            let msg = Message::parse_slice(&message).unwrap();
+            assert!(verify(&signature, &msg, &output_key).unwrap());
+            assert!(verify(&signature, &msg, &x_tilde).is_err());
+
+            if parities.len() == 2 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_fragment_fails_verification() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (round1, round1_msgs) = round1_states(prepares(2, &message));
+        let (mut round2, round2_msgs) = round2_states(round1, &round1_msgs);
+
+        // Party 2 sends a tampered fragment to party 1
+        let mut tampered: MessageRound2 = round2_msgs[1].body.clone();
+        tampered.sign_fragment[31] ^= 1;
+        let result = round2.remove(0).proceed(BroadcastMsgs {
+            my_ind: 1,
+            msgs: vec![tampered],
+        });
+        // The fragment is checked on its own before the aggregated signature
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::InvalidPartialSignature { party_ind: 2 }
+        );
+    }
+
+    #[test]
+    fn partial_sums_give_same_signature_as_full_broadcast() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (round1, round1_msgs) = round1_states(prepares(5, &message));
+        let (round2, mut round2_msgs) = round2_states(round1, &round1_msgs);
+
+        // Party 1 relays the fragments of parties 1 to 3, party 4 the ones of parties 4 and 5
+        let sums = vec![
+            PartialSum::combine(&round2_msgs[..3]).unwrap(),
+            PartialSum::combine(&round2_msgs[3..]).unwrap(),
+        ];
+        assert_eq!(sums[0].parties, vec![1, 2, 3]);
+
+        let mut round2 = round2.into_iter();
+        let full = round2
+            .next()
+            .unwrap()
+            .proceed(received(1, &round2_msgs))
+            .unwrap();
+        let last = round2.next_back().unwrap();
+        for r2 in round2 {
+            let result = r2.proceed_partial_sums(&sums).unwrap();
+            assert!(result.verify());
+            assert_eq!(result.s, full.s);
+            assert_eq!(result.r, full.r);
+        }
+
+        // Party 5 can't tell which fragment of the sum was tampered, it blames the group
+        round2_msgs[3].body.sign_fragment[31] ^= 1;
+        let tampered = vec![
+            sums[0].clone(),
+            PartialSum::combine(&round2_msgs[3..]).unwrap(),
+        ];
+        assert_eq!(
+            last.proceed_partial_sums(&tampered).unwrap_err(),
+            ProceedError::InvalidPartialSum {
+                party_ind: vec![4, 5]
+            }
+        );
+    }
+
+    #[test]
+    fn corrupted_fragment_is_blamed_on_its_sender() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (round1, round1_msgs) = round1_states(prepares(3, &message));
+        let (round2, mut round2_msgs) = round2_states(round1, &round1_msgs);
+
+        // Party 3 corrupts its fragment, party 2 is honest
+        round2_msgs[2].body.sign_fragment[31] ^= 1;
+        let results = sign_results(round2, &round2_msgs);
+        for result in &results[..2] {
+            assert_eq!(
+                result.as_ref().unwrap_err(),
+                &ProceedError::InvalidPartialSignature { party_ind: 3 }
+            );
+        }
+        // Party 3 only checks the honest fragments
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn mismatching_message_is_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let other = crate::cli::node::format_musig_msg(Vec::from("other".as_bytes()));
+        let mut prepares = prepares(3, &message);
+        prepares[2].message = other;
+
+        let (mut round1, round1_msgs) = round1_states(prepares);
+        let result = round1.remove(0).proceed(
+            received(1, &round1_msgs),
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::MessageMismatch { party_ind: vec![3] }
+        );
+    }
+
+    #[test]
+    fn skipped_round2_is_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut round1, round1_msgs) = round1_states(prepares(3, &message));
+        let round2 = round1
+            .remove(0)
+            .proceed(
+                received(1, &round1_msgs),
+                &mut Vec::<Msg<MessageRound2>>::new(),
+            )
+            .unwrap();
+        assert_eq!(round2.signers, vec![2, 3]);
+
+        // Party 3 took part in round 1 but sends no fragment
+        let fragment = MessageRound2 {
+            sign_fragment: PrivateKey::parse(&[1u8; 32]).unwrap().serialize().to_vec(),
+        };
+        let result = round2.proceed(BroadcastMsgs {
+            my_ind: 1,
+            msgs: vec![fragment],
+        });
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::SignerSetChanged {
+                round1: vec![2, 3],
+                round2: vec![2],
+            }
+        );
+    }
+
+    #[test]
+    fn fragment_bytes_round_trip() {
+        let fragment = PrivateKey::generate_random().unwrap().serialize();
+        let msg = MessageRound2::from_fragment_bytes(fragment).unwrap();
+        assert_eq!(msg.fragment_bytes().unwrap(), fragment);
+
+        assert_eq!(
+            MessageRound2::from_fragment_bytes([0xff; 32]).unwrap_err(),
+            Musig2Error::ScalarOutOfRange
+        );
+        let truncated = MessageRound2 {
+            sign_fragment: fragment[..31].to_vec(),
+        };
+        assert_eq!(
+            truncated.fragment_bytes().unwrap_err(),
+            Musig2Error::InvalidInputLength
+        );
+    }
+
+    #[test]
+    fn round1_restarts_with_fresh_nonces() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (round1, round1_msgs) = round1_states(prepares(3, &message));
+
+        // Message of party 3 is lost and the round times out, every party restarts
+        let (restarted, restarted_msgs) = round1_states(
+            round1
+                .into_iter()
+                .map(Round1::restart_with_fresh_nonces)
+                .collect(),
+        );
+        for (old, new) in round1_msgs.iter().zip(&restarted_msgs) {
+            assert_eq!(old.body.pubkey, new.body.pubkey);
+            assert_ne!(old.body.ephemeral_keys, new.body.ephemeral_keys);
+        }
+
+        let (_, round2_msgs) = round2_states(restarted, &restarted_msgs);
+        assert_eq!(round2_msgs.len(), 3);
+    }
+
+    #[test]
+    fn message_rejected_by_policy_aborts_signing() {
+        let prepare = |message: &[u8]| {
+            Prepare::new(
+                1,
+                2,
+                KeyPair::create().unwrap(),
+                message.to_vec(),
+                NonceMode::Default,
+            )
+            .with_message_policy(|message| message.len() <= 4)
+        };
+
+        let mut msgs = vec![];
+        assert_eq!(
+            prepare(b"too long").proceed(&mut msgs).unwrap_err(),
+            ProceedError::MessageRejectedByPolicy
+        );
+        assert!(msgs.is_empty(), "nothing is broadcast");
+        assert!(prepare(b"ok").proceed(&mut msgs).is_ok());
+
+        let mut round1 = prepare(b"").proceed(&mut msgs).unwrap();
+        assert_eq!(
+            round1.bind_message(b"too long".to_vec()),
+            Err(ProceedError::MessageRejectedByPolicy)
+        );
+        assert!(!round1.is_message_bound());
+    }
+
+    #[test]
+    fn pregenerated_nonces_are_used_once() {
+        let key_pair = KeyPair::create().unwrap();
+        let mut pool = key_pair.pregenerate_nonces(3, &mut OsRng).unwrap();
+        assert_eq!(pool.len(), 3);
+
+        let mut msgs = vec![];
+        while let Some(nonce) = pool.take() {
+            let expected = PublicKey::convert_to_vec(nonce.nonce().to_vec());
+            let prepare =
+                Prepare::new(1, 2, key_pair.clone(), b"test".to_vec(), NonceMode::Default);
+            prepare.with_pregenerated(nonce).proceed(&mut msgs).unwrap();
+            assert_eq!(msgs.last().unwrap().body.ephemeral_keys, expected);
+        }
+        assert!(pool.is_empty());
+        assert_eq!(msgs.len(), 3);
+        for (i, msg) in msgs.iter().enumerate() {
+            assert!(msgs[i + 1..]
+                .iter()
+                .all(|m| m.body.ephemeral_keys != msg.body.ephemeral_keys));
+        }
+
+        let mut other = KeyPair::create()
+            .unwrap()
+            .pregenerate_nonces(1, &mut OsRng)
+            .unwrap();
+        let prepare = Prepare::new(1, 2, key_pair, b"test".to_vec(), NonceMode::Default);
+        assert_eq!(
+            prepare
+                .with_pregenerated(other.take().unwrap())
+                .proceed(&mut msgs)
+                .unwrap_err(),
+            ProceedError::NonceKeyMismatch
+        );
+    }
+
+    #[test]
+    fn cancelling_nonces_are_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut round1, round1_msgs) = round1_states(prepares(2, &message));
+
+        // Party 2 sends the negated nonces of party 1, so every `R_j` is at infinity
+        let mut cancelling = round1_msgs[1].body.clone();
+        cancelling.ephemeral_keys = PublicKey::convert_to_vec(
+            PublicKey::convert_from_vec(round1_msgs[0].body.ephemeral_keys.clone())
+                .iter()
+                .map(|nonce| nonce.neg())
+                .collect(),
+        );
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 1,
+                msgs: vec![cancelling],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(result.unwrap_err(), ProceedError::InvalidAggregateNonce);
+    }
+
+    #[test]
+    fn sign_result_der_round_trip() {
+        let r = KeyPair::create().unwrap().public_key;
+        let mut small = [0u8; 32];
+        small[31] = 1;
+        // Top bit clear, top bit set (zero-prefixed) and leading zeros (stripped)
+        for s in vec![[0x7fu8; 32], [0x80u8; 32], small] {
+            let result = SignResult {
+                r: r.clone(),
+                s: PrivateKey::parse(&s).unwrap(),
+                commit: PrivateKey::parse(&[1u8; 32]).unwrap(),
+                aggregated_key: r.clone(),
+                signers: vec![],
+            };
+            let der = result.to_der();
+            assert_eq!(der[0], 0x30);
+            let signature = Signature::from_der(&der).unwrap();
+            assert_eq!(signature.rx.serialize(), r.x_coor());
+            assert_eq!(signature.s, result.s);
+            assert_eq!(signature.to_der(), der);
+        }
+
+        let der = SignResult {
+            r: r.clone(),
+            s: PrivateKey::parse(&[0x80u8; 32]).unwrap(),
+            commit: PrivateKey::parse(&[1u8; 32]).unwrap(),
+            aggregated_key: r,
+            signers: vec![],
+        }
+        .to_der();
+        assert!(Signature::from_der(&der[..der.len() - 1]).is_err());
+    }
+
+    /// Single-signer signatures on distinct messages, with the keys they verify under
+    fn signature_batch(n: u8) -> Vec<(SignResult, Vec<u8>, PublicKey)> {
+        (0..n)
+            .map(|i| {
+                let message = vec![i; 32];
+                let (signature, p) = bip340_sign(
+                    &PrivateKey::generate_random().unwrap(),
+                    &Message::parse_slice(&message).unwrap(),
+                );
+                let result = SignResult {
+                    r: PublicKey::parse_x_coor(&signature.rx.serialize()).unwrap(),
+                    s: signature.s,
+                    commit: schnorrsig_challenge(
+                        &signature.rx,
+                        &p,
+                        &Message::parse_slice(&message).unwrap(),
+                    )
+                    .unwrap()
+                    .into(),
+                    aggregated_key: p.clone(),
+                    signers: vec![],
+                };
+                assert_eq!(result.verify_message(&message, &p), Ok(()));
+                (result, message, p)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_verify_rejects_single_invalid_signature() {
+        assert_eq!(batch_verify(&[]), Ok(()));
+
+        let mut batch = signature_batch(5);
+        assert_eq!(batch_verify(&batch), Ok(()));
+        assert_eq!(batch_verify(&batch[..1]), Ok(()));
+
+        // Valid signature, but of another message
+        batch[3].1 = vec![0xff; 32];
+        assert_eq!(batch_verify(&batch), Err(Musig2Error::Invalid));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_batch_verify_100_signatures() {
+        let batch = signature_batch(100);
+
+        let started = std::time::Instant::now();
+        assert_eq!(batch_verify(&batch), Ok(()));
+        let batched = started.elapsed();
+
+        let started = std::time::Instant::now();
+        for (result, message, p) in &batch {
+            assert_eq!(result.verify_message(message, p), Ok(()));
+        }
+        let one_by_one = started.elapsed();
+
+        println!("batch: {:?}, one by one: {:?}", batched, one_by_one);
+    }
+
+    #[test]
+    fn sign_result_witness_length() {
+        let r = KeyPair::create().unwrap().public_key;
+        let result = SignResult {
+            r: r.clone(),
+            s: PrivateKey::parse(&[0x42u8; 32]).unwrap(),
+            commit: PrivateKey::parse(&[1u8; 32]).unwrap(),
+            aggregated_key: r,
+            signers: vec![],
+        };
+        assert_eq!(result.to_witness(None).len(), 64);
+        assert_eq!(result.to_witness(Some(0x00)).len(), 64);
+        assert_eq!(result.to_witness(None)[..], result.to_bytes()[..]);
+
+        // SIGHASH_ALL
+        let witness = result.to_witness(Some(0x01));
+        assert_eq!(witness.len(), 65);
+        assert_eq!(witness[64], 0x01);
+        assert_eq!(witness[..64], result.to_bytes()[..]);
+    }
+
+    #[test]
+    fn sign_result_bip340_bytes_round_trip() {
+        // BIP340 test vector 4
+        let pubkey =
+            hex::decode("D69C3509BB99E412E68B0FE8544E72837DFA30746D8BE2AA65975F29D22DC7B9")
+                .unwrap();
+        let message =
+            hex::decode("4DF3C3F68FCC83B27E9D42C90431A72499F17875C81A599B566C9889B9696703")
+                .unwrap();
+        let signature = hex::decode(
+            "00000000000000000000003B78CE563F89A0ED9414F5AA28AD0D96D6795F9C63\
+             76AFB1548AF603B3EB45C9F8207DEE1060CB71C04E80F593060B07D28308D7F4",
+        )
+        .unwrap();
+        let mut x = [0u8; 32];
+        x.copy_from_slice(&pubkey);
+        let key = PublicKey::parse_x_coor(&x).unwrap();
+        let message = Message::parse_slice(&message).unwrap();
+        let mut bytes = [0u8; 64];
+        bytes.copy_from_slice(&signature);
+
+        let result = SignResult::from_bytes(&bytes, &key, &message).unwrap();
+        assert!(!result.r.is_odd_y());
+        assert!(result.verify());
+        assert_eq!(result.to_bytes()[..], bytes[..]);
+
+        let other = Message::parse(&[7u8; 32]);
+        assert!(!SignResult::from_bytes(&bytes, &key, &other)
+            .unwrap()
+            .verify());
+    }
+
+    #[test]
+    fn off_curve_nonce_is_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+
+        let (mut round1, round1_msgs) = round1_states(prepares(2, &message));
+
+        // (1, 1) isn't on the curve
+        let mut off_curve = vec![4u8; 65];
+        off_curve[1..].iter_mut().for_each(|b| *b = 0);
+        off_curve[32] = 1;
+        off_curve[64] = 1;
+        let mut msg = round1_msgs[1].body.clone();
+        msg.ephemeral_keys[1] = off_curve;
+
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 1,
+                msgs: vec![msg],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::InvalidNonce {
+                party_ind: 2,
+                nonce_index: 1
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_public_key_is_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+
+        let (mut round1, round1_msgs) = round1_states(prepares(2, &message));
+
+        let mut msg = round1_msgs[1].body.clone();
+        msg.pubkey.truncate(33);
+
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 1,
+                msgs: vec![msg],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::InvalidPublicKey { party_ind: 2 }
+        );
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+
+        let (mut round1, round1_msgs) = round1_states(prepares(3, &message));
+
+        // Party 3 replays the nonces of party 1
+        let mut replayed = round1_msgs[2].body.clone();
+        replayed.ephemeral_keys = round1_msgs[0].body.ephemeral_keys.clone();
+
+        let result = round1.remove(1).proceed(
+            BroadcastMsgs {
+                my_ind: 2,
+                msgs: vec![round1_msgs[0].body.clone(), replayed.clone()],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::DuplicateNonce {
+                party_ind: vec![1, 3]
+            }
+        );
+
+        // Replaying our own nonces is caught as well
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 1,
+                msgs: vec![round1_msgs[1].body.clone(), replayed],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::DuplicateNonce {
+                party_ind: vec![1, 3]
+            }
+        );
+    }
+
+    #[test]
+    fn prehashed_large_message_is_signed() {
+        let message = vec![0xab; 1 << 20];
+        let digest = Prepare::prehash(&message);
+        let prepares = prepares(2, &digest);
+        let pks: Vec<PublicKey> = prepares
+            .iter()
+            .map(|p| p.key_pair.public_key.clone())
+            .collect();
+
+        let (round1, round1_msgs) = round1_states(prepares);
+        // Only the digest is broadcast
+        assert!(round1_msgs.iter().all(|m| m.body.message == digest));
+        let (round2, round2_msgs) = round2_states(round1, &round1_msgs);
+        let result = sign_results(round2, &round2_msgs).remove(0).unwrap();
+
+        let agg_pubkey = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        result.verify_message(&digest, &agg_pubkey).unwrap();
+    }
+
+    #[test]
+    fn adaptor_signature_completes_and_reveals_secret() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("swap".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..2).map(|_| KeyPair::create().unwrap()).collect();
+        let pks: Vec<PublicKey> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        let t = PrivateKey::generate_random().unwrap();
+        let adaptor = PublicKey::create_from_private_key(&t);
+
+        let prepares = key_pairs
+            .iter()
+            .enumerate()
+            .map(|(i, key_pair)| {
+                Prepare::new(
+                    i as u16 + 1,
+                    2,
+                    key_pair.clone(),
+                    message.clone(),
+                    NonceMode::Random,
+                )
+                .with_adaptor(adaptor.clone())
+            })
+            .collect();
+        let (round1, round1_msgs) = round1_states(prepares);
+        let (round2, round2_msgs) = round2_states(round1, &round1_msgs);
+        let adaptor_sigs: Vec<AdaptorSignature> = round2
+            .into_iter()
+            .map(|r2| {
+                let input = received(r2.my_ind, &round2_msgs);
+                r2.proceed_adaptor(input).unwrap()
+            })
+            .collect();
+        assert_eq!(adaptor_sigs[0], adaptor_sigs[1]);
+
+        let agg_pubkey = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        let msg = Message::parse_slice(&message).unwrap();
+        let s_prime = &adaptor_sigs[0];
+        assert!(s_prime.verify(&msg, &agg_pubkey).unwrap());
+        // Not a valid signature until completed
+        let incomplete = Signature {
+            rx: PrivateKey::parse(&s_prime.R.x_coor()).unwrap(),
+            s: s_prime.s_prime.clone(),
+        };
+        assert!(verify(&incomplete, &msg, &agg_pubkey).is_err());
+
+        let other = PrivateKey::generate_random().unwrap();
+        assert!(complete_adaptor(s_prime, &other).is_err());
+        let signature = complete_adaptor(s_prime, &t).unwrap();
+        assert!(verify(&signature, &msg, &agg_pubkey).unwrap());
+        assert_eq!(extract_secret(&signature, s_prime).unwrap(), t);
+    }
+
+    #[test]
+    fn truncated_nonces_are_rejected() {
+        let message = crate::cli::node::format_musig_msg(Vec::from("test".as_bytes()));
+
+        let (mut round1, round1_msgs) = round1_states(prepares(3, &message));
+
+        let mut truncated = round1_msgs[2].body.clone();
+        truncated.ephemeral_keys.truncate(1);
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 1,
+                msgs: vec![round1_msgs[1].body.clone(), truncated],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::NonceCountMismatch {
+                party_ind: 3,
+                expected: NONCE_COUNT,
+                got: 1
+            }
+        );
+
+        let mut empty = round1_msgs[0].body.clone();
+        empty.ephemeral_keys.clear();
+        let result = round1.remove(0).proceed(
+            BroadcastMsgs {
+                my_ind: 2,
+                msgs: vec![empty, round1_msgs[2].body.clone()],
+            },
+            &mut Vec::<Msg<MessageRound2>>::new(),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ProceedError::NonceCountMismatch {
+                party_ind: 1,
+                expected: NONCE_COUNT,
+                got: 0
+            }
+        );
+    }
+}
@@ -8,6 +8,7 @@ use futures::{
     sink::Sink,
     stream::{FusedStream, StreamExt},
 };
+use serde::Serialize;
 use std::{
     fmt::Debug,
     iter,
@@ -58,12 +59,13 @@ pub struct AsyncSimulation<SM: StateMachine> {
         >,
     >,
     exhausted: bool,
+    broadcast_abort: bool,
 }
 
 impl<SM> AsyncSimulation<SM>
 where
     SM: StateMachine + Send + 'static,
-    SM::MessageBody: Send + Clone + Unpin + 'static,
+    SM::MessageBody: Send + Clone + Unpin + Serialize + 'static,
     SM::Err: Send + Debug,
     SM::Output: Send,
 {
@@ -74,9 +76,17 @@ where
             tx,
             parties: vec![],
             exhausted: false,
+            broadcast_abort: false,
         }
     }
 
+    /// Makes every party [broadcast an abort](AsyncProtocol::set_broadcast_abort) once it hits
+    /// a critical error
+    pub fn broadcast_abort(&mut self, enable: bool) -> &mut Self {
+        self.broadcast_abort = enable;
+        self
+    }
+
     /// Adds protocol participant
     pub fn add_party(&mut self, party: SM) -> &mut Self {
         let rx = self.tx.subscribe();
@@ -108,7 +118,9 @@ where
 
         let mut parties = vec![];
         for party in self.parties.drain(..) {
-            let mut party = party.expect("guaranteed as simulation is not exhausted");
+            let mut party = party
+                .expect("guaranteed as simulation is not exhausted")
+                .set_broadcast_abort(self.broadcast_abort);
             let h = tokio::spawn(async { (party.run().await, party) });
             parties.push(h)
         }
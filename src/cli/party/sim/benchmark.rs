@@ -1,21 +1,41 @@
 use std::collections::BTreeMap;
-use std::fmt;
+use std::fmt::{self, Write};
 use std::time::{Duration, Instant};
 
-/// Measures duration of round proceeding
+/// Measures duration of round proceeding and of the message exchange
 pub struct Benchmark {
     results: Option<BenchmarkResults>,
+    sink: Option<Box<dyn FnMut(u16, Duration)>>,
 }
 
 impl Benchmark {
     pub fn enabled() -> Self {
         Self {
             results: Some(Default::default()),
+            sink: None,
         }
     }
 
     pub fn disabled() -> Self {
-        Self { results: None }
+        Self {
+            results: None,
+            sink: None,
+        }
+    }
+
+    /// Starts or stops collecting the results, the sink is kept
+    pub fn set_enabled(&mut self, enable: bool) {
+        self.results = if enable {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    /// Passes every measurement to `sink` as soon as it's taken, whether the results are
+    /// collected or not
+    pub fn set_sink(&mut self, sink: impl FnMut(u16, Duration) + 'static) {
+        self.sink = Some(Box::new(sink));
     }
 
     pub fn start(&mut self) -> Stopwatch {
@@ -25,14 +45,46 @@ impl Benchmark {
         }
     }
 
-    fn add_measurement(&mut self, round: u16, time: Duration) {
+    fn add_measurement(&mut self, round: u16, time: Duration, advanced: bool) {
+        if let Some(sink) = self.sink.as_mut() {
+            sink(round, time);
+        }
+        if let Some(results) = self.results.as_mut() {
+            let m = results.entry(round).or_default();
+            if advanced {
+                m.n += 1;
+                m.total_time += time;
+            } else {
+                m.non_advancing_n += 1;
+                m.non_advancing_time += time;
+            }
+        }
+    }
+
+    /// Saves a measurement taken apart, e.g. on another thread
+    ///
+    /// `advanced` tells whether the proceed advanced the round, like
+    /// [Stopwatch::stop_and_save] vs [Stopwatch::stop_and_save_non_advancing].
+    pub fn save(&mut self, round_n: u16, time: Duration, advanced: bool) {
+        self.add_measurement(round_n, time, advanced);
+    }
+
+    /// Saves the time a party spent exchanging messages while being in the `round`, i.e.
+    /// handling the incoming messages and sending the outgoing ones
+    pub fn save_exchange(&mut self, round: u16, time: Duration) {
+        if let Some(results) = self.results.as_mut() {
+            let m = results.entry(round).or_default();
+            m.exchange_n += 1;
+            m.exchange_time += time;
+        }
+    }
+
+    /// Accounts a message of `size` bytes sent in the `round`
+    pub fn add_message(&mut self, round: u16, size: usize) {
         if let Some(results) = self.results.as_mut() {
-            let m = results.entry(round).or_insert(Measurements {
-                n: 0,
-                total_time: Duration::default(),
-            });
-            m.n += 1;
-            m.total_time += time;
+            let m = results.entry(round).or_default();
+            m.messages_sent += 1;
+            m.bytes_sent += size as u64;
         }
     }
 
@@ -49,7 +101,14 @@ pub struct Stopwatch<'a> {
 impl<'a> Stopwatch<'a> {
     pub fn stop_and_save(self, round_n: u16) -> Duration {
         let time = Instant::now().duration_since(self.started_at);
-        self.b.add_measurement(round_n, time);
+        self.b.add_measurement(round_n, time, true);
+        time
+    }
+
+    /// Saves the measurement of a proceed which didn't advance the round
+    pub fn stop_and_save_non_advancing(self, round_n: u16) -> Duration {
+        let time = Instant::now().duration_since(self.started_at);
+        self.b.add_measurement(round_n, time, false);
         time
     }
 }
@@ -59,14 +118,247 @@ pub type BenchmarkResults = BTreeMap<u16, Measurements>;
 
 /// Benchmark results for particular round
 ///
-/// `n` measurements took in total `total_time`
+/// `n` measurements took in total `total_time`. Proceeds which didn't advance the round are
+/// counted apart: `non_advancing_n` of them took in total `non_advancing_time`. The message
+/// exchange is timed apart from proceeding: `exchange_n` exchanges took in total
+/// `exchange_time`.
+#[derive(Default)]
 pub struct Measurements {
     pub n: u16,
     pub total_time: Duration,
+    pub non_advancing_n: u16,
+    pub non_advancing_time: Duration,
+    pub exchange_n: u16,
+    pub exchange_time: Duration,
+    /// Number of messages sent by the parties while being in the round
+    ///
+    /// Only measured if enabled by `Simulation::measure_message_sizes`.
+    pub messages_sent: u32,
+    /// Total size of the messages sent in the round, in bytes
+    pub bytes_sent: u64,
+}
+
+impl Measurements {
+    /// Average duration of the proceeds which advanced the round, `None` if there were none
+    pub fn average(&self) -> Option<Duration> {
+        if self.n > 0 {
+            Some(self.total_time / u32::from(self.n))
+        } else {
+            None
+        }
+    }
+
+    /// Average duration of a party's message exchange, `None` if there was none
+    pub fn average_exchange(&self) -> Option<Duration> {
+        if self.exchange_n > 0 {
+            Some(self.exchange_time / u32::from(self.exchange_n))
+        } else {
+            None
+        }
+    }
+}
+
+/// Comparison of [BenchmarkResults] of two runs
+pub trait CompareBenchmarks {
+    /// Change of the average proceed duration of every round relative to the `baseline`
+    ///
+    /// Rounds which weren't measured in both runs are left out.
+    fn diff(&self, baseline: &BenchmarkResults) -> BenchmarkDiff;
+}
+
+impl CompareBenchmarks for BenchmarkResults {
+    fn diff(&self, baseline: &BenchmarkResults) -> BenchmarkDiff {
+        let mut change = BTreeMap::new();
+        for (round, measurements) in self {
+            let baseline = baseline.get(round).and_then(|m| m.average());
+            if let (Some(average), Some(baseline)) = (measurements.average(), baseline) {
+                if baseline > Duration::default() {
+                    let ratio = average.as_secs_f64() / baseline.as_secs_f64();
+                    change.insert(*round, (ratio - 1.) * 100.);
+                }
+            }
+        }
+        BenchmarkDiff { change }
+    }
+}
+
+/// Export of [BenchmarkResults] for external tooling, e.g. to keep performance history in CI
+///
+/// Every round gives one row (object): its index, number of measurements, total and average
+/// duration, the same for non-advancing proceeds and for the message exchange, and the traffic.
+/// Durations are in nanoseconds, an average is empty (`null`) if there were no measurements.
+pub trait ExportBenchmarks {
+    /// Results as CSV with a header row
+    fn to_csv(&self) -> String;
+    /// Results as JSON array
+    fn to_json(&self) -> String;
+}
+
+impl ExportBenchmarks for BenchmarkResults {
+    fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("round,n,total_ns,average_ns,non_advancing_n,non_advancing_total_ns,exchange_n,exchange_total_ns,exchange_average_ns,messages_sent,bytes_sent\n");
+        for (round, m) in self {
+            let average = m
+                .average()
+                .map(|average| average.as_nanos().to_string())
+                .unwrap_or_default();
+            let average_exchange = m
+                .average_exchange()
+                .map(|average| average.as_nanos().to_string())
+                .unwrap_or_default();
+            writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                round,
+                m.n,
+                m.total_time.as_nanos(),
+                average,
+                m.non_advancing_n,
+                m.non_advancing_time.as_nanos(),
+                m.exchange_n,
+                m.exchange_time.as_nanos(),
+                average_exchange,
+                m.messages_sent,
+                m.bytes_sent
+            )
+            .expect("writing to string cannot fail");
+        }
+        csv
+    }
+
+    fn to_json(&self) -> String {
+        let rounds: Vec<_> = self
+            .iter()
+            .map(|(round, m)| {
+                serde_json::json!({
+                    "round": round,
+                    "n": m.n,
+                    "total_ns": m.total_time.as_nanos() as u64,
+                    "average_ns": m.average().map(|average| average.as_nanos() as u64),
+                    "non_advancing_n": m.non_advancing_n,
+                    "non_advancing_total_ns": m.non_advancing_time.as_nanos() as u64,
+                    "exchange_n": m.exchange_n,
+                    "exchange_total_ns": m.exchange_time.as_nanos() as u64,
+                    "exchange_average_ns": m.average_exchange().map(|average| average.as_nanos() as u64),
+                    "messages_sent": m.messages_sent,
+                    "bytes_sent": m.bytes_sent,
+                })
+            })
+            .collect();
+        serde_json::Value::Array(rounds).to_string()
+    }
+}
+
+/// Per-round change of the average proceed duration, see [CompareBenchmarks::diff]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkDiff {
+    /// Change in percent, positive if the round got slower
+    pub change: BTreeMap<u16, f64>,
+}
+
+impl BenchmarkDiff {
+    /// Rounds which got slower by more than `threshold` percent
+    pub fn regressions(&self, threshold: f64) -> Vec<u16> {
+        self.change
+            .iter()
+            .filter(|(_, change)| **change > threshold)
+            .map(|(round, _)| *round)
+            .collect()
+    }
 }
 
 impl fmt::Debug for Measurements {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.total_time / u32::from(self.n))
+        if self.n > 0 {
+            write!(f, "{:?}", self.total_time / u32::from(self.n))?;
+        } else {
+            write!(f, "-")?;
+        }
+        if self.non_advancing_n > 0 {
+            write!(
+                f,
+                " (+{} non-advancing, {:?})",
+                self.non_advancing_n,
+                self.non_advancing_time / u32::from(self.non_advancing_n)
+            )?;
+        }
+        if let Some(exchange) = self.average_exchange() {
+            write!(f, " (exchange {:?})", exchange)?;
+        }
+        if self.messages_sent > 0 {
+            write!(
+                f,
+                " [{} message(s), {} bytes]",
+                self.messages_sent, self.bytes_sent
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BenchmarkResults, CompareBenchmarks, ExportBenchmarks, Measurements};
+    use std::time::Duration;
+
+    fn results(round_times_ms: &[(u16, u64)]) -> BenchmarkResults {
+        round_times_ms
+            .iter()
+            .map(|(round, ms)| {
+                let m = Measurements {
+                    n: 2,
+                    total_time: Duration::from_millis(2 * ms),
+                    non_advancing_n: 0,
+                    non_advancing_time: Duration::default(),
+                    ..Default::default()
+                };
+                (*round, m)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slower_round_is_flagged_as_regression() {
+        let baseline = results(&[(0, 10), (1, 100), (2, 50)]);
+        let slower = results(&[(0, 10), (1, 150), (2, 40), (3, 20)]);
+
+        let diff = slower.diff(&baseline);
+        assert_eq!(diff.change.len(), 3, "round 3 has no baseline");
+        assert!((diff.change[&1] - 50.).abs() < 1e-9);
+        assert!((diff.change[&2] + 20.).abs() < 1e-9);
+        assert_eq!(diff.regressions(10.), vec![1]);
+        assert!(baseline.diff(&baseline).regressions(0.).is_empty());
+    }
+
+    #[test]
+    fn export_has_one_row_per_round() {
+        let mut results = results(&[(0, 10), (1, 100), (2, 50)]);
+        let round1 = results.get_mut(&1).unwrap();
+        round1.exchange_n = 2;
+        round1.exchange_time = Duration::from_millis(30);
+
+        let csv = results.to_csv();
+        let rows: Vec<_> = csv.lines().collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0],
+            "round,n,total_ns,average_ns,non_advancing_n,non_advancing_total_ns,exchange_n,exchange_total_ns,exchange_average_ns,messages_sent,bytes_sent"
+        );
+        assert_eq!(rows[1], "0,2,20000000,10000000,0,0,0,0,,0,0");
+        assert_eq!(
+            rows[2],
+            "1,2,200000000,100000000,0,0,2,30000000,15000000,0,0"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&results.to_json()).unwrap();
+        let rounds = json.as_array().unwrap();
+        assert_eq!(rounds.len(), 3);
+        assert_eq!(rounds[1]["round"], 1);
+        assert_eq!(rounds[1]["average_ns"], 100_000_000);
+        assert_eq!(rounds[1]["exchange_n"], 2);
+        assert_eq!(rounds[1]["exchange_total_ns"], 30_000_000);
+        assert_eq!(rounds[1]["exchange_average_ns"], 15_000_000);
+        assert!(rounds[0]["exchange_average_ns"].is_null());
     }
 }
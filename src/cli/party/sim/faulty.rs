@@ -0,0 +1,283 @@
+#![allow(dead_code)]
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::runtime::Handle;
+
+use crate::cli::party::traits::state_machine::{Fragment, Msg, StateMachine};
+
+/// Fault injected by [Faulty] into the messages of a round
+pub enum Fault<B> {
+    /// Messages are never sent
+    Drop,
+    /// Messages are sent after a delay measured on the tokio clock
+    ///
+    /// The state machine sleeps on the blocking thread it [proceeds](StateMachine::proceed) on,
+    /// so only the messages queued by proceeding are delayed.
+    Delay(Duration),
+    /// Messages are modified before they are sent
+    Corrupt(Arc<dyn Fn(&mut B) + Send + Sync>),
+}
+
+impl<B> fmt::Debug for Fault<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Drop => write!(f, "Drop"),
+            Fault::Delay(delay) => write!(f, "Delay({:?})", delay),
+            Fault::Corrupt(_) => write!(f, "Corrupt"),
+        }
+    }
+}
+
+/// Wraps a state machine injecting a [Fault] once it reaches a specific round
+///
+/// Unlike tampering with messages in the [Simulation](super::simulation::Simulation), it's run
+/// by the [AsyncProtocol](crate::cli::party::async_protocol::AsyncProtocol), so timeouts, retries
+/// and error handling of the async runtime are exercised. A fault applies to the messages queued
+/// while the state machine is in the target round, i.e. to the messages of that round.
+pub struct Faulty<SM: StateMachine> {
+    inner: SM,
+    fault: Option<(u16, Fault<SM::MessageBody>)>,
+}
+
+impl<SM: StateMachine> Faulty<SM> {
+    /// Wraps `inner` without injecting anything
+    pub fn new(inner: SM) -> Self {
+        Self { inner, fault: None }
+    }
+
+    /// Injects the `fault` once the state machine reaches the `round`
+    pub fn at_round(mut self, round: u16, fault: Fault<SM::MessageBody>) -> Self {
+        self.fault = Some((round, fault));
+        self
+    }
+
+    /// Applies the fault to the messages queued since the queue was `queued` long, `proceeded`
+    /// tells whether they were queued by [proceed](StateMachine::proceed)
+    fn inject(&mut self, queued: usize, proceeded: bool) {
+        let fault = match &self.fault {
+            Some((round, fault)) if *round == self.inner.current_round() => fault,
+            _ => return,
+        };
+        let queue = self.inner.message_queue();
+        if queue.len() <= queued {
+            return;
+        }
+        match fault {
+            Fault::Drop => queue.truncate(queued),
+            Fault::Delay(delay) => {
+                if let (true, Ok(runtime)) = (proceeded, Handle::try_current()) {
+                    runtime.block_on(tokio::time::sleep(*delay));
+                }
+            }
+            Fault::Corrupt(corrupt) => queue[queued..]
+                .iter_mut()
+                .for_each(|msg| corrupt(&mut msg.body)),
+        }
+    }
+}
+
+impl<SM: StateMachine + fmt::Debug> fmt::Debug for Faulty<SM> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Faulty")
+            .field("inner", &self.inner)
+            .field("fault", &self.fault)
+            .finish()
+    }
+}
+
+impl<SM: StateMachine> StateMachine for Faulty<SM> {
+    type MessageBody = SM::MessageBody;
+    type Err = SM::Err;
+    type Output = SM::Output;
+
+    fn handle_incoming(&mut self, msg: Msg<Self::MessageBody>) -> Result<(), Self::Err> {
+        let queued = self.inner.message_queue().len();
+        let result = self.inner.handle_incoming(msg);
+        self.inject(queued, false);
+        result
+    }
+
+    fn message_queue(&mut self) -> &mut Vec<Msg<Self::MessageBody>> {
+        self.inner.message_queue()
+    }
+
+    fn wants_to_proceed(&self) -> bool {
+        self.inner.wants_to_proceed()
+    }
+
+    fn proceed(&mut self) -> Result<(), Self::Err> {
+        let queued = self.inner.message_queue().len();
+        let result = self.inner.proceed();
+        self.inject(queued, true);
+        result
+    }
+
+    fn round_timeout(&self) -> Option<Duration> {
+        self.inner.round_timeout()
+    }
+
+    fn round_timeout_reached(&mut self) -> Self::Err {
+        self.inner.round_timeout_reached()
+    }
+
+    fn is_round_expensive(&self) -> bool {
+        self.inner.is_round_expensive()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    fn pick_output(&mut self) -> Option<Result<Self::Output, Self::Err>> {
+        self.inner.pick_output()
+    }
+
+    fn completion_message(&self, output: &Self::Output) -> Option<Self::MessageBody> {
+        self.inner.completion_message(output)
+    }
+
+    fn abort_message(&self, error: &Self::Err) -> Option<Self::MessageBody> {
+        self.inner.abort_message(error)
+    }
+
+    fn abort_reason<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b str> {
+        self.inner.abort_reason(body)
+    }
+
+    fn acknowledgement(&self, msg: &Msg<Self::MessageBody>) -> Option<Self::MessageBody> {
+        self.inner.acknowledgement(msg)
+    }
+
+    fn acknowledged_round(&self, body: &Self::MessageBody) -> Option<u16> {
+        self.inner.acknowledged_round(body)
+    }
+
+    fn fragment_message(&self, fragment: Fragment) -> Option<Self::MessageBody> {
+        self.inner.fragment_message(fragment)
+    }
+
+    fn as_fragment<'b>(&self, body: &'b Self::MessageBody) -> Option<&'b Fragment> {
+        self.inner.as_fragment(body)
+    }
+
+    fn is_wrong_round(&self, error: &Self::Err) -> bool {
+        self.inner.is_wrong_round(error)
+    }
+
+    fn is_message_needed(&self, msg: &Msg<Self::MessageBody>) -> bool {
+        self.inner.is_message_needed(msg)
+    }
+
+    fn awaits_messages(&self) -> bool {
+        self.inner.awaits_messages()
+    }
+
+    fn current_round(&self) -> u16 {
+        self.inner.current_round()
+    }
+
+    fn total_rounds(&self) -> Option<u16> {
+        self.inner.total_rounds()
+    }
+
+    fn party_ind(&self) -> u16 {
+        self.inner.party_ind()
+    }
+
+    fn parties(&self) -> u16 {
+        self.inner.parties()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, Faulty};
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::party::{
+        async_protocol::{Error, RoundContext},
+        musig2_instance::{self, Musig2Instance, ProtocolMessage, M},
+        rounds::ProceedError,
+        sim::async_simulation::{AsyncSimulation, AsyncSimulationError},
+    };
+    use crate::cli::protocals::KeyPair;
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn corrupted_round2_fragment_aborts_honest_parties() {
+        tokio::time::pause();
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let party =
+            |i| Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap());
+        let corrupt = Fault::Corrupt(Arc::new(|msg: &mut ProtocolMessage| {
+            if let ProtocolMessage(M::Round2(m)) = msg {
+                m.sign_fragment[31] ^= 1;
+            }
+        }));
+
+        let results = AsyncSimulation::new()
+            .add_party(Faulty::new(party(1)))
+            .add_party(Faulty::new(party(2)).at_round(2, corrupt))
+            .add_party(Faulty::new(party(3)))
+            .run()
+            .await;
+
+        // Party 2 signs with the genuine fragments, the honest parties get a corrupted one and
+        // blame it on party 2
+        assert!(results[1].is_ok());
+        for i in [0, 2] {
+            assert!(matches!(
+                &results[i],
+                Err(AsyncSimulationError::ProtocolExecution(Error::Proceed(
+                    RoundContext {
+                        round: 2,
+                        error: musig2_instance::Error::ProceedRound(
+                            ProceedError::InvalidPartialSignature { party_ind: 2 }
+                        ),
+                    }
+                )))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_in_round2_stops_waiting_parties() {
+        // With the clock paused it only moves while every party waits, so parties left waiting
+        // hit the limit below long before anything else
+        tokio::time::pause();
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let party =
+            |i| Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap());
+        let corrupt = Fault::Corrupt(Arc::new(|msg: &mut ProtocolMessage| {
+            if let ProtocolMessage(M::Round2(m)) = msg {
+                m.sign_fragment[31] ^= 1;
+            }
+        }));
+
+        // Only party 2 gets every fragment, so it's the one to fail in Round2, while the others
+        // keep waiting for its fragment
+        let results = tokio::time::timeout(
+            Duration::from_secs(2),
+            AsyncSimulation::new()
+                .broadcast_abort(true)
+                .add_party(Faulty::new(party(1)).at_round(2, corrupt))
+                .add_party(Faulty::new(party(2)).at_round(2, Fault::Drop))
+                .add_party(Faulty::new(party(3)))
+                .run(),
+        )
+        .await
+        .expect("parties waited for the round timeout");
+
+        assert!(matches!(
+            results[1],
+            Err(AsyncSimulationError::ProtocolExecution(Error::Proceed(_)))
+        ));
+        for i in [0, 2] {
+            assert!(matches!(
+                results[i],
+                Err(AsyncSimulationError::ProtocolExecution(Error::Aborted {
+                    by: 2,
+                    ..
+                }))
+            ));
+        }
+    }
+}
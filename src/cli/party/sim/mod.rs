@@ -1,3 +1,4 @@
 mod async_simulation;
 pub mod benchmark;
+mod faulty;
 mod simulation;
@@ -1,10 +1,20 @@
 #![allow(dead_code)]
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rayon::prelude::*;
+use serde::Serialize;
 
 use crate::cli::party::traits::state_machine::*;
 
 use crate::cli::party::sim::benchmark::Benchmark;
-pub use crate::cli::party::sim::benchmark::{BenchmarkResults, Measurements};
+pub use crate::cli::party::sim::benchmark::{
+    BenchmarkDiff, BenchmarkResults, CompareBenchmarks, ExportBenchmarks, Measurements,
+};
 
 /// Emulates running protocol between local parties
 ///
@@ -18,18 +28,16 @@ pub use crate::cli::party::sim::benchmark::{BenchmarkResults, Measurements};
 /// performance regression.
 ///
 /// ## Limitations
-/// * No proper error handling. It should attach a context to returning error (like current round,
-///   what we was doing when error occurred, etc.). The only way to determine error context is to
-///   look at stdout and find out what happened from logs.
-/// * Logs everything to stdout. No choice.
+/// * Logs everything to stdout, unless a [transcript](Simulation::with_transcript) is recorded
+///   instead.
 ///
 /// ## Example
 /// ```no_run
 /// # use round_based::StateMachine;
-/// # use round_based::dev::Simulation;
+/// # use round_based::dev::{Simulation, SimulationError};
 /// # trait Builder { fn new(party_i: u16, party_n: u16) -> Self; }
 /// # fn is_valid<T>(_: &T) -> bool { true }
-/// # fn _test<Party: StateMachine + Builder>() -> Result<(), Party::Err>
+/// # fn _test<Party: StateMachine + Builder>() -> Result<(), SimulationError<Party::Err>>
 /// # where Party: std::fmt::Debug,
 /// #       Party::Err: std::fmt::Debug,
 /// #       Party::MessageBody: std::fmt::Debug + Clone,
@@ -43,20 +51,129 @@ pub use crate::cli::party::sim::benchmark::{BenchmarkResults, Measurements};
 /// # Ok(())
 /// # }
 /// ```
-pub struct Simulation<P> {
+pub struct Simulation<P: StateMachine> {
     /// Parties running a protocol
     ///
     /// Field is exposed mainly to allow examining parties state after simulation is completed.
     pub parties: Vec<P>,
     benchmark: Benchmark,
+    detect_premature_proceed: bool,
+    on_round_messages: Option<Box<dyn FnMut(&[Msg<P::MessageBody>])>>,
+    transcript: Option<Vec<TranscriptEntry>>,
+    drop_messages: Option<Box<dyn Fn(&Msg<P::MessageBody>) -> bool>>,
+    deadline: Option<Duration>,
+    mutators: HashMap<u16, Mutator<P::MessageBody>>,
+    message_order: Option<u64>,
+    message_size: Option<fn(&P::MessageBody) -> usize>,
+    parallel_proceed: Option<ParallelProceed<P>>,
+}
+
+type Mutator<B> = Box<dyn FnMut(&mut Msg<B>)>;
+
+/// Proceeds the parties at once, see [Simulation::parallel]
+type ParallelProceed<P> =
+    fn(&mut [&mut P]) -> Vec<Option<ProceedOutcome<<P as StateMachine>::Err>>>;
+
+/// Proceed of a party timed by its own stopwatch, see [Simulation::parallel]
+struct ProceedOutcome<E> {
+    round_old: u16,
+    round_new: u16,
+    time: Duration,
+    result: Result<(), E>,
+}
+
+/// Proceeds the `state` if it wants to, timing it
+fn proceed_timed<P: StateMachine>(state: &mut P) -> Option<ProceedOutcome<P::Err>> {
+    if !state.wants_to_proceed() {
+        return None;
+    }
+    let round_old = state.current_round();
+    let started = Instant::now();
+    let result = state.proceed();
+    Some(ProceedOutcome {
+        round_old,
+        round_new: state.current_round(),
+        time: started.elapsed(),
+        result,
+    })
 }
 
-impl<P> Simulation<P> {
+/// Error of [Simulation::run]
+#[derive(Debug)]
+pub enum SimulationError<E> {
+    /// A party produced critical error
+    Protocol {
+        err: E,
+        /// Party which produced the error
+        party: u16,
+        /// Round the party was in
+        round: u16,
+        /// What the party was doing: [Phase::Proceed], [Phase::HandleIncoming] or
+        /// [Phase::Finish]
+        phase: Phase,
+    },
+    /// Simulation didn't finish within its [deadline](Simulation::with_deadline)
+    DeadlineExceeded,
+}
+
+impl<E: Debug> fmt::Display for SimulationError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulationError::Protocol {
+                err,
+                party,
+                round,
+                phase,
+            } => write!(
+                f,
+                "party {} failed at {:?} in round {}: {:?}",
+                party, phase, round, err
+            ),
+            SimulationError::DeadlineExceeded => write!(f, "simulation deadline exceeded"),
+        }
+    }
+}
+
+/// Stage of the simulation an entry of the [transcript](Simulation::with_transcript) was
+/// recorded at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Proceed,
+    SendOutgoing,
+    HandleIncoming,
+    Finish,
+}
+
+/// Event of the simulation, see [Simulation::with_transcript]
+///
+/// `sender` and `receiver` describe the message for [Phase::SendOutgoing] and
+/// [Phase::HandleIncoming], `receiver` is `None` for a broadcast message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptEntry {
+    /// Party the event happened at
+    pub party: u16,
+    /// Round the party was in
+    pub round: u16,
+    pub phase: Phase,
+    pub sender: Option<u16>,
+    pub receiver: Option<u16>,
+}
+
+impl<P: StateMachine> Simulation<P> {
     /// Creates new simulation
     pub fn new() -> Self {
         Self {
             parties: vec![],
             benchmark: Benchmark::disabled(),
+            detect_premature_proceed: false,
+            on_round_messages: None,
+            transcript: None,
+            drop_messages: None,
+            deadline: None,
+            mutators: HashMap::new(),
+            message_order: None,
+            message_size: None,
+            parallel_proceed: None,
         }
     }
 
@@ -66,27 +183,157 @@ impl<P> Simulation<P> {
         self
     }
 
+    /// Adds protocol participant which tampers with its outgoing messages
+    ///
+    /// Every message sent by the `party` is passed through the `mutator` before it's delivered
+    /// to the other parties. Useful to make sure honest parties detect a misbehaving one.
+    pub fn add_malicious_party(
+        &mut self,
+        party: P,
+        mutator: impl FnMut(&mut Msg<P::MessageBody>) + 'static,
+    ) -> &mut Self {
+        self.mutators.insert(party.party_ind(), Box::new(mutator));
+        self.add_party(party)
+    }
+
     /// Enables benchmarks so they can be [retrieved](Simulation::benchmark_results) after simulation
     /// is completed
     pub fn enable_benchmarks(&mut self, enable: bool) -> &mut Self {
-        if enable {
-            self.benchmark = Benchmark::enabled()
-        } else {
-            self.benchmark = Benchmark::disabled()
-        }
+        self.benchmark.set_enabled(enable);
+        self
+    }
+
+    /// Streams benchmark measurements to `sink` as they're taken
+    ///
+    /// `sink` gets the round and the duration every time a [proceed](StateMachine::proceed) is
+    /// timed, so long simulations can be watched live. Works whether the benchmarks are
+    /// [enabled](Simulation::enable_benchmarks) or not.
+    pub fn with_benchmark_sink(&mut self, sink: impl FnMut(u16, Duration) + 'static) -> &mut Self {
+        self.benchmark.set_sink(sink);
+        self
+    }
+
+    /// Panics when a party [wants to proceed](StateMachine::wants_to_proceed) while it still
+    /// [awaits messages](StateMachine::awaits_messages) of the round
+    ///
+    /// Catches state machine bugs during development, instead of proceeding with incomplete
+    /// data.
+    pub fn detect_premature_proceed(&mut self, enable: bool) -> &mut Self {
+        self.detect_premature_proceed = enable;
+        self
+    }
+
+    /// Calls `inspect` with the messages sent in every round, before they are delivered
+    ///
+    /// Lets tests examine which messages crossed the wire without parsing stdout. The
+    /// messages can't be altered.
+    pub fn on_round_messages(
+        &mut self,
+        inspect: impl FnMut(&[Msg<P::MessageBody>]) + 'static,
+    ) -> &mut Self {
+        self.on_round_messages = Some(Box::new(inspect));
         self
     }
 
+    /// Silently discards the sent messages matching `predicate` instead of delivering them
+    ///
+    /// Simulates message loss, e.g. `|m| m.sender == 2` loses everything party 2 sends. A
+    /// dropped broadcast message is lost for every receiver. Unless the protocol recovers from
+    /// the loss, the parties wait for the message forever, so the simulation hangs: set a
+    /// [deadline](Simulation::with_deadline) to get [SimulationError::DeadlineExceeded] from
+    /// [run](Simulation::run) instead.
+    pub fn drop_messages(
+        &mut self,
+        predicate: impl Fn(&Msg<P::MessageBody>) -> bool + 'static,
+    ) -> &mut Self {
+        self.drop_messages = Some(Box::new(predicate));
+        self
+    }
+
+    /// Stops the simulation once it runs longer than `deadline`
+    pub fn with_deadline(&mut self, deadline: Duration) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Shuffles the messages of every round before delivering them, instead of delivering them in
+    /// the order they were sent
+    ///
+    /// The order is derived from the `seed`, so the same seed always gives the same delivery
+    /// order. Running over several seeds checks that the protocol doesn't depend on the order of
+    /// the messages.
+    pub fn with_message_order(&mut self, seed: u64) -> &mut Self {
+        self.message_order = Some(seed);
+        self
+    }
+
+    /// Records every event into a [transcript](Simulation::transcript) instead of logging it
+    /// to stdout
+    ///
+    /// Lets tests assert on the course of the simulation and keeps CI logs quiet.
+    pub fn with_transcript(&mut self) -> &mut Self {
+        self.transcript = Some(vec![]);
+        self
+    }
+
+    /// Events recorded so far if the [transcript](Simulation::with_transcript) is enabled
+    pub fn transcript(&self) -> Option<&[TranscriptEntry]> {
+        self.transcript.as_deref()
+    }
+
     /// Returns benchmark results if they were [enabled](Simulation::enable_benchmarks)
     ///
     /// Benchmarks show how much time (in average) [proceed](StateMachine::proceed) method takes for
     /// proceeding particular rounds. Benchmarks might help to find out which rounds are cheap to
-    /// proceed, and which of them are expensive to compute.
+    /// proceed, and which of them are expensive to compute. The time parties spend exchanging
+    /// messages in every round is measured apart, see [Measurements::exchange_time].
     pub fn benchmark_results(&self) -> Option<&BenchmarkResults> {
         self.benchmark.results()
     }
 }
 
+impl<P> Simulation<P>
+where
+    P: StateMachine,
+    P::MessageBody: Serialize,
+{
+    /// Makes [benchmarks](Simulation::enable_benchmarks) also measure the traffic: number and
+    /// total size of the sent messages
+    ///
+    /// Size of a message is the length of its body serialized to JSON. Messages are accounted to
+    /// the round the sender is in when it sends them, see [Measurements::bytes_sent].
+    pub fn measure_message_sizes(&mut self) -> &mut Self {
+        self.message_size = Some(|body| serde_json::to_vec(body).map_or(0, |bytes| bytes.len()));
+        self
+    }
+}
+
+impl<P> Simulation<P>
+where
+    P: StateMachine + Send,
+    P::Err: Send,
+{
+    /// Proceeds the parties in parallel on the rayon thread pool if `enable`
+    ///
+    /// Speeds up benchmarking the crypto of many parties. Messages are still exchanged
+    /// sequentially in the order of the parties, so the simulation runs the same either way.
+    /// Every party is timed by its own stopwatch, the measurements are saved in the order of
+    /// the parties once all of them proceeded.
+    pub fn parallel(&mut self, enable: bool) -> &mut Self {
+        self.parallel_proceed = if enable {
+            Some(|states| {
+                states
+                    .par_iter_mut()
+                    .map(|state| proceed_timed(&mut **state))
+                    .collect()
+            })
+        } else {
+            None
+        };
+        self
+    }
+}
+
 impl<P> Simulation<P>
 where
     P: StateMachine,
@@ -98,54 +345,163 @@ where
     ///
     /// ## Returns
     /// Returns either Vec of protocol outputs (one output for each one party) or first
-    /// occurred critical error.
+    /// occurred critical error, along with the party, round and phase it occurred at. An
+    /// exceeded [deadline](Simulation::with_deadline) is returned as
+    /// [SimulationError::DeadlineExceeded].
+    ///
+    /// A single party runs the protocol on its own, no message is exchanged then.
     ///
     /// ## Panics
-    /// * Number of parties is less than 2
-    pub fn run(&mut self) -> Result<Vec<P::Output>, P::Err> {
-        assert!(self.parties.len() >= 2, "at least two parties required");
+    /// * There are no parties
+    pub fn run(&mut self) -> Result<Vec<P::Output>, SimulationError<P::Err>> {
+        assert!(!self.parties.is_empty(), "at least one party required");
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        let mut message_order = self.message_order.map(StdRng::seed_from_u64);
 
+        let message_size = self.message_size;
+        let parallel_proceed = self.parallel_proceed;
+        let mut mutators: HashMap<u16, _> = self
+            .mutators
+            .iter_mut()
+            .map(|(i, mutator)| (*i, mutator))
+            .collect();
         let mut parties: Vec<_> = self
             .parties
             .iter_mut()
-            .map(|p| Party { state: p })
+            .map(|p| Party {
+                mutator: mutators.remove(&p.party_ind()),
+                message_size,
+                state: p,
+            })
             .collect();
+        let mut log = Log(self.transcript.as_mut());
 
-        println!("Simulation starts");
+        log.print(|| println!("Simulation starts"));
 
         let mut msgs_pull = vec![];
 
-        for party in &mut parties {
-            party.proceed_if_needed(&mut self.benchmark)?;
-            party.send_outgoing(&mut msgs_pull);
-        }
+        proceed_parties(
+            &mut parties,
+            parallel_proceed,
+            self.detect_premature_proceed,
+            &mut msgs_pull,
+            &mut self.benchmark,
+            &mut log,
+        )?;
 
-        if let Some(results) = finish_if_possible(&mut parties)? {
+        if let Some(results) = finish_if_possible(&mut parties, &mut log)? {
             return Ok(results);
         }
 
         loop {
-            let msgs_pull_frozen = msgs_pull.split_off(0);
-
-            for party in &mut parties {
-                party.handle_incoming(&msgs_pull_frozen)?;
-                party.send_outgoing(&mut msgs_pull);
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Err(SimulationError::DeadlineExceeded);
             }
+            let mut msgs_pull_frozen = msgs_pull.split_off(0);
+            if let Some(drop) = self.drop_messages.as_ref() {
+                msgs_pull_frozen.retain(|msg| !drop(msg));
+            }
+            if let Some(rng) = message_order.as_mut() {
+                msgs_pull_frozen.shuffle(rng);
+            }
+            if let Some(inspect) = self.on_round_messages.as_mut() {
+                inspect(&msgs_pull_frozen);
+            }
+            let msgs_index = MsgsIndex::new(&msgs_pull_frozen);
 
             for party in &mut parties {
-                party.proceed_if_needed(&mut self.benchmark)?;
-                party.send_outgoing(&mut msgs_pull);
+                let party_i = party.state.party_ind();
+                let round = party.state.current_round();
+                let started = Instant::now();
+                party.handle_incoming(msgs_index.for_party(party_i), &mut log)?;
+                party.send_outgoing(&mut msgs_pull, &mut self.benchmark, &mut log);
+                self.benchmark.save_exchange(round, started.elapsed());
             }
 
-            if let Some(results) = finish_if_possible(&mut parties)? {
+            proceed_parties(
+                &mut parties,
+                parallel_proceed,
+                self.detect_premature_proceed,
+                &mut msgs_pull,
+                &mut self.benchmark,
+                &mut log,
+            )?;
+
+            if let Some(results) = finish_if_possible(&mut parties, &mut log)? {
                 return Ok(results);
             }
         }
     }
 }
 
-struct Party<'p, P> {
+/// Proceeds every party that wants to, then sends its messages, in the order of the parties
+///
+/// With `parallel` set, all the parties are proceeded first, see [Simulation::parallel].
+fn proceed_parties<P>(
+    parties: &mut [Party<P>],
+    parallel: Option<ParallelProceed<P>>,
+    detect_premature_proceed: bool,
+    msgs_pull: &mut Vec<Msg<P::MessageBody>>,
+    benchmark: &mut Benchmark,
+    log: &mut Log,
+) -> Result<(), SimulationError<P::Err>>
+where
+    P: StateMachine,
+    P: Debug,
+    P::Err: Debug,
+    P::MessageBody: Debug + Clone,
+{
+    let proceed = match parallel {
+        Some(proceed) => proceed,
+        None => {
+            for party in parties.iter_mut() {
+                if detect_premature_proceed {
+                    party.assert_not_premature();
+                }
+                party.proceed_if_needed(benchmark, log)?;
+                party.send_outgoing(msgs_pull, benchmark, log);
+            }
+            return Ok(());
+        }
+    };
+
+    if detect_premature_proceed {
+        parties
+            .iter()
+            .for_each(|party| party.assert_not_premature());
+    }
+    let mut states: Vec<&mut P> = parties.iter_mut().map(|party| &mut *party.state).collect();
+    let outcomes = proceed(&mut states);
+    for (party, outcome) in parties.iter_mut().zip(outcomes) {
+        if let Some(outcome) = outcome {
+            party.save_proceed(outcome, benchmark, log)?;
+        }
+        party.send_outgoing(msgs_pull, benchmark, log);
+    }
+    Ok(())
+}
+
+struct Party<'p, P: StateMachine> {
     state: &'p mut P,
+    mutator: Option<&'p mut Mutator<P::MessageBody>>,
+    message_size: Option<fn(&P::MessageBody) -> usize>,
+}
+
+/// Records events into the transcript if there's one, otherwise they're printed to stdout
+struct Log<'t>(Option<&'t mut Vec<TranscriptEntry>>);
+
+impl Log<'_> {
+    fn print(&self, print: impl FnOnce()) {
+        if self.0.is_none() {
+            print()
+        }
+    }
+
+    fn record(&mut self, entry: impl FnOnce() -> TranscriptEntry) {
+        if let Some(transcript) = self.0.as_mut() {
+            transcript.push(entry())
+        }
+    }
 }
 
 impl<'p, P> Party<'p, P>
@@ -155,80 +511,268 @@ where
     P::Err: Debug,
     P::MessageBody: Debug + Clone,
 {
-    pub fn proceed_if_needed(&mut self, benchmark: &mut Benchmark) -> Result<(), P::Err> {
+    /// Panics if the party wants to proceed while it still awaits messages of the round
+    pub fn assert_not_premature(&self) {
+        assert!(
+            !(self.state.wants_to_proceed() && self.state.awaits_messages()),
+            "party {} wants to proceed round {} while it still awaits messages",
+            self.state.party_ind(),
+            self.state.current_round()
+        );
+    }
+
+    pub fn proceed_if_needed(
+        &mut self,
+        benchmark: &mut Benchmark,
+        log: &mut Log,
+    ) -> Result<(), SimulationError<P::Err>> {
         if !self.state.wants_to_proceed() {
             return Ok(());
         }
 
-        println!("Party {} wants to proceed", self.state.party_ind());
-        println!("  - before: {:?}", self.state);
+        let state = &*self.state;
+        log.print(|| {
+            println!("Party {} wants to proceed", state.party_ind());
+            println!("  - before: {:?}", state);
+        });
+        log.record(|| TranscriptEntry {
+            party: state.party_ind(),
+            round: state.current_round(),
+            phase: Phase::Proceed,
+            sender: None,
+            receiver: None,
+        });
 
         let round_old = self.state.current_round();
         let stopwatch = benchmark.start();
         match self.state.proceed() {
             Ok(()) => (),
-            Err(err) if err.is_critical() => return Err(err),
+            Err(err) if err.is_critical() => {
+                return Err(SimulationError::Protocol {
+                    err,
+                    party: self.state.party_ind(),
+                    round: round_old,
+                    phase: Phase::Proceed,
+                })
+            }
             Err(err) => {
-                println!("Non-critical error encountered: {:?}", err);
+                log.print(|| println!("Non-critical error encountered: {:?}", err));
             }
         }
         let round_new = self.state.current_round();
         let duration = if round_old != round_new {
-            Some(stopwatch.stop_and_save(round_old))
+            stopwatch.stop_and_save(round_old)
         } else {
-            None
+            stopwatch.stop_and_save_non_advancing(round_old)
         };
 
-        println!("  - after : {:?}", self.state);
-        println!("  - took  : {:?}", duration);
-        println!();
+        let state = &*self.state;
+        log.print(|| {
+            println!("  - after : {:?}", state);
+            println!("  - took  : {:?}", duration);
+            println!();
+        });
+
+        Ok(())
+    }
 
+    /// Accounts a proceed [timed](proceed_timed) apart, see [Simulation::parallel]
+    fn save_proceed(
+        &mut self,
+        outcome: ProceedOutcome<P::Err>,
+        benchmark: &mut Benchmark,
+        log: &mut Log,
+    ) -> Result<(), SimulationError<P::Err>> {
+        let party = self.state.party_ind();
+        let ProceedOutcome {
+            round_old,
+            round_new,
+            time,
+            result,
+        } = outcome;
+        log.record(|| TranscriptEntry {
+            party,
+            round: round_old,
+            phase: Phase::Proceed,
+            sender: None,
+            receiver: None,
+        });
+        match result {
+            Ok(()) => (),
+            Err(err) if err.is_critical() => {
+                return Err(SimulationError::Protocol {
+                    err,
+                    party,
+                    round: round_old,
+                    phase: Phase::Proceed,
+                })
+            }
+            Err(err) => {
+                log.print(|| println!("Non-critical error encountered: {:?}", err));
+            }
+        }
+        benchmark.save(round_old, time, round_old != round_new);
+
+        let state = &*self.state;
+        log.print(|| {
+            println!("Party {} proceeded", party);
+            println!("  - after : {:?}", state);
+            println!("  - took  : {:?}", time);
+            println!();
+        });
         Ok(())
     }
 
-    pub fn send_outgoing(&mut self, msgs_pull: &mut Vec<Msg<P::MessageBody>>) {
+    pub fn send_outgoing(
+        &mut self,
+        msgs_pull: &mut Vec<Msg<P::MessageBody>>,
+        benchmark: &mut Benchmark,
+        log: &mut Log,
+    ) {
         if !self.state.message_queue().is_empty() {
-            println!(
-                "Party {} sends {} message(s)",
-                self.state.party_ind(),
-                self.state.message_queue().len()
-            );
-            println!();
+            let (party, round) = (self.state.party_ind(), self.state.current_round());
+            let queue = self.state.message_queue();
+            log.print(|| {
+                println!("Party {} sends {} message(s)", party, queue.len());
+                println!();
+            });
+            for msg in queue.iter() {
+                log.record(|| TranscriptEntry {
+                    party,
+                    round,
+                    phase: Phase::SendOutgoing,
+                    sender: Some(msg.sender),
+                    receiver: msg.receiver,
+                });
+            }
 
-            msgs_pull.append(self.state.message_queue())
+            if let Some(mutator) = self.mutator.as_mut() {
+                queue.iter_mut().for_each(|msg| mutator(msg));
+            }
+            if let Some(message_size) = self.message_size {
+                for msg in queue.iter() {
+                    benchmark.add_message(round, message_size(&msg.body));
+                }
+            }
+            msgs_pull.append(queue)
         }
     }
 
-    pub fn handle_incoming(&mut self, msgs_pull: &[Msg<P::MessageBody>]) -> Result<(), P::Err> {
-        for msg in msgs_pull {
-            if Some(self.state.party_ind()) != msg.receiver
-                && (msg.receiver.is_some() || msg.sender == self.state.party_ind())
-            {
-                continue;
-            }
-            println!(
-                "Party {} got message from={}, broadcast={}: {:?}",
-                self.state.party_ind(),
-                msg.sender,
-                msg.receiver.is_none(),
-                msg.body,
-            );
-            println!("  - before: {:?}", self.state);
+    /// Handles the `msgs` addressed to the party, see [MsgsIndex::for_party]
+    pub fn handle_incoming<'m>(
+        &mut self,
+        msgs: impl IntoIterator<Item = &'m Msg<P::MessageBody>>,
+        log: &mut Log,
+    ) -> Result<(), SimulationError<P::Err>>
+    where
+        P::MessageBody: 'm,
+    {
+        for msg in msgs {
+            let state = &*self.state;
+            log.print(|| {
+                println!(
+                    "Party {} got message from={}, broadcast={}: {:?}",
+                    state.party_ind(),
+                    msg.sender,
+                    msg.receiver.is_none(),
+                    msg.body,
+                );
+                println!("  - before: {:?}", state);
+            });
+            log.record(|| TranscriptEntry {
+                party: state.party_ind(),
+                round: state.current_round(),
+                phase: Phase::HandleIncoming,
+                sender: Some(msg.sender),
+                receiver: msg.receiver,
+            });
+            let round = self.state.current_round();
             match self.state.handle_incoming(msg.clone()) {
                 Ok(()) => (),
-                Err(err) if err.is_critical() => return Err(err),
+                Err(err) if err.is_critical() => {
+                    return Err(SimulationError::Protocol {
+                        err,
+                        party: self.state.party_ind(),
+                        round,
+                        phase: Phase::HandleIncoming,
+                    })
+                }
                 Err(err) => {
-                    println!("Non-critical error encountered: {:?}", err);
+                    log.print(|| println!("Non-critical error encountered: {:?}", err));
                 }
             }
-            println!("  - after : {:?}", self.state);
-            println!();
+            let state = &*self.state;
+            log.print(|| {
+                println!("  - after : {:?}", state);
+                println!();
+            });
         }
         Ok(())
     }
 }
 
-fn finish_if_possible<P>(parties: &mut Vec<Party<P>>) -> Result<Option<Vec<P::Output>>, P::Err>
+/// Whether `party_i` should receive `msg`
+fn is_addressed_to<B>(msg: &Msg<B>, party_i: u16) -> bool {
+    match msg.receiver {
+        Some(receiver) => receiver == party_i,
+        None => msg.sender != party_i,
+    }
+}
+
+/// Messages of a round indexed by receiver, so that every party only visits the messages
+/// addressed to it
+struct MsgsIndex<'m, B> {
+    msgs: &'m [Msg<B>],
+    broadcast: Vec<usize>,
+    direct: HashMap<u16, Vec<usize>>,
+}
+
+impl<'m, B> MsgsIndex<'m, B> {
+    fn new(msgs: &'m [Msg<B>]) -> Self {
+        let mut broadcast = vec![];
+        let mut direct: HashMap<u16, Vec<usize>> = HashMap::new();
+        for (i, msg) in msgs.iter().enumerate() {
+            match msg.receiver {
+                Some(receiver) => direct.entry(receiver).or_default().push(i),
+                None => broadcast.push(i),
+            }
+        }
+        Self {
+            msgs,
+            broadcast,
+            direct,
+        }
+    }
+
+    /// Messages addressed to `party_i`, in the order they were sent
+    fn for_party(&self, party_i: u16) -> Vec<&'m Msg<B>> {
+        let no_direct = vec![];
+        let direct = self.direct.get(&party_i).unwrap_or(&no_direct);
+        let (mut b, mut d) = (0, 0);
+        let mut result = Vec::with_capacity(self.broadcast.len() + direct.len());
+        while b < self.broadcast.len() || d < direct.len() {
+            let i = if d == direct.len()
+                || (b < self.broadcast.len() && self.broadcast[b] < direct[d])
+            {
+                b += 1;
+                self.broadcast[b - 1]
+            } else {
+                d += 1;
+                direct[d - 1]
+            };
+            let msg = &self.msgs[i];
+            if is_addressed_to(msg, party_i) {
+                result.push(msg);
+            }
+        }
+        result
+    }
+}
+
+fn finish_if_possible<P>(
+    parties: &mut Vec<Party<P>>,
+    log: &mut Log,
+) -> Result<Option<Vec<P::Output>>, SimulationError<P::Err>>
 where
     P: StateMachine,
     P: Debug,
@@ -244,16 +788,31 @@ where
     if everyone_are_finished {
         let mut results = vec![];
         for party in parties {
-            results.push(
-                party
-                    .state
-                    .pick_output()
-                    .expect("is_finished == true, but pick_output == None")?,
-            )
+            let (party_i, round) = (party.state.party_ind(), party.state.current_round());
+            log.record(|| TranscriptEntry {
+                party: party_i,
+                round,
+                phase: Phase::Finish,
+                sender: None,
+                receiver: None,
+            });
+            let output = party
+                .state
+                .pick_output()
+                .expect("is_finished == true, but pick_output == None")
+                .map_err(|err| SimulationError::Protocol {
+                    err,
+                    party: party_i,
+                    round,
+                    phase: Phase::Finish,
+                })?;
+            results.push(output)
         }
 
-        println!("Simulation is finished");
-        println!();
+        log.print(|| {
+            println!("Simulation is finished");
+            println!();
+        });
 
         Ok(Some(results))
     } else {
@@ -268,38 +827,892 @@ where
             .map(|p| p.state.party_ind())
             .collect();
 
-        println!("Warning: some of parties have finished the protocol, but other parties have not");
-        println!("Finished parties:     {:?}", finished);
-        println!("Not finished parties: {:?}", not_finished);
-        println!();
+        log.print(|| {
+            println!(
+                "Warning: some of parties have finished the protocol, but other parties have not"
+            );
+            println!("Finished parties:     {:?}", finished);
+            println!("Not finished parties: {:?}", not_finished);
+            println!();
+        });
 
         Ok(None)
     }
 }
 
+/// Asserts that all parties agree on the signature, and that it verifies against the aggregate
+/// key of `pks`
+#[cfg(test)]
+pub(crate) fn assert_consensus(
+    results: &[crate::cli::party::rounds::SignResult],
+    message: &[u8],
+    pks: &[crate::cli::protocals::key::PublicKey],
+) {
+    use crate::cli::protocals::{
+        key::PrivateKey,
+        musig2::{verify, KeyAgg},
+        signature::Signature,
+    };
+    use secp256k1::Message;
+
+    assert!(!results.is_empty(), "no party produced a result");
+    let first = &results[0];
+    for (i, result) in results.iter().enumerate().skip(1) {
+        assert_eq!(
+            result.hash(),
+            first.hash(),
+            "party {} disagrees with party 1 on the signature",
+            i + 1
+        );
+    }
+
+    let x_tilde = KeyAgg::canonical(pks, &pks[0])
+        .expect("aggregate key")
+        .x_only_key();
+    let signature = Signature {
+        rx: PrivateKey::parse_slice(&first.r.x_coor()).expect("valid r"),
+        s: first.s.clone(),
+    };
+    let message = Message::parse_slice(message).expect("32-byte message");
+    assert!(
+        verify(&signature, &message, &x_tilde).unwrap_or(false),
+        "signature doesn't verify against the aggregate key"
+    );
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{assert_consensus, Log, Party, Phase, SimulationError, TranscriptEntry};
     use crate::cli::node::format_musig_msg;
-    use crate::cli::party::{musig2_instance::Musig2Instance, sim::simulation::Simulation};
-    use crate::cli::protocals::KeyPair;
+    use crate::cli::party::{
+        musig2_instance::{Error, Musig2Instance, ProtocolMessage, M},
+        observer::Musig2Verifier,
+        rounds::{ProceedError, SignResult},
+        sim::{benchmark::Benchmark, simulation::Simulation},
+        traits::state_machine::{IsCritical, Msg, StateMachine},
+    };
+    use crate::cli::protocals::error::Musig2Error;
+    use crate::cli::protocals::{aggregate_public_keys, key::PublicKey, KeyAgg, KeyPair};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
-    #[test]
-    fn simulate_musig_protocol() {
-        let message = format_musig_msg(Vec::from("test".as_bytes()));
+    /// Keeps failing to proceed with a non-critical error, never leaving round 1
+    #[derive(Debug)]
+    struct Stuck {
+        queue: Vec<Msg<()>>,
+    }
+
+    #[derive(Debug)]
+    struct NotYet;
+
+    impl IsCritical for NotYet {
+        fn is_critical(&self) -> bool {
+            false
+        }
+    }
 
-        let kp1 = KeyPair::create().unwrap();
-        let kp2 = KeyPair::create().unwrap();
-        let kp3 = KeyPair::create().unwrap();
+    impl StateMachine for Stuck {
+        type MessageBody = ();
+        type Err = NotYet;
+        type Output = ();
 
+        fn handle_incoming(&mut self, _msg: Msg<()>) -> Result<(), NotYet> {
+            Ok(())
+        }
+
+        fn message_queue(&mut self) -> &mut Vec<Msg<()>> {
+            &mut self.queue
+        }
+
+        fn wants_to_proceed(&self) -> bool {
+            true
+        }
+
+        fn proceed(&mut self) -> Result<(), NotYet> {
+            Err(NotYet)
+        }
+
+        fn round_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn round_timeout_reached(&mut self) -> NotYet {
+            NotYet
+        }
+
+        fn is_finished(&self) -> bool {
+            false
+        }
+
+        fn pick_output(&mut self) -> Option<Result<(), NotYet>> {
+            None
+        }
+
+        fn current_round(&self) -> u16 {
+            1
+        }
+
+        fn total_rounds(&self) -> Option<u16> {
+            Some(1)
+        }
+
+        fn party_ind(&self) -> u16 {
+            1
+        }
+
+        fn parties(&self) -> u16 {
+            2
+        }
+    }
+
+    /// Wants to proceed right away, although it awaits the messages of round 1
+    #[derive(Debug)]
+    struct Eager {
+        party_i: u16,
+        queue: Vec<Msg<()>>,
+    }
+
+    impl StateMachine for Eager {
+        type MessageBody = ();
+        type Err = NotYet;
+        type Output = ();
+
+        fn handle_incoming(&mut self, _msg: Msg<()>) -> Result<(), NotYet> {
+            Ok(())
+        }
+
+        fn message_queue(&mut self) -> &mut Vec<Msg<()>> {
+            &mut self.queue
+        }
+
+        fn wants_to_proceed(&self) -> bool {
+            true
+        }
+
+        fn proceed(&mut self) -> Result<(), NotYet> {
+            Ok(())
+        }
+
+        fn awaits_messages(&self) -> bool {
+            true
+        }
+
+        fn round_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn round_timeout_reached(&mut self) -> NotYet {
+            NotYet
+        }
+
+        fn is_finished(&self) -> bool {
+            false
+        }
+
+        fn pick_output(&mut self) -> Option<Result<(), NotYet>> {
+            None
+        }
+
+        fn current_round(&self) -> u16 {
+            1
+        }
+
+        fn total_rounds(&self) -> Option<u16> {
+            Some(1)
+        }
+
+        fn party_ind(&self) -> u16 {
+            self.party_i
+        }
+
+        fn parties(&self) -> u16 {
+            2
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "party 1 wants to proceed round 1 while it still awaits messages")]
+    fn premature_proceed_is_detected() {
+        Simulation::new()
+            .detect_premature_proceed(true)
+            .add_party(Eager {
+                party_i: 1,
+                queue: vec![],
+            })
+            .add_party(Eager {
+                party_i: 2,
+                queue: vec![],
+            })
+            .run()
+            .ok();
+    }
+
+    /// Simulation of `key_pairs` signing `message`, party `i` holds `key_pairs[i - 1]`
+    fn simulation_of(key_pairs: &[KeyPair], message: &[u8]) -> Simulation<Musig2Instance> {
+        let n = key_pairs.len() as u16;
         let mut simulation = Simulation::new();
+        for (i, kp) in key_pairs.iter().enumerate() {
+            simulation.add_party(Musig2Instance::with_fixed_seed(
+                i as u16 + 1,
+                n,
+                message.to_vec(),
+                kp.clone(),
+            ));
+        }
+        simulation
+    }
+
+    /// Simulation of three parties with fresh keys signing `message`, and their public keys
+    fn three_party_simulation(message: &[u8]) -> (Simulation<Musig2Instance>, Vec<PublicKey>) {
+        let key_pairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let pks = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        (simulation_of(&key_pairs, message), pks)
+    }
+
+    #[test]
+    fn simulate_musig_protocol() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, pks) = three_party_simulation(&message);
         simulation
             .enable_benchmarks(true)
-            .add_party(Musig2Instance::with_fixed_seed(1, 3, message.clone(), kp1))
-            .add_party(Musig2Instance::with_fixed_seed(2, 3, message.clone(), kp2))
-            .add_party(Musig2Instance::with_fixed_seed(3, 3, message.clone(), kp3));
+            .detect_premature_proceed(true);
         let result = simulation.run().expect("simulation failed");
+        assert_consensus(&result, &message, &pks);
         println!("sign result:{:?}", result[0]);
         println!("Benchmarks:");
         println!("{:#?}", simulation.benchmark_results().unwrap());
     }
+
+    /// Signer or verifier, so both can take part in one simulation
+    #[derive(Debug)]
+    enum Participant {
+        Signer(Musig2Instance),
+        Verifier(Musig2Verifier),
+    }
+
+    macro_rules! delegate {
+        ($self:expr, $p:ident => $e:expr) => {
+            match $self {
+                Participant::Signer($p) => $e,
+                Participant::Verifier($p) => $e,
+            }
+        };
+    }
+
+    impl StateMachine for Participant {
+        type MessageBody = ProtocolMessage;
+        type Err = Error;
+        type Output = SignResult;
+
+        fn handle_incoming(&mut self, msg: Msg<ProtocolMessage>) -> Result<(), Error> {
+            delegate!(self, p => p.handle_incoming(msg))
+        }
+
+        fn message_queue(&mut self) -> &mut Vec<Msg<ProtocolMessage>> {
+            delegate!(self, p => p.message_queue())
+        }
+
+        fn wants_to_proceed(&self) -> bool {
+            delegate!(self, p => p.wants_to_proceed())
+        }
+
+        fn proceed(&mut self) -> Result<(), Error> {
+            delegate!(self, p => p.proceed())
+        }
+
+        fn round_timeout(&self) -> Option<Duration> {
+            delegate!(self, p => p.round_timeout())
+        }
+
+        fn round_timeout_reached(&mut self) -> Error {
+            delegate!(self, p => p.round_timeout_reached())
+        }
+
+        fn is_finished(&self) -> bool {
+            delegate!(self, p => p.is_finished())
+        }
+
+        fn pick_output(&mut self) -> Option<Result<SignResult, Error>> {
+            delegate!(self, p => p.pick_output())
+        }
+
+        fn current_round(&self) -> u16 {
+            delegate!(self, p => p.current_round())
+        }
+
+        fn total_rounds(&self) -> Option<u16> {
+            delegate!(self, p => p.total_rounds())
+        }
+
+        fn party_ind(&self) -> u16 {
+            delegate!(self, p => p.party_ind())
+        }
+
+        fn parties(&self) -> u16 {
+            delegate!(self, p => p.parties())
+        }
+    }
+
+    #[test]
+    fn verifier_reaches_signers_result() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut simulation = Simulation::new();
+        for i in 1..=2 {
+            simulation.add_party(Participant::Signer(Musig2Instance::with_fixed_seed(
+                i,
+                2,
+                message.clone(),
+                KeyPair::create().unwrap(),
+            )));
+        }
+        simulation.add_party(Participant::Verifier(Musig2Verifier::new(
+            2,
+            message.clone(),
+        )));
+
+        let results = simulation.run().expect("simulation failed");
+        assert!(results[2].verify());
+        assert_eq!(results[2].hash(), results[0].hash());
+        assert_eq!(results[2].commit, results[0].commit);
+        assert_eq!(results[2].aggregated_key, results[0].aggregated_key);
+        assert_eq!(results[2].signers, vec![1, 2]);
+    }
+
+    #[test]
+    fn lost_broadcast_stalls_simulation() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, _) = three_party_simulation(&message);
+        // The round 1 broadcast of party 2 is lost
+        let dropped = std::cell::Cell::new(false);
+        simulation
+            .drop_messages(move |msg| {
+                let drop = msg.sender == 2 && !dropped.get();
+                if drop {
+                    dropped.set(true);
+                }
+                drop
+            })
+            .with_deadline(Duration::from_millis(200));
+
+        assert!(matches!(
+            simulation.run(),
+            Err(SimulationError::DeadlineExceeded)
+        ));
+        assert!(simulation.parties.iter().all(|p| !p.is_finished()));
+    }
+
+    #[test]
+    fn tampered_fragment_is_detected() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let party =
+            |i| Musig2Instance::with_fixed_seed(i, 3, message.clone(), KeyPair::create().unwrap());
+        let result = Simulation::new()
+            .add_party(party(1))
+            .add_party(party(2))
+            .add_malicious_party(party(3), |msg: &mut Msg<ProtocolMessage>| {
+                if let M::Round2(body) = &mut msg.body.0 {
+                    body.sign_fragment[31] ^= 1;
+                }
+            })
+            .run();
+
+        // Party 1 is the first to get all the fragments of round 2
+        match result {
+            Err(SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::InvalidPartialSignature { party_ind: 3 }),
+                party: 1,
+                round: 2,
+                phase: Phase::HandleIncoming,
+            }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn committed_nonces_sign_in_three_rounds() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<_> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let mut simulation = Simulation::new();
+        for (i, kp) in key_pairs.iter().enumerate() {
+            let party =
+                Musig2Instance::with_nonce_commitment(i as u16 + 1, 3, message.clone(), kp.clone());
+            assert_eq!(party.total_rounds(), Some(3));
+            simulation.add_party(party);
+        }
+        let results = simulation.run().unwrap();
+
+        let pks: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        assert_consensus(&results, &message, &pks);
+    }
+
+    #[test]
+    fn nonces_other_than_committed_are_rejected() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let party = |i| {
+            Musig2Instance::with_nonce_commitment(i, 3, message.clone(), KeyPair::create().unwrap())
+        };
+        let result = Simulation::new()
+            .add_party(party(1))
+            .add_party(party(2))
+            .add_malicious_party(party(3), |msg: &mut Msg<ProtocolMessage>| {
+                if let M::Round1(body) = &mut msg.body.0 {
+                    body.ephemeral_keys.swap(0, 1);
+                }
+            })
+            .run();
+
+        match result {
+            Err(SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::PartiesDidntRevealItsSeed { party_ind }),
+                ..
+            }) if party_ind == vec![3] => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn message_order_does_not_affect_signature() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<_> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let run = |seed| {
+            simulation_of(&key_pairs, &message)
+                .with_message_order(seed)
+                .run()
+                .unwrap()
+        };
+
+        assert_eq!(run(1)[0].to_bytes(), run(1)[0].to_bytes());
+        let results = run(0);
+        let pks: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        assert_consensus(&results, &message, &pks);
+        for seed in 1..5 {
+            assert_eq!(
+                run(seed)[0].to_bytes(),
+                results[0].to_bytes(),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_proceed_gives_same_signature() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<_> = (0..10).map(|_| KeyPair::create().unwrap()).collect();
+        let run = |parallel| {
+            let mut simulation = simulation_of(&key_pairs, &message);
+            simulation.enable_benchmarks(true).parallel(parallel);
+            let results = simulation.run().unwrap();
+            let measured = simulation.benchmark_results().unwrap()[&0].n;
+            (results, measured)
+        };
+
+        let (sequential, sequential_n) = run(false);
+        let (parallel, parallel_n) = run(true);
+        let pks: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        assert_consensus(&parallel, &message, &pks);
+        assert_eq!(parallel[0].to_bytes(), sequential[0].to_bytes());
+        assert_eq!(parallel_n, sequential_n);
+    }
+
+    #[test]
+    fn proceed_and_exchange_are_measured_apart() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, _) = three_party_simulation(&message);
+        simulation.enable_benchmarks(true);
+        simulation.run().unwrap();
+
+        let results = simulation.benchmark_results().unwrap();
+        // Round 0 is proceeded before any message is exchanged
+        assert_eq!(results[&0].n, 3);
+        assert_eq!(results[&0].exchange_n, 0);
+        for round in 1..=2 {
+            let measurements = &results[&round];
+            assert_eq!(measurements.n, 3, "round {}", round);
+            assert_eq!(measurements.exchange_n, 3, "round {}", round);
+            assert!(measurements.average_exchange().is_some(), "round {}", round);
+        }
+    }
+
+    #[test]
+    fn message_sizes_are_measured() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, _) = three_party_simulation(&message);
+        simulation.enable_benchmarks(true).measure_message_sizes();
+        simulation.run().unwrap();
+
+        let results = simulation.benchmark_results().unwrap();
+        for round in 1..=2 {
+            let measurements = &results[&round];
+            assert_eq!(measurements.messages_sent, 3, "round {}", round);
+            assert!(measurements.bytes_sent > 0, "round {}", round);
+        }
+    }
+
+    #[test]
+    fn key_agg_cache_is_shared_across_sessions() {
+        let key_pairs: Vec<_> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let pks: Vec<_> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        let cache = KeyAgg::precompute(&pks).unwrap();
+
+        for session in 0..2u8 {
+            let message = format_musig_msg(vec![session; 4]);
+            let mut simulation = Simulation::new();
+            for (i, kp) in key_pairs.iter().enumerate() {
+                simulation.add_party(
+                    Musig2Instance::with_fixed_seed(i as u16 + 1, 3, message.clone(), kp.clone())
+                        .key_agg_cache(cache.clone()),
+                );
+            }
+            let results = simulation.run().unwrap();
+            assert_consensus(&results, &message, &pks);
+            assert_eq!(results[0].aggregated_key.x_coor(), cache.X_tilde.x_coor());
+        }
+    }
+
+    #[test]
+    fn transcript_records_message_passing() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, _) = three_party_simulation(&message);
+        simulation.with_transcript().run().unwrap();
+
+        let entry = |party, phase, sender, receiver| (party, phase, sender, receiver);
+        let mut expected = vec![];
+        // Every party proceeds the preparation and broadcasts its nonces
+        for party in 1..=3 {
+            expected.push(entry(party, Phase::Proceed, None, None));
+            expected.push(entry(party, Phase::SendOutgoing, Some(party), None));
+        }
+        // Every party receives the nonces of the others and broadcasts its fragment
+        for party in 1..=3 {
+            for sender in (1..=3).filter(|s| *s != party) {
+                expected.push(entry(party, Phase::HandleIncoming, Some(sender), None));
+            }
+            expected.push(entry(party, Phase::SendOutgoing, Some(party), None));
+        }
+        // Every party receives the fragments of the others and finishes
+        for party in 1..=3 {
+            for sender in (1..=3).filter(|s| *s != party) {
+                expected.push(entry(party, Phase::HandleIncoming, Some(sender), None));
+            }
+        }
+        for party in 1..=3 {
+            expected.push(entry(party, Phase::Finish, None, None));
+        }
+
+        let transcript: Vec<_> = simulation
+            .transcript()
+            .unwrap()
+            .iter()
+            .map(|e: &TranscriptEntry| (e.party, e.phase, e.sender, e.receiver))
+            .collect();
+        assert_eq!(transcript, expected);
+    }
+
+    #[test]
+    fn signature_verifies_from_public_output() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, pks) = three_party_simulation(&message);
+        let result = simulation.run().expect("simulation failed");
+
+        let agg_pubkey = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        result[0].verify_message(&message, &agg_pubkey).unwrap();
+
+        let other = format_musig_msg(Vec::from("other".as_bytes()));
+        assert!(result[0].verify_message(&other, &agg_pubkey).is_err());
+        assert!(result[0].verify_message(&message, &pks[0]).is_err());
+    }
+
+    #[test]
+    fn dry_run_aggregate_matches_simulation() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let (mut simulation, pks) = three_party_simulation(&message);
+        let aggregate = aggregate_public_keys(&pks).unwrap();
+        let result = simulation.run().expect("simulation failed");
+
+        // The session signs for the aggregate lifted to even `y`
+        assert_eq!(result[0].aggregated_key, aggregate);
+        assert!(!aggregate.is_odd_y());
+        assert_eq!(
+            aggregate,
+            KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key()
+        );
+
+        assert_eq!(
+            aggregate_public_keys(&[]),
+            Err(Musig2Error::InvalidInputLength)
+        );
+        let duplicated = vec![pks[0].clone(), pks[1].clone(), pks[0].clone()];
+        assert_eq!(
+            aggregate_public_keys(&duplicated),
+            Err(Musig2Error::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn single_party_signs_under_its_own_aggregate() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let kp = KeyPair::create().unwrap();
+        let pks = vec![kp.public_key.clone()];
+
+        let mut simulation = Simulation::new();
+        simulation.add_party(Musig2Instance::with_fixed_seed(1, 1, message.clone(), kp));
+        let result = simulation.run().expect("simulation failed");
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].signers, vec![1]);
+        let agg_pubkey = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        assert_eq!(result[0].aggregated_key, agg_pubkey);
+        result[0].verify_message(&message, &agg_pubkey).unwrap();
+    }
+
+    #[test]
+    fn round1_messages_are_broadcast_once_per_party() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let rounds = Arc::new(Mutex::new(vec![]));
+        let recorded = rounds.clone();
+
+        let (mut simulation, _) = three_party_simulation(&message);
+        simulation.on_round_messages(move |msgs: &[Msg<ProtocolMessage>]| {
+            let senders: Vec<_> = msgs
+                .iter()
+                .filter(|msg| msg.receiver.is_none())
+                .map(|msg| msg.sender)
+                .collect();
+            recorded.lock().unwrap().push((msgs.len(), senders));
+        });
+        simulation.run().expect("simulation failed");
+
+        let rounds = rounds.lock().unwrap();
+        assert_eq!(rounds[0], (3, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn duplicate_party_index_aborts_simulation() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let mut simulation = Simulation::new();
+        for i in [1, 2, 2].iter() {
+            simulation.add_party(Musig2Instance::with_fixed_seed(
+                *i,
+                3,
+                message.clone(),
+                KeyPair::create().unwrap(),
+            ));
+        }
+        match simulation.run() {
+            Err(SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::DuplicatePartyIndex { party_ind: 2 }),
+                ..
+            }) => (),
+            other => panic!("expected duplicate party index, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_public_key_aborts_simulation() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let shared = KeyPair::create().unwrap();
+        let mut simulation = Simulation::new();
+        simulation
+            .add_party(Musig2Instance::with_fixed_seed(
+                1,
+                3,
+                message.clone(),
+                KeyPair::create().unwrap(),
+            ))
+            .add_party(Musig2Instance::with_fixed_seed(
+                2,
+                3,
+                message.clone(),
+                shared.clone(),
+            ))
+            .add_party(Musig2Instance::with_fixed_seed(3, 3, message, shared));
+        match simulation.run() {
+            Err(SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::DuplicatePublicKey { party_ind }),
+                ..
+            }) => {
+                assert_eq!(party_ind, vec![2, 3])
+            }
+            other => panic!("expected duplicate public key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn benchmark_sink_gets_every_measurement() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let measured = Arc::new(Mutex::new(vec![]));
+        let sink = measured.clone();
+
+        let (mut simulation, _) = three_party_simulation(&message);
+        simulation
+            .enable_benchmarks(true)
+            .with_benchmark_sink(move |round, time| sink.lock().unwrap().push((round, time)));
+        simulation.run().expect("simulation failed");
+
+        let measured = measured.lock().unwrap();
+        let results = simulation.benchmark_results().unwrap();
+        let timed: u16 = results.values().map(|m| m.n + m.non_advancing_n).sum();
+        assert_eq!(measured.len(), usize::from(timed));
+        for (round, m) in results {
+            let streamed: Duration = measured
+                .iter()
+                .filter(|(r, _)| r == round)
+                .map(|(_, time)| *time)
+                .sum();
+            assert_eq!(streamed, m.total_time + m.non_advancing_time);
+        }
+    }
+
+    #[test]
+    fn simulate_precommitted_nonces() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+        let pks: Vec<PublicKey> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        let mut parties: Vec<Musig2Instance> = key_pairs
+            .into_iter()
+            .enumerate()
+            .map(|(i, kp)| Musig2Instance::with_precommitted_nonces(i as u16 + 1, 3, kp))
+            .collect();
+
+        // Round 0: the nonces are exchanged before the message is known
+        let mut commitments = vec![];
+        for party in &mut parties {
+            party.proceed().unwrap();
+            commitments.append(party.message_queue());
+        }
+        for party in &mut parties {
+            for msg in commitments.iter().filter(|m| m.sender != party.party_ind()) {
+                party.handle_incoming(msg.clone()).unwrap();
+            }
+            assert_eq!(party.current_round(), 1);
+            assert!(!party.wants_to_proceed());
+        }
+
+        let mut simulation = Simulation::new();
+        for mut party in parties {
+            party.bind_message(message.clone()).unwrap();
+            assert!(party.bind_message(message.clone()).is_err());
+            simulation.add_party(party);
+        }
+        let result = simulation.run().expect("simulation failed");
+        assert_consensus(&result, &message, &pks);
+    }
+
+    #[test]
+    fn simulation_detects_key_impersonation() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+        let key_pairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::create().unwrap()).collect();
+
+        // Party 2 doesn't report the key agreed in advance
+        let mut signers: Vec<PublicKey> =
+            key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        signers[1] = KeyPair::create().unwrap().public_key;
+
+        let mut simulation = Simulation::new();
+        for (i, kp) in key_pairs.into_iter().enumerate() {
+            simulation.add_party(
+                Musig2Instance::with_fixed_seed(i as u16 + 1, 3, message.clone(), kp)
+                    .pin_signers(signers.clone())
+                    .unwrap(),
+            );
+        }
+        let err = simulation.run().unwrap_err();
+        assert!(matches!(
+            err,
+            SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::KeyImpersonation { party_ind: 2 }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn non_advancing_proceed_is_measured() {
+        let mut state = Stuck { queue: vec![] };
+        let mut benchmark = Benchmark::enabled();
+        Party {
+            state: &mut state,
+            mutator: None,
+            message_size: None,
+        }
+        .proceed_if_needed(&mut benchmark, &mut Log(None))
+        .unwrap();
+
+        let measurements = &benchmark.results().unwrap()[&1];
+        assert_eq!(measurements.n, 0);
+        assert_eq!(measurements.non_advancing_n, 1);
+    }
+
+    #[test]
+    fn simulation_rejects_party_index_out_of_range() {
+        let message = format_musig_msg(Vec::from("test".as_bytes()));
+
+        let mut simulation = Simulation::new();
+        for i in [1, 2, 5].iter() {
+            simulation.add_party(Musig2Instance::with_fixed_seed(
+                *i,
+                3,
+                message.clone(),
+                KeyPair::create().unwrap(),
+            ));
+        }
+        let err = simulation.run().unwrap_err();
+        assert!(matches!(
+            err,
+            SimulationError::Protocol {
+                err: Error::ProceedRound(ProceedError::PartyIndexOutOfRange {
+                    my_ind: 5,
+                    party_n: 3
+                }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_msgs_index_50_parties() {
+        use super::{is_addressed_to, MsgsIndex};
+        use crate::cli::party::traits::state_machine::Msg;
+        use std::time::Instant;
+
+        // Every party broadcasts one message and sends one to every other party
+        let n = 50u16;
+        let mut msgs = vec![];
+        for sender in 1..=n {
+            msgs.push(Msg {
+                sender,
+                receiver: None,
+                body: sender,
+            });
+            for receiver in (1..=n).filter(|r| *r != sender) {
+                msgs.push(Msg {
+                    sender,
+                    receiver: Some(receiver),
+                    body: sender,
+                });
+            }
+        }
+
+        let started = Instant::now();
+        let mut filtered = 0;
+        for party_i in 1..=n {
+            filtered += msgs.iter().filter(|m| is_addressed_to(m, party_i)).count();
+        }
+        let filter_time = started.elapsed();
+
+        let started = Instant::now();
+        let index = MsgsIndex::new(&msgs);
+        let mut indexed = 0;
+        for party_i in 1..=n {
+            indexed += index.for_party(party_i).len();
+        }
+        let index_time = started.elapsed();
+
+        assert_eq!(filtered, indexed);
+        println!("filter: {:?}, index: {:?}", filter_time, index_time);
+    }
 }
@@ -1,6 +1,6 @@
 //! Abstraction of state machine, per party is a state machine
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::time::Duration;
 
 /// State machine of party involved in round-based protocol
@@ -46,6 +46,30 @@ pub trait StateMachine {
     /// Messages can be sent in any order.
     fn message_queue(&mut self) -> &mut Vec<Msg<Self::MessageBody>>;
 
+    /// Drains the [message queue](Self::message_queue) encoding every message to the wire format
+    ///
+    /// Body of every returned message holds [wire bytes](Msg::to_wire_bytes) of the whole queued
+    /// message, sender and receiver are kept aside so a byte-oriented transport can route it
+    /// without decoding. The queue is left untouched if any message fails to encode.
+    fn message_queue_bytes(&mut self) -> serde_json::Result<Vec<Msg<Vec<u8>>>>
+    where
+        Self::MessageBody: Serialize,
+    {
+        let queue = self.message_queue();
+        let encoded = queue
+            .iter()
+            .map(|msg| {
+                Ok(Msg {
+                    sender: msg.sender,
+                    receiver: msg.receiver,
+                    body: msg.to_wire_bytes()?,
+                })
+            })
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        queue.clear();
+        Ok(encoded)
+    }
+
     /// Indicates whether StateMachine wants to perform some expensive computation
     fn wants_to_proceed(&self) -> bool;
 
@@ -85,6 +109,13 @@ pub trait StateMachine {
     /// Reaching timeout always aborts computation, no matter what error is returned: critical or not.
     fn round_timeout_reached(&mut self) -> Self::Err;
 
+    /// Whether proceeding the current round is expensive, so a
+    /// [timeout strategy](crate::cli::party::async_protocol::TimeoutStrategy) may give it a
+    /// longer deadline
+    fn is_round_expensive(&self) -> bool {
+        false
+    }
+
     /// Indicates whether protocol is finished and output can be obtained by calling
     /// [pick_output](Self::pick_output) method.
     fn is_finished(&self) -> bool;
@@ -100,6 +131,84 @@ pub trait StateMachine {
     /// After `Some(_)` has been obtained via this method, StateMachine must be utilized (dropped).
     fn pick_output(&mut self) -> Option<Result<Self::Output, Self::Err>>;
 
+    /// Message broadcast after the protocol is finished with `output`
+    ///
+    /// Lets peers learn that the protocol succeeded (and cross-check the output) so they can
+    /// stop promptly. Returns `None` by default, i.e. nothing is announced.
+    fn completion_message(&self, _output: &Self::Output) -> Option<Self::MessageBody> {
+        None
+    }
+
+    /// Message broadcast after the protocol is aborted with a critical `error`
+    ///
+    /// Lets peers stop promptly instead of waiting for the round timeout. Returns `None` by
+    /// default, i.e. nothing is announced, see
+    /// [set_broadcast_abort](crate::cli::party::async_protocol::AsyncProtocol::set_broadcast_abort).
+    fn abort_message(&self, _error: &Self::Err) -> Option<Self::MessageBody> {
+        None
+    }
+
+    /// Reason carried by `body`, or `None` if `body` isn't an
+    /// [abort message](StateMachine::abort_message)
+    fn abort_reason<'b>(&self, _body: &'b Self::MessageBody) -> Option<&'b str> {
+        None
+    }
+
+    /// Message acknowledging receipt of `msg`, sent back to its sender
+    ///
+    /// Returns `None` by default, i.e. the message isn't acknowledged.
+    fn acknowledgement(&self, _msg: &Msg<Self::MessageBody>) -> Option<Self::MessageBody> {
+        None
+    }
+
+    /// Round whose message is acknowledged by `body`, or `None` if `body` isn't an
+    /// [acknowledgement](StateMachine::acknowledgement)
+    fn acknowledged_round(&self, _body: &Self::MessageBody) -> Option<u16> {
+        None
+    }
+
+    /// Message body carrying `fragment` of an outgoing message over the size limit
+    ///
+    /// Returns `None` by default, i.e. messages can't be fragmented, see
+    /// [set_max_message_size](crate::cli::party::async_protocol::AsyncProtocol::set_max_message_size).
+    fn fragment_message(&self, _fragment: Fragment) -> Option<Self::MessageBody> {
+        None
+    }
+
+    /// Fragment carried by `body`, or `None` if `body` isn't a
+    /// [fragment](StateMachine::fragment_message)
+    fn as_fragment<'b>(&self, _body: &'b Self::MessageBody) -> Option<&'b Fragment> {
+        None
+    }
+
+    /// Whether `error` returned by [handle_incoming](Self::handle_incoming) means the message
+    /// belongs to a later round, so it can be handled once the round changes
+    ///
+    /// Such messages are kept aside rather than dropped, see
+    /// [buffer_early_messages](crate::cli::party::async_protocol::AsyncProtocol::buffer_early_messages).
+    /// Returns `false` by default, i.e. every non-critical error is just reported.
+    fn is_wrong_round(&self, _error: &Self::Err) -> bool {
+        false
+    }
+
+    /// Whether `msg` is still needed to complete the protocol
+    ///
+    /// Messages which aren't needed, e.g. duplicates or messages of a past round, may be
+    /// dropped by a full incoming buffer, see
+    /// [set_incoming_buffer](crate::cli::party::async_protocol::AsyncProtocol::set_incoming_buffer).
+    /// Returns `true` by default, i.e. nothing is ever dropped.
+    fn is_message_needed(&self, _msg: &Msg<Self::MessageBody>) -> bool {
+        true
+    }
+
+    /// Whether the current round still lacks messages required to proceed it
+    ///
+    /// Lets the simulation catch a state machine which [wants to proceed](Self::wants_to_proceed)
+    /// prematurely. Returns `false` by default, i.e. nothing is checked.
+    fn awaits_messages(&self) -> bool {
+        false
+    }
+
     /// Sequential number of current round
     ///
     /// Can be increased by 1 as result of calling either [proceed](StateMachine::proceed) or
@@ -148,6 +257,36 @@ impl<B> Msg<B> {
     }
 }
 
+impl<B: Serialize> Msg<B> {
+    /// Encodes the message as json, e.g. to split it into [fragments](Fragment)
+    ///
+    /// Peer-to-peer transports send the versioned binary frames of the
+    /// [codec](crate::cli::party::codec) instead.
+    pub fn to_wire_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+impl<B: DeserializeOwned> Msg<B> {
+    /// Decodes the message [encoded](Msg::to_wire_bytes) by a peer
+    pub fn from_wire_bytes(bytes: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// Piece of the [wire bytes](Msg::to_wire_bytes) of a message too large to be sent at once
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Fragment {
+    /// Identifies the fragmented message among the ones of the same sender
+    pub id: u64,
+    /// Position of the fragment, lies in range `[0; count)`
+    pub index: u16,
+    /// Number of fragments the message is split into
+    pub count: u16,
+    /// Bytes of the message at this position
+    pub bytes: Vec<u8>,
+}
+
 /// Distinguish a critical error from not critical
 ///
 /// For semantic, see [StateMachine trait](StateMachine) (in particular,
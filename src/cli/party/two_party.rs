@@ -0,0 +1,130 @@
+//! Two-party MuSig2, the common setting of payment channels
+use super::{
+    async_protocol::{self, AsyncProtocol},
+    musig2_instance::{self, Musig2Instance, ProtocolMessage},
+    traits::state_machine::Msg,
+};
+use crate::cli::protocals::{
+    error::Musig2Error,
+    key::PublicKey,
+    musig2::{KeyAgg, KeyPair},
+};
+use futures::{
+    sink::Sink,
+    stream::{FusedStream, Stream},
+};
+
+/// Signs `msg` together with the owner of `peer_key` over the duplex `channel`
+///
+/// `channel` is the pair of the incoming stream from the peer and the outgoing sink to it.
+/// Both sides derive their party index from the canonical key order, so no extra coordination
+/// is needed, and the signer set is pinned to the two keys. The nonces are derived from the
+/// message and the keys hedged with fresh randomness, see
+/// [sign_deterministic_with_aux](crate::cli::protocals::musig2::sign_deterministic_with_aux),
+/// so signing the same message twice never reuses them.
+///
+/// `msg` is the 32-byte message to sign. Returns the 64-byte BIP340 signature `R.x || s`,
+/// valid under the aggregate key of `my_kp.public_key` and `peer_key`.
+pub async fn sign<I, O, IErr>(
+    my_kp: KeyPair,
+    peer_key: PublicKey,
+    msg: &[u8],
+    channel: (I, O),
+) -> Result<[u8; 64], Error<IErr, O::Error>>
+where
+    I: Stream<Item = Result<Msg<ProtocolMessage>, IErr>> + FusedStream + Unpin,
+    O: Sink<Msg<ProtocolMessage>> + Unpin,
+{
+    if msg.len() != 32 {
+        return Err(Error::InvalidInput(Musig2Error::InvalidInputLength));
+    }
+    if my_kp.public_key == peer_key {
+        return Err(Error::InvalidInput(Musig2Error::InvalidPublicKey));
+    }
+
+    let signers = KeyAgg::sort_canonical(&[my_kp.public_key.clone(), peer_key]);
+    let my_ind = if signers[0] == my_kp.public_key { 1 } else { 2 };
+    let instance = Musig2Instance::with_fixed_seed(my_ind, 2, msg.to_vec(), my_kp)
+        .pin_signers(signers)
+        .expect("fresh instance accepts the signer set")
+        .deterministic_nonce_with_aux();
+
+    let (incoming, outgoing) = channel;
+    let result = AsyncProtocol::new(instance, incoming, outgoing)
+        .run()
+        .await
+        .map_err(Error::Protocol)?;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&result.r.x_coor());
+    signature[32..].copy_from_slice(&result.s.serialize());
+    Ok(signature)
+}
+
+/// Error of the two-party signing
+#[derive(Debug)]
+pub enum Error<IErr, OErr> {
+    /// Message isn't 32 bytes long, or the peer key is our own
+    InvalidInput(Musig2Error),
+    /// Executing the protocol failed
+    Protocol(async_protocol::Error<musig2_instance::Error, IErr, OErr>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::node::format_musig_msg;
+    use crate::cli::protocals::{musig2::verify, signature::Signature};
+    use core::convert::TryFrom;
+    use futures::{channel::mpsc, StreamExt};
+    use secp256k1::Message;
+
+    /// Runs both sides of [sign] over an in-memory duplex channel
+    async fn sign_both(alice: KeyPair, bob: KeyPair, msg: &[u8]) -> ([u8; 64], [u8; 64]) {
+        let (to_bob, from_alice) = mpsc::unbounded();
+        let (to_alice, from_bob) = mpsc::unbounded();
+        let (alice_sig, bob_sig) = futures::future::join(
+            sign(
+                alice.clone(),
+                bob.public_key.clone(),
+                msg,
+                (from_bob.map(Ok::<_, ()>), to_bob),
+            ),
+            sign(
+                bob,
+                alice.public_key,
+                msg,
+                (from_alice.map(Ok::<_, ()>), to_alice),
+            ),
+        )
+        .await;
+        (alice_sig.unwrap(), bob_sig.unwrap())
+    }
+
+    #[tokio::test]
+    async fn two_parties_sign_over_duplex_channel() {
+        let alice = KeyPair::create().unwrap();
+        let bob = KeyPair::create().unwrap();
+        let pks = [alice.public_key.clone(), bob.public_key.clone()];
+        let msg = format_musig_msg(Vec::from("channel update".as_bytes()));
+
+        let (alice_sig, bob_sig) = sign_both(alice, bob, &msg).await;
+        assert_eq!(alice_sig[..], bob_sig[..]);
+
+        let x_tilde = KeyAgg::canonical(&pks, &pks[0]).unwrap().x_only_key();
+        let signature = Signature::try_from(alice_sig).unwrap();
+        let message = Message::parse_slice(&msg).unwrap();
+        assert!(verify(&signature, &message, &x_tilde).unwrap());
+    }
+
+    #[tokio::test]
+    async fn signing_twice_uses_fresh_nonces() {
+        let alice = KeyPair::create().unwrap();
+        let bob = KeyPair::create().unwrap();
+        let msg = format_musig_msg(Vec::from("channel update".as_bytes()));
+
+        let (first, _) = sign_both(alice.clone(), bob.clone(), &msg).await;
+        let (second, _) = sign_both(alice, bob, &msg).await;
+        assert_ne!(first[..32], second[..32]);
+    }
+}
@@ -1,6 +1,13 @@
 //! Mechanism for tracking protocol execution
 
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    io::Write,
+};
+
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
 
 use crate::cli::party::traits::state_machine::StateMachine;
 
@@ -13,15 +20,71 @@ use crate::cli::party::traits::state_machine::StateMachine;
 pub trait ProtocolWatcher<SM: StateMachine> {
     /// StateMachine produced a not critical error. Execution continues.
     fn caught_non_critical_error(&mut self, when: When, err: SM::Err);
+
+    /// Broadcast message sent in the `round` reached no receivers, i.e. no one is listening.
+    ///
+    /// Only reported when [delivery confirmation](super::async_protocol::AsyncProtocol::confirm_delivery) is
+    /// enabled.
+    fn broadcast_not_delivered(&mut self, _round: u16) {}
+
+    /// Message of `sender` numbered `received` arrived while `expected` was expected next
+    ///
+    /// Only reported when [sequence tracking](super::async_protocol::AsyncProtocol::track_sequence)
+    /// is enabled.
+    fn sequence_gap(&mut self, _sender: u16, _expected: u64, _received: u64) {}
+
+    /// Protocol advanced from round `from` to round `to`, `from` is `None` for the initial round
+    fn round_transition(&mut self, _from: Option<u16>, _to: u16) {}
 }
 
 /// Claims at which stage event occurred
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub enum When {
     HandleIncoming,
     Proceed,
 }
 
+/// Non critical error caught during protocol execution, see
+/// [run_verbose](super::async_protocol::AsyncProtocol::run_verbose)
+#[derive(Debug, Clone)]
+pub struct NonCriticalEvent<E> {
+    pub when: When,
+    pub error: E,
+}
+
+/// Watcher that collects non critical errors and passes every event on to the `inner` watcher
+pub(crate) struct CollectingWatcher<W, E> {
+    pub inner: W,
+    pub events: Vec<NonCriticalEvent<E>>,
+}
+
+impl<SM, W> ProtocolWatcher<SM> for CollectingWatcher<W, SM::Err>
+where
+    SM: StateMachine,
+    SM::Err: Clone,
+    W: ProtocolWatcher<SM>,
+{
+    fn caught_non_critical_error(&mut self, when: When, err: SM::Err) {
+        self.events.push(NonCriticalEvent {
+            when,
+            error: err.clone(),
+        });
+        self.inner.caught_non_critical_error(when, err)
+    }
+
+    fn broadcast_not_delivered(&mut self, round: u16) {
+        self.inner.broadcast_not_delivered(round)
+    }
+
+    fn sequence_gap(&mut self, sender: u16, expected: u64, received: u64) {
+        self.inner.sequence_gap(sender, expected, received)
+    }
+
+    fn round_transition(&mut self, from: Option<u16>, to: u16) {
+        self.inner.round_transition(from, to)
+    }
+}
+
 /// Watcher that doesn't do anything when event happens
 pub struct BlindWatcher;
 
@@ -43,4 +106,130 @@ where
     fn caught_non_critical_error(&mut self, when: When, err: SM::Err) {
         eprintln!("Caught non critical error at {:?}: {:?}", when, err);
     }
+
+    fn broadcast_not_delivered(&mut self, round: u16) {
+        eprintln!("Broadcast message of round {} reached no receivers", round);
+    }
+
+    fn sequence_gap(&mut self, sender: u16, expected: u64, received: u64) {
+        eprintln!(
+            "Message of party {} is out of sequence: expected {}, received {}",
+            sender, expected, received
+        );
+    }
+
+    fn round_transition(&mut self, from: Option<u16>, to: u16) {
+        match from {
+            Some(from) => eprintln!("Protocol advanced from round {} to round {}", from, to),
+            None => eprintln!("Protocol started in round {}", to),
+        }
+    }
+}
+
+/// Watcher that writes every event to `writer` as a line of JSON, for machine-readable logs
+///
+/// Every line is an object with the `event` name and its fields, e.g.
+/// `{"event":"caught_non_critical_error","when":"HandleIncoming","error":"..."}`. The error is
+/// written with its [Display] implementation, so the error type of the state machine must
+/// implement it. Failing to write an event is logged and otherwise ignored.
+pub struct JsonWatcher<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonWatcher<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Takes back the writer, e.g. a buffer the events were captured into
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn emit(&mut self, event: serde_json::Value) {
+        let result = serde_json::to_writer(&mut self.writer, &event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(err) = result {
+            warn!("couldn't write watcher event: {}", err);
+        }
+    }
+}
+
+impl<SM, W> ProtocolWatcher<SM> for JsonWatcher<W>
+where
+    SM: StateMachine,
+    SM::Err: Display,
+    W: Write,
+{
+    fn caught_non_critical_error(&mut self, when: When, err: SM::Err) {
+        self.emit(json!({
+            "event": "caught_non_critical_error",
+            "when": when,
+            "error": err.to_string(),
+        }));
+    }
+
+    fn broadcast_not_delivered(&mut self, round: u16) {
+        self.emit(json!({
+            "event": "broadcast_not_delivered",
+            "round": round,
+        }));
+    }
+
+    fn sequence_gap(&mut self, sender: u16, expected: u64, received: u64) {
+        self.emit(json!({
+            "event": "sequence_gap",
+            "sender": sender,
+            "expected": expected,
+            "received": received,
+        }));
+    }
+
+    fn round_transition(&mut self, from: Option<u16>, to: u16) {
+        self.emit(json!({
+            "event": "round_transition",
+            "from": from,
+            "to": to,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonWatcher, ProtocolWatcher, When};
+    use crate::cli::party::{
+        musig2_instance::{Error, Musig2Instance},
+        store_err::StoreErr,
+    };
+
+    #[test]
+    fn json_watcher_writes_one_object_per_line() {
+        let mut watcher = JsonWatcher::new(Vec::new());
+        ProtocolWatcher::<Musig2Instance>::caught_non_critical_error(
+            &mut watcher,
+            When::HandleIncoming,
+            Error::HandleMsg(StoreErr::MsgOverwrite),
+        );
+        ProtocolWatcher::<Musig2Instance>::broadcast_not_delivered(&mut watcher, 1);
+        ProtocolWatcher::<Musig2Instance>::sequence_gap(&mut watcher, 2, 3, 5);
+
+        let output = String::from_utf8(watcher.into_inner()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "caught_non_critical_error");
+        assert_eq!(events[0]["when"], "HandleIncoming");
+        assert_eq!(
+            events[0]["error"],
+            "handle message: got message which was already received"
+        );
+        assert_eq!(events[1]["event"], "broadcast_not_delivered");
+        assert_eq!(events[1]["round"], 1);
+        assert_eq!(events[2]["event"], "sequence_gap");
+        assert_eq!(events[2]["sender"], 2);
+        assert_eq!(events[2]["received"], 5);
+    }
 }
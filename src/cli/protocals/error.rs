@@ -12,11 +12,17 @@ pub enum Musig2Error {
     InvalidMessage,
     InvalidInputLength,
     TweakOutOfRange,
+    /// Scalar is not canonical, i.e. not below the curve order
+    ScalarOutOfRange,
+    /// Signature has zero `s` or its `R` is the point at infinity
+    DegenerateSignature,
 
     InvalidHexCharacter,
     InvalidStringLength,
     OddLength,
     XCoordinateNotExist,
+    /// X coordinate isn't below the field size
+    XCoordinateOutOfRange,
 }
 
 impl From<secp256k1::Error> for Musig2Error {
@@ -12,17 +12,73 @@ use secp256k1::{
 };
 
 use super::error::Musig2Error;
+use core::cmp::Ordering;
 use core::convert::TryFrom;
 use core::ops::Neg;
 use light_bitcoin_schnorr::{taggedhash::HashInto, xonly::XOnly};
 use rand_core::{OsRng, RngCore};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, str::FromStr};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::Zeroize;
 
+/// Compared by coordinates, which [Field] compares normalized, so the same point is equal
+/// whatever computation gave it, see also [PublicKey::ct_eq]
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PublicKey(pub Affine);
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Compared in constant time, see [PrivateKey::ct_eq]
+#[derive(Debug, Clone)]
 pub struct PrivateKey(pub Scalar);
 
+/// Compares the normalized encodings in constant time
+///
+/// Only whether a key is the point at infinity is compared in variable time.
+impl ConstantTimeEq for PublicKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        match (self.0.is_infinity(), other.0.is_infinity()) {
+            (false, false) => self.serialize()[..].ct_eq(&other.serialize()[..]),
+            (infinity, other_infinity) => Choice::from(u8::from(infinity == other_infinity)),
+        }
+    }
+}
+
+/// Compares the encodings in constant time, so comparing secrets doesn't leak timing
+impl ConstantTimeEq for PrivateKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        let (mut bytes, mut other_bytes) = (self.serialize(), other.serialize());
+        let eq = bytes[..].ct_eq(&other_bytes[..]);
+        bytes.zeroize();
+        other_bytes.zeroize();
+        eq
+    }
+}
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+impl Eq for PrivateKey {}
+
+/// Overwrites the secret scalar with zeros
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// The secret scalar is [zeroized](Zeroize) once the key goes out of scope
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 /// Public key multiplication and addition calculations
 impl PublicKey {
     pub fn add_point(&self, rhs: &Self) -> Result<PublicKey, Musig2Error> {
@@ -49,6 +105,57 @@ impl PublicKey {
 
         Ok(PublicKey(Affine::from_gej(&r)))
     }
+
+    /// Compute `sum(coeffs[i] * keys[i])`
+    ///
+    /// Every product is a separate scalar multiplication, it isn't a multi-scalar
+    /// multiplication. Fails on mismatched lengths, a zero coefficient or a sum at infinity.
+    pub fn sum_with_coefficients(
+        keys: &[PublicKey],
+        coeffs: &[PrivateKey],
+    ) -> Result<PublicKey, Musig2Error> {
+        if keys.is_empty() || keys.len() != coeffs.len() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        let zero = Scalar::from_int(0);
+        let mut sum = Jacobian::default();
+        sum.set_infinity();
+        for (key, coeff) in keys.iter().zip(coeffs) {
+            if coeff.0.is_zero() {
+                return Err(Musig2Error::InvalidPrivateKey);
+            }
+            let mut r = Jacobian::default();
+            let pt = Jacobian::from_ge(&key.0);
+            ECMULT_CONTEXT.ecmult(&mut r, &pt, &coeff.0, &zero);
+            sum = sum.add_var(&r, None);
+        }
+
+        if sum.is_infinity() {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        Ok(PublicKey(Affine::from_gej(&sum)))
+    }
+
+    /// Compute the sum of the `points`
+    ///
+    /// Fails for no points or a sum at infinity.
+    pub fn aggregate(points: &[PublicKey]) -> Result<PublicKey, Musig2Error> {
+        let mut sum = Jacobian::default();
+        sum.set_infinity();
+        for point in points {
+            sum = sum.add_ge_var(&point.0, None);
+        }
+
+        if sum.is_infinity() {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        Ok(PublicKey(Affine::from_gej(&sum)))
+    }
+
+    /// Compute `self + tweak*G`, e.g. a BIP341 taproot output key
+    pub fn add_tweak(&self, tweak: &PrivateKey) -> Result<PublicKey, Musig2Error> {
+        self.add_point(&PublicKey::create_from_private_key(tweak))
+    }
 }
 
 /// Secret key multiplication and addition calculations
@@ -61,6 +168,11 @@ impl PrivateKey {
         Ok(PrivateKey(v))
     }
 
+    /// Compute `self + tweak`, the private key of [PublicKey::add_tweak]
+    pub fn add_tweak(&self, tweak: &PrivateKey) -> Result<PrivateKey, Musig2Error> {
+        self.add_scalar(tweak)
+    }
+
     pub fn mul_scalar(&self, rhs: &Self) -> Result<Self, Musig2Error> {
         let v = &self.0 * &rhs.0;
         if v.is_zero() {
@@ -82,6 +194,20 @@ impl PrivateKey {
     }
 }
 
+/// Orders public keys by their compressed serialization, lexicographically
+impl Ord for PublicKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.serialize_compressed()
+            .cmp(&other.serialize_compressed())
+    }
+}
+
+impl PartialOrd for PublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl From<Affine> for PublicKey {
     fn from(p: Affine) -> Self {
         PublicKey(p)
@@ -102,7 +228,8 @@ impl From<Scalar> for PrivateKey {
 
 impl From<PrivateKey> for Scalar {
     fn from(s: PrivateKey) -> Self {
-        s.0
+        // The key wipes its own copy when dropped
+        s.0.clone()
     }
 }
 impl From<PublicKey> for XOnly {
@@ -140,9 +267,123 @@ impl TryFrom<&str> for PublicKey {
     }
 }
 
+/// Compressed hex form, see [PublicKey::serialize_compressed]
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.serialize_compressed()))
+    }
+}
+
+/// Parses the compressed hex form, or the x-only hex form like `TryFrom<&str>` does
+impl FromStr for PublicKey {
+    type Err = Musig2Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)?;
+        match bytes.len() {
+            33 => PublicKey::parse_compressed(array_ref!(bytes, 0, 33)),
+            32 => PublicKey::try_from(s),
+            _ => Err(Musig2Error::InvalidStringLength),
+        }
+    }
+}
+
+/// Encoded as the 33-byte compressed form, hex in human-readable formats
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = self.serialize_compressed();
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(bytes))
+        } else {
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = deserialize_key_bytes(deserializer, 33)?;
+        PublicKey::parse_compressed(array_ref!(bytes, 0, 33))
+            .map_err(|e| de::Error::custom(format!("invalid public key: {:?}", e)))
+    }
+}
+
+/// Encoded as the 32-byte scalar, hex in human-readable formats
+impl Serialize for PrivateKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = self.serialize();
+        let result = if serializer.is_human_readable() {
+            let mut hex = hex::encode(bytes);
+            let result = serializer.serialize_str(&hex);
+            hex.zeroize();
+            result
+        } else {
+            serializer.serialize_bytes(&bytes)
+        };
+        bytes.zeroize();
+        result
+    }
+}
+
+impl<'de> Deserialize<'de> for PrivateKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut bytes = deserialize_key_bytes(deserializer, 32)?;
+        let key = PrivateKey::parse_slice(&bytes);
+        bytes.zeroize();
+        key.map_err(|e| de::Error::custom(format!("invalid private key: {:?}", e)))
+    }
+}
+
+/// Reads `len` bytes of a key, hex encoded in human-readable formats
+fn deserialize_key_bytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    len: usize,
+) -> Result<Vec<u8>, D::Error> {
+    let bytes = if deserializer.is_human_readable() {
+        deserializer.deserialize_str(KeyBytesVisitor(len))?
+    } else {
+        deserializer.deserialize_bytes(KeyBytesVisitor(len))?
+    };
+    if bytes.len() != len {
+        return Err(de::Error::invalid_length(
+            bytes.len(),
+            &KeyBytesVisitor(len),
+        ));
+    }
+    Ok(bytes)
+}
+
+struct KeyBytesVisitor(usize);
+
+impl<'de> Visitor<'de> for KeyBytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes or their hex encoding", self.0)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        hex::decode(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(self.0);
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(bytes)
+    }
+}
+
 impl HashInto for PrivateKey {
     fn hash_into(&self, hash: &mut impl digest::Digest) {
-        hash.update(self.0.b32())
+        let mut bytes = self.0.b32();
+        hash.update(bytes);
+        bytes.zeroize();
     }
 }
 
@@ -198,6 +439,15 @@ impl PublicKey {
         y.is_odd()
     }
 
+    /// Base58Check encoding of `version || compressed key`, e.g. to display an aggregate key
+    /// Bitcoin-style
+    #[cfg(feature = "base58")]
+    pub fn to_base58check(&self, version: u8) -> String {
+        bs58::encode(self.serialize_compressed())
+            .with_check_version(version)
+            .into_string()
+    }
+
     pub fn create_from_private_key(s: &PrivateKey) -> PublicKey {
         let mut pj = Jacobian::default();
         ECMULT_GEN_CONTEXT.ecmult_gen(&mut pj, &s.0);
@@ -206,24 +456,60 @@ impl PublicKey {
         PublicKey(p)
     }
 
+    /// Compute `sk * base` for a base point other than the standard generator, e.g. a NUMS
+    /// point of a commitment scheme
+    pub fn from_private_with_base(
+        sk: &PrivateKey,
+        base: &PublicKey,
+    ) -> Result<PublicKey, Musig2Error> {
+        if base.0.is_infinity() {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        base.mul_scalar(sk)
+    }
+
     pub fn neg(&self) -> PublicKey {
         // let p: Affine = self.0.clone();
         // let p = p.neg();
         PublicKey(self.0.neg())
     }
 
-    pub fn parse(p: &[u8; 65]) -> Result<Self, Musig2Error> {
+    /// Inverse of [serialize_compressed](PublicKey::serialize_compressed), the prefix selects
+    /// the parity of `y`
+    pub fn parse_compressed(p: &[u8; 33]) -> Result<Self, Musig2Error> {
+        let odd = match p[0] {
+            TAG_PUBKEY_EVEN => false,
+            TAG_PUBKEY_ODD => true,
+            _ => return Err(Musig2Error::InvalidPublicKey),
+        };
         let mut x = Field::default();
-        let mut y = Field::default();
-        if !x.set_b32(array_ref!(p, 1, 32)) {
-            return Err(Musig2Error::InvalidPublicKey);
+        let mut elem = Affine::default();
+        if x.set_b32(array_ref!(p, 1, 32)) && elem.set_xo_var(&x, odd) {
+            Ok(PublicKey(elem))
+        } else {
+            Err(Musig2Error::InvalidPublicKey)
         }
+    }
+
+    pub fn parse(p: &[u8; 65]) -> Result<Self, Musig2Error> {
+        Self::parse_xy(array_ref!(p, 1, 32), array_ref!(p, 33, 32))
+    }
+
+    /// Parse the raw `x || y` form without the prefix byte of [`PublicKey::serialize`], as
+    /// used by Ethereum tooling
+    pub fn parse_raw_xy(p: &[u8; 64]) -> Result<Self, Musig2Error> {
+        Self::parse_xy(array_ref!(p, 0, 32), array_ref!(p, 32, 32))
+    }
 
-        if !y.set_b32(array_ref!(p, 33, 32)) {
+    /// Point with the big-endian coordinates `x` and `y`, which must be on the curve
+    fn parse_xy(x: &[u8; 32], y: &[u8; 32]) -> Result<Self, Musig2Error> {
+        let mut fx = Field::default();
+        let mut fy = Field::default();
+        if !fx.set_b32(x) || !fy.set_b32(y) {
             return Err(Musig2Error::InvalidPublicKey);
         }
         let mut elem = Affine::default();
-        elem.set_xy(&x, &y);
+        elem.set_xy(&fx, &fy);
 
         if elem.is_infinity() {
             return Err(Musig2Error::InvalidPublicKey);
@@ -237,16 +523,24 @@ impl PublicKey {
 
     /// Convert [`x_coor`] to [`PublicKey`]
     ///
-    /// Recover the public key from the x coordinate in the schnorr signature;
-    /// Reference ift_x(x): [BIP340]: https://github.com/bitcoin/bips/blob/master/bip-0340.mediawiki
+    /// Recover the public key from the x coordinate in the schnorr signature, following
+    /// `lift_x(x)` of [BIP340](https://github.com/bitcoin/bips/blob/master/bip-0340.mediawiki):
+    /// the point has even `y`. Fails with [`Musig2Error::XCoordinateOutOfRange`] if `x` isn't
+    /// below the field size `p`, and with [`Musig2Error::XCoordinateNotExist`] if there's no
+    /// point with such `x`.
     pub fn parse_x_coor(x: &[u8; 32]) -> Result<Self, Musig2Error> {
         let mut elem = Field::default();
+        if !elem.set_b32(x) {
+            return Err(Musig2Error::XCoordinateOutOfRange);
+        }
         let mut affine = Affine::default();
-        if elem.set_b32(x) && affine.set_xo_var(&elem, false) {
-            Ok(Self(affine))
-        } else {
-            Err(Musig2Error::XCoordinateNotExist)
+        if !affine.set_xo_var(&elem, false) {
+            return Err(Musig2Error::XCoordinateNotExist);
         }
+        affine.x.normalize();
+        affine.y.normalize();
+        debug_assert!(!affine.y.is_odd());
+        Ok(Self(affine))
     }
 
     pub fn convert_from_vec(v: Vec<Vec<u8>>) -> Vec<Self> {
@@ -293,16 +587,276 @@ impl PrivateKey {
         }
         let mut k = [0u8; 32];
         k.copy_from_slice(s);
-        Self::parse(&k)
+        let key = Self::parse(&k);
+        k.zeroize();
+        key
     }
 
     pub fn neg(&self) -> Self {
         PrivateKey(self.0.clone().neg())
     }
 
+    /// Draw a key from `OsRng`, redrawing until it's a non-zero scalar below the curve order
     pub fn generate_random() -> Result<Self, Musig2Error> {
         let mut key: [u8; 32] = [0u8; 32];
-        OsRng.fill_bytes(&mut key);
-        Self::parse(&key)
+        loop {
+            OsRng.fill_bytes(&mut key);
+            match Self::parse(&key) {
+                Ok(private_key) if !private_key.0.is_zero() => {
+                    key.zeroize();
+                    return Ok(private_key);
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_x_coor_follows_bip340_lift_x() {
+        let lift_x = |x: &str| {
+            let mut bytes = [0u8; 32];
+            bytes.copy_from_slice(&hex::decode(x).unwrap());
+            PublicKey::parse_x_coor(&bytes)
+        };
+
+        // Generator, its `y` is even
+        let g = lift_x("79BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798").unwrap();
+        assert_eq!(
+            g,
+            PublicKey::create_from_private_key(&PrivateKey(Scalar::from_int(1)))
+        );
+        // Keys of the BIP340 test vectors 0 and 1
+        for x in &[
+            "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        ] {
+            let pk = lift_x(x).unwrap();
+            assert!(!pk.is_odd_y());
+            assert_eq!(hex::encode_upper(pk.x_coor()), *x);
+        }
+
+        // Test vector 5, not on the curve
+        assert_eq!(
+            lift_x("EEFDEA4CDB677750A420FEE807EACF21EB9898AE79B9768766E4FAA04A2D4A34"),
+            Err(Musig2Error::XCoordinateNotExist)
+        );
+        // Test vector 14, exceeds the field size
+        assert_eq!(
+            lift_x("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30"),
+            Err(Musig2Error::XCoordinateOutOfRange)
+        );
+        // Field size `p` itself
+        assert_eq!(
+            lift_x("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F"),
+            Err(Musig2Error::XCoordinateOutOfRange)
+        );
+    }
+
+    #[test]
+    fn same_point_is_equal_whatever_computation_gave_it() {
+        let k = PrivateKey::generate_random().unwrap();
+        let one = PrivateKey(Scalar::from_int(1));
+        let direct = PublicKey::create_from_private_key(&k);
+        // `(k - 1)*G + G`, summed in jacobian coordinates
+        let summed = PublicKey::create_from_private_key(&k.add_scalar(&one.neg()).unwrap())
+            .add_point(&PublicKey::create_from_private_key(&one))
+            .unwrap();
+
+        assert!(bool::from(direct.ct_eq(&summed)));
+        assert_eq!(direct, summed);
+        assert!(!bool::from(direct.ct_eq(&direct.neg())));
+
+        let same = PrivateKey::parse(&k.serialize()).unwrap();
+        assert!(bool::from(k.ct_eq(&same)));
+        assert!(!bool::from(k.ct_eq(&k.neg())));
+        assert_eq!(k, same);
+    }
+
+    #[test]
+    fn test_sum_with_coefficients() {
+        let keys: Vec<PublicKey> = (0..4)
+            .map(|_| PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap()))
+            .collect();
+        let coeffs: Vec<PrivateKey> = (0..4)
+            .map(|_| PrivateKey::generate_random().unwrap())
+            .collect();
+
+        let naive = keys
+            .iter()
+            .zip(&coeffs)
+            .map(|(k, a)| k.mul_scalar(a).unwrap())
+            .fold(None, |acc: Option<PublicKey>, p| match acc {
+                None => Some(p),
+                Some(acc) => Some(acc.add_point(&p).unwrap()),
+            })
+            .unwrap();
+
+        assert_eq!(
+            PublicKey::sum_with_coefficients(&keys, &coeffs).unwrap(),
+            naive
+        );
+        assert_eq!(
+            PublicKey::sum_with_coefficients(&keys, &coeffs[..3]),
+            Err(Musig2Error::InvalidInputLength)
+        );
+    }
+
+    #[test]
+    fn test_private_key_is_cleared_on_drop() {
+        let mut key = PrivateKey::parse(&[7u8; 32]).unwrap();
+        let scalar: Scalar = key.clone().into();
+        assert!(!scalar.is_zero(), "moving the scalar out still works");
+
+        // Dropping the key runs the same zeroization
+        key.zeroize();
+        assert!(key.0.is_zero());
+    }
+
+    #[test]
+    fn test_from_private_with_base() {
+        let generator = PublicKey::create_from_private_key(&PrivateKey(Scalar::from_int(1)));
+        let sk = PrivateKey::generate_random().unwrap();
+        assert_eq!(
+            PublicKey::from_private_with_base(&sk, &generator).unwrap(),
+            PublicKey::create_from_private_key(&sk)
+        );
+        assert_eq!(
+            PublicKey::from_private_with_base(&PrivateKey(Scalar::from_int(0)), &generator),
+            Err(Musig2Error::InvalidPrivateKey)
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_xy_round_trip() {
+        let pk = PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap());
+        let mut raw = [0u8; 64];
+        raw.copy_from_slice(&pk.serialize()[1..]);
+        assert_eq!(PublicKey::parse_raw_xy(&raw).unwrap(), pk);
+
+        // Off the curve
+        raw[63] ^= 1;
+        assert_eq!(
+            PublicKey::parse_raw_xy(&raw),
+            Err(Musig2Error::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let points: Vec<PublicKey> = (0..3)
+            .map(|_| PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap()))
+            .collect();
+        let (a, b, c) = (&points[0], &points[1], &points[2]);
+        assert_eq!(
+            PublicKey::aggregate(&points).unwrap(),
+            a.add_point(b).unwrap().add_point(c).unwrap()
+        );
+        assert_eq!(
+            PublicKey::aggregate(&[]),
+            Err(Musig2Error::InvalidPublicKey)
+        );
+        assert_eq!(
+            PublicKey::aggregate(&[a.clone(), a.neg()]),
+            Err(Musig2Error::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn test_add_tweak() {
+        let sk = PrivateKey::generate_random().unwrap();
+        let pk = PublicKey::create_from_private_key(&sk);
+        let tweak = PrivateKey::generate_random().unwrap();
+        assert_eq!(
+            pk.add_tweak(&tweak).unwrap(),
+            PublicKey::create_from_private_key(&sk.add_tweak(&tweak).unwrap())
+        );
+        // Tweaking by the negated private key yields the point at infinity
+        assert_eq!(pk.add_tweak(&sk.neg()), Err(Musig2Error::InvalidPublicKey));
+    }
+
+    #[test]
+    fn test_keys_serde_round_trip() {
+        let sk = PrivateKey::generate_random().unwrap();
+        let pk = PublicKey::create_from_private_key(&sk);
+
+        let json = serde_json::to_string(&pk).unwrap();
+        assert_eq!(
+            json,
+            format!("\"{}\"", hex::encode(pk.serialize_compressed()))
+        );
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), pk);
+        let json = serde_json::to_string(&sk).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex::encode(sk.serialize())));
+        assert_eq!(serde_json::from_str::<PrivateKey>(&json).unwrap(), sk);
+
+        let bad_prefix = format!("\"05{}\"", hex::encode(pk.x_coor()));
+        assert!(serde_json::from_str::<PublicKey>(&bad_prefix).is_err());
+        let overflow = format!("\"{}\"", hex::encode([0xffu8; 32]));
+        assert!(serde_json::from_str::<PrivateKey>(&overflow).is_err());
+        let short = format!("\"{}\"", hex::encode([1u8; 31]));
+        assert!(serde_json::from_str::<PrivateKey>(&short).is_err());
+    }
+
+    #[test]
+    fn test_parse_compressed_round_trip() {
+        for _ in 0..16 {
+            let pk = PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap());
+            let parsed = PublicKey::parse_compressed(&pk.serialize_compressed()).unwrap();
+            assert_eq!(parsed, pk);
+            assert_eq!(parsed.is_odd_y(), pk.is_odd_y());
+        }
+
+        let mut bad = PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap())
+            .serialize_compressed();
+        bad[0] = TAG_PUBKEY_FULL;
+        assert_eq!(
+            PublicKey::parse_compressed(&bad),
+            Err(Musig2Error::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn test_display_from_str_round_trip() {
+        for _ in 0..16 {
+            let pk = PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap());
+            let shown = pk.to_string();
+            assert_eq!(shown, hex::encode(pk.serialize_compressed()));
+            assert_eq!(shown.parse::<PublicKey>().unwrap(), pk);
+        }
+
+        let pk = PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap());
+        let x_only = hex::encode(pk.x_coor());
+        assert_eq!(
+            x_only.parse::<PublicKey>().unwrap(),
+            PublicKey::try_from(x_only.as_str()).unwrap()
+        );
+        assert_eq!(
+            hex::encode([2u8; 31]).parse::<PublicKey>(),
+            Err(Musig2Error::InvalidStringLength)
+        );
+    }
+
+    #[test]
+    fn test_public_key_order() {
+        let mut keys: Vec<PublicKey> = (0..8)
+            .map(|_| PublicKey::create_from_private_key(&PrivateKey::generate_random().unwrap()))
+            .collect();
+        let mut hexes: Vec<String> = keys
+            .iter()
+            .map(|pk| hex::encode(pk.serialize_compressed()))
+            .collect();
+
+        keys.sort();
+        hexes.sort();
+        let sorted: Vec<String> = keys
+            .iter()
+            .map(|pk| hex::encode(pk.serialize_compressed()))
+            .collect();
+        assert_eq!(sorted, hexes);
     }
 }
@@ -18,14 +18,18 @@
 #![allow(non_snake_case)]
 
 use super::error::{Musig2Error, Musig2Error::Invalid};
-use core::ops::Neg;
+use core::{convert::TryFrom, ops::Neg};
 use secp256k1::{
     curve::{Affine, Jacobian, Scalar, ECMULT_CONTEXT},
     Message,
 };
+use std::collections::VecDeque;
+use zeroize::Zeroize;
 
 use digest::Digest;
 use light_bitcoin_schnorr::taggedhash::*;
+use rand_core::{CryptoRng, RngCore};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 
 use super::key::{PrivateKey, PublicKey};
 use crate::cli::protocals::signature::Signature;
@@ -33,6 +37,9 @@ use crate::cli::protocals::signature::Signature;
 #[allow(non_upper_case_globals)]
 const Nv: usize = 2;
 
+/// Number of nonces every signer contributes to a session
+pub const NONCE_COUNT: usize = Nv;
+
 #[derive(Debug, Clone)]
 pub struct KeyPair {
     pub public_key: PublicKey,
@@ -41,13 +48,19 @@ pub struct KeyPair {
 
 impl KeyPair {
     pub fn create() -> Result<KeyPair, Musig2Error> {
-        let private_key = PrivateKey::generate_random()?;
+        Ok(Self::generate())
+    }
+
+    /// Generate a fresh key pair, always valid as unusable scalars are redrawn
+    pub fn generate() -> KeyPair {
+        let private_key =
+            PrivateKey::generate_random().expect("generation redraws until the key is valid");
         let public_key = PublicKey::create_from_private_key(&private_key);
 
-        Ok(KeyPair {
+        KeyPair {
             public_key,
             private_key,
-        })
+        }
     }
 
     #[allow(dead_code)]
@@ -59,12 +72,78 @@ impl KeyPair {
             private_key,
         })
     }
+
+    /// Generate `count` nonces ahead of the sessions they will be used in, e.g. during idle time
+    ///
+    /// The nonces are drawn from `rng` like by [sign_random].
+    pub fn pregenerate_nonces<R: RngCore + CryptoRng>(
+        &self,
+        count: usize,
+        rng: &mut R,
+    ) -> Result<NoncePool, Musig2Error> {
+        let nonces = (0..count)
+            .map(|_| {
+                let (nonce, state) = sign_random(self.clone(), rng)?;
+                Ok(PregeneratedNonce { nonce, state })
+            })
+            .collect::<Result<VecDeque<_>, Musig2Error>>()?;
+        Ok(NoncePool { nonces })
+    }
+}
+
+/// Pool of nonces generated by [KeyPair::pregenerate_nonces]
+///
+/// Neither the pool nor its nonces can be cloned, so every nonce is handed out and used once.
+#[derive(Debug)]
+pub struct NoncePool {
+    nonces: VecDeque<PregeneratedNonce>,
+}
+
+impl NoncePool {
+    /// Takes the next nonce out of the pool, `None` once the pool is exhausted
+    pub fn take(&mut self) -> Option<PregeneratedNonce> {
+        self.nonces.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nonces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nonces.is_empty()
+    }
+}
+
+/// Nonce taken out of a [NoncePool], consumed by the session it's used in
+#[derive(Debug)]
+pub struct PregeneratedNonce {
+    nonce: Vec<PublicKey>,
+    state: State,
+}
+
+impl PregeneratedNonce {
+    /// Public nonces to be broadcast
+    pub fn nonce(&self) -> &[PublicKey] {
+        &self.nonce
+    }
+
+    /// Key pair the nonce was generated for
+    pub fn public_key(&self) -> &PublicKey {
+        &self.state.keypair.public_key
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<PublicKey>, State) {
+        (self.nonce, self.state)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct KeyAgg {
     pub X_tilde: PublicKey,
     pub a_i: PrivateKey,
+    /// Aggregation coefficients of all signers, in signer order
+    pub coefficients: Vec<PrivateKey>,
+    pub party_index: usize,
 }
 
 impl KeyAgg {
@@ -98,26 +177,335 @@ impl KeyAgg {
             })
             .collect();
 
-        let X_tilde_vec: Vec<PublicKey> = pks
+        let sum = PublicKey::sum_with_coefficients(pks, &hash_vec)?;
+
+        Ok(KeyAgg {
+            X_tilde: sum,
+            a_i: hash_vec[party_index].clone(),
+            coefficients: hash_vec,
+            party_index,
+        })
+    }
+
+    /// Aggregation coefficient `a_i = H(L, X_i)` of the signer at `index`
+    ///
+    /// Lets an auditor check `X_tilde == sum(a_i * X_i)` without trusting the aggregation.
+    pub fn coefficient_for(&self, index: usize) -> Option<&PrivateKey> {
+        self.coefficients.get(index)
+    }
+
+    /// Aggregate the keys of a fixed signer set once, to be reused by every session it signs
+    ///
+    /// The keys are taken in canonical order, like [`KeyAgg::canonical`] does.
+    pub fn precompute(pks: &[PublicKey]) -> Result<KeyAggCache, Musig2Error> {
+        if pks.is_empty() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        let pks = Self::sort_canonical(pks);
+        let key_agg = Self::key_aggregation_n(&pks, 0)?;
+        Ok(KeyAggCache {
+            pks,
+            X_tilde: key_agg.X_tilde,
+            coefficients: key_agg.coefficients,
+        })
+    }
+
+    /// Sort the keys by compressed encoding, the canonical signer order
+    pub fn sort_canonical(pks: &[PublicKey]) -> Vec<PublicKey> {
+        let mut sorted = pks.to_vec();
+        sorted.sort();
+        sorted
+    }
+
+    /// Aggregate the keys in canonical order
+    ///
+    /// Every party derives the same `X_tilde` regardless of the order it received the keys in.
+    /// The party index of the result is the position of `my_key` in the canonical order.
+    pub fn canonical(pks: &[PublicKey], my_key: &PublicKey) -> Result<KeyAgg, Musig2Error> {
+        let sorted = Self::sort_canonical(pks);
+        let party_index = sorted
             .iter()
-            .zip(&hash_vec)
-            .map(|(pk, hash)| pk.mul_scalar(hash).expect("should be valid private key"))
-            .collect();
+            .position(|pk| pk == my_key)
+            .ok_or(Musig2Error::InvalidPublicKey)?;
+        Self::key_aggregation_n(&sorted, party_index)
+    }
 
-        let sum = X_tilde_vec
+    /// Restore a persisted [`KeyAgg`] without re-running the aggregation
+    ///
+    /// Only the shape of the parts is checked here, use [`KeyAgg::from_parts_strict`]
+    /// to also check the coefficients against the signer set.
+    pub fn from_parts(
+        x_tilde: PublicKey,
+        coefficients: Vec<PrivateKey>,
+        my_index: usize,
+    ) -> Result<KeyAgg, Musig2Error> {
+        if my_index >= coefficients.len() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        if coefficients.iter().any(|a| a.0.is_zero()) {
+            return Err(Musig2Error::InvalidPrivateKey);
+        }
+        if x_tilde.0.is_infinity() || !x_tilde.0.is_valid_var() {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+
+        Ok(KeyAgg {
+            X_tilde: x_tilde,
+            a_i: coefficients[my_index].clone(),
+            coefficients,
+            party_index: my_index,
+        })
+    }
+
+    /// Restore a persisted [`KeyAgg`] and check that `X_tilde == sum(a_i * X_i)`
+    pub fn from_parts_strict(
+        x_tilde: PublicKey,
+        coefficients: Vec<PrivateKey>,
+        my_index: usize,
+        pks: &[PublicKey],
+    ) -> Result<KeyAgg, Musig2Error> {
+        if pks.len() != coefficients.len() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        let key_agg = Self::from_parts(x_tilde, coefficients, my_index)?;
+
+        let sum = PublicKey::sum_with_coefficients(pks, &key_agg.coefficients)?;
+
+        if sum != key_agg.X_tilde {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        Ok(key_agg)
+    }
+
+    /// Derive a one-time aggregate key for the session `session_id`
+    ///
+    /// Returns the tweaked key `X_tilde + t*G` and the tweak `t`, a tagged hash of `X_tilde`
+    /// and `session_id`. A signature under the tweaked key is obtained by adding `c * t` to the
+    /// aggregated `s`, where `c` is the challenge computed with the tweaked key.
+    pub fn derive_session_key(
+        &self,
+        session_id: &[u8; 32],
+    ) -> Result<(PublicKey, PrivateKey), Musig2Error> {
+        let mut h = sha2::Sha256::default().tagged(b"MuSig/session");
+        h.update(&self.X_tilde.x_coor()[..]);
+        h.update(&session_id[..]);
+        let tweak = PrivateKey::parse_slice(h.finalize().as_slice())?;
+        if tweak.0.is_zero() {
+            return Err(Musig2Error::InvalidPrivateKey);
+        }
+
+        let session_key = self
+            .X_tilde
+            .add_point(&PublicKey::create_from_private_key(&tweak))?;
+        Ok((session_key, tweak))
+    }
+
+    /// Whether `X_tilde` has odd `y`
+    ///
+    /// A BIP340 verifier only sees the x coordinate and lifts it to even `y`, so with odd `y`
+    /// the signers sign for `-X_tilde` instead, see [`KeyAgg::key_challenge`].
+    pub fn has_odd_y(&self) -> bool {
+        self.X_tilde.is_odd_y()
+    }
+
+    /// `X_tilde` lifted to even `y`, the key a BIP340 signature of the signers is valid under
+    pub fn x_only_key(&self) -> PublicKey {
+        if self.has_odd_y() {
+            self.X_tilde.neg()
+        } else {
+            self.X_tilde.clone()
+        }
+    }
+
+    /// Challenge the key part of a signature fragment is multiplied by
+    ///
+    /// It's the challenge `c` itself, negated if `X_tilde` [has odd `y`](KeyAgg::has_odd_y)
+    /// (BIP327), so that the fragments add up to a signature under [`KeyAgg::x_only_key`].
+    pub fn key_challenge(&self, c: &PrivateKey) -> PrivateKey {
+        if self.has_odd_y() {
+            c.neg()
+        } else {
+            c.clone()
+        }
+    }
+
+    /// Aggregate key tweaked by `tweak` as an x-only tweak, e.g. a BIP341 taproot tweak
+    ///
+    /// Follows BIP327: the tweak is added to [`KeyAgg::x_only_key`], i.e. `Q = g*X_tilde + t*G`
+    /// with `g = -1` if `X_tilde` has odd `y`, then `Q` is lifted to even `y` in turn. Returns
+    /// `Q` with even `y`, whether the signers sign for their negated keys, i.e. the parity
+    /// accumulator `gacc` times the parity of `Q` is `-1`, and the tweak accumulator `tacc`
+    /// times the parity of `Q`, which is added to the aggregated `s` as `c*tacc`.
+    pub fn tweaked_key(
+        &self,
+        tweak: &PrivateKey,
+    ) -> Result<(PublicKey, bool, PrivateKey), Musig2Error> {
+        // Starting from `gacc = 1` and `tacc = 0`, the tweak of the key lifted to even `y`
+        // gives `gacc = g` and `tacc = t`
+        let gacc_negated = self.has_odd_y();
+        let tweaked = self.x_only_key().add_tweak(tweak)?;
+        if tweaked.is_odd_y() {
+            Ok((tweaked.neg(), !gacc_negated, tweak.neg()))
+        } else {
+            Ok((tweaked, gacc_negated, tweak.clone()))
+        }
+    }
+
+    /// Key the signers sign for, i.e. the aggregate key optionally [tweaked](KeyAgg::tweaked_key)
+    /// and lifted to even `y`
+    ///
+    /// Returns the key, whether the signers sign for their negated keys and the tweak to add to
+    /// the aggregated `s` as `c*tacc`, if any.
+    pub fn signing_key(
+        &self,
+        tweak: Option<&PrivateKey>,
+    ) -> Result<(PublicKey, bool, Option<PrivateKey>), Musig2Error> {
+        match tweak {
+            Some(tweak) => {
+                let (tweaked, negated, tacc) = self.tweaked_key(tweak)?;
+                Ok((tweaked, negated, Some(tacc)))
+            }
+            None => Ok((self.x_only_key(), self.has_odd_y(), None)),
+        }
+    }
+}
+
+/// Collects the signer keys one at a time as they arrive, then aggregates them
+///
+/// The coefficient of every key commits to the whole signer set, so the aggregation itself
+/// runs in [`KeyAggBuilder::finalize`] once the last key has arrived. Until then the keys are
+/// only kept in signer order, in place of a separate list built after collecting them.
+#[derive(Debug, Clone)]
+pub struct KeyAggBuilder {
+    keys: Vec<Option<PublicKey>>,
+}
+
+impl KeyAggBuilder {
+    /// Expects the keys of `n` signers
+    pub fn new(n: usize) -> Self {
+        KeyAggBuilder {
+            keys: vec![None; n],
+        }
+    }
+
+    /// Adds the key of the signer at `index`, counting from zero
+    ///
+    /// Fails if `index` is out of range or its key was already added.
+    pub fn add(&mut self, index: usize, pk: PublicKey) -> Result<(), Musig2Error> {
+        match self.keys.get_mut(index) {
+            Some(slot @ None) => {
+                *slot = Some(pk);
+                Ok(())
+            }
+            Some(Some(_)) => Err(Musig2Error::InvalidPublicKey),
+            None => Err(Musig2Error::InvalidInputLength),
+        }
+    }
+
+    /// Whether the key of every signer was added
+    pub fn is_complete(&self) -> bool {
+        self.keys.iter().all(Option::is_some)
+    }
+
+    /// The collected keys in signer order, fails unless [complete](KeyAggBuilder::is_complete)
+    pub fn keys(&self) -> Result<Vec<PublicKey>, Musig2Error> {
+        if self.keys.is_empty() {
+            return Err(Musig2Error::InvalidInputLength);
+        }
+        self.keys
             .iter()
-            .skip(1)
-            .fold(X_tilde_vec[0].clone(), |acc, pk| {
-                acc.add_point(pk).expect("should be valid public key")
-            });
+            .cloned()
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Musig2Error::InvalidInputLength)
+    }
+
+    /// Aggregates the keys in canonical order, see [`KeyAgg::canonical`]
+    pub fn finalize(&self, my_key: &PublicKey) -> Result<KeyAgg, Musig2Error> {
+        KeyAgg::canonical(&self.keys()?, my_key)
+    }
+}
+
+/// Aggregation of a fixed signer set, see [`KeyAgg::precompute`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyAggCache {
+    /// Signer keys in canonical order
+    pks: Vec<PublicKey>,
+    pub X_tilde: PublicKey,
+    coefficients: Vec<PrivateKey>,
+}
+
+impl KeyAggCache {
+    /// Signer keys the cache was computed for, in canonical order
+    pub fn signers(&self) -> &[PublicKey] {
+        &self.pks
+    }
 
+    /// Whether the cache was computed for exactly the keys `pks`, in any order
+    pub fn matches(&self, pks: &[PublicKey]) -> bool {
+        KeyAgg::sort_canonical(pks) == self.pks
+    }
+
+    /// Same as [`KeyAgg::canonical`], without aggregating the keys again
+    ///
+    /// Fails if `pks` isn't the signer set of the cache or `my_key` isn't in it.
+    pub fn key_agg(&self, pks: &[PublicKey], my_key: &PublicKey) -> Result<KeyAgg, Musig2Error> {
+        if !self.matches(pks) {
+            return Err(Musig2Error::InvalidPublicKey);
+        }
+        let party_index = self
+            .pks
+            .iter()
+            .position(|pk| pk == my_key)
+            .ok_or(Musig2Error::InvalidPublicKey)?;
         Ok(KeyAgg {
-            X_tilde: sum,
-            a_i: hash_vec[party_index].clone(),
+            X_tilde: self.X_tilde.clone(),
+            a_i: self.coefficients[party_index].clone(),
+            coefficients: self.coefficients.clone(),
+            party_index,
         })
     }
 }
 
+/// Index of the key with the smallest compressed encoding, `None` for no keys
+///
+/// Lets the signers elect a coordinator without communicating: the first key in the
+/// [canonical order](KeyAgg::sort_canonical) wins, whatever order every party holds the keys in.
+pub fn select_coordinator(keys: &[PublicKey]) -> Option<usize> {
+    keys.iter()
+        .enumerate()
+        .min_by_key(|(_, pk)| *pk)
+        .map(|(i, _)| i)
+}
+
+/// Persisted form of [`KeyAgg`]
+///
+/// The keys use their own serde encoding, i.e. the aggregate key is compressed.
+#[derive(Serialize, Deserialize)]
+struct KeyAggParts {
+    x_tilde: PublicKey,
+    coefficients: Vec<PrivateKey>,
+    my_index: usize,
+}
+
+impl Serialize for KeyAgg {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        KeyAggParts {
+            x_tilde: self.X_tilde.clone(),
+            coefficients: self.coefficients.clone(),
+            my_index: self.party_index,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyAgg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let parts = KeyAggParts::deserialize(deserializer)?;
+        KeyAgg::from_parts(parts.x_tilde, parts.coefficients, parts.my_index)
+            .map_err(|e| D::Error::custom(format!("invalid key aggregation: {:?}", e)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EphemeralKey {
     pub keypair: KeyPair,
@@ -146,6 +534,68 @@ impl EphemeralKey {
         })
     }
 
+    /// Derive the `pad`-th nonce from the private key, the message and the signer set
+    ///
+    /// The private key is masked with the auxiliary randomness `aux_rand` first, as BIP340
+    /// recommends for nonce derivation. The signer set is hashed in canonical order, so every
+    /// ordering of `signers` gives the same nonce.
+    pub fn create_deterministic_with_aux(
+        x1: &KeyPair,
+        message: &[u8],
+        signers: &[PublicKey],
+        aux_rand: &[u8; 32],
+        pad: usize,
+    ) -> Result<EphemeralKey, Musig2Error> {
+        let mut aux = sha2::Sha256::default().tagged(b"MuSig/aux");
+        aux.update(aux_rand);
+        let mut masked = [0u8; 32];
+        for ((m, k), a) in masked
+            .iter_mut()
+            .zip(x1.private_key.serialize().iter())
+            .zip(aux.finalize().iter())
+        {
+            *m = k ^ a;
+        }
+
+        let mut h = sha2::Sha256::default().tagged(b"MuSig/deterministic/aux/nonce");
+        h.update(&masked);
+        masked.zeroize();
+        h.update(&(message.len() as u64).to_be_bytes());
+        h.update(message);
+        for pk in KeyAgg::sort_canonical(signers) {
+            h.update(&pk.serialize_compressed()[..]);
+        }
+        h.update(&(pad as u32).to_be_bytes());
+        let tagged = h.finalize();
+
+        let ephemeral_private_key = PrivateKey::parse_slice(tagged.as_slice())?;
+        let ephemeral_public_key = PublicKey::create_from_private_key(&ephemeral_private_key);
+
+        Ok(EphemeralKey {
+            keypair: KeyPair {
+                public_key: ephemeral_public_key,
+                private_key: ephemeral_private_key,
+            },
+        })
+    }
+
+    /// Draw a nonce from `rng`, unrelated to any key or message
+    pub fn create_random<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+    ) -> Result<EphemeralKey, Musig2Error> {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let ephemeral_private_key = PrivateKey::parse(&bytes)?;
+        let ephemeral_public_key = PublicKey::create_from_private_key(&ephemeral_private_key);
+
+        Ok(EphemeralKey {
+            keypair: KeyPair {
+                public_key: ephemeral_public_key,
+                private_key: ephemeral_private_key,
+            },
+        })
+    }
+
     pub fn create_vec_from_private_key(x1: &KeyPair) -> Result<Vec<EphemeralKey>, Musig2Error> {
         let mut EphemeralKeys_vec: Vec<EphemeralKey> = vec![];
         for i in 0..Nv {
@@ -171,6 +621,59 @@ pub fn sign(x: KeyPair) -> Result<(Vec<PublicKey>, State), Musig2Error> {
     ))
 }
 
+/// Like [`sign`], but the nonces are drawn from `rng`, so every call gives new ones
+pub fn sign_random<R: RngCore + CryptoRng + ?Sized>(
+    x: KeyPair,
+    rng: &mut R,
+) -> Result<(Vec<PublicKey>, State), Musig2Error> {
+    let ephk_vec = (0..Nv)
+        .map(|_| EphemeralKey::create_random(rng))
+        .collect::<Result<Vec<_>, _>>()?;
+    let msg = ephk_vec
+        .iter()
+        .map(|eph_key| eph_key.keypair.public_key.clone())
+        .collect();
+    Ok((
+        msg,
+        State {
+            keypair: x,
+            ephk_vec,
+        },
+    ))
+}
+
+/// Like [`sign`], but the nonces are bound to the message and the signer set, and the private
+/// key is masked with `aux_rand` before hashing
+///
+/// Passing fresh randomness as `aux_rand` hedges against both a weak RNG, the nonces still
+/// depend on the private key, and fault attacks on a purely deterministic derivation. The
+/// message is hashed into every nonce, so two different messages never yield the same nonce,
+/// whatever `aux_rand` is. `aux_rand` must be fresh for every session: reusing it for the same
+/// message and signers reproduces the nonces, and a co-signer restarting the session with
+/// another nonce of its own then gets two partial signatures for one nonce, which leaks the
+/// private key.
+pub fn sign_deterministic_with_aux(
+    x: KeyPair,
+    message: &[u8],
+    signers: &[PublicKey],
+    aux_rand: [u8; 32],
+) -> Result<(Vec<PublicKey>, State), Musig2Error> {
+    let ephk_vec = (0..Nv)
+        .map(|i| EphemeralKey::create_deterministic_with_aux(&x, message, signers, &aux_rand, i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let msg = ephk_vec
+        .iter()
+        .map(|eph_key| eph_key.keypair.public_key.clone())
+        .collect();
+    Ok((
+        msg,
+        State {
+            keypair: x,
+            ephk_vec,
+        },
+    ))
+}
+
 #[derive(Debug, Clone)]
 pub struct State {
     pub keypair: KeyPair,
@@ -178,17 +681,18 @@ pub struct State {
 }
 
 impl State {
-    fn add_ephemeral_keys(&self, msg_vec: &[Vec<PublicKey>]) -> Vec<PublicKey> {
-        let mut R_j_vec: Vec<PublicKey> = vec![];
-        for j in 0..Nv {
-            let pk_0j = self.ephk_vec[j].clone().keypair.public_key;
-            //println!("{:?}", self.ephk_vec[j]);
-            let R_j = msg_vec.iter().fold(pk_0j, |acc, ephk| {
-                acc.add_point(&ephk[j]).expect("should be valid public key")
-            });
-            R_j_vec.push(R_j);
-        }
-        R_j_vec
+    /// Our nonces added to the ones of the peers, fails if they cancel out
+    fn add_ephemeral_keys(
+        &self,
+        msg_vec: &[Vec<PublicKey>],
+    ) -> Result<Vec<PublicKey>, Musig2Error> {
+        let mut nonces = vec![self
+            .ephk_vec
+            .iter()
+            .map(|ephk| ephk.keypair.public_key.clone())
+            .collect()];
+        nonces.extend_from_slice(msg_vec);
+        aggregate_nonces(&nonces)
     }
 
     fn compute_signature_share(
@@ -228,43 +732,23 @@ impl State {
         party_index: usize,
     ) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
         let key_agg = KeyAgg::key_aggregation_n(pks, party_index)?;
-        let R_j_vec = self.add_ephemeral_keys(&msg_vec);
-        let mut b_coefficients: Vec<PrivateKey> = vec![PrivateKey(Scalar::from_int(1))];
-        for j in 1..Nv {
-            let mut hnon_preimage: Vec<PrivateKey> =
-                vec![PrivateKey::parse_slice(&key_agg.X_tilde.x_coor())?];
-            for i in R_j_vec.iter().take(Nv) {
-                hnon_preimage.push(PrivateKey::parse_slice(&i.x_coor())?);
-            }
-            hnon_preimage.push(PrivateKey::parse_slice(message)?);
-            hnon_preimage.push(PrivateKey(Scalar::from_int(j as u32)));
-            let mut h = sha2::Sha256::default().tagged(b"BIP0340/challenge");
-            for d in hnon_preimage.iter() {
-                h = h.add(d)
-            }
-            let tagged = h.finalize();
-            let b_j = PrivateKey::parse_slice(tagged.as_slice())?;
-            b_coefficients.push(b_j);
-        }
-        let R_0 = R_j_vec[0].mul_scalar(&b_coefficients[0])?;
-        let R = R_j_vec
-            .iter()
-            .zip(b_coefficients.clone())
-            .skip(1)
-            .map(|(R_j, b_j)| R_j.mul_scalar(&b_j))
-            .fold(
-                Ok(R_0),
-                |acc: Result<PublicKey, Musig2Error>, R_j: Result<PublicKey, Musig2Error>| {
-                    acc?.add_point(&R_j?)
-                },
-            )?;
-        let rx1: PrivateKey = PrivateKey::parse_slice(&R.x_coor())?;
-        let pkx1: PublicKey = key_agg.X_tilde;
-        let msg1 = Message::parse_slice(message)?;
-        let c = schnorrsig_challenge(&rx1, &pkx1, &msg1)?;
-        Ok((c.into(), R, b_coefficients))
+        let R_j_vec = self.add_ephemeral_keys(&msg_vec)?;
+        compute_challenge_params(message, &key_agg.X_tilde, &R_j_vec)
     }
 
+    /// Like [`State::compute_global_params`], but for the aggregate key `X` given by the
+    /// caller, e.g. a [tweaked](KeyAgg::tweaked_key) one
+    pub fn compute_params_for_key(
+        &self,
+        message: &[u8],
+        X: &PublicKey,
+        msg_vec: &[Vec<PublicKey>],
+    ) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
+        let R_j_vec = self.add_ephemeral_keys(msg_vec)?;
+        compute_challenge_params(message, X, &R_j_vec)
+    }
+
+    /// Signature fragment valid under the [x-only](KeyAgg::x_only_key) aggregate key
     pub fn sign_prime(
         &self,
         message: &[u8],
@@ -273,13 +757,88 @@ impl State {
         party_index: usize,
     ) -> Result<(StatePrime, PrivateKey), Musig2Error> {
         let key_agg = KeyAgg::key_aggregation_n(pks, party_index)?;
+        let R_j_vec = self.add_ephemeral_keys(&msg_vec)?;
+        let (c, R, b_coefficients) = compute_challenge_params(message, &key_agg.X_tilde, &R_j_vec)?;
+        self.sign_with_key_agg(&key_agg, &key_agg.key_challenge(&c), R, &b_coefficients)
+    }
 
+    /// Adaptor signature fragment, the nonce being offset by the adaptor point `T = t*G`
+    ///
+    /// The fragments add up to the `s'` of an [`AdaptorSignature`], which becomes a valid
+    /// signature under the [x-only](KeyAgg::x_only_key) aggregate key once `t` is known.
+    pub fn sign_prime_adaptor(
+        &self,
+        message: &[u8],
+        pks: &[PublicKey],
+        msg_vec: Vec<Vec<PublicKey>>,
+        party_index: usize,
+        adaptor: &PublicKey,
+    ) -> Result<(StatePrime, PrivateKey), Musig2Error> {
+        let key_agg = KeyAgg::key_aggregation_n(pks, party_index)?;
+        let R_j_vec = self.add_ephemeral_keys(&msg_vec)?;
         let (c, R, b_coefficients) =
-            self.compute_global_params(message, pks, msg_vec, party_index)?;
+            compute_adaptor_challenge_params(message, &key_agg.X_tilde, &R_j_vec, adaptor)?;
+        self.sign_with_key_agg(&key_agg, &key_agg.key_challenge(&c), R, &b_coefficients)
+    }
+
+    /// Like [`State::compute_params_for_key`], but with the nonce offset by the adaptor point,
+    /// see [`compute_adaptor_challenge_params`]
+    pub fn compute_adaptor_params_for_key(
+        &self,
+        message: &[u8],
+        X: &PublicKey,
+        msg_vec: &[Vec<PublicKey>],
+        adaptor: &PublicKey,
+    ) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
+        let R_j_vec = self.add_ephemeral_keys(msg_vec)?;
+        compute_adaptor_challenge_params(message, X, &R_j_vec, adaptor)
+    }
+
+    /// Like [`State::sign_prime`], but with the challenge `c` supplied by the caller
+    ///
+    /// Lets MuSig2 be composed into an outer protocol whose transcript yields the challenge.
+    /// Every signer must supply the identical `c`, otherwise the fragments don't add up.
+    pub fn sign_prime_with_challenge(
+        &self,
+        message: &[u8],
+        pks: &[PublicKey],
+        msg_vec: Vec<Vec<PublicKey>>,
+        party_index: usize,
+        c: &PrivateKey,
+    ) -> Result<(StatePrime, PrivateKey), Musig2Error> {
+        let key_agg = KeyAgg::key_aggregation_n(pks, party_index)?;
+        let R_j_vec = self.add_ephemeral_keys(&msg_vec)?;
+        let (_, R, b_coefficients) = compute_challenge_params(message, &key_agg.X_tilde, &R_j_vec)?;
+        self.sign_with_key_agg(&key_agg, &key_agg.key_challenge(c), R, &b_coefficients)
+    }
+
+    /// Signature fragment for the challenge `c` and the nonce parameters `R`, `b_coefficients`
+    ///
+    /// `c` multiplies only the key part of the fragment, so a signer signing for a negated
+    /// aggregate key passes `-c`, see [`KeyAgg::tweaked_key`].
+    pub fn sign_with_params(
+        &self,
+        pks: &[PublicKey],
+        party_index: usize,
+        c: &PrivateKey,
+        R: PublicKey,
+        b_coefficients: &[PrivateKey],
+    ) -> Result<(StatePrime, PrivateKey), Musig2Error> {
+        let key_agg = KeyAgg::key_aggregation_n(pks, party_index)?;
+        self.sign_with_key_agg(&key_agg, c, R, b_coefficients)
+    }
 
+    /// Like [`State::sign_with_params`], but with the keys already aggregated
+    pub fn sign_with_key_agg(
+        &self,
+        key_agg: &KeyAgg,
+        c: &PrivateKey,
+        R: PublicKey,
+        b_coefficients: &[PrivateKey],
+    ) -> Result<(StatePrime, PrivateKey), Musig2Error> {
         let is_odd = R.is_odd_y();
         let s_i =
-            self.compute_signature_share(&b_coefficients, &c, &self.keypair, &key_agg.a_i, is_odd)?;
+            self.compute_signature_share(b_coefficients, c, &self.keypair, &key_agg.a_i, is_odd)?;
         Ok((
             StatePrime {
                 R,
@@ -290,6 +849,105 @@ impl State {
     }
 }
 
+/// Aggregate key of the signers, without running a session
+///
+/// The keys are taken in canonical order like the signing rounds do, and the result is lifted
+/// to even `y` like [`KeyAgg::x_only_key`], so it's the key a session of the same signers signs
+/// for. Fails on an empty signer set and on a key given twice.
+pub fn aggregate_public_keys(pks: &[PublicKey]) -> Result<PublicKey, Musig2Error> {
+    let cache = KeyAgg::precompute(pks)?;
+    let signers = cache.signers();
+    if signers.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(Musig2Error::InvalidPublicKey);
+    }
+    Ok(cache.key_agg(signers, &signers[0])?.x_only_key())
+}
+
+/// Aggregate the nonces of all signers: R_j = sum(R_ij)
+///
+/// Fails with [`Musig2Error::InvalidPublicKey`] if some `R_j` is the point at infinity, e.g.
+/// a signer sent the negated nonces of another one.
+pub fn aggregate_nonces(nonces: &[Vec<PublicKey>]) -> Result<Vec<PublicKey>, Musig2Error> {
+    if nonces.is_empty() || nonces.iter().any(|n| n.len() != Nv) {
+        return Err(Musig2Error::InvalidInputLength);
+    }
+    (0..Nv)
+        .map(|j| {
+            let R_ij: Vec<PublicKey> = nonces.iter().map(|ephk| ephk[j].clone()).collect();
+            PublicKey::aggregate(&R_ij)
+        })
+        .collect()
+}
+
+/// Compute global parameters: c, R, and the b's coefficients
+///
+/// Only public data is involved, the aggregate key and the aggregated nonces.
+pub fn compute_challenge_params(
+    message: &[u8],
+    X_tilde: &PublicKey,
+    R_j_vec: &[PublicKey],
+) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
+    challenge_params(message, X_tilde, R_j_vec, None)
+}
+
+/// Like [`compute_challenge_params`], but the nonce `R` is offset by the adaptor point `T`
+///
+/// The `b` coefficients still commit to the aggregated nonces alone, only `R + T` goes into
+/// the challenge, see [`AdaptorSignature`].
+pub fn compute_adaptor_challenge_params(
+    message: &[u8],
+    X_tilde: &PublicKey,
+    R_j_vec: &[PublicKey],
+    adaptor: &PublicKey,
+) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
+    challenge_params(message, X_tilde, R_j_vec, Some(adaptor))
+}
+
+fn challenge_params(
+    message: &[u8],
+    X_tilde: &PublicKey,
+    R_j_vec: &[PublicKey],
+    adaptor: Option<&PublicKey>,
+) -> Result<(PrivateKey, PublicKey, Vec<PrivateKey>), Musig2Error> {
+    let mut b_coefficients: Vec<PrivateKey> = vec![PrivateKey(Scalar::from_int(1))];
+    for j in 1..Nv {
+        let mut hnon_preimage: Vec<PrivateKey> = vec![PrivateKey::parse_slice(&X_tilde.x_coor())?];
+        for i in R_j_vec.iter().take(Nv) {
+            hnon_preimage.push(PrivateKey::parse_slice(&i.x_coor())?);
+        }
+        hnon_preimage.push(PrivateKey::parse_slice(message)?);
+        hnon_preimage.push(PrivateKey(Scalar::from_int(j as u32)));
+        let mut h = sha2::Sha256::default().tagged(b"BIP0340/challenge");
+        for d in hnon_preimage.iter() {
+            h = h.add(d)
+        }
+        let tagged = h.finalize();
+        let b_j = PrivateKey::parse_slice(tagged.as_slice())?;
+        b_coefficients.push(b_j);
+    }
+    let R_0 = R_j_vec[0].mul_scalar(&b_coefficients[0])?;
+    let R = R_j_vec
+        .iter()
+        .zip(b_coefficients.clone())
+        .skip(1)
+        .map(|(R_j, b_j)| R_j.mul_scalar(&b_j))
+        .fold(
+            Ok(R_0),
+            |acc: Result<PublicKey, Musig2Error>, R_j: Result<PublicKey, Musig2Error>| {
+                acc?.add_point(&R_j?)
+            },
+        )?;
+    let R = match adaptor {
+        Some(T) => R.add_point(T)?,
+        None => R,
+    };
+    let rx1: PrivateKey = PrivateKey::parse_slice(&R.x_coor())?;
+    let pkx1: PublicKey = X_tilde.clone();
+    let msg1 = Message::parse_slice(message)?;
+    let c = schnorrsig_challenge(&rx1, &pkx1, &msg1)?;
+    Ok((c.into(), R, b_coefficients))
+}
+
 #[derive(Debug, Clone)]
 pub struct StatePrime {
     pub R: PublicKey,
@@ -308,6 +966,186 @@ pub fn sign_double_prime(
         })
 }
 
+/// Signature encrypted under the adaptor point `T = t*G`, e.g. for an atomic swap
+///
+/// `R` is the nonce of the completed signature, the aggregated nonce offset by `T`, while `s'`
+/// lacks the adaptor secret `t`. Anyone knowing `t` [completes](complete_adaptor) it, and
+/// anyone seeing the completed signature [extracts](extract_secret) `t` from it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdaptorSignature {
+    pub R: PublicKey,
+    pub s_prime: PrivateKey,
+    /// Adaptor point `T`
+    pub adaptor: PublicKey,
+}
+
+impl AdaptorSignature {
+    /// Checks that the signature completes to a valid signature on `msg` under `pubkey`
+    ///
+    /// `s'⋅G == R' + c⋅P - ±T`, where `R'` is `R` lifted to even `y` and `T` is negated along
+    /// with it.
+    pub fn verify(&self, msg: &Message, pubkey: &PublicKey) -> Result<bool, Musig2Error> {
+        let rx = PrivateKey::parse(&self.R.x_coor())?;
+        let P = PublicKey::parse_x_coor(&pubkey.x_coor())?;
+        let c: PrivateKey = schnorrsig_challenge(&rx, &P, msg)?.into();
+        let (R, T) = if self.R.is_odd_y() {
+            (self.R.neg(), self.adaptor.neg())
+        } else {
+            (self.R.clone(), self.adaptor.clone())
+        };
+        let expected = R.add_point(&P.mul_scalar(&c)?)?;
+        let actual = PublicKey::create_from_private_key(&self.s_prime).add_point(&T)?;
+        Ok(actual == expected)
+    }
+}
+
+/// Completes the adaptor signature `s_prime` with the adaptor secret `t`
+///
+/// Fails if `t` isn't the discrete logarithm of the adaptor point.
+pub fn complete_adaptor(
+    s_prime: &AdaptorSignature,
+    t: &PrivateKey,
+) -> Result<Signature, Musig2Error> {
+    if PublicKey::create_from_private_key(t) != s_prime.adaptor {
+        return Err(Musig2Error::InvalidPrivateKey);
+    }
+    let t = if s_prime.R.is_odd_y() {
+        t.neg()
+    } else {
+        t.clone()
+    };
+    Ok(Signature {
+        rx: PrivateKey::parse(&s_prime.R.x_coor())?,
+        s: s_prime.s_prime.add_scalar(&t)?,
+    })
+}
+
+/// Extracts the adaptor secret `t` from the completed signature `s` and the adaptor signature
+/// `s_prime` it was completed from
+pub fn extract_secret(
+    s: &Signature,
+    s_prime: &AdaptorSignature,
+) -> Result<PrivateKey, Musig2Error> {
+    if s.rx.serialize() != s_prime.R.x_coor() {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    let t = s.s.add_scalar(&s_prime.s_prime.neg())?;
+    let t = if s_prime.R.is_odd_y() { t.neg() } else { t };
+    if PublicKey::create_from_private_key(&t) != s_prime.adaptor {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    Ok(t)
+}
+
+/// Verify the signature fragment `s_i` of a single signer
+///
+/// `nonce` are the nonces the signer announced in the first round, `coeff` is its key
+/// aggregation coefficient `a_i`, while `challenge`, `b_coefficients` and the aggregate
+/// nonce `R` come from [`compute_challenge_params`]. Checks
+/// `s_i⋅G == ±sum(b_j⋅R_ij) + c⋅a_i⋅X_i`, the sign following the parity of `R`.
+///
+/// A failing fragment identifies the signer who caused the final signature to be invalid.
+pub fn verify_partial(
+    fragment: &PrivateKey,
+    party_key: &PublicKey,
+    nonce: &[PublicKey],
+    challenge: &PrivateKey,
+    coeff: &PrivateKey,
+    b_coefficients: &[PrivateKey],
+    R: &PublicKey,
+) -> bool {
+    match partial_commitment(party_key, nonce, challenge, coeff, b_coefficients, R) {
+        Ok(expected) => PublicKey::create_from_private_key(fragment) == expected,
+        Err(_) => false,
+    }
+}
+
+/// Point `s_i⋅G` the signature fragment of a single signer must match, see [`verify_partial`]
+///
+/// The relation is linear, so the sum of several fragments matches the sum of their points.
+pub fn partial_commitment(
+    party_key: &PublicKey,
+    nonce: &[PublicKey],
+    challenge: &PrivateKey,
+    coeff: &PrivateKey,
+    b_coefficients: &[PrivateKey],
+    R: &PublicKey,
+) -> Result<PublicKey, Musig2Error> {
+    if nonce.len() != Nv || b_coefficients.len() != Nv {
+        return Err(Musig2Error::InvalidInputLength);
+    }
+    let R_i = PublicKey::sum_with_coefficients(nonce, b_coefficients)?;
+    let R_i = if R.is_odd_y() { R_i.neg() } else { R_i };
+    R_i.add_point(&party_key.mul_scalar(&challenge.mul_scalar(coeff)?)?)
+}
+
+/// Public transcript showing that a signer's fragment was correct
+///
+/// Everything the fragment depends on is public, so a third party can recompute the key
+/// aggregation coefficient, the aggregate nonce and the challenge itself rather than trusting
+/// them. Only covers sessions whose challenge is computed by [`compute_challenge_params`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContributionProof {
+    pub message: Vec<u8>,
+    /// Keys of all signers in the order they were aggregated in
+    pub signers: Vec<PublicKey>,
+    /// Nonces announced by every signer, in the order of `signers`
+    pub nonces: Vec<Vec<PublicKey>>,
+    /// Position of the signer in `signers`
+    pub party_index: usize,
+    pub fragment: PrivateKey,
+}
+
+/// Records the contribution of the signer at `party_index`, see [`ContributionProof`]
+pub fn contribution_proof(
+    message: &[u8],
+    signers: &[PublicKey],
+    nonces: &[Vec<PublicKey>],
+    party_index: usize,
+    fragment: &PrivateKey,
+) -> ContributionProof {
+    ContributionProof {
+        message: message.to_vec(),
+        signers: signers.to_vec(),
+        nonces: nonces.to_vec(),
+        party_index,
+        fragment: fragment.clone(),
+    }
+}
+
+impl ContributionProof {
+    /// Checks that the signer's fragment satisfies the partial verification relation
+    pub fn verify(&self) -> bool {
+        let params = || -> Result<_, Musig2Error> {
+            if self.signers.len() != self.nonces.len() || self.party_index >= self.signers.len() {
+                return Err(Musig2Error::InvalidInputLength);
+            }
+            let key_agg = KeyAgg::key_aggregation_n(&self.signers, self.party_index)?;
+            let R_j_vec = aggregate_nonces(&self.nonces)?;
+            let (c, R, b_coefficients) =
+                compute_challenge_params(&self.message, &key_agg.X_tilde, &R_j_vec)?;
+            Ok((
+                key_agg.a_i.clone(),
+                key_agg.key_challenge(&c),
+                R,
+                b_coefficients,
+            ))
+        };
+        match params() {
+            Ok((a_i, c, R, b_coefficients)) => verify_partial(
+                &self.fragment,
+                &self.signers[self.party_index],
+                &self.nonces[self.party_index],
+                &c,
+                &a_i,
+                &b_coefficients,
+                &R,
+            ),
+            Err(_) => false,
+        }
+    }
+}
+
 /// Construct schnorr sig challenge
 /// hash(R_x|P_x|msg)
 pub fn schnorrsig_challenge(
@@ -327,13 +1165,28 @@ pub fn schnorrsig_challenge(
 }
 
 /// Verify a schnorr signature
+///
+/// Use [`VerifyContext`] instead when checking many signatures under the same key.
 pub fn verify(
     signature: &Signature,
     msg: &Message,
     pubkey: &PublicKey,
 ) -> Result<bool, Musig2Error> {
+    let (rx, s) = check_signature_encoding(signature)?;
+    VerifyContext::new(pubkey)?.verify_parsed(rx, s, msg)
+}
+
+/// Rejects degenerate, off-curve and overflowing signature encodings
+fn check_signature_encoding(
+    signature: &Signature,
+) -> Result<(PrivateKey, PrivateKey), Musig2Error> {
     let (rx, s) = (signature.rx.clone(), signature.s.clone());
 
+    // Zero `s` and all-zero `rx`, the encoding of the point at infinity, are degenerate forms
+    if s.0.is_zero() || rx.0.is_zero() {
+        return Err(Musig2Error::DegenerateSignature);
+    }
+
     // Determine if the x coordinate is on the elliptic curve
     // Also here it will be verified that there are two y's at point x
     if PublicKey::parse_x_coor(&rx.serialize()).is_err() {
@@ -347,45 +1200,141 @@ pub fn verify(
         return Err(Invalid);
     }
 
-    let P: Affine = pubkey.clone().into();
+    Ok((rx, s))
+}
 
-    if !P.is_valid_var() {
-        return Err(Invalid);
+/// Verifies schnorr signatures under one fixed public key
+///
+/// The multiplication tables of `secp256k1` are static and shared by every call
+/// of [`verify`] already, so what a context saves is the per-key work: the key
+/// is validated, lifted to jacobian coordinates and its x coordinate encoded
+/// for the challenge hash once, in [`VerifyContext::new`], instead of on every
+/// verification.
+#[derive(Debug, Clone)]
+pub struct VerifyContext {
+    pj: Jacobian,
+    pkx: PublicKey,
+}
+
+impl VerifyContext {
+    /// Prepares verification under `pubkey`, failing if it isn't a valid curve point
+    pub fn new(pubkey: &PublicKey) -> Result<Self, Musig2Error> {
+        let P: Affine = pubkey.clone().into();
+
+        if !P.is_valid_var() {
+            return Err(Invalid);
+        }
+
+        let mut pj = Jacobian::default();
+        pj.set_ge(&P);
+
+        Ok(Self { pj, pkx: P.into() })
     }
 
-    let mut pj = Jacobian::default();
-    pj.set_ge(&P);
+    /// Verify a schnorr signature under the key of this context
+    ///
+    /// Gives exactly the same result as the stateless [`verify`].
+    pub fn verify(&self, signature: &Signature, msg: &Message) -> Result<bool, Musig2Error> {
+        let (rx, s) = check_signature_encoding(signature)?;
+        self.verify_parsed(rx, s, msg)
+    }
 
-    let pkx: PublicKey = P.into();
+    fn verify_parsed(
+        &self,
+        rx: PrivateKey,
+        s: PrivateKey,
+        msg: &Message,
+    ) -> Result<bool, Musig2Error> {
+        let h = schnorrsig_challenge(&rx, &self.pkx, msg)?;
 
-    let h = schnorrsig_challenge(&rx, &pkx, msg)?;
+        let mut rj = Jacobian::default();
+        ECMULT_CONTEXT.ecmult(&mut rj, &self.pj, &h.neg(), &s.into());
 
-    let mut rj = Jacobian::default();
-    ECMULT_CONTEXT.ecmult(&mut rj, &pj, &h.neg(), &s.into());
+        let mut R = Affine::from_gej(&rj);
 
-    let mut R = Affine::from_gej(&rj);
+        if R.is_infinity() {
+            return Err(Musig2Error::DegenerateSignature);
+        }
 
-    if R.is_infinity() {
-        return Err(Invalid);
-    }
+        R.y.normalize_var();
+
+        if R.y.is_odd() {
+            return Err(Invalid);
+        }
 
-    R.y.normalize_var();
+        let mut rr = R.x;
+        rr.normalize();
 
-    if R.y.is_odd() {
-        return Err(Invalid);
+        // R = s⋅G - h⋅P, x(R) == rx
+        if rx == PrivateKey::parse_slice(&rr.b32())? {
+            Ok(true)
+        } else {
+            Err(Invalid)
+        }
     }
+}
 
-    let mut rr = R.x;
-    rr.normalize();
+/// Taproot output key of the `internal` key committing to the script tree `merkle_root`
+///
+/// Follows BIP341: `Q = P + t*G`, where `P` is the `internal` key with even `y` and
+/// `t = hash_TapTweak(P.x || merkle_root)`, the merkle root being omitted for key-spend only
+/// outputs. Returns `Q` and the tweak `t`.
+pub fn taproot_output_key(
+    internal: &PublicKey,
+    merkle_root: Option<[u8; 32]>,
+) -> Result<(PublicKey, PrivateKey), Musig2Error> {
+    let internal = PublicKey::parse_x_coor(&internal.x_coor())?;
+    let mut h = sha2::Sha256::default().tagged(b"TapTweak");
+    h.update(&internal.x_coor()[..]);
+    if let Some(merkle_root) = merkle_root {
+        h.update(&merkle_root[..]);
+    }
+    let tweak = PrivateKey::parse_slice(h.finalize().as_slice())?;
+    let output_key = internal.add_point(&PublicKey::create_from_private_key(&tweak))?;
+    Ok((output_key, tweak))
+}
 
-    // R = s⋅G - h⋅P, x(R) == rx
-    if rx == PrivateKey::parse_slice(&rr.b32())? {
-        Ok(true)
+/// Verify a taproot key-spend signature of the output key of `internal` and `merkle_root`
+///
+/// See [`taproot_output_key`].
+pub fn verify_taproot(
+    sig: &[u8; 64],
+    msg: &[u8],
+    internal: &PublicKey,
+    merkle_root: Option<[u8; 32]>,
+) -> Result<(), Musig2Error> {
+    let (output_key, _) = taproot_output_key(internal, merkle_root)?;
+    // The output key is x-only on chain
+    let output_key = PublicKey::parse_x_coor(&output_key.x_coor())?;
+    let signature = Signature::try_from(*sig)?;
+    let message = Message::parse_slice(msg)?;
+    if verify(&signature, &message, &output_key)? {
+        Ok(())
     } else {
-        Err(Invalid)
+        Err(Musig2Error::InvalidSignature)
     }
 }
 
+/// BIP340 signature of `msg` by the single signer `private_key`, and the x-only public key it
+/// verifies under
+#[cfg(test)]
+pub(crate) fn bip340_sign(private_key: &PrivateKey, msg: &Message) -> (Signature, PublicKey) {
+    let public_key = PublicKey::create_from_private_key(private_key);
+    let d = if public_key.is_odd_y() {
+        private_key.neg()
+    } else {
+        private_key.clone()
+    };
+    let public_key = PublicKey::parse_x_coor(&public_key.x_coor()).unwrap();
+    let k = PrivateKey::generate_random().unwrap();
+    let r = PublicKey::create_from_private_key(&k);
+    let k = if r.is_odd_y() { k.neg() } else { k };
+    let rx = PrivateKey::parse(&r.x_coor()).unwrap();
+    let e: PrivateKey = schnorrsig_challenge(&rx, &public_key, msg).unwrap().into();
+    let s = k.add_scalar(&e.mul_scalar(&d).unwrap()).unwrap();
+    (Signature { rx, s }, public_key)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::cli::protocals::signature::Signature;
@@ -410,8 +1359,449 @@ mod tests {
         verify(&s, &m, &pk).unwrap()
     }
 
+    #[test]
+    fn test_verify_rejects_degenerate_signature() {
+        let signature = Signature::try_from(SIGNATURE_4).unwrap();
+        let pk = PublicKey::try_from(PUBKEY_4).unwrap();
+        let m = Message::parse_slice(&hex::decode(MESSAGE_4).unwrap()[..]).unwrap();
+        let zero = PrivateKey(Scalar::from_int(0));
+
+        let zero_s = Signature {
+            rx: signature.rx.clone(),
+            s: zero.clone(),
+        };
+        assert_eq!(
+            verify(&zero_s, &m, &pk),
+            Err(Musig2Error::DegenerateSignature)
+        );
+
+        let infinity_r = Signature {
+            rx: zero,
+            s: signature.s,
+        };
+        assert_eq!(
+            verify(&infinity_r, &m, &pk),
+            Err(Musig2Error::DegenerateSignature)
+        );
+    }
+
+    #[test]
+    fn test_derive_session_key() {
+        let key_pairs: Vec<KeyPair> = (0..2).map(|_| KeyPair::create().unwrap()).collect();
+        let pks: Vec<PublicKey> = key_pairs.iter().map(|kp| kp.public_key.clone()).collect();
+        let key_agg = KeyAgg::key_aggregation_n(&pks, 0).unwrap();
+
+        // Aggregated secret `x = sum(a_i * x_i)` behind `X_tilde`
+        let x = key_pairs
+            .iter()
+            .zip(key_agg.coefficients.iter())
+            .map(|(kp, a)| kp.private_key.mul_scalar(a).unwrap())
+            .fold(PrivateKey(Scalar::from_int(0)), |acc, s| {
+                acc.add_scalar(&s).unwrap()
+            });
+
+        let (key_1, tweak_1) = key_agg.derive_session_key(&[1u8; 32]).unwrap();
+        let (key_2, tweak_2) = key_agg.derive_session_key(&[2u8; 32]).unwrap();
+        assert_ne!(key_1, key_2);
+        for (key, tweak) in [(key_1, tweak_1), (key_2, tweak_2)].iter() {
+            let secret = x.add_scalar(tweak).unwrap();
+            assert_eq!(&PublicKey::create_from_private_key(&secret), key);
+        }
+    }
+
+    #[test]
+    fn test_tweaked_key_matches_taproot_output_key() {
+        // x-only tweaking only differs from adding the tweak to `X_tilde` if it has odd `y`
+        let key_agg = loop {
+            let pks: Vec<PublicKey> = (0..2)
+                .map(|_| KeyPair::create().unwrap().public_key)
+                .collect();
+            let key_agg = KeyAgg::canonical(&pks, &pks[0]).unwrap();
+            if key_agg.has_odd_y() {
+                break key_agg;
+            }
+        };
+        let (output_key, tweak) = taproot_output_key(&key_agg.X_tilde, None).unwrap();
+
+        let (tweaked, negated, tacc) = key_agg.tweaked_key(&tweak).unwrap();
+        assert!(!tweaked.is_odd_y());
+        assert_eq!(tweaked.x_coor(), output_key.x_coor());
+        // The accumulators describe the tweaked key in terms of `X_tilde`
+        let signed_for = if negated {
+            key_agg.X_tilde.neg()
+        } else {
+            key_agg.X_tilde.clone()
+        };
+        assert_eq!(signed_for.add_tweak(&tacc).unwrap(), tweaked);
+    }
+
+    #[test]
+    fn test_signing_key_is_even_y() {
+        let key_agg = loop {
+            let pks: Vec<PublicKey> = (0..2)
+                .map(|_| KeyPair::create().unwrap().public_key)
+                .collect();
+            let key_agg = KeyAgg::canonical(&pks, &pks[0]).unwrap();
+            if key_agg.has_odd_y() {
+                break key_agg;
+            }
+        };
+        let (key, negated, tacc) = key_agg.signing_key(None).unwrap();
+        assert_eq!(key, key_agg.X_tilde.neg());
+        assert!(negated);
+        assert!(tacc.is_none());
+
+        let tweak = PrivateKey::parse(&[1; 32]).unwrap();
+        let (key, negated, tacc) = key_agg.signing_key(Some(&tweak)).unwrap();
+        assert_eq!(
+            (key, negated, tacc.unwrap()),
+            key_agg.tweaked_key(&tweak).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deterministic_nonce_with_aux() {
+        let key_pair = KeyPair::create().unwrap();
+        let other = KeyPair::create().unwrap();
+        let signers = vec![key_pair.public_key.clone(), other.public_key.clone()];
+        let reversed: Vec<PublicKey> = signers.iter().rev().cloned().collect();
+        let aux = [3u8; 32];
+
+        let (nonce_1, _) =
+            sign_deterministic_with_aux(key_pair.clone(), b"message", &signers, aux).unwrap();
+        let (nonce_2, _) =
+            sign_deterministic_with_aux(key_pair.clone(), b"message", &reversed, aux).unwrap();
+        assert_eq!(nonce_1, nonce_2);
+
+        // Different messages never share a nonce, even under the same auxiliary randomness
+        let (nonce_3, _) =
+            sign_deterministic_with_aux(key_pair.clone(), b"other message", &signers, aux).unwrap();
+        assert!(nonce_1.iter().all(|n| !nonce_3.contains(n)));
+
+        let (nonce_4, _) =
+            sign_deterministic_with_aux(key_pair.clone(), b"message", &signers, [4u8; 32]).unwrap();
+        assert!(nonce_1.iter().all(|n| !nonce_4.contains(n)));
+
+        // Another signer set never shares a nonce, even for the same message
+        let single = [key_pair.public_key.clone()];
+        let (nonce_5, _) = sign_deterministic_with_aux(key_pair, b"message", &single, aux).unwrap();
+        assert!(nonce_1.iter().all(|n| !nonce_5.contains(n)));
+    }
+
     #[test]
     fn test_verify() {
         assert!(check_verify(SIGNATURE_4, MESSAGE_4, PUBKEY_4));
     }
+
+    #[test]
+    fn odd_aggregate_key_signs_for_x_only_key() {
+        let message = [3u8; 32];
+        // Pick keys until the aggregate has odd `y`
+        let (keys, key_agg) = loop {
+            let keys = vec![KeyPair::create().unwrap(), KeyPair::create().unwrap()];
+            let pks: Vec<PublicKey> = keys.iter().map(|k| k.public_key.clone()).collect();
+            let key_agg = KeyAgg::key_aggregation_n(&pks, 0).unwrap();
+            if key_agg.has_odd_y() {
+                break (keys, key_agg);
+            }
+        };
+        let pks: Vec<PublicKey> = keys.iter().map(|k| k.public_key.clone()).collect();
+        let rounds: Vec<_> = keys.iter().map(|k| sign(k.clone()).unwrap()).collect();
+        let nonces: Vec<Vec<PublicKey>> = rounds.iter().map(|(n, _)| n.clone()).collect();
+
+        let (state_0, _) = rounds[0]
+            .1
+            .sign_prime(&message, &pks, vec![nonces[1].clone()], 0)
+            .unwrap();
+        let (state_1, s_1) = rounds[1]
+            .1
+            .sign_prime(&message, &pks, vec![nonces[0].clone()], 1)
+            .unwrap();
+        let s = sign_double_prime(state_0, &[s_1]).unwrap();
+        let signature = Signature {
+            rx: PrivateKey::parse_slice(&state_1.R.x_coor()).unwrap(),
+            s,
+        };
+
+        // BIP340 verifiers only know the x coordinate
+        let x_only = PublicKey::parse_x_coor(&key_agg.X_tilde.x_coor()).unwrap();
+        assert_eq!(x_only.serialize()[..], key_agg.x_only_key().serialize()[..]);
+        let m = Message::parse_slice(&message).unwrap();
+        assert_eq!(verify(&signature, &m, &x_only), Ok(true));
+        assert!(verify(&signature, &m, &key_agg.X_tilde).is_err());
+    }
+
+    #[test]
+    fn key_agg_cache_is_reused() {
+        let pks: Vec<PublicKey> = (0..3)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let cache = KeyAgg::precompute(&pks).unwrap();
+
+        let mut reversed = pks.clone();
+        reversed.reverse();
+        for (session_pks, my_key) in vec![(&pks, &pks[0]), (&reversed, &pks[2])] {
+            let cached = cache.key_agg(session_pks, my_key).unwrap();
+            let fresh = KeyAgg::canonical(session_pks, my_key).unwrap();
+            assert_eq!(cached.X_tilde, cache.X_tilde);
+            assert_eq!(cached.X_tilde, fresh.X_tilde);
+            assert_eq!(cached.a_i, fresh.a_i);
+            assert_eq!(cached.party_index, fresh.party_index);
+        }
+
+        // Another signer set
+        let other = vec![pks[0].clone(), KeyPair::create().unwrap().public_key];
+        assert!(!cache.matches(&other));
+        assert!(cache.key_agg(&other, &pks[0]).is_err());
+    }
+
+    #[test]
+    fn test_verify_context_matches_verify() {
+        let signature = Signature::try_from(SIGNATURE_4).unwrap();
+        let pk = PublicKey::try_from(PUBKEY_4).unwrap();
+        let m = Message::parse_slice(&hex::decode(MESSAGE_4).unwrap()[..]).unwrap();
+        let ctx = VerifyContext::new(&pk).unwrap();
+
+        assert_eq!(ctx.verify(&signature, &m), verify(&signature, &m, &pk));
+        assert_eq!(ctx.verify(&signature, &m), Ok(true));
+
+        let other = Message::parse(&[7u8; 32]);
+        assert_eq!(ctx.verify(&signature, &other), Err(Invalid));
+        assert_eq!(verify(&signature, &other, &pk), Err(Invalid));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_verify_context_1000_signatures() {
+        let signature = Signature::try_from(SIGNATURE_4).unwrap();
+        let pk = PublicKey::try_from(PUBKEY_4).unwrap();
+        let m = Message::parse_slice(&hex::decode(MESSAGE_4).unwrap()[..]).unwrap();
+
+        let start = std::time::Instant::now();
+        for _ in 0..1000 {
+            assert!(verify(&signature, &m, &pk).unwrap());
+        }
+        let stateless = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let ctx = VerifyContext::new(&pk).unwrap();
+        for _ in 0..1000 {
+            assert!(ctx.verify(&signature, &m).unwrap());
+        }
+        let with_context = start.elapsed();
+
+        println!(
+            "1000 verifications: stateless {:?}, with context {:?}",
+            stateless, with_context
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_agg_is_order_independent() {
+        let pks: Vec<PublicKey> = (0..4)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let mut reversed = pks.clone();
+        reversed.reverse();
+
+        let key_agg = KeyAgg::canonical(&pks, &pks[1]).unwrap();
+        let key_agg_reversed = KeyAgg::canonical(&reversed, &pks[1]).unwrap();
+        assert_eq!(key_agg.X_tilde, key_agg_reversed.X_tilde);
+        assert_eq!(key_agg.a_i, key_agg_reversed.a_i);
+        assert_eq!(key_agg.party_index, key_agg_reversed.party_index);
+    }
+
+    #[test]
+    fn test_key_agg_from_parts_round_trip() {
+        let pks: Vec<PublicKey> = (0..3)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let key_agg = KeyAgg::key_aggregation_n(&pks, 1).unwrap();
+
+        let json = serde_json::to_string(&key_agg).unwrap();
+        assert!(json.contains(&hex::encode(key_agg.X_tilde.serialize_compressed())));
+        let restored: KeyAgg = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.X_tilde, key_agg.X_tilde);
+        assert_eq!(restored.a_i, key_agg.a_i);
+        assert_eq!(restored.coefficients, key_agg.coefficients);
+
+        let strict = KeyAgg::from_parts_strict(
+            restored.X_tilde.clone(),
+            restored.coefficients.clone(),
+            1,
+            &pks,
+        );
+        assert!(strict.is_ok());
+
+        let mut coefficients = key_agg.coefficients.clone();
+        coefficients.swap(0, 2);
+        assert!(KeyAgg::from_parts_strict(key_agg.X_tilde, coefficients, 1, &pks).is_err());
+    }
+
+    #[test]
+    fn test_generated_key_pairs_are_valid() {
+        for _ in 0..1000 {
+            let kp = KeyPair::generate();
+            assert!(!kp.private_key.0.is_zero());
+            assert!(!kp.public_key.0.is_infinity());
+            assert!(kp.public_key.0.is_valid_var());
+            assert_eq!(
+                kp.public_key,
+                PublicKey::create_from_private_key(&kp.private_key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_coefficients_reproduce_aggregate_key() {
+        let pks: Vec<PublicKey> = (0..4)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let key_agg = KeyAgg::key_aggregation_n(&pks, 2).unwrap();
+
+        let mut sum = pks[0]
+            .mul_scalar(key_agg.coefficient_for(0).unwrap())
+            .unwrap();
+        for (i, pk) in pks.iter().enumerate().skip(1) {
+            let term = pk.mul_scalar(key_agg.coefficient_for(i).unwrap()).unwrap();
+            sum = sum.add_point(&term).unwrap();
+        }
+        assert_eq!(sum, key_agg.X_tilde);
+        assert_eq!(key_agg.coefficient_for(2), Some(&key_agg.a_i));
+        assert_eq!(key_agg.coefficient_for(pks.len()), None);
+    }
+
+    #[test]
+    fn test_verify_partial() {
+        let message = [7u8; 32];
+        let keys = vec![KeyPair::create().unwrap(), KeyPair::create().unwrap()];
+        let pks: Vec<PublicKey> = keys.iter().map(|k| k.public_key.clone()).collect();
+        let rounds: Vec<_> = keys.iter().map(|k| sign(k.clone()).unwrap()).collect();
+        let nonces: Vec<Vec<PublicKey>> = rounds.iter().map(|(n, _)| n.clone()).collect();
+
+        let key_agg = KeyAgg::key_aggregation_n(&pks, 0).unwrap();
+        let R_j_vec = aggregate_nonces(&nonces).unwrap();
+        let (c, R, b_coefficients) =
+            compute_challenge_params(&message, &key_agg.X_tilde, &R_j_vec).unwrap();
+        let (_, s_0) = rounds[0]
+            .1
+            .sign_prime(&message, &pks, vec![nonces[1].clone()], 0)
+            .unwrap();
+
+        let c = key_agg.key_challenge(&c);
+        let check = |fragment: &PrivateKey| {
+            verify_partial(
+                fragment,
+                &pks[0],
+                &nonces[0],
+                &c,
+                &key_agg.a_i,
+                &b_coefficients,
+                &R,
+            )
+        };
+        assert!(check(&s_0));
+        assert!(!check(
+            &s_0.add_scalar(&PrivateKey(Scalar::from_int(1))).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_select_coordinator_is_order_independent() {
+        let mut keys: Vec<PublicKey> = (0..5)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let coordinator = keys[select_coordinator(&keys).unwrap()].clone();
+        assert_eq!(coordinator, KeyAgg::sort_canonical(&keys)[0]);
+
+        for _ in 0..keys.len() {
+            keys.rotate_left(1);
+            assert_eq!(keys[select_coordinator(&keys).unwrap()], coordinator);
+        }
+        keys.reverse();
+        assert_eq!(keys[select_coordinator(&keys).unwrap()], coordinator);
+        assert_eq!(select_coordinator(&[]), None);
+    }
+
+    #[test]
+    fn test_incremental_key_aggregation_matches_batch() {
+        let pks: Vec<PublicKey> = (0..5)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let batch = KeyAgg::canonical(&pks, &pks[2]).unwrap();
+
+        let mut builder = KeyAggBuilder::new(pks.len());
+        for i in [3, 0, 4, 2, 1].iter() {
+            assert!(!builder.is_complete());
+            assert!(builder.finalize(&pks[2]).is_err());
+            builder.add(*i, pks[*i].clone()).unwrap();
+        }
+        assert!(builder.is_complete());
+        assert!(builder.add(1, pks[1].clone()).is_err());
+        assert!(builder.add(pks.len(), pks[1].clone()).is_err());
+
+        let incremental = builder.finalize(&pks[2]).unwrap();
+        assert_eq!(incremental.X_tilde, batch.X_tilde);
+        assert_eq!(incremental.a_i, batch.a_i);
+        assert_eq!(incremental.party_index, batch.party_index);
+        assert_eq!(builder.keys().unwrap(), pks);
+    }
+
+    #[test]
+    fn test_contribution_proof() {
+        let message = [9u8; 32];
+        let keys = vec![KeyPair::create().unwrap(), KeyPair::create().unwrap()];
+        let pks: Vec<PublicKey> = keys.iter().map(|k| k.public_key.clone()).collect();
+        let rounds: Vec<_> = keys.iter().map(|k| sign(k.clone()).unwrap()).collect();
+        let nonces: Vec<Vec<PublicKey>> = rounds.iter().map(|(n, _)| n.clone()).collect();
+        let (_, s_1) = rounds[1]
+            .1
+            .sign_prime(&message, &pks, vec![nonces[0].clone()], 1)
+            .unwrap();
+
+        let proof = contribution_proof(&message, &pks, &nonces, 1, &s_1);
+        assert!(proof.verify());
+
+        let blamed_wrong_signer = ContributionProof {
+            party_index: 0,
+            ..proof.clone()
+        };
+        assert!(!blamed_wrong_signer.verify());
+        let tampered = ContributionProof {
+            fragment: s_1.add_scalar(&PrivateKey(Scalar::from_int(1))).unwrap(),
+            ..proof
+        };
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn test_verify_taproot_key_spend() {
+        let message = [3u8; 32];
+        let merkle_root = [5u8; 32];
+        let internal = KeyPair::create().unwrap();
+
+        // Tweak the internal private key the way a taproot wallet does
+        let d = if internal.public_key.is_odd_y() {
+            internal.private_key.neg()
+        } else {
+            internal.private_key.clone()
+        };
+        let (output_key, tweak) =
+            taproot_output_key(&internal.public_key, Some(merkle_root)).unwrap();
+        let d = d.add_scalar(&tweak).unwrap();
+
+        // BIP340 signature under the output key
+        let (signature, q) = bip340_sign(&d, &Message::parse_slice(&message).unwrap());
+        assert_eq!(q.x_coor(), output_key.x_coor());
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&signature.rx.serialize());
+        sig[32..].copy_from_slice(&signature.s.serialize());
+
+        assert_eq!(
+            verify_taproot(&sig, &message, &internal.public_key, Some(merkle_root)),
+            Ok(())
+        );
+        assert!(verify_taproot(&sig, &message, &internal.public_key, None).is_err());
+        assert!(verify_taproot(&sig, &message, &internal.public_key, Some([6u8; 32])).is_err());
+    }
 }
@@ -76,7 +76,7 @@ mod tests1 {
             assert!(verify(
                 &signature,
                 &Message::parse_slice(&message).unwrap(),
-                &party1_key_agg.X_tilde
+                &party1_key_agg.x_only_key()
             )
             .unwrap());
         }
@@ -2,8 +2,14 @@
 //!
 //! More details:
 //! [`BIP340`]: https://github.com/bitcoin/bips/blob/master/bip-0340.mediawiki#design
-use super::{error::Musig2Error, key::PrivateKey};
+use super::{
+    error::Musig2Error,
+    key::{PrivateKey, PublicKey},
+    musig2::{KeyAgg, NONCE_COUNT},
+};
 use core::convert::{TryFrom, TryInto};
+use digest::Digest;
+use light_bitcoin_schnorr::taggedhash::*;
 
 /// A standard for 64-byte Schnorr signatures over the elliptic curve secp256k1
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -12,6 +18,99 @@ pub struct Signature {
     pub s: PrivateKey,
 }
 
+/// DER `SEQUENCE { INTEGER rx, INTEGER s }` container, for tooling which predates Schnorr
+///
+/// Only the container is shared with ECDSA: the signature is still a BIP340 Schnorr signature
+/// and must be verified as such.
+impl Signature {
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_der(&self.rx.serialize(), &self.s.serialize())
+    }
+
+    pub fn from_der(bytes: &[u8]) -> Result<Self, Musig2Error> {
+        if bytes.len() < 2 || bytes[0] != 0x30 || usize::from(bytes[1]) != bytes.len() - 2 {
+            return Err(Musig2Error::InvalidSignature);
+        }
+        let mut reader = &bytes[2..];
+        let rx = decode_der_integer(&mut reader)?;
+        let s = decode_der_integer(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Musig2Error::InvalidSignature);
+        }
+        Ok(Signature {
+            rx: PrivateKey::parse(&rx)?,
+            s: PrivateKey::parse(&s)?,
+        })
+    }
+}
+
+/// Session id derived from the signer set, the `message` and the [NONCE_COUNT]
+///
+/// The signers are hashed in [canonical order](KeyAgg::sort_canonical), so all parties derive
+/// the same id without coordinating, whatever order every party holds the keys in.
+pub fn derive_session_id(signers: &[PublicKey], message: &[u8]) -> [u8; 32] {
+    let mut h = sha2::Sha256::default().tagged(b"MuSig/session_id");
+    for pk in KeyAgg::sort_canonical(signers) {
+        h.update(&pk.serialize_compressed()[..]);
+    }
+    h.update(&(message.len() as u64).to_be_bytes());
+    h.update(message);
+    h.update(&(NONCE_COUNT as u32).to_be_bytes());
+
+    let mut id = [0u8; 32];
+    id.copy_from_slice(h.finalize().as_slice());
+    id
+}
+
+/// DER encoding of the 32-byte big-endian `rx` and `s`, see [Signature::to_der]
+pub fn encode_der(rx: &[u8; 32], s: &[u8; 32]) -> Vec<u8> {
+    let rx = encode_der_integer(rx);
+    let s = encode_der_integer(s);
+    let mut der = vec![0x30, (rx.len() + s.len()) as u8];
+    der.extend(rx);
+    der.extend(s);
+    der
+}
+
+/// Minimal encoding of an unsigned integer, prefixed with a zero byte if the top bit is set
+fn encode_der_integer(value: &[u8; 32]) -> Vec<u8> {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(31);
+    let mut int = vec![0x02, 0];
+    if value[start] & 0x80 != 0 {
+        int.push(0);
+    }
+    int.extend_from_slice(&value[start..]);
+    int[1] = (int.len() - 2) as u8;
+    int
+}
+
+fn decode_der_integer(reader: &mut &[u8]) -> Result<[u8; 32], Musig2Error> {
+    if reader.len() < 2 || reader[0] != 0x02 {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    let len = usize::from(reader[1]);
+    if len == 0 || reader.len() < 2 + len {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    let int = &reader[2..2 + len];
+    *reader = &reader[2 + len..];
+    // Negative or not minimally encoded
+    if int[0] & 0x80 != 0 || (len > 1 && int[0] == 0 && int[1] & 0x80 == 0) {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    let int = if int[0] == 0 && len > 1 {
+        &int[1..]
+    } else {
+        int
+    };
+    if int.len() > 32 {
+        return Err(Musig2Error::InvalidSignature);
+    }
+    let mut value = [0u8; 32];
+    value[32 - int.len()..].copy_from_slice(int);
+    Ok(value)
+}
+
 impl TryFrom<&str> for Signature {
     type Error = Musig2Error;
 
@@ -37,3 +136,26 @@ impl TryFrom<[u8; 64]> for Signature {
         Ok(Signature { rx, s })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::protocals::musig2::KeyPair;
+
+    #[test]
+    fn test_derive_session_id_is_order_independent() {
+        let mut keys: Vec<PublicKey> = (0..4)
+            .map(|_| KeyPair::create().unwrap().public_key)
+            .collect();
+        let id = derive_session_id(&keys, b"message");
+
+        for _ in 0..keys.len() {
+            keys.rotate_left(1);
+            assert_eq!(derive_session_id(&keys, b"message"), id);
+        }
+        keys.reverse();
+        assert_eq!(derive_session_id(&keys, b"message"), id);
+        assert_ne!(derive_session_id(&keys, b"other message"), id);
+        assert_ne!(derive_session_id(&keys[1..], b"message"), id);
+    }
+}